@@ -0,0 +1,253 @@
+use binaryninja::architecture::{Architecture, CoreArchitecture, Register};
+use binaryninja::callingconvention::{CallingConvention as CoreCallingConvention, CallingConventionBase};
+use registers::ArchKind;
+use state::State;
+
+// Where a call's arguments and return values live. Stack offsets are measured from the stack
+// pointer at the call instruction, before any return address is pushed.
+#[derive(Clone)]
+pub struct CallingConvention {
+    pub name: String,
+    pub int_args: Vec<String>,
+    pub float_args: Vec<String>,
+    pub int_return: String,
+    // Holds the high half of values wider than a register
+    pub int_return_high: Option<String>,
+    pub float_return: Option<String>,
+    pub slot_size: usize,
+    // Space the caller reserves above the stack arguments, like the Windows x64 shadow space
+    pub stack_offset: u64,
+    // Arguments take a position in both register lists, so float argument n is in float register n
+    pub shared_slots: bool,
+    // Variadic floating point arguments are passed like integers
+    pub variadic_in_int: bool,
+    // Doubles passed in integer slots start at an even slot
+    pub align_double: bool,
+    // The callee pops its stack arguments when it returns
+    pub callee_cleans: bool,
+    // Size of a C long, which Windows keeps at 4 bytes on 64 bit targets
+    pub long_size: usize,
+}
+
+fn names(regs: &[&str]) -> Vec<String> {
+    return regs.iter().map(|r| String::from(*r)).collect();
+}
+
+impl CallingConvention {
+    pub fn sysv() -> CallingConvention {
+        return CallingConvention {
+            name: String::from("sysv"),
+            int_args: names(&["rdi", "rsi", "rdx", "rcx", "r8", "r9"]),
+            float_args: names(&["xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7"]),
+            int_return: String::from("rax"),
+            int_return_high: Some(String::from("rdx")),
+            float_return: Some(String::from("xmm0")),
+            slot_size: 8,
+            stack_offset: 0,
+            shared_slots: false,
+            variadic_in_int: false,
+            align_double: false,
+            callee_cleans: false,
+            long_size: 8,
+        }
+    }
+
+    pub fn win64() -> CallingConvention {
+        return CallingConvention {
+            name: String::from("win64"),
+            int_args: names(&["rcx", "rdx", "r8", "r9"]),
+            float_args: names(&["xmm0", "xmm1", "xmm2", "xmm3"]),
+            stack_offset: 0x20,
+            shared_slots: true,
+            variadic_in_int: true,
+            long_size: 4,
+            ..CallingConvention::sysv()
+        }
+    }
+
+    pub fn cdecl() -> CallingConvention {
+        return CallingConvention {
+            name: String::from("cdecl"),
+            int_args: Vec::new(),
+            float_args: Vec::new(),
+            int_return: String::from("eax"),
+            int_return_high: Some(String::from("edx")),
+            // x87 results come back in st0 which isn't modelled as a plain register
+            float_return: None,
+            slot_size: 4,
+            variadic_in_int: true,
+            long_size: 4,
+            ..CallingConvention::sysv()
+        }
+    }
+
+    pub fn stdcall() -> CallingConvention {
+        return CallingConvention {
+            name: String::from("stdcall"),
+            callee_cleans: true,
+            ..CallingConvention::cdecl()
+        }
+    }
+
+    pub fn aapcs() -> CallingConvention {
+        return CallingConvention {
+            name: String::from("aapcs"),
+            int_args: names(&["r0", "r1", "r2", "r3"]),
+            float_args: names(&["d0", "d1", "d2", "d3", "d4", "d5", "d6", "d7"]),
+            int_return: String::from("r0"),
+            int_return_high: Some(String::from("r1")),
+            float_return: Some(String::from("d0")),
+            slot_size: 4,
+            variadic_in_int: true,
+            align_double: true,
+            long_size: 4,
+            ..CallingConvention::sysv()
+        }
+    }
+
+    pub fn aarch64() -> CallingConvention {
+        return CallingConvention {
+            name: String::from("aarch64"),
+            int_args: names(&["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"]),
+            float_args: names(&["d0", "d1", "d2", "d3", "d4", "d5", "d6", "d7"]),
+            int_return: String::from("x0"),
+            int_return_high: Some(String::from("x1")),
+            float_return: Some(String::from("d0")),
+            ..CallingConvention::sysv()
+        }
+    }
+
+    pub fn riscv(address_size: usize) -> CallingConvention {
+        return CallingConvention {
+            name: String::from("riscv"),
+            int_args: names(&["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"]),
+            float_args: names(&["fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7"]),
+            int_return: String::from("a0"),
+            int_return_high: Some(String::from("a1")),
+            float_return: Some(String::from("fa0")),
+            slot_size: address_size,
+            variadic_in_int: true,
+            align_double: address_size == 4,
+            long_size: address_size,
+            ..CallingConvention::sysv()
+        }
+    }
+
+    pub fn by_name(name: &str, address_size: usize) -> Option<CallingConvention> {
+        return match name {
+            "sysv" => Some(CallingConvention::sysv()),
+            "win64" => Some(CallingConvention::win64()),
+            "cdecl" => Some(CallingConvention::cdecl()),
+            "stdcall" => Some(CallingConvention::stdcall()),
+            "aapcs" => Some(CallingConvention::aapcs()),
+            "aarch64" => Some(CallingConvention::aarch64()),
+            "riscv" => Some(CallingConvention::riscv(address_size)),
+            _ => None,
+        };
+    }
+
+    // Default convention for an architecture when Binary Ninja doesn't give us one
+    pub fn for_arch(arch: &CoreArchitecture, windows: bool) -> CallingConvention {
+        return match ArchKind::of(arch) {
+            ArchKind::X86_64 => if windows { CallingConvention::win64() } else { CallingConvention::sysv() },
+            ArchKind::X86 => CallingConvention::cdecl(),
+            ArchKind::Aarch64 => CallingConvention::aarch64(),
+            ArchKind::Arm => CallingConvention::aapcs(),
+            ArchKind::Riscv => CallingConvention::riscv(arch.address_size()),
+            ArchKind::Unknown => {
+                warn!("No calling convention known for {}, using System V", arch.name().as_ref());
+                CallingConvention::sysv()
+            }
+        };
+    }
+
+    // Builds the convention from Binary Ninja's, filling in what it doesn't describe from the architecture
+    pub fn from_binja(cc: &CoreCallingConvention<CoreArchitecture>, arch: &CoreArchitecture, windows: bool) -> CallingConvention {
+        let defaults = CallingConvention::for_arch(arch, windows);
+        let int_args: Vec<String> = cc.int_arg_registers().iter().map(|r| String::from(r.name().as_ref())).collect();
+        let slot_size = arch.address_size();
+
+        return CallingConvention {
+            name: String::from(cc.name().as_ref()),
+            float_args: cc.float_arg_registers().iter().map(|r| String::from(r.name().as_ref())).collect(),
+            int_return: match cc.return_int_reg() {
+                Some(reg) => String::from(reg.name().as_ref()),
+                None => defaults.int_return.clone(),
+            },
+            int_return_high: cc.return_hi_int_reg().map(|r| String::from(r.name().as_ref())),
+            float_return: cc.return_float_reg().map(|r| String::from(r.name().as_ref())),
+            slot_size: slot_size,
+            stack_offset: if cc.reserved_stack_space_for_arg_registers() { (int_args.len() * slot_size) as u64 } else { 0 },
+            shared_slots: cc.arg_registers_shared_index(),
+            callee_cleans: cc.stack_adjusted_on_return(),
+            int_args: int_args,
+            ..defaults
+        }
+    }
+
+    // Integer argument n, arguments past the registers are on the stack
+    pub fn arg(&self, state: &State, n: usize) -> Result<u64, String> {
+        if n < self.int_args.len() {
+            return Ok(state.regs.get(&self.int_args[n]) as u64);
+        }
+        let addr = state.regs.sp().wrapping_add(self.stack_offset + ((n - self.int_args.len()) * self.slot_size) as u64);
+        return Ok(state.memory.load(addr, self.slot_size)? as u64);
+    }
+
+    // Floating point argument n. For conventions with shared slots n is the argument's position,
+    // otherwise it counts floating point arguments only.
+    pub fn float_arg(&self, state: &State, n: usize) -> Result<f64, String> {
+        if n < self.float_args.len() {
+            return Ok(f64::from_bits(state.regs.get(&self.float_args[n]) as u64));
+        }
+        return Err(format!("Floating point argument {} is passed on the stack in {}", n, self.name));
+    }
+
+    // Reads the next variadic double, advancing the integer and float argument positions past it
+    pub fn variadic_float(&self, state: &State, next: &mut usize, floats: &mut usize) -> Result<f64, String> {
+        if !self.variadic_in_int && *floats < self.float_args.len() {
+            *floats += 1;
+            return self.float_arg(state, *floats - 1);
+        }
+
+        if self.slot_size >= 8 {
+            *next += 1;
+            return Ok(f64::from_bits(self.arg(state, *next - 1)?));
+        }
+        if self.align_double && *next % 2 == 1 {
+            *next += 1;
+        }
+        let low = self.arg(state, *next)?;
+        let high = self.arg(state, *next + 1)?;
+        *next += 2;
+        return Ok(f64::from_bits((high << 32) | (low & 0xffffffff)));
+    }
+
+    // Values wider than the return register put their high half in the high return register
+    pub fn set_return(&self, state: &mut State, value: u64) {
+        state.regs.set(&self.int_return, value as u128);
+        if self.slot_size < 8 {
+            if let Some(ref high) = self.int_return_high {
+                state.regs.set(high, (value >> (self.slot_size * 8)) as u128);
+            }
+        }
+    }
+
+    pub fn set_float_return(&self, state: &mut State, value: f64) -> Result<(), String> {
+        match self.float_return {
+            Some(ref reg) => {
+                state.regs.set(reg, value.to_bits() as u128);
+                return Ok(());
+            }
+            None => return Err(format!("No floating point return register in {}", self.name)),
+        }
+    }
+
+    // Pops the stack arguments for conventions where the callee cleans up
+    pub fn cleanup(&self, state: &mut State, arg_count: usize) {
+        if self.callee_cleans && arg_count > self.int_args.len() {
+            let sp = state.regs.sp().wrapping_add(((arg_count - self.int_args.len()) * self.slot_size) as u64);
+            state.regs.set_sp(sp);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use cpython::{Python, PyDict, PyResult};
+use registers::Registers;
 
 pub struct Debugger<'p> {
     pub py: Python<'p>,
@@ -64,21 +65,21 @@ impl<'p> Debugger<'p> {
         }
     }
 
-    pub fn regs_print(&self) {
-
-        let rax: u64 = self.py.eval("dbg.reg_read(\"rax\")", None, None).expect("Failed to get rax").extract(self.py).expect("Failed to get rax");
-        let rbx: u64 = self.py.eval("dbg.reg_read(\"rbx\")", None, None).expect("Failed to get rbx").extract(self.py).expect("Failed to get rbx");
-        let rcx: u64 = self.py.eval("dbg.reg_read(\"rcx\")", None, None).expect("Failed to get rcx").extract(self.py).expect("Failed to get rcx");
-        let rdx: u64 = self.py.eval("dbg.reg_read(\"rdx\")", None, None).expect("Failed to get rdx").extract(self.py).expect("Failed to get rdx");
-        let rsi: u64 = self.py.eval("dbg.reg_read(\"rsi\")", None, None).expect("Failed to get rsi").extract(self.py).expect("Failed to get rsi");
-        let rdi: u64 = self.py.eval("dbg.reg_read(\"rdi\")", None, None).expect("Failed to get rdi").extract(self.py).expect("Failed to get rdi");
-        let rip: u64 = self.py.eval("dbg.reg_read(\"rip\")", None, None).expect("Failed to get rip").extract(self.py).expect("Failed to get rip");
-        let rbp: u64 = self.py.eval("dbg.reg_read(\"rbp\")", None, None).expect("Failed to get rbp").extract(self.py).expect("Failed to get rbp");
-        let rsp: u64 = self.py.eval("dbg.reg_read(\"rsp\")", None, None).expect("Failed to get rsp").extract(self.py).expect("Failed to get rsp");
-
-        info!("rax: 0x{:012x}\trbx: 0x{:012x}\trcx: 0x{:012x}", rax, rbx, rcx);
-        info!("rdx: 0x{:012x}\trsi: 0x{:012x}\trdi: 0x{:012x}", rdx, rsi, rdi);
-        info!("rip: 0x{:012x}\trbp: 0x{:012x}\trsp: 0x{:012x}", rip, rbp, rsp);
+    // Prints the architecture's full width registers as reported by the debug adapter
+    pub fn regs_print(&self, regs: &Registers) {
+        let names = regs.full_width_names();
+        for chunk in names.chunks(3) {
+            let line: Vec<String> = chunk.iter().map(|name| format!("{}: 0x{:012x}", name, self.reg_read(name))).collect();
+            info!("{}", line.join("\t"));
+        }
+    }
+
+    // Copies the debuggee's registers into an emulator register file
+    pub fn regs_sync(&self, regs: &mut Registers) {
+        for name in regs.full_width_names() {
+            let value = self.reg_read(&name);
+            regs.set(&name, value as u128);
+        }
     }
 
     pub fn reg_read(&self, reg: &str) -> u64 {
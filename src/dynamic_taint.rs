@@ -0,0 +1,475 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use program::*;
+use state::State;
+use registers::Registers;
+use expression::{self, Expr};
+use procedures::{self, Procedures};
+use syscalls::{self, Abi};
+use shadow::{self, Context, Labels, RegisterShadow, Shadow, Taint, TaintSet};
+use taint_policy::*;
+use taint_tracker::{record, MAX_BUFFER, MAX_TRAIL};
+
+// Arguments whose taint is passed on to the return value of a call answered by a procedure
+const PROCEDURE_ARGS: usize = 6;
+
+// Procedures whose return value depends on the data their arguments point to, with how many of
+// their leading arguments are pointers they read
+pub const READS_POINTEE: [(&str, usize); 14] = [
+    ("atoi", 1), ("atol", 1), ("atoll", 1), ("strtol", 1), ("strtoul", 1), ("strtoll", 1), ("strtoull", 1),
+    ("strlen", 1), ("strcmp", 2), ("strncmp", 2), ("memcmp", 2), ("strchr", 1), ("strrchr", 1), ("strstr", 2),
+];
+
+// Leading arguments of a procedure whose pointees its return value depends on
+pub fn pointees_read(name: &str) -> usize {
+    return READS_POINTEE.iter().find(|&&(procedure, _)| procedure == name).map_or(0, |&(_, n)| n);
+}
+
+// Integer arguments a call passes, from the callee's type when it has one and otherwise from
+// the procedure that models it
+fn arguments(program: &Program, procedures: &Procedures, addr: u64, name: &str) -> usize {
+    if let Some((_, Some(parameters))) = program.calling_convention_of(addr) {
+        return parameters.min(PROCEDURE_ARGS);
+    }
+    return procedures.arguments(name).map_or(PROCEDURE_ARGS, |n| n.min(PROCEDURE_ARGS));
+}
+
+// A call about to be made. Whether it entered the callee or was answered by a procedure is only
+// known after the emulator stepped over it.
+struct PendingCall {
+    name: String,
+    function: u64,
+    index: usize,
+    args: Vec<u64>,
+    // Labels of the arguments, and of what they point to for the procedures that read it
+    taint: TaintSet,
+    // Bytes a copy procedure moves, with their destination. None is the returned pointer.
+    copy: Option<(Option<u64>, Taint)>,
+}
+
+// A syscall about to be made, reads label the bytes they put in memory by file and offset
+struct PendingSyscall {
+    name: &'static str,
+    args: Vec<u64>,
+    path: String,
+    pos: usize,
+}
+
+// Taint shadowing the emulator's state byte for byte. The emulator propagates it before and
+// after each step, so it follows the path the program really takes.
+pub struct DynamicTaint {
+    pub regs: RegisterShadow,
+    pub flags: HashMap<String, TaintSet>,
+    pub memory: Shadow<u64>,
+    pub labels: Labels,
+    pub policy: Policy,
+    // Every time tainted data reached a sink
+    pub flows: Vec<Flow>,
+    // Branches and jumps whose condition or target depends on tainted data, and memory accesses
+    // through tainted pointers, with the labels that reach them
+    pub tainted_branches: Vec<(u64, TaintSet)>,
+    pub tainted_pointers: Vec<(u64, TaintSet)>,
+    // Instructions that moved tainted data
+    pub trail: Vec<u64>,
+    call: Option<PendingCall>,
+    syscall: Option<PendingSyscall>,
+}
+
+// Evaluates an expression against the state without changing it, undoing the flags it writes
+// and any pops
+fn evaluate(state: &mut State, expr: &Expr) -> Option<u64> {
+    let flags = state.flags.values.clone();
+    let sp = state.regs.sp();
+    let value = expression::eval_expression(expr.clone(), state).ok().map(|value| value as u64);
+    state.flags.values = flags;
+    state.regs.set_sp(sp);
+    return value;
+}
+
+// The shadow with the concrete state, loads read the bytes at the address the state gives
+struct Concrete<'s> {
+    taint: &'s DynamicTaint,
+    state: RefCell<&'s mut State>,
+}
+
+impl<'s> Context for Concrete<'s> {
+    fn reg(&self, name: &str) -> Taint {
+        return self.taint.regs.get(&self.state.borrow().regs, name);
+    }
+
+    fn flag(&self, name: &str) -> TaintSet {
+        return self.taint.flags.get(name).cloned().unwrap_or(TaintSet::new());
+    }
+
+    fn flags(&self) -> TaintSet {
+        let mut set = TaintSet::new();
+        for flag in self.taint.flags.values() {
+            set.extend(flag.iter().cloned());
+        }
+        return set;
+    }
+
+    fn load(&self, addr: &Expr, size: usize) -> Taint {
+        return match evaluate(&mut self.state.borrow_mut(), addr) {
+            Some(addr) => self.taint.memory.read(&addr, size),
+            None => Taint::clean(size),
+        };
+    }
+
+    fn pop(&self, size: usize) -> Taint {
+        return self.taint.memory.read(&self.state.borrow().regs.sp(), size);
+    }
+}
+
+impl DynamicTaint {
+    pub fn new() -> DynamicTaint {
+        return DynamicTaint {
+            regs: RegisterShadow::new(),
+            flags: HashMap::new(),
+            memory: Shadow::new(),
+            labels: Labels::new(),
+            policy: Policy::libc(),
+            flows: Vec::new(),
+            tainted_branches: Vec::new(),
+            tainted_pointers: Vec::new(),
+            trail: Vec::new(),
+            call: None,
+            syscall: None,
+        }
+    }
+
+    // Taints every byte of the register with the label
+    pub fn taint_register(&mut self, registers: &Registers, reg: &str, label: &str) {
+        let label = self.labels.add(label);
+        let size = registers.info(reg).map(|def| def.size).unwrap_or(registers.address_size);
+        let mut set = TaintSet::new();
+        set.insert(label);
+        self.regs.set(registers, reg, Taint::uniform(&set, size));
+    }
+
+    // Labels each byte with the name and its index, like "argv[1] byte 3"
+    pub fn taint_memory(&mut self, addr: u64, size: usize, name: &str) {
+        let mut taint = Taint::clean(size);
+        for i in 0..size {
+            taint.bytes[i].insert(self.labels.add(&format!("{} byte {}", name, i)));
+        }
+        self.memory.write(&addr, &taint);
+    }
+
+    pub fn describe(&self, set: &TaintSet) -> Vec<String> {
+        return self.labels.describe(set);
+    }
+
+    // Full width names of the registers holding tainted data
+    pub fn regs_tainted(&self) -> Vec<String> {
+        return self.regs.tainted();
+    }
+
+    fn taint_of(&self, state: &mut State, expr: &Expr) -> Taint {
+        let concrete = Concrete { taint: self, state: RefCell::new(state) };
+        return shadow::expression_taint(&concrete, expr);
+    }
+
+    fn mark(&mut self, addr: u64) {
+        if self.trail.last() != Some(&addr) {
+            self.trail.push(addr);
+        }
+        if self.trail.len() > MAX_TRAIL {
+            self.trail.remove(0);
+        }
+    }
+
+    // Records tainted data reaching a sink, once per sink and address
+    fn flow(&mut self, addr: u64, sink: &Sink, set: &TaintSet) {
+        let sink = sink.to_string();
+        if self.flows.iter().any(|flow| flow.addr == addr && flow.sink == sink) {
+            return;
+        }
+        let flow = Flow {
+            sources: self.labels.describe(set),
+            sink: sink,
+            addr: addr,
+            path: self.trail.clone(),
+        };
+        warn!("{}", flow.describe());
+        self.flows.push(flow);
+    }
+
+    // Records loads whose address depends on tainted data
+    fn check_pointers(&mut self, state: &mut State, expr: &Expr) {
+        let mut set = TaintSet::new();
+        for addr in shadow::load_addresses(expr) {
+            set.extend(self.taint_of(state, addr).union());
+        }
+        if !set.is_empty() {
+            info!("0x{:x} Access through a tainted pointer", state.addr);
+            record(&mut self.tainted_pointers, state.addr, set);
+        }
+    }
+
+    fn write_flags(&mut self, expr: &Expr, set: TaintSet) {
+        for flag in shadow::flags_written(expr) {
+            if set.is_empty() {
+                self.flags.remove(&flag);
+            } else {
+                self.flags.insert(flag, set.clone());
+            }
+        }
+    }
+
+    // Propagates taint over an instruction before the emulator executes it, while the state still
+    // holds the values its expressions read
+    pub fn before(&mut self, inst: &Inst, state: &mut State, program: &Program, procedures: &Procedures, abi: &Abi) {
+        use LlilInst::*;
+
+        let addr = inst.addr;
+        match inst.llil {
+            SetReg(ref llil) => {
+                self.check_pointers(state, &llil.expr);
+                let taint = self.taint_of(state, &llil.expr);
+                self.write_flags(&llil.expr, taint.union());
+                if taint.is_tainted() {
+                    info!("0x{:x} Tainted register {}", addr, llil.reg);
+                    self.mark(addr);
+                }
+                self.regs.set(&state.regs, &llil.reg, taint);
+            }
+            SetRegSplit(ref llil) => {
+                self.check_pointers(state, &llil.source_expr);
+                let taint = self.taint_of(state, &llil.source_expr);
+                self.write_flags(&llil.source_expr, taint.union());
+                if taint.is_tainted() {
+                    self.mark(addr);
+                }
+                let size = llil.size;
+                let low = Taint { bytes: taint.bytes.iter().take(size).cloned().collect() };
+                let high = Taint { bytes: taint.bytes.iter().skip(size).cloned().collect() };
+                self.regs.set(&state.regs, &llil.dest_reg_low, low.resize(size, false));
+                self.regs.set(&state.regs, &llil.dest_reg_high, high.resize(size, false));
+            }
+            SetFlag(ref llil) => {
+                self.check_pointers(state, &llil.expr);
+                let set = self.taint_of(state, &llil.expr).union();
+                if set.is_empty() {
+                    self.flags.remove(&llil.flag);
+                } else {
+                    self.flags.insert(llil.flag.clone(), set);
+                }
+            }
+            Store(ref llil) => {
+                self.check_pointers(state, &llil.source_expr);
+                self.check_pointers(state, &llil.dest_mem_expr);
+                let pointer = self.taint_of(state, &llil.dest_mem_expr).union();
+                if !pointer.is_empty() {
+                    record(&mut self.tainted_pointers, addr, pointer);
+                }
+                let taint = self.taint_of(state, &llil.source_expr).resize(llil.size, false);
+                self.write_flags(&llil.source_expr, taint.union());
+                if taint.is_tainted() {
+                    self.mark(addr);
+                }
+                if let Some(dest) = evaluate(state, &llil.dest_mem_expr) {
+                    self.memory.write(&dest, &taint);
+                }
+            }
+            Push(ref llil) => {
+                self.check_pointers(state, &llil.expr);
+                let taint = self.taint_of(state, &llil.expr).resize(llil.size, false);
+                if taint.is_tainted() {
+                    self.mark(addr);
+                }
+                let sp = state.regs.sp().wrapping_sub(llil.size as u64);
+                self.memory.write(&sp, &taint);
+            }
+            If(ref llil) => {
+                self.check_pointers(state, &llil.condition);
+                let set = self.taint_of(state, &llil.condition).union();
+                if !set.is_empty() {
+                    info!("0x{:x} Branch depends on {}", addr, self.labels.describe(&set).join(", "));
+                    self.mark(addr);
+                    record(&mut self.tainted_branches, addr, set);
+                }
+            }
+            Jump(ref llil) => self.jump(state, addr, &llil.target),
+            JumpTo(ref llil) => self.jump(state, addr, &llil.target),
+            Call(ref llil) => {
+                self.check_pointers(state, &llil.target);
+                let set = self.taint_of(state, &llil.target).union();
+                if !set.is_empty() {
+                    let sinks: Vec<Sink> = self.policy.sinks.iter().filter(|sink| match **sink {
+                        Sink::CallTarget => true,
+                        _ => false,
+                    }).cloned().collect();
+                    for sink in sinks {
+                        self.flow(addr, &sink, &set);
+                    }
+                }
+                self.call(state, program, procedures, &llil.target);
+            }
+            Syscall() => {
+                let number = state.regs.get(&abi.number) as u64;
+                if let Some(name) = syscalls::name(abi.table, number) {
+                    let args: Vec<u64> = abi.args.iter().map(|reg| state.regs.get(reg) as u64).collect();
+                    let (path, pos) = match state.fs.descriptor(args[0]) {
+                        Ok(descriptor) => (descriptor.path.clone(), descriptor.pos),
+                        Err(_) => (String::new(), 0),
+                    };
+                    self.syscall = Some(PendingSyscall { name: name, args: args, path: path, pos: pos });
+                }
+            }
+            Value(ref expr) => {
+                self.check_pointers(state, expr);
+                let set = self.taint_of(state, expr).union();
+                self.write_flags(expr, set);
+            }
+            Ret(_) | Goto(_) | NoRet() | Nop() | Bp() | Trap() | LlilInst::Undef() => (),
+        }
+    }
+
+    // Finishes calls and syscalls once the emulator has stepped over them
+    pub fn after(&mut self, state: &mut State, abi: &Abi) {
+        if let Some(call) = self.call.take() {
+            if state.function == call.function && state.index == call.index + 1 {
+                self.returned(state, call);
+            } else if state.regs.link_register.is_none() {
+                // The return address pushed on entering the callee is clean
+                let word = state.regs.address_size;
+                self.memory.write(&state.regs.sp(), &Taint::clean(word));
+            } else if let Some(lr) = state.regs.link_register.clone() {
+                self.regs.set(&state.regs, &lr, Taint::clean(state.regs.address_size));
+            }
+        }
+
+        if let Some(syscall) = self.syscall.take() {
+            let ret = state.regs.get(&abi.ret) as u64;
+            self.regs.set(&state.regs, &abi.ret, Taint::clean(state.regs.address_size));
+            // Errors come back as small negative numbers
+            let read = syscall.name == "read" && ret != 0 && ret < syscalls::errno(4095);
+            if read {
+                let mut taint = Taint::clean(ret as usize);
+                for i in 0..ret as usize {
+                    taint.bytes[i].insert(self.labels.add(&format!("{} byte {}", syscall.path, syscall.pos + i)));
+                }
+                self.memory.write(&syscall.args[1], &taint);
+                info!("0x{:x} Tainted 0x{:x} bytes read from {}", state.addr, ret, syscall.path);
+                self.mark(state.addr);
+            }
+        }
+    }
+
+    fn jump(&mut self, state: &mut State, addr: u64, target: &Expr) {
+        self.check_pointers(state, target);
+        let set = self.taint_of(state, target).union();
+        if !set.is_empty() {
+            info!("0x{:x} Jump target depends on {}", addr, self.labels.describe(&set).join(", "));
+            record(&mut self.tainted_branches, addr, set);
+        }
+    }
+
+    // Labels on an argument of the call about to be made, in a register or on the stack
+    fn arg_taint(&self, state: &State, n: usize) -> TaintSet {
+        let callconv = &state.callconv;
+        if let Some(reg) = callconv.int_args.get(n) {
+            return self.regs.get(&state.regs, reg).union();
+        }
+        let slot = state.regs.sp().wrapping_add(callconv.stack_offset + ((n - callconv.int_args.len()) * callconv.slot_size) as u64);
+        return self.memory.read(&slot, callconv.slot_size).union();
+    }
+
+    // Taint of the string at an address, including its terminator
+    fn string_taint(&self, state: &State, addr: u64) -> Taint {
+        let len = procedures::read_string(state, addr).map(|bytes| bytes.len()).unwrap_or(0);
+        return self.memory.read(&addr, (len + 1).min(MAX_BUFFER));
+    }
+
+    // Checks the sinks of the function being called and keeps what its procedure will need
+    fn call(&mut self, state: &mut State, program: &Program, procedures: &Procedures, target: &Expr) {
+        let slot = expression::got_slot(target);
+        let addr = match evaluate(state, target) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let name = match slot.and_then(|slot| program.import_symbol(slot)).or_else(|| program.resolve_import(addr)) {
+            Some(import) => import.name,
+            None => program.function_at(addr).map(|function| function.name.clone()).unwrap_or(String::new()),
+        };
+        let args: Vec<u64> = (0..PROCEDURE_ARGS).map(|n| state.arg(n).unwrap_or(0)).collect();
+
+        for sink in self.policy.sinks_of(&name) {
+            if let Sink::Argument { arg, pointee, .. } = sink {
+                let mut set = self.arg_taint(state, arg);
+                if pointee {
+                    set.extend(self.string_taint(state, args[arg]).union());
+                }
+                if !set.is_empty() {
+                    self.flow(state.addr, &sink, &set);
+                }
+            }
+        }
+
+        // The return value depends on the arguments the procedure takes, and the data it reads
+        let mut taint = TaintSet::new();
+        for n in 0..arguments(program, procedures, addr, &name) {
+            taint.extend(self.arg_taint(state, n));
+        }
+        for n in 0..pointees_read(&name) {
+            if state.memory.is_mapped(args[n]) {
+                taint.extend(self.string_taint(state, args[n]).union());
+            }
+        }
+
+        // Copies keep the labels of every byte they move
+        let copy = match name.as_str() {
+            "memcpy" | "memmove" | "strncpy" => Some((Some(args[0]), self.memory.read(&args[1], (args[2] as usize).min(MAX_BUFFER)))),
+            "strcpy" => Some((Some(args[0]), self.string_taint(state, args[1]))),
+            "strcat" => {
+                let end = procedures::read_string(state, args[0]).map(|bytes| bytes.len()).unwrap_or(0);
+                Some((Some(args[0] + end as u64), self.string_taint(state, args[1])))
+            }
+            "strdup" => Some((None, self.string_taint(state, args[0]))),
+            _ => None,
+        };
+
+        self.call = Some(PendingCall {
+            name: name,
+            function: state.function,
+            index: state.index,
+            args: args,
+            taint: taint,
+            copy: copy,
+        });
+    }
+
+    // A call that was answered by a procedure or skipped, the return value gets the labels of the
+    // arguments and the policy's sources apply
+    fn returned(&mut self, state: &mut State, call: PendingCall) {
+        let ret = state.callconv.int_return.clone();
+        let size = state.regs.info(&ret).map(|def| def.size).unwrap_or(state.regs.address_size);
+        self.regs.set(&state.regs, &ret, Taint::uniform(&call.taint, size));
+
+        if let Some((dest, taint)) = call.copy {
+            let dest = dest.unwrap_or(state.regs.get(&ret) as u64);
+            if taint.is_tainted() {
+                self.mark(state.addr);
+            }
+            self.memory.write(&dest, &taint);
+        }
+
+        let site = format!("{}@0x{:x}", call.name, state.addr);
+        for source in self.policy.sources_of(&call.name) {
+            match source {
+                Source::Return { .. } => self.taint_register(&state.regs, &ret, &site),
+                Source::Buffer { arg, ref size, .. } => {
+                    let size = match *size {
+                        Size::Fixed(size) => size,
+                        Size::Arg(n) => call.args[n] as usize,
+                    };
+                    self.taint_memory(call.args[arg], size.min(MAX_BUFFER), &site);
+                }
+                _ => continue,
+            }
+            info!("0x{:x} Tainted {}", state.addr, source);
+            self.mark(state.addr);
+        }
+    }
+}
@@ -1,205 +1,318 @@
-use std::collections::HashMap;
+use std::mem;
 use program::*;
 use state::*;
-use procedures;
+use procedures::Procedures;
+use syscalls::{Abi, Syscalls};
+use process::{self, Process};
+use tls;
+use expression;
+use dynamic_taint::DynamicTaint;
 
 pub struct Emulator<'a> {
     pub program: &'a Program<'a>,
     pub state: State,
+    // Calls to these run the procedure instead of the called code
+    pub procedures: Procedures,
+    pub syscalls: Syscalls,
+    // Taint shadowing the state, propagated with every step when it's set
+    pub taint: Option<DynamicTaint>,
 }
 
 impl<'a> Emulator<'a> {
     pub fn new(program: &'a Program, state: State) -> Emulator<'a> {
+        let abi = match program.arch() {
+            Ok(arch) => Abi::for_arch(&arch),
+            Err(_) => Abi::x86_64(),
+        };
         return Emulator {
             program: program,
             state: state,
+            procedures: Procedures::libc(),
+            syscalls: Syscalls::linux(abi),
+            taint: None,
         }
     }
-    // Searches for the entry point and starts working with it there.
+    // Starts at the entry point with the stack the kernel would set up.
     pub fn entry(program: &'a Program) -> Emulator<'a> {
-        for function in program.functions() {
-            if function.name.eq("_start") {
-
-                let temp = function.llil_start();
-
-                return Emulator {
-                    program: program,
-                    state: State {
-                        addr: temp,
-                        index: 0,
-                        memory: Memory::new(),
-                        regs: Regsx64::new(),
-                        call_stack: Vec::new(),
-                        stdin: String::from(""),
-                    },
-                }
-            }
-        }
-        return Emulator {
-            program: program,
-            state: State {
-                addr: 0,
-                index: 0,
-                memory: Memory::new(),
-                regs: Regsx64::new(),
-                call_stack: Vec::new(),
-                stdin: String::from(""),
-            },
+        let mut emulator = match Emulator::at_address(program, program.entry_point()) {
+            Ok(emulator) => emulator,
+            Err(_) => Emulator::at_function(program, "_start"),
+        };
+        if let Err(err) = emulator.start_process(&Process::new(program)) {
+            error!("Failed to set up the process: {}", err);
         }
+        return emulator;
     }
-    // Searches for the main function and starts working with it there. 
+    // Searches for the main function and starts working with it there.
     pub fn main(program: &'a Program) -> Emulator<'a> {
+        let mut emulator = Emulator::at_function(program, "main");
+        if let Err(err) = emulator.start_process(&Process::new(program)) {
+            error!("Failed to set up the process: {}", err);
+        }
+        return emulator;
+    }
+
+    // Builds the initial stack from the process's arguments and environment. Starting anywhere
+    // but the entry point is treated as a call to main(argc, argv, envp).
+    pub fn start_process(&mut self, process: &Process) -> Result<(), String> {
+        let stack = process::build_stack(&mut self.state, self.program, process)?;
+        tls::setup(&mut self.state, self.program)?;
+        let entry = self.program.location_of(self.program.entry_point()).ok();
+        if entry != Some((self.state.function, self.state.index)) {
+            process::enter_main(&mut self.state, &stack)?;
+        }
+        return Ok(());
+    }
+
+    pub fn at_function(program: &'a Program, name: &str) -> Emulator<'a> {
+        let mut state = State::from_program(program);
         for function in program.functions() {
-            if function.name.eq("main") {
-
-                let temp = function.llil_start();
-
-                return Emulator {
-                    program: program,
-                    state: State {
-                        addr: temp,
-                        index: 0,
-                        memory: Memory::new(),
-                        regs: Regsx64::new(),
-                        call_stack: Vec::new(),
-                        stdin: String::from(""),
-                    },
-                }
+            if function.name.eq_ignore_ascii_case(name) {
+                state.addr = function.addr;
+                state.function = function.addr;
+                state.index = 0;
+                break;
             }
         }
-        return Emulator {
-            program: program,
-            state: State {
-                addr: 0,
-                index: 0,
-                memory: Memory::new(),
-                regs: Regsx64::new(),
-                call_stack: Vec::new(),
-                stdin: String::from(""),
-            },
-        }
+        return Emulator::new(program, state);
+    }
+
+    // Starts at any address inside a function rather than at its start
+    pub fn at_address(program: &'a Program, addr: u64) -> Result<Emulator<'a>, String> {
+        let (function, index) = program.location_of(addr)?;
+        let mut state = State::from_program(program);
+        state.addr = addr;
+        state.function = function;
+        state.index = index;
+        return Ok(Emulator::new(program, state));
     }
 
+    // Starts shadowing the state with taint, sources and sinks come from the policy
+    pub fn enable_taint(&mut self) -> &mut DynamicTaint {
+        return self.taint.get_or_insert(DynamicTaint::new());
+    }
+
+    // Executes the LLIL instruction at the current function and index
     pub fn step(&mut self) -> Result<String, String>{
         use LlilInst::*;
-        use expression::Expr::*;
         use expression::eval_expression;
-        let indexes: Vec<Index> = self.program.insts_at_addr(self.state.addr).expect("No instructions ad address");
-        for index in indexes {
-            let inst = index.inst;
-        
-
-        
-        // let inst: Inst = self.program.inst_at(self.state.addr).expect("No such instruction");
-        
+
+        if let Some(status) = self.state.exited {
+            return Err(format!("Program exited with status {}", status));
+        }
+
+        let inst = self.program.llil_at(self.state.function, self.state.index)?;
+        self.state.addr = inst.addr;
+
+        if let Some(ref mut taint) = self.taint {
+            taint.before(&inst, &mut self.state, self.program, &self.procedures, &self.syscalls.abi);
+        }
+
+        // Set when an instruction transfers control somewhere other than the next instruction
+        let mut next: Option<(u64, usize)> = None;
+
         // This is where we check which instruction it is and set the state to the necessary values.
-            match inst.llil {
-                // set the value of llil thet 
-                SetReg(llil) => {
-                    let val = eval_expression(llil.expr, &self.state);
-                    self.state.regs.set(llil.reg, val);
-                    info!("0x{:x} Set register to 0x{:x}", self.state.addr, val);
-                }
-                SetRegSplit(llil) => {
-                    let val = eval_expression(llil.source_expr, &self.state);
-                    let high: u32 = (val >> 32) as u32;
-                    let low: u32 = val as u32;
-
-                    self.state.regs.set(llil.dest_reg_low, low as u64);
-                    self.state.regs.set(llil.dest_reg_high, high as u64);
-                    info!("0x{:x} Set registers to 0x{:x} and 0x{:x}", self.state.addr, low, high);
+        match inst.llil {
+            SetReg(llil) => {
+                let val = eval_expression(llil.expr, &mut self.state)?;
+                self.state.regs.set(&llil.reg, val);
+                info!("0x{:x} Set register {} to 0x{:x}", self.state.addr, llil.reg, val);
+            }
+            SetRegSplit(llil) => {
+                let val = eval_expression(llil.source_expr, &mut self.state)?;
+                let high = expression::truncate(val >> (llil.size * 8), llil.size);
+                let low = expression::truncate(val, llil.size);
+
+                self.state.regs.set(&llil.dest_reg_low, low);
+                self.state.regs.set(&llil.dest_reg_high, high);
+                info!("0x{:x} Set registers to 0x{:x} and 0x{:x}", self.state.addr, low, high);
+            }
+            SetFlag(llil) => {
+                let val = eval_expression(llil.expr, &mut self.state)?;
+                self.state.flags.set(&llil.flag, val != 0);
+                info!("0x{:x} Set flag {} to {}", self.state.addr, llil.flag, val != 0);
+            }
+            Push(llil) => {
+                let val = eval_expression(llil.expr, &mut self.state)?;
+                let sp = self.state.regs.sp().wrapping_sub(llil.size as u64);
+                self.state.regs.set_sp(sp);
+                self.state.memory.store(sp, llil.size, val)?;
+                info!("0x{:x} Pushed 0x{:x}", self.state.addr, val);
+            }
+            If(llil) => {
+                let result = eval_expression(llil.condition, &mut self.state)?;
+                info!("0x{:x} Compare returned 0x{:x}", self.state.addr, result);
+                if result == 0 {
+                    next = Some((self.state.function, llil.target_false));
+                    info!(" > Branching false - index = {}", llil.target_false);
+                } else {
+                    next = Some((self.state.function, llil.target_true));
+                    info!(" > Branching true - index = {}", llil.target_true);
                 }
-                Push(llil) => {
-                    match llil.expr {
-                        Reg(r) => {
-                            info!("0x{:x} Pushing register {}", self.state.addr, r.name);
-                            self.state.memory.store(self.state.regs.rsp, self.state.regs.get(r.name));
-                        },
-                        _ => info!("0x{:x} Pushing other", self.state.addr),
+            }
+            Store(llil) => {
+                let val = eval_expression(llil.source_expr, &mut self.state)?;
+                let addr = eval_expression(llil.dest_mem_expr, &mut self.state)? as u64;
+
+                self.state.memory.store(addr, llil.size, val)?;
+
+                info!("0x{:x} Stored 0x{:x} at 0x{:x}", self.state.addr, val, addr);
+            }
+            Call(llil) => {
+                // Calls through a GOT slot are resolved by the slot, its contents may not be bound yet
+                let slot = expression::got_slot(&llil.target);
+                let target = eval_expression(llil.target, &mut self.state)? as u64;
+                info!("0x{:x} Call to function at address 0x{:x}", self.state.addr, target);
+                next = self.call(target, slot)?;
+            }
+            Goto(llil) => {
+                info!("0x{:x} Goto instruction at {}", self.state.addr, llil.target);
+                next = Some((self.state.function, llil.target));
+            }
+            Jump(llil) => {
+                let target = eval_expression(llil.target, &mut self.state)? as u64;
+                info!("0x{:x} Jump instruction to 0x{:x}", self.state.addr, target);
+                next = Some(self.resolve(target)?);
+            }
+            JumpTo(llil) => {
+                let target = eval_expression(llil.target, &mut self.state)? as u64;
+                info!("0x{:x} Jump table to 0x{:x}", self.state.addr, target);
+                next = Some(self.resolve(target)?);
+            }
+            Ret(llil) => {
+                let target = eval_expression(llil.target, &mut self.state)? as u64;
+                info!("0x{:x} Return to 0x{:x}", self.state.addr, target);
+
+                match self.state.call_stack.pop() {
+                    Some(ref frame) if frame.return_addr == target => {
+                        next = Some((frame.function, frame.index));
                     }
-                    self.state.regs.rsp -= 8;
-                }
-                If(llil) => {
-                    let result: u64 = eval_expression(llil.condition, &self.state);
-                    info!("0x{:x} Compare returned 0x{:x}", self.state.addr, result);
-                    if result == 0 {
-                        /* This goes to the wrong address */
-                        self.state.addr = llil.target_false;
-                        info!(" > Branching false - addr = 0x{:x}", llil.target_false);
-                    } else {
-                        /* This goes to the wrong address */ 
-                        self.state.addr = llil.target_true;
-                        info!(" > Branching true - addr = 0x{:x}", llil.target_true);
+                    Some(frame) => {
+                        warn!("0x{:x} Return address 0x{:x} doesn't match call stack (expected 0x{:x})", self.state.addr, target, frame.return_addr);
+                        next = Some(self.program.location_of(target)?);
                     }
+                    None => return Err(String::from("Returned from the starting function")),
                 }
-                Store(llil) => {
-                    let val: u64 = eval_expression(llil.source_expr, &self.state);
-                    let addr: u64 = eval_expression(llil.dest_mem_expr, &self.state);
+            },
+            NoRet() => {
+                info!("0x{:x} NoRet instruction", self.state.addr);
+            },
+            Syscall() => {
+                self.syscalls.call(&mut self.state)?;
+            },
+            Nop() => {
+                info!("0x{:x} Nop instruction", self.state.addr);
+            },
+            Bp() => {
+                info!("0x{:x} Bp instruction", self.state.addr);
+            },
+            Trap() => {
+                info!("0x{:x} Trap instruction", self.state.addr);
+            },
+            LlilInst::Undef() => {
+                info!("0x{:x} Undef instruction", self.state.addr);
+            },
+            Value(expr) => {
+                eval_expression(expr, &mut self.state)?;
+                info!("0x{:x} Evaluated expression for its flags", self.state.addr);
+            }
+        }
 
-                    self.state.memory.store(addr, val);
+        match next {
+            Some((function, index)) => {
+                self.state.function = function;
+                self.state.index = index;
+            }
+            None => self.state.index += 1,
+        }
+        if let Some(ref mut taint) = self.taint {
+            taint.after(&mut self.state, &self.syscalls.abi);
+        }
+        return Ok(String::from("Successful Step!"));
+    }
 
-                    info!("0x{:x} Stored 0x{:x} at 0x{:x}", self.state.addr, val, addr);
-                }
-                Call(llil) => {
-                    match llil.target {
-                        Value(v) => {
-                            info!("0x{:x} Call to function at address 0x{:x}", self.state.addr, v);
-                            let state = &mut self.state; 
-                            //TODO: Need to somehow get function name
-                            /* This is valid but need to figure out whether we are calling a library function or not */
-                            let res = self.program.function_at(v);
-                            match res {
-                                Ok(func) => procedures::call(func.name, state),
-                                _ => info!("Couldn't find function value."),
-                            };
-                            // self.state.memory.store(self.state.addr, self.state.addr);
-                            // self.state.regs.rsp -= 8;
-                            // self.state.addr = v;
-                        },
-                        _ => error!("0x{:x} Calling other", self.state.addr),
-                    }
-                }
-                Goto(llil) => {
-                    info!("0x{:x} Goto instruction at {}", self.state.addr, llil.target);
-                    self.state.index = llil.target as usize - 1;
-                }
-                Jump(llil) => {
-                    info!("0x{:x} Jump instructiion to {}", self.state.addr, llil.addr);
-                    self.state.addr = llil.addr;
+    // Maps a jump target to a location, preferring the current function
+    fn resolve(&self, target: u64) -> Result<(u64, usize), String> {
+        if let Ok(function) = self.program.function_at(self.state.function) {
+            if let Ok(index) = function.llil_index_at(target) {
+                return Ok((function.addr, index));
+            }
+        }
+        return self.program.location_of(target);
+    }
+
+    // Calls a function, returning the location to continue at if control enters the callee
+    fn call(&mut self, target: u64, slot: Option<u64>) -> Result<Option<(u64, usize)>, String> {
+        let function = self.program.function_at(target);
+        let import = slot.and_then(|slot| self.program.import_symbol(slot))
+            .or_else(|| self.program.resolve_import(target));
+        let (name, library) = match import {
+            Some(ref import) => (import.name.clone(), import.library.clone()),
+            None => (function.as_ref().map(|f| f.name.clone()).unwrap_or(String::new()), None),
+        };
+
+        // Procedures see the arguments the way the callee's type says they were passed
+        let (callconv, parameters) = match self.program.calling_convention_of(target) {
+            Some((callconv, parameters)) => (callconv, parameters),
+            None => (self.state.callconv.clone(), None),
+        };
+        let default = mem::replace(&mut self.state.callconv, callconv);
+        let hooked = self.procedures.call(&mut self.state, target, &name, library.as_ref().map(|l| l.as_str()));
+        let callconv = mem::replace(&mut self.state.callconv, default);
+
+        if hooked? {
+            info!(" > Hooked call to {} at 0x{:x}", name, target);
+            if let Some(parameters) = parameters {
+                callconv.cleanup(&mut self.state, parameters);
+            }
+            return Ok(None);
+        }
+
+        // Imports without a procedure run the loaded library's code, or are skipped when no library
+        // exports them, leaving the return value register as it was
+        let function = if import.is_some() || self.program.is_import(target) {
+            match self.program.export(&name) {
+                Some(addr) => {
+                    info!(" > Calling {} in library code at 0x{:x}", name, addr);
+                    self.program.function_at(addr)
                 }
-                Ret(llil) => {
-                    info!("0x{:x} Return instruction at {}", self.state.addr, llil.addr);
-                    self.state.addr = llil.addr; 
-                },
-                NoRet() => {
-                    info!("0x{:x} NoRet instruction", self.state.addr);
-                },
-                Syscall() => {
-                    info!("0x{:x} Syscall instruction", self.state.addr);
-                },
-                Nop() => {
-                    info!("0x{:x} Nop instruction", self.state.addr);
-                },
-                Bp() => {
-                    info!("0x{:x} Bp instruction", self.state.addr); 
-                },
-                Trap() => {
-                    info!("0x{:x} Trap instruction", self.state.addr); 
-                },
-                LlilInst::Undef() => {
-                    info!("0x{:x} Undef instruction", self.state.addr);
-                },
-                _ => {
-                    error!("0x{:x} Unimplemented instruction", self.state.addr);
+                None => {
+                    error!("0x{:x} Calling unknown library function {}", self.state.addr, name);
+                    return Ok(None);
                 }
             }
-        }
-        let nextInst = self.program.inst_after(self.state.addr);
-        self.state.addr = match nextInst {
-            Ok(inst) => inst.addr,
-            Err(error) => return Err(String::from("Failed to get next instruction")),
+        } else {
+            function
         };
-        return Ok(String::from("Successful Step!"));
+
+        let function = match function {
+            Ok(function) => function,
+            Err(_) => return Err(format!("Call to 0x{:x} which isn't the start of a function", target)),
+        };
+
+        // The next LLIL instruction isn't there when the call is the last one in the function,
+        // so the return address comes from the call instruction itself
+        let index = self.state.index + 1;
+        let ret = self.state.addr + self.program.instruction_len(self.state.addr)? as u64;
+
+        // Architectures with a link register don't put the return address on the stack
+        match self.state.regs.link_register.clone() {
+            Some(lr) => self.state.regs.set(&lr, ret as u128),
+            None => {
+                let size = self.state.regs.address_size;
+                let sp = self.state.regs.sp().wrapping_sub(size as u64);
+                self.state.regs.set_sp(sp);
+                self.state.memory.store(sp, size, ret as u128)?;
+            }
+        }
+
+        self.state.call_stack.push(Frame {
+            function: self.state.function,
+            index: index,
+            return_addr: ret,
+        });
+        info!(" > Entering {} with return address 0x{:x}", function.name, ret);
+        return Ok(Some((function.addr, 0)));
     }
 
 }
@@ -1,19 +1,32 @@
-use binaryninja::architecture::{CoreArchitecture, Register, RegisterInfo};
+use binaryninja::architecture::{CoreArchitecture, CoreRegister, CoreFlagWrite, Register, RegisterInfo, Flag as ArchFlag, FlagWrite, FlagGroup as ArchFlagGroup};
+use binaryninja::llil;
 use binaryninja::llil::{Expression, ValueExpr, Finalized, NonSSA, RegularNonSSA};
 use state::State;
+use flags;
+use flags::{Condition, FlagOp};
 
+#[derive(Clone)]
 pub enum Expr {
     Reg(Reg),
     Flag(Flag),
+    FlagBit(FlagBit),
+    FlagCond(FlagCond),
+    FlagGroup(FlagGroup),
 
     Load(Load),
 
-    Value(u64),
+    Value(Value),
 
     Add(Arithmetic), Sub(Arithmetic), And(Arithmetic), Or(Arithmetic), Xor(Arithmetic), Mul(Arithmetic), Divu(Arithmetic), Divs(Arithmetic), Modu(Arithmetic), Mods(Arithmetic),
     Lsl(Arithmetic), Lsr(Arithmetic), Asr(Arithmetic), Rol(Arithmetic), Ror(Arithmetic),
     MulsDp(Arithmetic), MuluDp(Arithmetic),
     DivuDp(DivDp), DivsDp(DivDp), ModuDp(DivDp), ModsDp(DivDp), 
+    Adc(Carry), Sbb(Carry), Rlc(Carry), Rrc(Carry),
+    Neg(Unary), Not(Unary), Sx(Unary), Zx(Unary), LowPart(Unary), BoolToInt(Unary),
+
+    Fadd(Arithmetic), Fsub(Arithmetic), Fmul(Arithmetic), Fdiv(Arithmetic),
+    Fsqrt(Unary), Fneg(Unary), Fabs(Unary), FloatToInt(Unary), IntToFloat(Unary), FloatConv(Unary),
+    RoundToInt(Unary), Floor(Unary), Ceil(Unary), Ftrunc(Unary),
 
     CmpE(Cmp),
     CmpSlt(Cmp),
@@ -26,128 +39,440 @@ pub enum Expr {
     CmpUge(Cmp),
     CmpUgt(Cmp),
 
+    FcmpE(Cmp), FcmpNe(Cmp), FcmpLt(Cmp), FcmpLe(Cmp), FcmpGe(Cmp), FcmpGt(Cmp), FcmpO(Cmp), FcmpUo(Cmp),
+
     Undef(Undef),
     Pop(Pop), 
 }
 
+#[derive(Clone)]
 pub struct Reg {
-    pub name: String
+    pub name: String,
+    pub size: usize,
 }
 
+#[derive(Clone)]
 pub struct Load {
     pub source_mem: Box<self::Expr>,
+    pub size: usize,
 }
 
+#[derive(Clone)]
 pub struct Flag {
     pub name: String
 }
 
+// A flag moved into a bit of an integer
+#[derive(Clone)]
+pub struct FlagBit {
+    pub name: String,
+    pub bit: usize,
+    pub size: usize,
+}
+
+// Condition is None for conditions we can't evaluate, such as floating point ones
+#[derive(Clone)]
+pub struct FlagCond {
+    pub condition: Option<Condition>,
+    pub expr: String,
+}
+
+#[derive(Clone)]
+pub struct FlagGroup {
+    pub name: String,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Clone)]
 pub struct Value {
-    pub value: String
+    pub value: u64,
+    pub size: usize,
 }
 
+// Size is the size of the operands, the result is always a boolean
+#[derive(Clone)]
 pub struct Cmp {
     pub left: Box<self::Expr>, 
-    pub right: Box<self::Expr>
+    pub right: Box<self::Expr>,
+    pub size: usize,
 }
 
+#[derive(Clone)]
 pub struct DivDp {
     pub high: Box<self::Expr>,
     pub low: Box<self::Expr>,
     pub right: Box<self::Expr>,
+    pub size: usize,
+}
+
+// Operations that take the carry flag as a third operand
+#[derive(Clone)]
+pub struct Carry {
+    pub left: Box<self::Expr>,
+    pub right: Box<self::Expr>,
+    pub carry: Box<self::Expr>,
+    pub size: usize,
+    pub flags: Vec<String>,
+}
+
+// Size is the size of the result, the operand keeps its own size
+#[derive(Clone)]
+pub struct Unary {
+    pub operand: Box<self::Expr>,
+    pub size: usize,
+    pub flags: Vec<String>,
 }
 
+#[derive(Clone)]
 pub struct Undef {
     pub expr: String,
 }
 
+#[derive(Clone)]
 pub struct Pop {
-    pub expr: String
+    pub expr: String,
+    pub size: usize,
+}
+
+// Flags are the names of the flags the operation writes
+#[derive(Clone)]
+pub struct Arithmetic { pub left: Box<self::Expr>, pub right: Box<self::Expr>, pub size: usize, pub flags: Vec<String> }
+
+impl Expr {
+    // Size in bytes of the value the expression produces, booleans have a size of 0
+    pub fn size(&self) -> usize {
+        match self {
+            Expr::Reg(r) => r.size,
+            Expr::FlagBit(f) => f.size,
+            Expr::Load(l) => l.size,
+            Expr::Value(v) => v.size,
+            Expr::Pop(p) => p.size,
+
+            Expr::Add(s) | Expr::Sub(s) | Expr::And(s) | Expr::Or(s) | Expr::Xor(s) | Expr::Mul(s) |
+            Expr::Divu(s) | Expr::Divs(s) | Expr::Modu(s) | Expr::Mods(s) |
+            Expr::Lsl(s) | Expr::Lsr(s) | Expr::Asr(s) | Expr::Rol(s) | Expr::Ror(s) => s.size,
+
+            // Double precision multiplies produce a result twice the size of their operands
+            Expr::MulsDp(s) | Expr::MuluDp(s) => s.size * 2,
+            Expr::DivuDp(s) | Expr::DivsDp(s) | Expr::ModuDp(s) | Expr::ModsDp(s) => s.size,
+            Expr::Adc(s) | Expr::Sbb(s) | Expr::Rlc(s) | Expr::Rrc(s) => s.size,
+
+            Expr::Fadd(s) | Expr::Fsub(s) | Expr::Fmul(s) | Expr::Fdiv(s) => s.size,
+            Expr::Neg(s) | Expr::Not(s) | Expr::Sx(s) | Expr::Zx(s) | Expr::LowPart(s) | Expr::BoolToInt(s) |
+            Expr::Fsqrt(s) | Expr::Fneg(s) | Expr::Fabs(s) | Expr::FloatToInt(s) | Expr::IntToFloat(s) | Expr::FloatConv(s) |
+            Expr::RoundToInt(s) | Expr::Floor(s) | Expr::Ceil(s) | Expr::Ftrunc(s) => s.size,
+
+            _ => 0,
+        }
+    }
+}
+
+// Slot a call through a GOT entry loads its target from, like call [rip+puts@GOT]. The slot
+// names the import even before its contents are bound.
+pub fn got_slot(target: &Expr) -> Option<u64> {
+    if let Expr::Load(ref load) = *target {
+        if let Expr::Value(ref value) = *load.source_mem {
+            return Some(value.value);
+        }
+    }
+    return None;
+}
+
+// Bit mask covering a value of the given size in bytes
+pub fn mask(size: usize) -> u128 {
+    if size == 0 {
+        return 1;
+    }
+    if size >= 16 {
+        return u128::max_value();
+    }
+    return (1 << (size * 8)) - 1;
+}
+
+pub fn truncate(value: u128, size: usize) -> u128 {
+    return value & mask(size);
+}
+
+// Interprets the low size bytes of the value as a signed integer
+pub fn sign_extend(value: u128, size: usize) -> i128 {
+    if size == 0 || size >= 16 {
+        return value as i128;
+    }
+    let shift = 128 - size * 8;
+    return ((value << shift) as i128) >> shift;
 }
 
-pub struct Arithmetic { pub left: Box<self::Expr>, pub right: Box<self::Expr> }
+fn shift_left(value: u128, amount: u128, size: usize) -> u128 {
+    if amount >= (size * 8) as u128 {
+        return 0;
+    }
+    return truncate(value << amount, size);
+}
+
+fn shift_right(value: u128, amount: u128, size: usize) -> u128 {
+    if amount >= (size * 8) as u128 {
+        return 0;
+    }
+    return truncate(value, size) >> amount;
+}
+
+fn arithmetic_shift_right(value: u128, amount: u128, size: usize) -> u128 {
+    let amount = if amount >= (size * 8) as u128 { (size * 8 - 1) as u128 } else { amount };
+    return truncate((sign_extend(value, size) >> amount) as u128, size);
+}
+
+fn rotate_left(value: u128, amount: u128, size: usize) -> u128 {
+    let bits = (size * 8) as u128;
+    if bits == 0 {
+        return value;
+    }
+    let amount = amount % bits;
+    let value = truncate(value, size);
+    if amount == 0 {
+        return value;
+    }
+    return truncate((value << amount) | (value >> (bits - amount)), size);
+}
+
+fn rotate_right(value: u128, amount: u128, size: usize) -> u128 {
+    let bits = (size * 8) as u128;
+    if bits == 0 {
+        return value;
+    }
+    return rotate_left(value, bits - amount % bits, size);
+}
+
+// Rotates through the carry flag, returning the result and the carry shifted out
+fn rotate_carry_left(value: u128, amount: u128, carry: bool, size: usize) -> (u128, bool) {
+    let bits = size * 8;
+    let (mut value, mut carry) = (truncate(value, size), carry);
+    for _ in 0..(amount % (bits as u128 + 1)) {
+        let out = (value >> (bits - 1)) & 1 == 1;
+        value = truncate((value << 1) | carry as u128, size);
+        carry = out;
+    }
+    return (value, carry);
+}
+
+fn rotate_carry_right(value: u128, amount: u128, carry: bool, size: usize) -> (u128, bool) {
+    let bits = size * 8;
+    let (mut value, mut carry) = (truncate(value, size), carry);
+    for _ in 0..(amount % (bits as u128 + 1)) {
+        let out = value & 1 == 1;
+        value = (value >> 1) | ((carry as u128) << (bits - 1));
+        carry = out;
+    }
+    return (value, carry);
+}
+
+// Floats are stored as their raw bits, sizes are single, double and x87 extended precision
+pub fn to_float(value: u128, size: usize) -> Result<f64, String> {
+    match size {
+        4 => return Ok(f32::from_bits(value as u32) as f64),
+        8 => return Ok(f64::from_bits(value as u64)),
+        10 => {
+            let sign = if (value >> 79) & 1 == 1 { -1.0 } else { 1.0 };
+            let exponent = ((value >> 64) & 0x7fff) as i32;
+            let mantissa = value as u64;
+            if exponent == 0x7fff {
+                return Ok(if mantissa << 1 == 0 { sign * ::std::f64::INFINITY } else { ::std::f64::NAN });
+            }
+            // The extended format has an explicit integer bit so the mantissa is scaled by 2^63
+            return Ok(sign * (mantissa as f64) * 2f64.powi(exponent - 16383 - 63));
+        }
+        _ => return Err(format!("Unsupported float size {}", size)),
+    }
+}
+
+pub fn from_float(value: f64, size: usize) -> Result<u128, String> {
+    match size {
+        4 => return Ok((value as f32).to_bits() as u128),
+        8 => return Ok(value.to_bits() as u128),
+        10 => {
+            let bits = value.to_bits();
+            let sign = ((bits >> 63) as u128) << 79;
+            let exponent = ((bits >> 52) & 0x7ff) as i32;
+            let fraction = bits & ((1 << 52) - 1);
+
+            if exponent == 0x7ff {
+                let mantissa = if fraction == 0 { 1 << 63 } else { 0xc000000000000000 };
+                return Ok(sign | (0x7fff << 64) | mantissa);
+            }
+            if exponent == 0 && fraction == 0 {
+                return Ok(sign);
+            }
+
+            let (mantissa, exponent) = if exponent == 0 {
+                // Subnormal doubles are normal in the wider exponent range
+                let shift = (fraction << 11).leading_zeros() as i32;
+                ((fraction << 11) << shift, -1022 - shift)
+            } else {
+                ((1 << 63) | (fraction << 11), exponent - 1023)
+            };
+            return Ok(sign | (((exponent + 16383) as u128) << 64) | mantissa as u128);
+        }
+        _ => return Err(format!("Unsupported float size {}", size)),
+    }
+}
+
+// Rounds halfway cases to even like the default floating point rounding mode
+pub fn round_even(value: f64) -> f64 {
+    let rounded = value.round();
+    if (value - value.trunc()).abs() == 0.5 {
+        return 2.0 * (value / 2.0).round();
+    }
+    return rounded;
+}
+
+// Names of the flags an operation's flag write type sets
+fn flags_written(write: Option<CoreFlagWrite>) -> Vec<String> {
+    return match write {
+        Some(write) => write.flags_written().iter().map(|f| String::from(f.name().as_ref())).collect(),
+        None => Vec::new(),
+    };
+}
+
+// Architecture registers go by their own name, temporaries by their LLIL index
+pub fn register_name(reg: &llil::Register<CoreRegister>) -> String {
+    match *reg {
+        llil::Register::ArchReg(ref r) => String::from(r.name().as_ref()),
+        llil::Register::Temp(t) => format!("temp{}", t),
+    }
+}
 
 pub fn build_expression(expr: &Expression<CoreArchitecture, Finalized, NonSSA<RegularNonSSA>, ValueExpr>) -> Expr {
     use llil::ExprInfo::*;
 
     match expr.info() {
-        Pop(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        FlagBit(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Adc(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Sbb(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Rlc(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Rrc(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Neg(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Not(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Sx(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        Zx(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        LowPart(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        FlagCond(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        FlagGroup(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
-        BoolToInt(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
         Unimpl(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
         UnimplMem(ref op) => Expr::Undef(self::Undef {expr: String::from(format!("{:?}", expr))}),
 
         Reg(ref op) => {
             Expr::Reg(self::Reg {
-                name: String::from(format!("{:?}", op.source_reg()))
+                name: register_name(&op.source_reg()),
+                size: op.size(),
             })
         }
 
         Flag(ref op) => {
             Expr::Flag(self::Flag {
-                name: String::from(format!("{:?}", expr))
+                name: String::from(op.source_flag().name().as_ref()),
+            })
+        }
+
+        FlagBit(ref op) => {
+            Expr::FlagBit(self::FlagBit {
+                name: String::from(op.source_flag().name().as_ref()),
+                bit: op.bit_index(),
+                size: op.size(),
+            })
+        }
+
+        FlagCond(ref op) => {
+            Expr::FlagCond(self::FlagCond {
+                condition: flags::condition_from(op.condition()),
+                expr: String::from(format!("{:?}", expr)),
+            })
+        }
+
+        // Flag groups carry one condition per semantic class, we don't track classes so any of them will do
+        FlagGroup(ref op) => {
+            let group = op.flag_group();
+            Expr::FlagGroup(self::FlagGroup {
+                name: String::from(group.name().as_ref()),
+                condition: group.flag_conditions().values().next().and_then(|c| flags::condition_from(*c)),
             })
         }
 
         Load(ref op) => {
             Expr::Load(self::Load {
-                source_mem: Box::new(build_expression(&op.source_mem_expr()))
+                source_mem: Box::new(build_expression(&op.source_mem_expr())),
+                size: op.size(),
             })
         }
 
         Const(ref op) | ConstPtr(ref op) => {
-            Expr::Value(op.value())
+            Expr::Value(self::Value {
+                value: op.value(),
+                size: op.size(),
+            })
         }
 
 
-        CmpE (ref op) => {Expr::CmpE(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpSlt (ref op) => {Expr::CmpSlt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpSle (ref op) => {Expr::CmpSle(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpSge (ref op) => {Expr::CmpSge(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpSgt (ref op) => {Expr::CmpSgt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
+        CmpE (ref op) => {Expr::CmpE(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpSlt (ref op) => {Expr::CmpSlt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpSle (ref op) => {Expr::CmpSle(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpSge (ref op) => {Expr::CmpSge(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpSgt (ref op) => {Expr::CmpSgt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
         
-        CmpNe (ref op) => {Expr::CmpNe(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpUlt (ref op) => {Expr::CmpUlt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpUle (ref op) => {Expr::CmpUle(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpUge (ref op) => {Expr::CmpUge(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        CmpUgt (ref op) => {Expr::CmpUgt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-
-        Add (ref op) => {Expr::Add(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Sub (ref op) => {Expr::Sub(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        And (ref op) => {Expr::And(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Or (ref op) => {Expr::Or(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Xor (ref op) => {Expr::Xor(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Mul (ref op) => {Expr::Mul(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Divu (ref op) => {Expr::Divu(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Divs (ref op) => {Expr::Divs(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Modu (ref op) => {Expr::Modu(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Mods (ref op) => {Expr::Mods(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-
-        Lsl (ref op) => {Expr::Lsl(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Lsr (ref op) => {Expr::Lsr(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Asr (ref op) => {Expr::Asr(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Rol (ref op) => {Expr::Rol(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        Ror (ref op) => {Expr::Ror(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-
-        MulsDp (ref op) => {Expr::MulsDp(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-        MuluDp (ref op) => {Expr::MuluDp(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right()))})}
-
-        DivuDp (ref op) => {Expr::DivuDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right()))})}
-        DivsDp (ref op) => {Expr::DivsDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right()))})}
-        ModuDp (ref op) => {Expr::ModuDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right()))})}
-        ModsDp (ref op) => {Expr::ModsDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right()))})}
-
+        CmpNe (ref op) => {Expr::CmpNe(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpUlt (ref op) => {Expr::CmpUlt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpUle (ref op) => {Expr::CmpUle(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpUge (ref op) => {Expr::CmpUge(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        CmpUgt (ref op) => {Expr::CmpUgt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+
+        Add (ref op) => {Expr::Add(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Sub (ref op) => {Expr::Sub(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        And (ref op) => {Expr::And(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Or (ref op) => {Expr::Or(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Xor (ref op) => {Expr::Xor(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Mul (ref op) => {Expr::Mul(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Divu (ref op) => {Expr::Divu(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Divs (ref op) => {Expr::Divs(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Modu (ref op) => {Expr::Modu(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Mods (ref op) => {Expr::Mods(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        Lsl (ref op) => {Expr::Lsl(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Lsr (ref op) => {Expr::Lsr(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Asr (ref op) => {Expr::Asr(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Rol (ref op) => {Expr::Rol(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Ror (ref op) => {Expr::Ror(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        MulsDp (ref op) => {Expr::MulsDp(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        MuluDp (ref op) => {Expr::MuluDp(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        DivuDp (ref op) => {Expr::DivuDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        DivsDp (ref op) => {Expr::DivsDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        ModuDp (ref op) => {Expr::ModuDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        ModsDp (ref op) => {Expr::ModsDp(self::DivDp {low: Box::new(build_expression(&op.low())), high: Box::new(build_expression(&op.high())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+
+
+        Adc (ref op) => {Expr::Adc(self::Carry {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), carry: Box::new(build_expression(&op.carry())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Sbb (ref op) => {Expr::Sbb(self::Carry {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), carry: Box::new(build_expression(&op.carry())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Rlc (ref op) => {Expr::Rlc(self::Carry {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), carry: Box::new(build_expression(&op.carry())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Rrc (ref op) => {Expr::Rrc(self::Carry {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), carry: Box::new(build_expression(&op.carry())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        Neg (ref op) => {Expr::Neg(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Not (ref op) => {Expr::Not(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Sx (ref op) => {Expr::Sx(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Zx (ref op) => {Expr::Zx(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        LowPart (ref op) => {Expr::LowPart(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        BoolToInt (ref op) => {Expr::BoolToInt(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        Fadd (ref op) => {Expr::Fadd(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Fsub (ref op) => {Expr::Fsub(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Fmul (ref op) => {Expr::Fmul(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Fdiv (ref op) => {Expr::Fdiv(self::Arithmetic {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        Fsqrt (ref op) => {Expr::Fsqrt(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Fneg (ref op) => {Expr::Fneg(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Fabs (ref op) => {Expr::Fabs(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        FloatToInt (ref op) => {Expr::FloatToInt(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        IntToFloat (ref op) => {Expr::IntToFloat(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        FloatConv (ref op) => {Expr::FloatConv(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        RoundToInt (ref op) => {Expr::RoundToInt(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Floor (ref op) => {Expr::Floor(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Ceil (ref op) => {Expr::Ceil(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+        Ftrunc (ref op) => {Expr::Ftrunc(self::Unary {operand: Box::new(build_expression(&op.operand())), size: op.size(), flags: flags_written(op.flag_write())})}
+
+        FcmpE (ref op) => {Expr::FcmpE(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpNE (ref op) => {Expr::FcmpNe(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpLT (ref op) => {Expr::FcmpLt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpLE (ref op) => {Expr::FcmpLe(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpGE (ref op) => {Expr::FcmpGe(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpGT (ref op) => {Expr::FcmpGt(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpO (ref op) => {Expr::FcmpO(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
+        FcmpUO (ref op) => {Expr::FcmpUo(self::Cmp {left: Box::new(build_expression(&op.left())), right: Box::new(build_expression(&op.right())), size: op.size()})}
         
         Undef(ref op) => {
             Expr::Undef(self::Undef {
@@ -157,52 +482,323 @@ pub fn build_expression(expr: &Expression<CoreArchitecture, Finalized, NonSSA<Re
         
         Pop(ref op) => {
             Expr::Pop(self::Pop {
-                expr: String::from(format!("{:?}", expr))
+                expr: String::from(format!("{:?}", expr)),
+                size: op.size(),
             })
         }
     }
 }
 
-pub fn eval_expression<'a>(expr: Expr, state: &State) -> u64 {
-    match expr {
-        Expr::Value(v) => v,
-        Expr::Reg(r) => state.regs.get(r.name),
-        Expr::Load(l) => eval_expression(*l.source_mem, state),
+pub fn eval_expression<'a>(expr: Expr, state: &mut State) -> Result<u128, String> {
+    let size = expr.size();
 
-        Expr::CmpE(s) => if eval_expression(*s.left, state) == eval_expression(*s.right, state) {1} else {0},
-        Expr::CmpSlt(s) => if (eval_expression(*s.left, state) as i64) < (eval_expression(*s.right, state) as i64) {1} else {0},
-        Expr::CmpSle(s) => if (eval_expression(*s.left, state) as i64) <= (eval_expression(*s.right, state) as i64) {1} else {0},
-        Expr::CmpSge(s) => if (eval_expression(*s.left, state) as i64) >= (eval_expression(*s.right, state) as i64) {1} else {0},
-        Expr::CmpSgt(s) => if (eval_expression(*s.left, state) as i64) > (eval_expression(*s.right, state) as i64) {1} else {0},
+    let value = match expr {
+        Expr::Value(v) => truncate(v.value as u128, v.size),
+        Expr::Reg(r) => truncate(state.regs.get(&r.name), r.size),
+        Expr::Load(l) => {
+            let addr = eval_expression(*l.source_mem, state)? as u64;
+            state.memory.load(addr, l.size)?
+        }
+        Expr::Pop(p) => {
+            let sp = state.regs.sp();
+            let value = state.memory.load(sp, p.size)?;
+            state.regs.set_sp(sp.wrapping_add(p.size as u64));
+            value
+        }
+
+        Expr::CmpE(s) => if truncate(eval_expression(*s.left, state)?, s.size) == truncate(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpSlt(s) => if sign_extend(eval_expression(*s.left, state)?, s.size) < sign_extend(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpSle(s) => if sign_extend(eval_expression(*s.left, state)?, s.size) <= sign_extend(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpSge(s) => if sign_extend(eval_expression(*s.left, state)?, s.size) >= sign_extend(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpSgt(s) => if sign_extend(eval_expression(*s.left, state)?, s.size) > sign_extend(eval_expression(*s.right, state)?, s.size) {1} else {0},
 
-        Expr::CmpNe(s) => if eval_expression(*s.left, state) != eval_expression(*s.right, state) {1} else {0},
-        Expr::CmpUlt(s) => if eval_expression(*s.left, state) < eval_expression(*s.right, state) {1} else {0},
-        Expr::CmpUle(s) => if eval_expression(*s.left, state) <= eval_expression(*s.right, state) {1} else {0},
-        Expr::CmpUge(s) => if eval_expression(*s.left, state) >= eval_expression(*s.right, state) {1} else {0},
-        Expr::CmpUgt(s) => if eval_expression(*s.left, state) > eval_expression(*s.right, state) {1} else {0},
+        Expr::CmpNe(s) => if truncate(eval_expression(*s.left, state)?, s.size) != truncate(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpUlt(s) => if truncate(eval_expression(*s.left, state)?, s.size) < truncate(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpUle(s) => if truncate(eval_expression(*s.left, state)?, s.size) <= truncate(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpUge(s) => if truncate(eval_expression(*s.left, state)?, s.size) >= truncate(eval_expression(*s.right, state)?, s.size) {1} else {0},
+        Expr::CmpUgt(s) => if truncate(eval_expression(*s.left, state)?, s.size) > truncate(eval_expression(*s.right, state)?, s.size) {1} else {0},
 
 
-        Expr::Add(s) => eval_expression(*s.left, state).overflowing_add(eval_expression(*s.right, state)).0,
-        Expr::Sub(s) => eval_expression(*s.left, state).overflowing_sub(eval_expression(*s.right, state)).0,
-        Expr::And(s) => eval_expression(*s.left, state) & eval_expression(*s.right, state),
-        Expr::Or(s) => eval_expression(*s.left, state) | eval_expression(*s.right, state),
-        Expr::Xor(s) => eval_expression(*s.left, state) ^ eval_expression(*s.right, state),
-        Expr::Mul(s) => eval_expression(*s.left, state).overflowing_mul(eval_expression(*s.right, state)).0,
-        Expr::Divu(s) => eval_expression(*s.left, state) / eval_expression(*s.right, state),
-        Expr::Divs(s) => eval_expression(*s.left, state) / eval_expression(*s.right, state),
-        Expr::Modu(s) => eval_expression(*s.left, state) % eval_expression(*s.right, state),
-        Expr::Mods(s) => eval_expression(*s.left, state) % eval_expression(*s.right, state),
+        Expr::Add(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(left.wrapping_add(right), size);
+            state.flags.write(&s.flags, FlagOp::Add, left, right, result, size);
+            result
+        }
+        Expr::Sub(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(left.wrapping_sub(right), size);
+            state.flags.write(&s.flags, FlagOp::Sub, left, right, result, size);
+            result
+        }
+        Expr::And(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(left & right, size);
+            state.flags.write(&s.flags, FlagOp::Logic, left, right, result, size);
+            result
+        }
+        Expr::Or(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(left | right, size);
+            state.flags.write(&s.flags, FlagOp::Logic, left, right, result, size);
+            result
+        }
+        Expr::Xor(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(left ^ right, size);
+            state.flags.write(&s.flags, FlagOp::Logic, left, right, result, size);
+            result
+        }
+        Expr::Mul(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(left.wrapping_mul(right), size);
+            state.flags.write(&s.flags, FlagOp::Mul, left, right, result, size);
+            result
+        }
+        Expr::Divu(s) => {
+            let left = truncate(eval_expression(*s.left, state)?, size);
+            let right = truncate(eval_expression(*s.right, state)?, size);
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            state.flags.write(&s.flags, FlagOp::Other, left, right, left / right, size);
+            left / right
+        }
+        Expr::Divs(s) => {
+            let left = sign_extend(eval_expression(*s.left, state)?, size);
+            let right = sign_extend(eval_expression(*s.right, state)?, size);
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            let result = truncate(left.wrapping_div(right) as u128, size);
+            state.flags.write(&s.flags, FlagOp::Other, left as u128, right as u128, result, size);
+            result
+        }
+        Expr::Modu(s) => {
+            let left = truncate(eval_expression(*s.left, state)?, size);
+            let right = truncate(eval_expression(*s.right, state)?, size);
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            state.flags.write(&s.flags, FlagOp::Other, left, right, left % right, size);
+            left % right
+        }
+        Expr::Mods(s) => {
+            let left = sign_extend(eval_expression(*s.left, state)?, size);
+            let right = sign_extend(eval_expression(*s.right, state)?, size);
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            let result = truncate(left.wrapping_rem(right) as u128, size);
+            state.flags.write(&s.flags, FlagOp::Other, left as u128, right as u128, result, size);
+            result
+        }
         
-        Expr::Lsl(s) => eval_expression(*s.left, state) << eval_expression(*s.right, state),
-        Expr::Lsr(s) => eval_expression(*s.left, state) >> eval_expression(*s.right, state),
-        Expr::Asr(s) => eval_expression(*s.left, state) << eval_expression(*s.right, state),
-        Expr::Rol(s) => eval_expression(*s.left, state) << eval_expression(*s.right, state),
-        Expr::Ror(s) => eval_expression(*s.left, state) >> eval_expression(*s.right, state),
+        Expr::Lsl(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = shift_left(left, right, size);
+            state.flags.write(&s.flags, FlagOp::ShiftLeft, left, right, result, size);
+            result
+        }
+        Expr::Lsr(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = shift_right(left, right, size);
+            state.flags.write(&s.flags, FlagOp::ShiftRight, left, right, result, size);
+            result
+        }
+        Expr::Asr(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = arithmetic_shift_right(left, right, size);
+            state.flags.write(&s.flags, FlagOp::ShiftRight, left, right, result, size);
+            result
+        }
+        Expr::Rol(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = rotate_left(left, right, size);
+            state.flags.write(&s.flags, FlagOp::Other, left, right, result, size);
+            result
+        }
+        Expr::Ror(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = rotate_right(left, right, size);
+            state.flags.write(&s.flags, FlagOp::Other, left, right, result, size);
+            result
+        }
+
+        Expr::MulsDp(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let result = truncate(sign_extend(left, s.size).wrapping_mul(sign_extend(right, s.size)) as u128, size);
+            state.flags.write(&s.flags, FlagOp::Mul, left, right, result, s.size);
+            result
+        }
+        Expr::MuluDp(s) => {
+            let (left, right) = (truncate(eval_expression(*s.left, state)?, s.size), truncate(eval_expression(*s.right, state)?, s.size));
+            let result = truncate(left.wrapping_mul(right), size);
+            state.flags.write(&s.flags, FlagOp::MulUnsigned, left, right, result, s.size);
+            result
+        }
+
+        Expr::DivuDp(s) => {
+            let (left, right) = eval_double(s.high, s.low, s.right, s.size, state)?;
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            truncate(left / right, size)
+        }
+        Expr::ModuDp(s) => {
+            let (left, right) = eval_double(s.high, s.low, s.right, s.size, state)?;
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            truncate(left % right, size)
+        }
+        Expr::DivsDp(s) => {
+            let (left, right) = eval_double(s.high, s.low, s.right, s.size, state)?;
+            let (left, right) = (sign_extend(left, s.size * 2), sign_extend(right, s.size));
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            truncate(left.wrapping_div(right) as u128, size)
+        }
+        Expr::ModsDp(s) => {
+            let (left, right) = eval_double(s.high, s.low, s.right, s.size, state)?;
+            let (left, right) = (sign_extend(left, s.size * 2), sign_extend(right, s.size));
+            if right == 0 {
+                return Err(String::from("Division by zero"));
+            }
+            truncate(left.wrapping_rem(right) as u128, size)
+        }
+
+        Expr::Adc(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let carry = if eval_expression(*s.carry, state)? != 0 {1} else {0};
+            let result = truncate(left.wrapping_add(right).wrapping_add(carry), size);
+            state.flags.write(&s.flags, FlagOp::Add, left, right, result, size);
+            result
+        }
+        Expr::Sbb(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let carry = if eval_expression(*s.carry, state)? != 0 {1} else {0};
+            let result = truncate(left.wrapping_sub(right).wrapping_sub(carry), size);
+            state.flags.write(&s.flags, FlagOp::Sub, left, right, result, size);
+            result
+        }
+        Expr::Rlc(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let carry = eval_expression(*s.carry, state)? != 0;
+            let (result, carry) = rotate_carry_left(left, right, carry, size);
+            state.flags.write(&s.flags, FlagOp::RotateCarry(carry), left, right, result, size);
+            result
+        }
+        Expr::Rrc(s) => {
+            let (left, right) = (eval_expression(*s.left, state)?, eval_expression(*s.right, state)?);
+            let carry = eval_expression(*s.carry, state)? != 0;
+            let (result, carry) = rotate_carry_right(left, right, carry, size);
+            state.flags.write(&s.flags, FlagOp::RotateCarry(carry), left, right, result, size);
+            result
+        }
+
+        Expr::Neg(s) => {
+            let operand = eval_expression(*s.operand, state)?;
+            let result = truncate(0u128.wrapping_sub(operand), size);
+            state.flags.write(&s.flags, FlagOp::Sub, 0, operand, result, size);
+            result
+        }
+        Expr::Not(s) => {
+            let operand = eval_expression(*s.operand, state)?;
+            let result = truncate(!operand, size);
+            state.flags.write(&s.flags, FlagOp::Logic, operand, 0, result, size);
+            result
+        }
+        Expr::Sx(s) => {
+            let operand_size = s.operand.size();
+            truncate(sign_extend(eval_expression(*s.operand, state)?, operand_size) as u128, size)
+        }
+        Expr::Zx(s) => {
+            let operand_size = s.operand.size();
+            truncate(eval_expression(*s.operand, state)?, operand_size)
+        }
+        Expr::LowPart(s) => truncate(eval_expression(*s.operand, state)?, size),
+        Expr::BoolToInt(s) => if eval_expression(*s.operand, state)? != 0 {1} else {0},
+
+        Expr::Fadd(s) => {
+            let (left, right) = (to_float(eval_expression(*s.left, state)?, size)?, to_float(eval_expression(*s.right, state)?, size)?);
+            from_float(left + right, size)?
+        }
+        Expr::Fsub(s) => {
+            let (left, right) = (to_float(eval_expression(*s.left, state)?, size)?, to_float(eval_expression(*s.right, state)?, size)?);
+            from_float(left - right, size)?
+        }
+        Expr::Fmul(s) => {
+            let (left, right) = (to_float(eval_expression(*s.left, state)?, size)?, to_float(eval_expression(*s.right, state)?, size)?);
+            from_float(left * right, size)?
+        }
+        Expr::Fdiv(s) => {
+            let (left, right) = (to_float(eval_expression(*s.left, state)?, size)?, to_float(eval_expression(*s.right, state)?, size)?);
+            from_float(left / right, size)?
+        }
 
-        Expr::MulsDp(s) => eval_expression(*s.left, state).overflowing_mul(eval_expression(*s.right, state)).0,
-        Expr::MuluDp(s) => eval_expression(*s.left, state).overflowing_mul(eval_expression(*s.right, state)).0,
+        Expr::Fsqrt(s) => from_float(to_float(eval_expression(*s.operand, state)?, size)?.sqrt(), size)?,
+        Expr::Fneg(s) => from_float(-to_float(eval_expression(*s.operand, state)?, size)?, size)?,
+        Expr::Fabs(s) => from_float(to_float(eval_expression(*s.operand, state)?, size)?.abs(), size)?,
+        Expr::RoundToInt(s) => from_float(round_even(to_float(eval_expression(*s.operand, state)?, size)?), size)?,
+        Expr::Floor(s) => from_float(to_float(eval_expression(*s.operand, state)?, size)?.floor(), size)?,
+        Expr::Ceil(s) => from_float(to_float(eval_expression(*s.operand, state)?, size)?.ceil(), size)?,
+        Expr::Ftrunc(s) => from_float(to_float(eval_expression(*s.operand, state)?, size)?.trunc(), size)?,
+        Expr::FloatConv(s) => {
+            let operand_size = s.operand.size();
+            from_float(to_float(eval_expression(*s.operand, state)?, operand_size)?, size)?
+        }
+        Expr::FloatToInt(s) => {
+            let operand_size = s.operand.size();
+            truncate(to_float(eval_expression(*s.operand, state)?, operand_size)?.trunc() as i128 as u128, size)
+        }
+        Expr::IntToFloat(s) => {
+            let operand_size = s.operand.size();
+            from_float(sign_extend(eval_expression(*s.operand, state)?, operand_size) as f64, size)?
+        }
+
+        Expr::FcmpE(s) => if to_float(eval_expression(*s.left, state)?, s.size)? == to_float(eval_expression(*s.right, state)?, s.size)? {1} else {0},
+        Expr::FcmpNe(s) => if to_float(eval_expression(*s.left, state)?, s.size)? != to_float(eval_expression(*s.right, state)?, s.size)? {1} else {0},
+        Expr::FcmpLt(s) => if to_float(eval_expression(*s.left, state)?, s.size)? < to_float(eval_expression(*s.right, state)?, s.size)? {1} else {0},
+        Expr::FcmpLe(s) => if to_float(eval_expression(*s.left, state)?, s.size)? <= to_float(eval_expression(*s.right, state)?, s.size)? {1} else {0},
+        Expr::FcmpGe(s) => if to_float(eval_expression(*s.left, state)?, s.size)? >= to_float(eval_expression(*s.right, state)?, s.size)? {1} else {0},
+        Expr::FcmpGt(s) => if to_float(eval_expression(*s.left, state)?, s.size)? > to_float(eval_expression(*s.right, state)?, s.size)? {1} else {0},
+        Expr::FcmpO(s) => {
+            let (left, right) = (to_float(eval_expression(*s.left, state)?, s.size)?, to_float(eval_expression(*s.right, state)?, s.size)?);
+            if !left.is_nan() && !right.is_nan() {1} else {0}
+        }
+        Expr::FcmpUo(s) => {
+            let (left, right) = (to_float(eval_expression(*s.left, state)?, s.size)?, to_float(eval_expression(*s.right, state)?, s.size)?);
+            if left.is_nan() || right.is_nan() {1} else {0}
+        }
+
+        Expr::Flag(f) => if state.flags.get(&f.name) {1} else {0},
+        Expr::FlagBit(f) => if state.flags.get(&f.name) {truncate(1 << f.bit, f.size)} else {0},
+        Expr::FlagCond(f) => match f.condition {
+            Some(condition) => if state.flags.condition(condition)? {1} else {0},
+            None => return Err(format!("Unsupported flag condition {}", f.expr)),
+        },
+        Expr::FlagGroup(f) => match f.condition {
+            Some(condition) => if state.flags.condition(condition)? {1} else {0},
+            None => return Err(format!("Flag group {} has no supported condition", f.name)),
+        },
+
+        // There's no value to make up for something Binary Ninja couldn't lift
+        Expr::Undef(s) => return Err(format!("Can't evaluate unimplemented expression {}", s.expr)),
+    };
+
+    return Ok(value);
+}
 
-        Expr::Undef(s) => {error!("Undef expr {:?}", s.expr); 1},
-        _ => {error!("Unimplemented expr"); 1}
+// Evaluates a double precision dividend from its halves, along with the divisor
+fn eval_double(high: Box<Expr>, low: Box<Expr>, right: Box<Expr>, size: usize, state: &mut State) -> Result<(u128, u128), String> {
+    // The double width dividend has to fit in a u128
+    if size > 8 {
+        return Err(format!("Double precision division of {} byte operands isn't supported", size));
     }
+    let high = truncate(eval_expression(*high, state)?, size);
+    let low = truncate(eval_expression(*low, state)?, size);
+    let right = truncate(eval_expression(*right, state)?, size);
+    return Ok(((high << (size * 8)) | low, right));
 }
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use binaryninja::architecture::{Architecture, CoreArchitecture, Flag, FlagRole, FlagCondition};
+use expression::{mask, truncate, sign_extend};
+
+// What a flag means, taken from the architecture's flag roles
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Zero,
+    Negative,
+    Positive,
+    Carry,
+    // Carry flag that is set when a subtraction doesn't borrow (ARM style)
+    InvertedCarry,
+    Overflow,
+    HalfCarry,
+    EvenParity,
+    OddParity,
+    Special,
+}
+
+// Flag conditions LLIL can test, in terms of flag roles
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Condition {
+    E, Ne,
+    Slt, Ult, Sle, Ule, Sge, Uge, Sgt, Ugt,
+    Neg, Pos, O, No,
+}
+
+// The kind of operation that wrote the flags, needed for carry and overflow
+#[derive(Clone, Copy, PartialEq)]
+pub enum FlagOp {
+    // Additions and subtractions may include a carry in
+    Add,
+    Sub,
+    Logic,
+    Mul,
+    MulUnsigned,
+    ShiftLeft,
+    ShiftRight,
+    // Rotates through carry know the carry they shifted out
+    RotateCarry(bool),
+    Other,
+}
+
+pub struct Flags {
+    pub roles: HashMap<String, Role>,
+    pub values: HashMap<String, bool>,
+}
+
+impl Flags {
+    // Flags without an architecture, they can be set and read but have no roles
+    pub fn empty() -> Flags {
+        return Flags {
+            roles: HashMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn new(arch: &CoreArchitecture) -> Flags {
+        let mut flags = Flags::empty();
+
+        for flag in arch.flags() {
+            let role = match flag.role(None) {
+                FlagRole::ZeroFlagRole => Role::Zero,
+                FlagRole::NegativeSignFlagRole => Role::Negative,
+                FlagRole::PositiveSignFlagRole => Role::Positive,
+                FlagRole::CarryFlagRole => Role::Carry,
+                FlagRole::CarryFlagWithInvertedSubtractRole => Role::InvertedCarry,
+                FlagRole::OverflowFlagRole => Role::Overflow,
+                FlagRole::HalfCarryFlagRole => Role::HalfCarry,
+                FlagRole::EvenParityFlagRole => Role::EvenParity,
+                FlagRole::OddParityFlagRole => Role::OddParity,
+                _ => Role::Special,
+            };
+            flags.roles.insert(String::from(flag.name().as_ref()), role);
+        }
+        return flags;
+    }
+
+    pub fn get(&self, name: &str) -> bool {
+        return *self.values.get(name).unwrap_or(&false);
+    }
+
+    pub fn set(&mut self, name: &str, value: bool) {
+        self.values.insert(String::from(name), value);
+    }
+
+    // Value of the first flag with the role, errors if the architecture doesn't have one
+    fn role(&self, role: Role) -> Result<bool, String> {
+        for (name, r) in self.roles.iter() {
+            if *r == role {
+                return Ok(self.get(name));
+            }
+        }
+        return Err(String::from("Architecture has no flag for the condition"));
+    }
+
+    fn negative(&self) -> Result<bool, String> {
+        return self.role(Role::Negative).or_else(|_| self.role(Role::Positive).map(|p| !p));
+    }
+
+    // Whether the last subtraction borrowed, whichever way the architecture stores carry
+    fn borrow(&self) -> Result<bool, String> {
+        return self.role(Role::Carry).or_else(|_| self.role(Role::InvertedCarry).map(|c| !c));
+    }
+
+    pub fn condition(&self, condition: Condition) -> Result<bool, String> {
+        use self::Condition::*;
+
+        let result = match condition {
+            E => self.role(Role::Zero)?,
+            Ne => !self.role(Role::Zero)?,
+            Slt => self.negative()? != self.role(Role::Overflow)?,
+            Sge => self.negative()? == self.role(Role::Overflow)?,
+            Sle => self.role(Role::Zero)? || self.negative()? != self.role(Role::Overflow)?,
+            Sgt => !self.role(Role::Zero)? && self.negative()? == self.role(Role::Overflow)?,
+            Ult => self.borrow()?,
+            Uge => !self.borrow()?,
+            Ule => self.borrow()? || self.role(Role::Zero)?,
+            Ugt => !self.borrow()? && !self.role(Role::Zero)?,
+            Neg => self.negative()?,
+            Pos => !self.negative()?,
+            O => self.role(Role::Overflow)?,
+            No => !self.role(Role::Overflow)?,
+        };
+        return Ok(result);
+    }
+
+    // Sets the written flags from an operation's operands and result, size is the operand size
+    pub fn write(&mut self, written: &Vec<String>, op: FlagOp, left: u128, right: u128, result: u128, size: usize) {
+        if written.is_empty() {
+            return;
+        }
+
+        let bits = size * 8;
+        let sign = |v: u128| bits > 0 && (truncate(v, size) >> (bits - 1)) & 1 == 1;
+        let (left, right, low) = (truncate(left, size), truncate(right, size), truncate(result, size));
+
+        let carry = match op {
+            // The result only equals the left operand with a non-zero right when a carry in wrapped around
+            FlagOp::Add => low < left || (low == left && right != 0),
+            FlagOp::Sub => low > left || (low == left && right != 0),
+            FlagOp::Mul => sign_extend(left, size).wrapping_mul(sign_extend(right, size)) != sign_extend(low, size),
+            FlagOp::MulUnsigned => result & !mask(size) != 0,
+            FlagOp::ShiftLeft => right > 0 && right <= bits as u128 && (left >> (bits as u128 - right)) & 1 == 1,
+            FlagOp::ShiftRight => right > 0 && right <= bits as u128 && (left >> (right - 1)) & 1 == 1,
+            FlagOp::RotateCarry(carry) => carry,
+            FlagOp::Logic | FlagOp::Other => false,
+        };
+        let overflow = match op {
+            FlagOp::Add => sign(left) == sign(right) && sign(low) != sign(left),
+            FlagOp::Sub => sign(left) != sign(right) && sign(low) != sign(left),
+            FlagOp::Mul | FlagOp::MulUnsigned => carry,
+            _ => false,
+        };
+
+        for name in written {
+            let value = match self.roles.get(name) {
+                Some(Role::Zero) => low == 0,
+                Some(Role::Negative) => sign(low),
+                Some(Role::Positive) => !sign(low),
+                Some(Role::Carry) => carry,
+                Some(Role::InvertedCarry) => if op == FlagOp::Sub { !carry } else { carry },
+                Some(Role::Overflow) => overflow,
+                Some(Role::HalfCarry) => (left ^ right ^ low) & 0x10 != 0,
+                Some(Role::EvenParity) => (low & 0xff).count_ones() % 2 == 0,
+                Some(Role::OddParity) => (low & 0xff).count_ones() % 2 == 1,
+                // Architecture specific flags keep their value
+                _ => continue,
+            };
+            self.set(name, value);
+        }
+    }
+
+    // Prints every flag that has been written
+    pub fn print(&self) {
+        let mut names: Vec<&String> = self.values.keys().collect();
+        names.sort();
+        let line: Vec<String> = names.iter().map(|name| format!("{} {}", name, if self.get(name) {1} else {0})).collect();
+        if !line.is_empty() {
+            info!("\t{}", line.join("\t"));
+        }
+    }
+}
+
+pub fn condition_from(condition: FlagCondition) -> Option<Condition> {
+    use binaryninja::architecture::FlagCondition::*;
+
+    return match condition {
+        LLFC_E => Some(Condition::E),
+        LLFC_NE => Some(Condition::Ne),
+        LLFC_SLT => Some(Condition::Slt),
+        LLFC_ULT => Some(Condition::Ult),
+        LLFC_SLE => Some(Condition::Sle),
+        LLFC_ULE => Some(Condition::Ule),
+        LLFC_SGE => Some(Condition::Sge),
+        LLFC_UGE => Some(Condition::Uge),
+        LLFC_SGT => Some(Condition::Sgt),
+        LLFC_UGT => Some(Condition::Ugt),
+        LLFC_NEG => Some(Condition::Neg),
+        LLFC_POS => Some(Condition::Pos),
+        LLFC_O => Some(Condition::O),
+        LLFC_NO => Some(Condition::No),
+        // Floating point conditions
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn x86() -> Flags {
+        let mut flags = Flags::empty();
+        for &(name, role) in [("z", Role::Zero), ("s", Role::Negative), ("c", Role::Carry), ("o", Role::Overflow)].iter() {
+            flags.roles.insert(String::from(name), role);
+        }
+        return flags;
+    }
+
+    fn written() -> Vec<String> {
+        return vec![String::from("z"), String::from("s"), String::from("c"), String::from("o")];
+    }
+
+    #[test]
+    fn add_carry_and_overflow() {
+        let mut flags = x86();
+        flags.write(&written(), FlagOp::Add, 0xff, 1, 0x100, 1);
+        assert!(flags.get("c") && flags.get("z") && !flags.get("o"));
+
+        flags.write(&written(), FlagOp::Add, 0x7f, 1, 0x80, 1);
+        assert!(!flags.get("c") && flags.get("o") && flags.get("s"));
+    }
+
+    #[test]
+    fn add_with_carry_in_wraps_to_left() {
+        // 0xff + 0xff + 1 is 0xff again, which only a carry in produces
+        let mut flags = x86();
+        flags.write(&written(), FlagOp::Add, 0xff, 0xff, 0x1ff, 1);
+        assert!(flags.get("c"));
+    }
+
+    #[test]
+    fn sub_borrow_and_conditions() {
+        let mut flags = x86();
+        flags.write(&written(), FlagOp::Sub, 1, 2, 1u128.wrapping_sub(2), 4);
+        assert!(flags.get("c") && !flags.get("o"));
+        assert!(flags.condition(Condition::Ult).unwrap());
+        assert!(flags.condition(Condition::Slt).unwrap());
+
+        // INT_MIN - 1 overflows to INT_MAX, signed less than still holds
+        flags.write(&written(), FlagOp::Sub, 0x80000000, 1, 0x7fffffff, 4);
+        assert!(flags.get("o") && !flags.get("s") && !flags.get("c"));
+        assert!(flags.condition(Condition::Slt).unwrap());
+        assert!(!flags.condition(Condition::Ult).unwrap());
+    }
+
+    #[test]
+    fn inverted_carry_on_sub() {
+        let mut flags = Flags::empty();
+        flags.roles.insert(String::from("c"), Role::InvertedCarry);
+        flags.write(&vec![String::from("c")], FlagOp::Sub, 2, 1, 1, 4);
+        assert!(flags.get("c"));
+        assert!(flags.condition(Condition::Uge).unwrap());
+    }
+
+    #[test]
+    fn shift_carry_out() {
+        let mut flags = x86();
+        flags.write(&written(), FlagOp::ShiftLeft, 0x80, 1, 0x100, 1);
+        assert!(flags.get("c") && flags.get("z"));
+        flags.write(&written(), FlagOp::ShiftRight, 1, 1, 0, 1);
+        assert!(flags.get("c"));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use state::*;
+
+// Chunks are aligned like glibc's and start after a header holding their size
+pub const ALIGN: u64 = 16;
+pub const HEADER: u64 = 16;
+// Unallocated bytes after every chunk so writes past the end don't land in the next one
+pub const REDZONE: u64 = 16;
+// Allocations at least this big get their own mapping
+pub const MMAP_THRESHOLD: u64 = 0x20000;
+// Anything bigger than this fails like it would on a real system. Mapped pages are backed by host
+// memory as soon as they're mapped, so this also bounds what one allocation costs the emulator.
+pub const MAX_ALLOCATION: u64 = 1 << 28;
+
+#[derive(Clone)]
+pub struct Chunk {
+    // Address handed to the program
+    pub addr: u64,
+    // Size the program asked for, and the usable size after alignment
+    pub size: u64,
+    pub capacity: u64,
+    pub freed: bool,
+    // Chunks with their own mapping are unmapped when freed
+    pub mapped: bool,
+    // Addresses of the calls that allocated and freed the chunk
+    pub alloc_site: u64,
+    pub free_site: Option<u64>,
+}
+
+impl Chunk {
+    pub fn contains(&self, addr: u64) -> bool {
+        return addr >= self.addr && addr < self.addr + self.capacity;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeapError {
+    DoubleFree { addr: u64, site: u64 },
+    // Freeing or reallocating a pointer that malloc didn't return
+    InvalidFree { addr: u64, site: u64 },
+    UseAfterFree { addr: u64, chunk: u64, site: u64 },
+    Overflow { addr: u64, chunk: u64, site: u64 },
+}
+
+impl HeapError {
+    pub fn describe(&self) -> String {
+        return match *self {
+            HeapError::DoubleFree { addr, site } => format!("0x{:x} Double free of 0x{:x}", site, addr),
+            HeapError::InvalidFree { addr, site } => format!("0x{:x} Free of 0x{:x} which isn't an allocation", site, addr),
+            HeapError::UseAfterFree { addr, chunk, site } => format!("0x{:x} Use of 0x{:x} in freed chunk 0x{:x}", site, addr, chunk),
+            HeapError::Overflow { addr, chunk, site } => format!("0x{:x} Access to 0x{:x} past the end of chunk 0x{:x}", site, addr, chunk),
+        };
+    }
+}
+
+// Chunk metadata for everything the program allocated. Small chunks are carved out of the
+// program break, freed ones are reused first fit.
+pub struct Heap {
+    pub chunks: BTreeMap<u64, Chunk>,
+    // Next free address at the top of the break backed region
+    pub top: u64,
+    pub errors: Vec<HeapError>,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        return Heap {
+            chunks: BTreeMap::new(),
+            top: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    // The chunk whose allocation or redzone covers the address
+    pub fn chunk_near(&self, addr: u64) -> Option<&Chunk> {
+        return self.chunks.range(..=addr).next_back()
+            .map(|(_, chunk)| chunk)
+            .filter(|chunk| addr < chunk.addr + chunk.capacity + REDZONE);
+    }
+
+    pub fn chunk_containing(&self, addr: u64) -> Option<&Chunk> {
+        return self.chunk_near(addr).filter(|chunk| chunk.contains(addr));
+    }
+
+    // Classifies an access of size bytes, None when it's fine or not on the heap
+    pub fn check(&self, addr: u64, size: usize, site: u64) -> Option<HeapError> {
+        for i in 0..size as u64 {
+            let addr = addr + i;
+            if let Some(chunk) = self.chunk_near(addr) {
+                if chunk.freed {
+                    return Some(HeapError::UseAfterFree { addr: addr, chunk: chunk.addr, site: site });
+                }
+                if addr >= chunk.addr + chunk.size {
+                    return Some(HeapError::Overflow { addr: addr, chunk: chunk.addr, site: site });
+                }
+            }
+        }
+        return None;
+    }
+
+    fn report(&mut self, err: HeapError) {
+        error!("{}", err.describe());
+        self.errors.push(err);
+    }
+}
+
+fn align(value: u64, align: u64) -> u64 {
+    return (value + align - 1) & !(align - 1);
+}
+
+// Allocates size bytes aligned to align, returning 0 when it can't
+pub fn allocate(state: &mut State, size: u64, alignment: u64) -> u64 {
+    if size > MAX_ALLOCATION {
+        return 0;
+    }
+    let alignment = alignment.max(ALIGN);
+    let capacity = align(size.max(1), ALIGN);
+    let site = state.addr;
+
+    // Reuse the first freed chunk that fits, mapped chunks are gone once freed
+    let reuse = state.heap.chunks.values()
+        .find(|chunk| chunk.freed && !chunk.mapped && chunk.capacity >= capacity && chunk.addr % alignment == 0)
+        .map(|chunk| chunk.addr);
+
+    let (addr, capacity, mapped) = match reuse {
+        Some(addr) => (addr, state.heap.chunks[&addr].capacity, false),
+        None if capacity >= MMAP_THRESHOLD => {
+            let start = match state.map_anonymous(0, HEADER + alignment + capacity, Perms::RW) {
+                Some(start) => start,
+                None => return 0,
+            };
+            (align(start + HEADER, alignment), capacity, true)
+        }
+        None => {
+            if state.heap.top == 0 {
+                state.heap.top = state.brk;
+            }
+            let addr = align(state.heap.top + HEADER, alignment);
+            let end = addr + capacity + REDZONE;
+            if end > state.brk && state.set_brk(end) != end {
+                return 0;
+            }
+            state.heap.top = end;
+            (addr, capacity, false)
+        }
+    };
+
+    // The header holds the chunk size like glibc's, for programs that peek at it
+    let size_field = state.regs.address_size;
+    state.memory.store(addr - size_field as u64, size_field, (capacity + HEADER) as u128).ok();
+
+    state.heap.chunks.insert(addr, Chunk {
+        addr: addr,
+        size: size,
+        capacity: capacity,
+        freed: false,
+        mapped: mapped,
+        alloc_site: site,
+        free_site: None,
+    });
+    info!("0x{:x} Allocated 0x{:x} bytes at 0x{:x}", site, size, addr);
+    return addr;
+}
+
+pub fn malloc(state: &mut State, size: u64) -> u64 {
+    return allocate(state, size, ALIGN);
+}
+
+// Freeing NULL does nothing, bad frees are recorded instead of stopping emulation
+pub fn free(state: &mut State, addr: u64) {
+    if addr == 0 {
+        return;
+    }
+    let site = state.addr;
+
+    let chunk = match state.heap.chunks.get(&addr) {
+        Some(chunk) => chunk.clone(),
+        None => {
+            state.heap.report(HeapError::InvalidFree { addr: addr, site: site });
+            return;
+        }
+    };
+    if chunk.freed {
+        state.heap.report(HeapError::DoubleFree { addr: addr, site: site });
+        return;
+    }
+
+    if chunk.mapped {
+        let start = (chunk.addr - HEADER) & !(PAGE_SIZE - 1);
+        if let Err(err) = state.memory.unmap(start, chunk.addr + chunk.capacity - start) {
+            info!("0x{:x} Couldn't unmap 0x{:x}: {}", site, addr, err);
+        }
+    }
+    if let Some(chunk) = state.heap.chunks.get_mut(&addr) {
+        chunk.freed = true;
+        chunk.free_site = Some(site);
+    }
+    info!("0x{:x} Freed 0x{:x}", site, addr);
+}
+
+pub fn calloc(state: &mut State, n: u64, size: u64) -> Result<u64, String> {
+    let total = match n.checked_mul(size) {
+        Some(total) => total,
+        None => return Ok(0),
+    };
+    let addr = malloc(state, total);
+    if addr != 0 {
+        state.memory.fill(addr, total, 0)?;
+    }
+    return Ok(addr);
+}
+
+// Grows in place when the chunk has room, otherwise moves the contents to a new chunk
+pub fn realloc(state: &mut State, addr: u64, size: u64) -> Result<u64, String> {
+    if addr == 0 {
+        return Ok(malloc(state, size));
+    }
+    if size == 0 {
+        free(state, addr);
+        return Ok(0);
+    }
+
+    let chunk = match state.heap.chunks.get(&addr) {
+        Some(chunk) if !chunk.freed => chunk.clone(),
+        Some(_) => {
+            let site = state.addr;
+            state.heap.report(HeapError::UseAfterFree { addr: addr, chunk: addr, site: site });
+            return Ok(0);
+        }
+        None => {
+            let site = state.addr;
+            state.heap.report(HeapError::InvalidFree { addr: addr, site: site });
+            return Ok(0);
+        }
+    };
+
+    if size <= chunk.capacity {
+        if let Some(chunk) = state.heap.chunks.get_mut(&addr) {
+            chunk.size = size;
+        }
+        return Ok(addr);
+    }
+
+    let new = malloc(state, size);
+    if new == 0 {
+        return Ok(0);
+    }
+    let bytes = state.memory.read_bytes(addr, chunk.size.min(size) as usize)?;
+    state.memory.write_bytes(new, &bytes)?;
+    free(state, addr);
+    return Ok(new);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let mut state = State::new();
+        state.brk_start = 0x10000000;
+        state.brk = 0x10000000;
+        state.addr = 0x401000;
+        return state;
+    }
+
+    #[test]
+    fn double_free() {
+        let mut state = state();
+        let addr = malloc(&mut state, 0x20);
+        assert!(addr != 0);
+        free(&mut state, addr);
+        assert!(state.heap.errors.is_empty());
+        state.addr = 0x401010;
+        free(&mut state, addr);
+        assert_eq!(state.heap.errors, vec![HeapError::DoubleFree { addr: addr, site: 0x401010 }]);
+    }
+
+    #[test]
+    fn free_of_interior_pointer() {
+        let mut state = state();
+        let addr = malloc(&mut state, 0x20);
+        free(&mut state, addr + 8);
+        assert_eq!(state.heap.errors, vec![HeapError::InvalidFree { addr: addr + 8, site: 0x401000 }]);
+    }
+
+    #[test]
+    fn calloc_zeroes_reused_chunks() {
+        let mut state = state();
+        let addr = malloc(&mut state, 0x10);
+        state.memory.write_bytes(addr, &[0xff; 0x10]).unwrap();
+        free(&mut state, addr);
+        assert_eq!(calloc(&mut state, 2, 8).unwrap(), addr);
+        assert_eq!(state.memory.read_bytes(addr, 0x10).unwrap(), vec![0; 0x10]);
+    }
+
+    #[test]
+    fn oversized_requests_fail() {
+        let mut state = state();
+        assert_eq!(calloc(&mut state, u64::max_value(), 2).unwrap(), 0);
+        assert_eq!(malloc(&mut state, MAX_ALLOCATION + 1), 0);
+        assert!(state.heap.chunks.is_empty());
+    }
+
+    #[test]
+    fn mapped_chunks_are_unmapped_when_freed() {
+        let mut state = state();
+        let addr = malloc(&mut state, MMAP_THRESHOLD);
+        assert!(state.memory.is_mapped(addr) && addr < MMAP_TOP);
+        free(&mut state, addr);
+        assert!(!state.memory.is_mapped(addr));
+    }
+}
@@ -6,18 +6,32 @@ extern crate rayon;
 extern crate z3;
 extern crate cpython;
 
-mod program;
-mod state;
-mod expression;
-mod procedures;
-mod run;
+pub mod program;
+pub mod state;
+pub mod registers;
+pub mod flags;
+pub mod callconv;
+pub mod expression;
+pub mod procedures;
+pub mod syscalls;
+pub mod vfs;
+pub mod heap;
+pub mod process;
+pub mod tls;
+pub mod run;
 mod solver;
 mod debugger;
-mod project;
-mod python;
+pub mod project;
+pub mod python;
 mod debugger_ui;
-mod emulator;
-mod taint_tracker;
+pub mod emulator;
+pub mod shadow;
+pub mod taint_tracker;
+pub mod taint_policy;
+pub mod dynamic_taint;
+pub mod static_taint;
+pub mod symbolic_executor;
+pub mod liftcheck;
 
 use binaryninja::binaryview::{BinaryView};
 use binaryninja::command;
@@ -31,12 +45,12 @@ use project::*;
 #[allow(non_snake_case)]
 pub extern "C" fn CorePluginInit() -> bool {
     binaryninja::logger::init(log::LevelFilter::Trace).expect("Failed to set up logging");
-    command::register_for_address("TEST ANALYSIS PLUGIN", "Description goes here", run_plugin1);
+    command::register_for_address("NAF\\Emulate from main", "Emulates the program from main and logs the report", emulate_command);
     
     true
 }
 
-pub fn run_plugin1(bv: &BinaryView, _addr: u64) {
+pub fn emulate_command(bv: &BinaryView, _addr: u64) {
     let gil = Python::acquire_gil();
     run::run(Project::new(bv, gil.python()));
 }
@@ -0,0 +1,176 @@
+#[macro_use]
+extern crate log;
+extern crate binaryninja;
+extern crate binja_riscv as naf;
+
+use std::env;
+use std::process;
+use log::{Log, Metadata, Record, LevelFilter};
+use naf::program::Program;
+use naf::run::{self, Options, Start, Format};
+
+const USAGE: &'static str = "Usage: naf <emulate|taint|scan|symex|liftcheck> <file> [options]
+
+Options:
+    --start <function|address>    Function name or address to start at (default main)
+    --steps <n>                   Maximum number of steps (default 50)
+    --argv0 <string>              argv[0] of the program (default the file's path)
+    --arg <string>                Argument passed to the program, can be repeated
+    --env <name>=<value>          Environment variable, replaces the default environment, can be repeated
+    --stdin <string>              Contents of stdin for emulation
+    --file <path>=<host path>     Makes a host file available to the emulated program at path
+    --sysroot <dir>               Loads the program's shared libraries from dir and runs their code
+    --procedure <name>            Keeps using the procedure for name with --sysroot, can be repeated
+    --taint                       Tracks taint from input while emulating
+    --find <address>              Address symbolic execution searches for
+    --avoid <address,...>         Addresses symbolic execution avoids
+    --format <text|json>          Output format (default text)
+    --verbose                     Log every instruction";
+
+// Logs go to stderr so the report on stdout stays machine readable
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        return true;
+    }
+
+    fn log(&self, record: &Record) {
+        eprintln!("[{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    let result = if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u64>()
+    };
+    return result.map_err(|_| format!("Invalid number {}", value));
+}
+
+fn parse_args(args: &[String]) -> Result<(String, String, Options, LevelFilter), String> {
+    if args.len() < 2 {
+        return Err(String::from("Missing command or file"));
+    }
+
+    let command = args[0].clone();
+    let file = args[1].clone();
+    let mut options = Options::new();
+    let mut level = LevelFilter::Warn;
+
+    let mut i = 2;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if flag == "--verbose" {
+            level = LevelFilter::Info;
+            i += 1;
+            continue;
+        }
+        if flag == "--taint" {
+            options.taint = true;
+            i += 1;
+            continue;
+        }
+
+        let value = match args.get(i + 1) {
+            Some(value) => value.as_str(),
+            None => return Err(format!("Missing value for {}", flag)),
+        };
+        match flag {
+            "--start" => {
+                options.start = match parse_number(value) {
+                    Ok(addr) => Start::Address(addr),
+                    Err(_) => Start::Function(String::from(value)),
+                };
+            }
+            "--steps" => options.steps = parse_number(value)? as usize,
+            "--argv0" => options.argv0 = Some(String::from(value)),
+            "--arg" => options.args.push(String::from(value)),
+            "--env" => options.env.get_or_insert(Vec::new()).push(String::from(value)),
+            "--stdin" => options.stdin = String::from(value),
+            "--file" => {
+                match value.find('=') {
+                    Some(i) => options.files.push((String::from(&value[..i]), String::from(&value[i + 1..]))),
+                    None => return Err(format!("Expected <path>=<host path> for --file, got {}", value)),
+                }
+            }
+            "--sysroot" => options.sysroot = Some(String::from(value)),
+            "--procedure" => options.procedures.push(String::from(value)),
+            "--find" => options.find = Some(parse_number(value)?),
+            "--avoid" => {
+                for addr in value.split(',') {
+                    options.avoid.push(parse_number(addr)?);
+                }
+            }
+            "--format" => {
+                options.format = match value {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    _ => return Err(format!("Unknown format {}", value)),
+                };
+            }
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+        i += 2;
+    }
+    return Ok((command, file, options, level));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, file, options, level) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    log::set_logger(&LOGGER).expect("Failed to set up logging");
+    log::set_max_level(level);
+
+    binaryninja::headless::init();
+
+    let code = match binaryninja::open_view(&file) {
+        Ok(bv) => {
+            let mut program = Program::new(&bv);
+            if let Some(ref sysroot) = options.sysroot {
+                match program.load_libraries(sysroot) {
+                    Ok(loaded) => info!("Loaded libraries {}", loaded.join(", ")),
+                    Err(err) => error!("{}", err),
+                }
+            }
+            let report = match command.as_str() {
+                "emulate" => Some(run::emulate(&program, &options)),
+                "taint" => Some(run::taint(&program, &options)),
+                "scan" => Some(run::scan(&program, &options)),
+                "symex" => Some(run::symex(&program, &options)),
+                "liftcheck" => Some(run::liftcheck(&program, &options)),
+                _ => None,
+            };
+
+            match report {
+                Some(report) => {
+                    println!("{}", report.render(options.format));
+                    if report.has_error() { 1 } else { 0 }
+                }
+                None => {
+                    eprintln!("Unknown command {}\n\n{}", command, USAGE);
+                    2
+                }
+            }
+        }
+        Err(err) => {
+            error!("Failed to open {}: {}", file, err);
+            1
+        }
+    };
+
+    binaryninja::headless::shutdown();
+    process::exit(code);
+}
@@ -1,49 +1,1353 @@
-use state;
+use std::collections::HashMap;
+use state::State;
+use expression::sign_extend;
+use vfs::*;
+use heap;
 
-pub fn call(name: String, state: &mut state::State) {
-    /**
-     * Parses the name of function and matches it with the appropriate function.
-     **/
-    let mut_state = state; 
-    match name.as_str() {
-        "puts" => puts(mut_state),
-        "printf" => printf(mut_state),
-        "fgets" => fgets(mut_state),
-        "strlen" => strlen(mut_state),
-        "atoi" => atoi(mut_state),
-        _ => unknown(mut_state),
+// Longest string a procedure will scan before giving up on finding the terminator
+const MAX_STRING: usize = 0x10000;
+
+pub type Procedure = Box<Fn(&mut State) -> Result<(), String>>;
+
+// Models of functions the emulator runs instead of stepping into them.
+// Names and libraries are matched case insensitively since function names are lowercased.
+pub struct Procedures {
+    names: HashMap<String, Procedure>,
+    // Integer arguments of the procedures registered by name, where the count is fixed
+    args: HashMap<String, usize>,
+    addresses: HashMap<u64, Procedure>,
+    libraries: HashMap<(String, String), Procedure>,
+}
+
+impl Procedures {
+    pub fn new() -> Procedures {
+        return Procedures {
+            names: HashMap::new(),
+            args: HashMap::new(),
+            addresses: HashMap::new(),
+            libraries: HashMap::new(),
+        }
+    }
+
+    // Registry with the libc models registered by name, along with how many integer arguments
+    // they take. Variadic ones don't say.
+    pub fn libc() -> Procedures {
+        let mut procedures = Procedures::new();
+
+        procedures.register_args("strlen", 1, Box::new(strlen));
+        procedures.register_args("strnlen", 2, Box::new(strnlen));
+        procedures.register_args("strcmp", 2, Box::new(strcmp));
+        procedures.register_args("strncmp", 3, Box::new(strncmp));
+        procedures.register_args("strncat", 3, Box::new(strncat));
+        procedures.register_args("strchr", 2, Box::new(strchr));
+        procedures.register_args("strrchr", 2, Box::new(strrchr));
+        procedures.register_args("strstr", 2, Box::new(strstr));
+        for &(name, args) in [("strcpy", 2), ("__strcpy_chk", 3)].iter() {
+            procedures.register_args(name, args, Box::new(strcpy));
+        }
+        for &(name, args) in [("strncpy", 3), ("__strncpy_chk", 4)].iter() {
+            procedures.register_args(name, args, Box::new(strncpy));
+        }
+        for &(name, args) in [("strcat", 2), ("__strcat_chk", 3)].iter() {
+            procedures.register_args(name, args, Box::new(strcat));
+        }
+
+        for &(name, args) in [("memcpy", 3), ("memmove", 3), ("__memcpy_chk", 4), ("__memmove_chk", 4)].iter() {
+            procedures.register_args(name, args, Box::new(memcpy));
+        }
+        for &(name, args) in [("memset", 3), ("__memset_chk", 4)].iter() {
+            procedures.register_args(name, args, Box::new(memset));
+        }
+        procedures.register_args("memcmp", 3, Box::new(memcmp));
+        procedures.register_args("memchr", 3, Box::new(memchr));
+
+        for name in ["atoi", "atol", "atoll"].iter() {
+            let name = *name;
+            procedures.register_args(name, 1, Box::new(move |state: &mut State| atoi(state, name)));
+        }
+        for &(name, signed) in [("strtol", true), ("strtoul", false)].iter() {
+            procedures.register_args(name, 3, Box::new(move |state: &mut State| {
+                let size = state.callconv.long_size;
+                strtol(state, signed, size)
+            }));
+        }
+        for &(name, signed) in [("strtoll", true), ("strtoull", false)].iter() {
+            procedures.register_args(name, 3, Box::new(move |state: &mut State| strtol(state, signed, 8)));
+        }
+        for name in ["toupper", "tolower", "isdigit", "isalpha", "isalnum", "isspace", "isupper", "islower", "isxdigit"].iter() {
+            let name = *name;
+            procedures.register_args(name, 1, Box::new(move |state: &mut State| ctype(state, name)));
+        }
+
+        procedures.register_args("puts", 1, Box::new(puts));
+        procedures.register_args("putchar", 1, Box::new(putchar));
+        procedures.register("printf", Box::new(|state: &mut State| printf(state, 0)));
+        procedures.register("__printf_chk", Box::new(|state: &mut State| printf(state, 1)));
+        procedures.register("sprintf", Box::new(sprintf));
+        procedures.register("snprintf", Box::new(snprintf));
+        procedures.register("fprintf", Box::new(|state: &mut State| fprintf(state, 1)));
+        procedures.register("__fprintf_chk", Box::new(|state: &mut State| fprintf(state, 2)));
+        procedures.register_args("fputs", 2, Box::new(fputs));
+        for name in ["fputc", "putc"].iter() {
+            procedures.register_args(name, 2, Box::new(fputc));
+        }
+        procedures.register_args("fwrite", 4, Box::new(fwrite));
+        procedures.register_args("fflush", 1, Box::new(fflush));
+
+        for name in ["fopen", "fopen64"].iter() {
+            procedures.register_args(name, 2, Box::new(fopen));
+        }
+        procedures.register_args("fclose", 1, Box::new(fclose));
+        procedures.register_args("fread", 4, Box::new(fread));
+        procedures.register_args("fgets", 3, Box::new(fgets));
+        for name in ["fgetc", "getc"].iter() {
+            procedures.register_args(name, 1, Box::new(fgetc));
+        }
+        procedures.register_args("getchar", 0, Box::new(|state: &mut State| getc(state, 0)));
+        for name in ["open", "open64"].iter() {
+            procedures.register(name, Box::new(open));
+        }
+        procedures.register_args("close", 1, Box::new(close));
+        procedures.register_args("read", 3, Box::new(read));
+        procedures.register_args("write", 3, Box::new(write));
+        procedures.register_args("lseek", 3, Box::new(lseek));
+        for name in ["scanf", "__isoc99_scanf"].iter() {
+            procedures.register(name, Box::new(scanf));
+        }
+        for name in ["fscanf", "__isoc99_fscanf"].iter() {
+            procedures.register(name, Box::new(fscanf));
+        }
+        for name in ["sscanf", "__isoc99_sscanf"].iter() {
+            procedures.register(name, Box::new(sscanf));
+        }
+        procedures.register_args("malloc", 1, Box::new(malloc));
+        procedures.register_args("calloc", 2, Box::new(calloc));
+        procedures.register_args("realloc", 2, Box::new(realloc));
+        procedures.register_args("free", 1, Box::new(free));
+        for name in ["aligned_alloc", "memalign"].iter() {
+            procedures.register_args(name, 2, Box::new(memalign));
+        }
+        procedures.register_args("posix_memalign", 3, Box::new(posix_memalign));
+        procedures.register_args("malloc_usable_size", 1, Box::new(malloc_usable_size));
+        procedures.register_args("strdup", 1, Box::new(strdup));
+        procedures.register_args("strndup", 2, Box::new(strndup));
+
+        for name in ["exit", "_exit"].iter() {
+            procedures.register_args(name, 1, Box::new(exit));
+        }
+        return procedures;
+    }
+
+    // Registering a name again replaces the existing procedure
+    pub fn register(&mut self, name: &str, procedure: Procedure) {
+        self.args.remove(&name.to_ascii_lowercase());
+        self.names.insert(name.to_ascii_lowercase(), procedure);
+    }
+
+    // Registers a procedure that always takes the same number of integer arguments
+    pub fn register_args(&mut self, name: &str, args: usize, procedure: Procedure) {
+        self.register(name, procedure);
+        self.args.insert(name.to_ascii_lowercase(), args);
+    }
+
+    pub fn register_address(&mut self, addr: u64, procedure: Procedure) {
+        self.addresses.insert(addr, procedure);
+    }
+
+    // Only matches imports of the name from that library
+    pub fn register_library(&mut self, library: &str, name: &str, procedure: Procedure) {
+        self.libraries.insert((library.to_ascii_lowercase(), name.to_ascii_lowercase()), procedure);
+    }
+
+    pub fn unregister(&mut self, name: &str) {
+        self.names.remove(&name.to_ascii_lowercase());
+        self.args.remove(&name.to_ascii_lowercase());
+    }
+
+    pub fn unregister_address(&mut self, addr: u64) {
+        self.addresses.remove(&addr);
+    }
+
+    // Drops every hook by name except the listed ones, so real library code runs for the rest
+    pub fn retain(&mut self, names: &[String]) {
+        let names: Vec<String> = names.iter().map(|name| name.to_ascii_lowercase()).collect();
+        self.names.retain(|name, _| names.contains(name));
+        self.args.retain(|name, _| names.contains(name));
+    }
+
+    // How many integer arguments the procedure registered by name takes, if that's fixed
+    pub fn arguments(&self, name: &str) -> Option<usize> {
+        return self.args.get(&name.to_ascii_lowercase()).cloned();
+    }
+
+    // Address hooks win over library hooks, which win over hooks by name
+    pub fn lookup(&self, addr: u64, name: &str, library: Option<&str>) -> Option<&Procedure> {
+        if let Some(procedure) = self.addresses.get(&addr) {
+            return Some(procedure);
+        }
+        if let Some(library) = library {
+            if let Some(procedure) = self.libraries.get(&(library.to_ascii_lowercase(), name.to_ascii_lowercase())) {
+                return Some(procedure);
+            }
+        }
+        return self.names.get(&name.to_ascii_lowercase());
     }
-    
+
+    // Runs the procedure for a call if there is one, returning whether the call was hooked
+    pub fn call(&self, state: &mut State, addr: u64, name: &str, library: Option<&str>) -> Result<bool, String> {
+        match self.lookup(addr, name, library) {
+            Some(procedure) => {
+                procedure(state)?;
+                return Ok(true);
+            }
+            None => return Ok(false),
+        }
+    }
+}
+
+pub fn read_string(state: &State, addr: u64) -> Result<Vec<u8>, String> {
+    return read_string_n(state, addr, MAX_STRING).and_then(|bytes| {
+        if bytes.len() == MAX_STRING {
+            return Err(format!("Unterminated string at 0x{:x}", addr));
+        }
+        return Ok(bytes);
+    });
+}
+
+// Reads at most n bytes of a string, stopping at the terminator
+fn read_string_n(state: &State, addr: u64, n: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    while bytes.len() < n {
+        let byte = state.memory.load(addr + bytes.len() as u64, 1)? as u8;
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+    return Ok(bytes);
+}
+
+fn write_string(state: &mut State, addr: u64, bytes: &[u8]) -> Result<(), String> {
+    state.memory.write_bytes(addr, bytes)?;
+    return state.memory.write_bytes(addr + bytes.len() as u64, &[0]);
+}
+
+fn is_space(c: u8) -> bool {
+    return c == b' ' || (c >= b'\t' && c <= b'\r');
 }
 
 /* These simulate various procedure calls */
 
-fn puts(state: &mut state::State) {
-    info!("0x{:x} Calling procedures puts() (printing string at 0x{:x})", state.addr, state.regs.rdi);
-    state.regs.rax = 0;
+fn strlen(state: &mut State) -> Result<(), String> {
+    let s = state.arg(0)?;
+    let len = read_string(state, s)?.len();
+    info!("0x{:x} Calling procedures strlen() on 0x{:x}, returning {}", state.addr, s, len);
+    state.set_return(len as u64);
+    return Ok(());
+}
+
+fn strnlen(state: &mut State) -> Result<(), String> {
+    let (s, n) = (state.arg(0)?, state.arg(1)? as usize);
+    let len = read_string_n(state, s, n)?.len();
+    state.set_return(len as u64);
+    return Ok(());
+}
+
+// Compares like the C library, bytes are unsigned and the terminator compares as zero
+fn compare(left: &[u8], right: &[u8], n: usize) -> i64 {
+    for i in 0..n {
+        let l = *left.get(i).unwrap_or(&0) as i64;
+        let r = *right.get(i).unwrap_or(&0) as i64;
+        if l != r || l == 0 {
+            return l - r;
+        }
+    }
+    return 0;
+}
+
+fn strcmp(state: &mut State) -> Result<(), String> {
+    let left = read_string(state, state.arg(0)?)?;
+    let right = read_string(state, state.arg(1)?)?;
+    let result = compare(&left, &right, left.len().max(right.len()) + 1);
+    info!("0x{:x} Calling procedures strcmp() on {:?} and {:?}, returning {}", state.addr, String::from_utf8_lossy(&left), String::from_utf8_lossy(&right), result);
+    state.set_return(result as u64);
+    return Ok(());
+}
+
+fn strncmp(state: &mut State) -> Result<(), String> {
+    let n = state.arg(2)? as usize;
+    let left = read_string_n(state, state.arg(0)?, n)?;
+    let right = read_string_n(state, state.arg(1)?, n)?;
+    let result = compare(&left, &right, n);
+    state.set_return(result as u64);
+    return Ok(());
+}
+
+fn strcpy(state: &mut State) -> Result<(), String> {
+    let (dst, src) = (state.arg(0)?, state.arg(1)?);
+    let bytes = read_string(state, src)?;
+    write_string(state, dst, &bytes)?;
+    state.set_return(dst);
+    return Ok(());
+}
+
+// Copies at most n bytes and pads the rest of the destination with zeros
+fn strncpy(state: &mut State) -> Result<(), String> {
+    let (dst, src, n) = (state.arg(0)?, state.arg(1)?, state.arg(2)? as usize);
+    let mut bytes = read_string_n(state, src, n)?;
+    bytes.resize(n, 0);
+    state.memory.write_bytes(dst, &bytes)?;
+    state.set_return(dst);
+    return Ok(());
+}
+
+fn strcat(state: &mut State) -> Result<(), String> {
+    let (dst, src) = (state.arg(0)?, state.arg(1)?);
+    let end = dst + read_string(state, dst)?.len() as u64;
+    let bytes = read_string(state, src)?;
+    write_string(state, end, &bytes)?;
+    state.set_return(dst);
+    return Ok(());
+}
+
+fn strncat(state: &mut State) -> Result<(), String> {
+    let (dst, src, n) = (state.arg(0)?, state.arg(1)?, state.arg(2)? as usize);
+    let end = dst + read_string(state, dst)?.len() as u64;
+    let bytes = read_string_n(state, src, n)?;
+    write_string(state, end, &bytes)?;
+    state.set_return(dst);
+    return Ok(());
+}
+
+// The terminator is part of the string, so searching for zero finds the end
+fn strchr(state: &mut State) -> Result<(), String> {
+    let (s, c) = (state.arg(0)?, state.arg(1)? as u8);
+    let mut bytes = read_string(state, s)?;
+    bytes.push(0);
+    let result = match bytes.iter().position(|&b| b == c) {
+        Some(i) => s + i as u64,
+        None => 0,
+    };
+    state.set_return(result);
+    return Ok(());
 }
 
-fn printf(state: &mut state::State) {
-    info!("0x{:x} Calling procedures printf() (printing string at 0x{:x})", state.addr, state.regs.rdi);
-    state.regs.rax = 0;
+fn strrchr(state: &mut State) -> Result<(), String> {
+    let (s, c) = (state.arg(0)?, state.arg(1)? as u8);
+    let mut bytes = read_string(state, s)?;
+    bytes.push(0);
+    let result = match bytes.iter().rposition(|&b| b == c) {
+        Some(i) => s + i as u64,
+        None => 0,
+    };
+    state.set_return(result);
+    return Ok(());
 }
 
-fn fgets(state: &mut state::State) {
-    info!("0x{:x} Calling procedures fgets(), adding string ...", state.addr);
-    state.stdin = String::from("1234");
-    state.regs.rax = 0;
+fn strstr(state: &mut State) -> Result<(), String> {
+    let haystack_addr = state.arg(0)?;
+    let haystack = read_string(state, haystack_addr)?;
+    let needle = read_string(state, state.arg(1)?)?;
+    let result = if needle.is_empty() {
+        haystack_addr
+    } else {
+        match haystack.windows(needle.len()).position(|w| w == &needle[..]) {
+            Some(i) => haystack_addr + i as u64,
+            None => 0,
+        }
+    };
+    state.set_return(result);
+    return Ok(());
 }
 
-fn strlen(state: &mut state::State) {
-    info!("0x{:x} Calling procedures strlen()", state.addr);
-    state.regs.rax = 4;
+// Reading everything before writing makes this correct for overlapping memmove too
+fn memcpy(state: &mut State) -> Result<(), String> {
+    let (dst, src, n) = (state.arg(0)?, state.arg(1)?, state.arg(2)? as usize);
+    let bytes = state.memory.read_bytes(src, n)?;
+    state.memory.write_bytes(dst, &bytes)?;
+    info!("0x{:x} Calling procedures memcpy() copying {} bytes from 0x{:x} to 0x{:x}", state.addr, n, src, dst);
+    state.set_return(dst);
+    return Ok(());
 }
 
-fn atoi(state: &mut state::State) {
-    info!("0x{:x} Calling procedures atoi()", state.addr);
-    state.regs.rax = 6;
+fn memset(state: &mut State) -> Result<(), String> {
+    let (dst, c, n) = (state.arg(0)?, state.arg(1)? as u8, state.arg(2)?);
+    state.memory.fill(dst, n, c)?;
+    state.set_return(dst);
+    return Ok(());
 }
 
-fn unknown(state: &mut state::State) {
-    error!("Calling unknown library");
-}
\ No newline at end of file
+fn memcmp(state: &mut State) -> Result<(), String> {
+    let n = state.arg(2)? as usize;
+    let left = state.memory.read_bytes(state.arg(0)?, n)?;
+    let right = state.memory.read_bytes(state.arg(1)?, n)?;
+    let result = match left.iter().zip(right.iter()).find(|&(l, r)| l != r) {
+        Some((&l, &r)) => l as i64 - r as i64,
+        None => 0,
+    };
+    state.set_return(result as u64);
+    return Ok(());
+}
+
+fn memchr(state: &mut State) -> Result<(), String> {
+    let (s, c, n) = (state.arg(0)?, state.arg(1)? as u8, state.arg(2)? as usize);
+    let bytes = state.memory.read_bytes(s, n)?;
+    let result = match bytes.iter().position(|&b| b == c) {
+        Some(i) => s + i as u64,
+        None => 0,
+    };
+    state.set_return(result);
+    return Ok(());
+}
+
+// Parses an integer the way strtol does after leading whitespace, returning the value and bytes consumed.
+// Base 0 picks the base from the prefix. None means no digits were found.
+fn parse_integer(bytes: &[u8], base: u32) -> Option<(i128, usize)> {
+    let mut i = 0;
+    let negative = match bytes.get(0) {
+        Some(&b'-') => { i += 1; true }
+        Some(&b'+') => { i += 1; false }
+        _ => false,
+    };
+
+    let hex_prefix = bytes.get(i) == Some(&b'0') && (bytes.get(i + 1) == Some(&b'x') || bytes.get(i + 1) == Some(&b'X'))
+        && bytes.get(i + 2).map_or(false, |c| (*c as char).is_digit(16));
+    let base = match base {
+        0 if hex_prefix => 16,
+        0 if bytes.get(i) == Some(&b'0') => 8,
+        0 => 10,
+        base => base,
+    };
+    if base == 16 && hex_prefix {
+        i += 2;
+    }
+
+    let start = i;
+    let mut value: i128 = 0;
+    while let Some(digit) = bytes.get(i).and_then(|c| (*c as char).to_digit(base)) {
+        // Saturate rather than overflow, callers clamp to their own range
+        value = value.saturating_mul(base as i128).saturating_add(digit as i128);
+        i += 1;
+    }
+    if i == start {
+        return None;
+    }
+    return Some((if negative { -value } else { value }, i));
+}
+
+fn skip_space(bytes: &[u8]) -> usize {
+    return bytes.iter().take_while(|&&c| is_space(c)).count();
+}
+
+fn atoi(state: &mut State, name: &str) -> Result<(), String> {
+    let bytes = read_string(state, state.arg(0)?)?;
+    let start = skip_space(&bytes);
+    let value = parse_integer(&bytes[start..], 10).map_or(0, |(value, _)| value);
+    let value = match name {
+        "atoi" => value as i32 as i64,
+        "atol" if state.callconv.long_size == 4 => value as i32 as i64,
+        _ => value as i64,
+    };
+    info!("0x{:x} Calling procedures atoi() on {:?}, returning {}", state.addr, String::from_utf8_lossy(&bytes), value);
+    state.set_return(value as u64);
+    return Ok(());
+}
+
+// Parses an integer, clamping it to the size bytes of the result type
+fn strtol(state: &mut State, signed: bool, size: usize) -> Result<(), String> {
+    let (s, end, base) = (state.arg(0)?, state.arg(1)?, state.arg(2)? as u32);
+    if base == 1 || base > 36 {
+        state.set_return(0);
+        return Ok(());
+    }
+
+    let bytes = read_string(state, s)?;
+    let start = skip_space(&bytes);
+    let (value, consumed) = match parse_integer(&bytes[start..], base) {
+        Some((value, consumed)) => (value, start + consumed),
+        // Without any digits the end pointer is the start of the string
+        None => (0, 0),
+    };
+
+    let bits = size * 8;
+    let max = if signed { (1i128 << (bits - 1)) - 1 } else { (1i128 << bits) - 1 };
+    let result = if signed {
+        value.max(-max - 1).min(max) as i64 as u64
+    } else if value.abs() > max {
+        max as u64
+    } else if value < 0 {
+        // Negative values are negated in the unsigned type
+        (value.abs() as u64).wrapping_neg() & max as u64
+    } else {
+        value as u64
+    };
+
+    if end != 0 {
+        let size = state.regs.address_size;
+        state.memory.store(end, size, (s + consumed as u64) as u128)?;
+    }
+    state.set_return(result);
+    return Ok(());
+}
+
+fn ctype(state: &mut State, name: &str) -> Result<(), String> {
+    let c = state.arg(0)? as u32;
+    let ch = if c < 0x80 { c as u8 as char } else { '\0' };
+    let result = match name {
+        "toupper" => if ch.is_ascii_lowercase() { c - 0x20 } else { c },
+        "tolower" => if ch.is_ascii_uppercase() { c + 0x20 } else { c },
+        "isdigit" => ch.is_ascii_digit() as u32,
+        "isalpha" => ch.is_ascii_alphabetic() as u32,
+        "isalnum" => ch.is_ascii_alphanumeric() as u32,
+        "isspace" => is_space(c as u8) as u32 & (c < 0x80) as u32,
+        "isupper" => ch.is_ascii_uppercase() as u32,
+        "islower" => ch.is_ascii_lowercase() as u32,
+        _ => ch.is_ascii_hexdigit() as u32,
+    };
+    state.set_return(result as u64);
+    return Ok(());
+}
+
+fn malloc(state: &mut State) -> Result<(), String> {
+    let size = state.arg(0)?;
+    let addr = heap::malloc(state, size);
+    state.set_return(addr);
+    return Ok(());
+}
+
+fn calloc(state: &mut State) -> Result<(), String> {
+    let (n, size) = (state.arg(0)?, state.arg(1)?);
+    let addr = heap::calloc(state, n, size)?;
+    state.set_return(addr);
+    return Ok(());
+}
+
+fn realloc(state: &mut State) -> Result<(), String> {
+    let (addr, size) = (state.arg(0)?, state.arg(1)?);
+    let addr = heap::realloc(state, addr, size)?;
+    state.set_return(addr);
+    return Ok(());
+}
+
+fn free(state: &mut State) -> Result<(), String> {
+    let addr = state.arg(0)?;
+    heap::free(state, addr);
+    return Ok(());
+}
+
+fn memalign(state: &mut State) -> Result<(), String> {
+    let (alignment, size) = (state.arg(0)?, state.arg(1)?);
+    let addr = if alignment.is_power_of_two() { heap::allocate(state, size, alignment) } else { 0 };
+    state.set_return(addr);
+    return Ok(());
+}
+
+// Returns an error number rather than setting errno
+fn posix_memalign(state: &mut State) -> Result<(), String> {
+    let (ptr, alignment, size) = (state.arg(0)?, state.arg(1)?, state.arg(2)?);
+    if !alignment.is_power_of_two() {
+        state.set_return(22);
+        return Ok(());
+    }
+    let addr = heap::allocate(state, size, alignment);
+    if addr == 0 {
+        state.set_return(12);
+        return Ok(());
+    }
+    let size = state.regs.address_size;
+    state.memory.store(ptr, size, addr as u128)?;
+    state.set_return(0);
+    return Ok(());
+}
+
+fn malloc_usable_size(state: &mut State) -> Result<(), String> {
+    let addr = state.arg(0)?;
+    let result = match state.heap.chunks.get(&addr) {
+        Some(chunk) if !chunk.freed => chunk.capacity,
+        _ => 0,
+    };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn strdup(state: &mut State) -> Result<(), String> {
+    let bytes = read_string(state, state.arg(0)?)?;
+    let addr = heap::malloc(state, bytes.len() as u64 + 1);
+    if addr != 0 {
+        write_string(state, addr, &bytes)?;
+    }
+    state.set_return(addr);
+    return Ok(());
+}
+
+fn strndup(state: &mut State) -> Result<(), String> {
+    let (s, n) = (state.arg(0)?, state.arg(1)? as usize);
+    let bytes = read_string_n(state, s, n)?;
+    let addr = heap::malloc(state, bytes.len() as u64 + 1);
+    if addr != 0 {
+        write_string(state, addr, &bytes)?;
+    }
+    state.set_return(addr);
+    return Ok(());
+}
+
+// FILE pointers from fopen are this plus the descriptor, the program never looks inside them
+pub const FILE_BASE: u64 = 0xf11e0000;
+const MAX_FILES: u64 = 0x1000;
+
+// Descriptor behind a FILE pointer, from fopen or one of the standard stream globals. Streams
+// that are neither use the default descriptor.
+fn stream_fd(state: &State, stream: u64, default: u64) -> u64 {
+    if stream >= FILE_BASE && stream < FILE_BASE + MAX_FILES {
+        return stream - FILE_BASE;
+    }
+    return state.fs.streams.get(&stream).cloned().unwrap_or(default);
+}
+
+// Writes to a descriptor, a failed write is reported to the program rather than stopping emulation
+fn output(state: &mut State, fd: u64, bytes: &[u8]) -> bool {
+    if let Err(errno) = state.fs.write(fd, bytes) {
+        info!("0x{:x} Write to file descriptor {} failed with errno {}", state.addr, fd, errno);
+        return false;
+    }
+    return true;
+}
+
+fn puts(state: &mut State) -> Result<(), String> {
+    let mut bytes = read_string(state, state.arg(0)?)?;
+    info!("0x{:x} Calling procedures puts() printing {:?}", state.addr, String::from_utf8_lossy(&bytes));
+    bytes.push(b'\n');
+    let result = if output(state, 1, &bytes) { 0 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn fputs(state: &mut State) -> Result<(), String> {
+    let bytes = read_string(state, state.arg(0)?)?;
+    let fd = stream_fd(state, state.arg(1)?, 1);
+    let result = if output(state, fd, &bytes) { 0 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn putchar(state: &mut State) -> Result<(), String> {
+    let c = state.arg(0)? as u8;
+    let result = if output(state, 1, &[c]) { c as u64 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn fputc(state: &mut State) -> Result<(), String> {
+    let c = state.arg(0)? as u8;
+    let fd = stream_fd(state, state.arg(1)?, 1);
+    let result = if output(state, fd, &[c]) { c as u64 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+// Stops emulation the same way the exit syscall does
+fn exit(state: &mut State) -> Result<(), String> {
+    let status = state.arg(0)? & 0xff;
+    info!("0x{:x} Program exited with status {}", state.addr, status);
+    state.exited = Some(status);
+    return Ok(());
+}
+
+// The flag argument of __printf_chk comes before the format
+fn printf(state: &mut State, format_arg: usize) -> Result<(), String> {
+    let fmt = read_string(state, state.arg(format_arg)?)?;
+    let out = format(state, &fmt, format_arg + 1)?;
+    info!("0x{:x} Calling procedures printf() printing {:?}", state.addr, String::from_utf8_lossy(&out));
+    let result = if output(state, 1, &out) { out.len() as u64 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn fprintf(state: &mut State, format_arg: usize) -> Result<(), String> {
+    let fd = stream_fd(state, state.arg(0)?, 1);
+    let fmt = read_string(state, state.arg(format_arg)?)?;
+    let out = format(state, &fmt, format_arg + 1)?;
+    let result = if output(state, fd, &out) { out.len() as u64 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn sprintf(state: &mut State) -> Result<(), String> {
+    let dst = state.arg(0)?;
+    let fmt = read_string(state, state.arg(1)?)?;
+    let out = format(state, &fmt, 2)?;
+    write_string(state, dst, &out)?;
+    state.set_return(out.len() as u64);
+    return Ok(());
+}
+
+// Writes at most n - 1 bytes but returns the length the full output would have had
+fn snprintf(state: &mut State) -> Result<(), String> {
+    let (dst, n) = (state.arg(0)?, state.arg(1)? as usize);
+    let fmt = read_string(state, state.arg(2)?)?;
+    let out = format(state, &fmt, 3)?;
+    if n > 0 {
+        let len = out.len().min(n - 1);
+        write_string(state, dst, &out[..len])?;
+    }
+    state.set_return(out.len() as u64);
+    return Ok(());
+}
+
+// Modes are translated to open flags, fopen returns NULL when the open fails
+fn fopen(state: &mut State) -> Result<(), String> {
+    let path = String::from_utf8_lossy(&read_string(state, state.arg(0)?)?).into_owned();
+    let mode = read_string(state, state.arg(1)?)?;
+
+    let update = mode.contains(&b'+');
+    let mut flags = match mode.first() {
+        Some(b'r') => if update { O_RDWR } else { O_RDONLY },
+        Some(b'w') => (if update { O_RDWR } else { O_WRONLY }) | O_CREAT | O_TRUNC,
+        Some(b'a') => (if update { O_RDWR } else { O_WRONLY }) | O_CREAT | O_APPEND,
+        _ => {
+            state.set_return(0);
+            return Ok(());
+        }
+    };
+    if mode.contains(&b'x') {
+        flags |= O_EXCL;
+    }
+
+    let result = match state.fs.open(&path, flags) {
+        Ok(fd) if fd < MAX_FILES => FILE_BASE + fd,
+        Ok(fd) => {
+            state.fs.close(fd).ok();
+            0
+        }
+        Err(_) => 0,
+    };
+    info!("0x{:x} Calling procedures fopen() on {} returning 0x{:x}", state.addr, path, result);
+    state.set_return(result);
+    return Ok(());
+}
+
+fn fclose(state: &mut State) -> Result<(), String> {
+    let fd = stream_fd(state, state.arg(0)?, MAX_FILES);
+    let result = if state.fs.close(fd).is_ok() { 0 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+// Nothing is buffered so there's never anything to flush
+fn fflush(state: &mut State) -> Result<(), String> {
+    state.set_return(0);
+    return Ok(());
+}
+
+fn fread(state: &mut State) -> Result<(), String> {
+    let (buf, size, n) = (state.arg(0)?, state.arg(1)? as usize, state.arg(2)? as usize);
+    let fd = stream_fd(state, state.arg(3)?, 0);
+    if size == 0 {
+        state.set_return(0);
+        return Ok(());
+    }
+
+    // Only whole items are read
+    let len = match state.fs.remaining(fd) {
+        Ok(remaining) => (remaining.len() / size).min(n) * size,
+        Err(_) => 0,
+    };
+    let bytes = state.fs.read(fd, len).unwrap_or(Vec::new());
+    state.memory.write_bytes(buf, &bytes)?;
+    state.set_return((bytes.len() / size) as u64);
+    return Ok(());
+}
+
+fn fwrite(state: &mut State) -> Result<(), String> {
+    let (buf, size, n) = (state.arg(0)?, state.arg(1)? as usize, state.arg(2)? as usize);
+    let fd = stream_fd(state, state.arg(3)?, 1);
+    let len = match size.checked_mul(n) {
+        Some(len) => len,
+        None => {
+            state.set_return(0);
+            return Ok(());
+        }
+    };
+    let bytes = state.memory.read_bytes(buf, len)?;
+    let result = if output(state, fd, &bytes) { n as u64 } else { 0 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn fgets(state: &mut State) -> Result<(), String> {
+    let (buf, n) = (state.arg(0)?, state.arg(1)? as usize);
+    let fd = stream_fd(state, state.arg(2)?, 0);
+    let len = match state.fs.remaining(fd) {
+        Ok(remaining) if n > 0 && !remaining.is_empty() => {
+            let len = remaining.iter().take(n - 1).position(|&c| c == b'\n').map_or(n - 1, |i| i + 1);
+            len.min(remaining.len())
+        }
+        _ => 0,
+    };
+    if len == 0 {
+        state.set_return(0);
+        return Ok(());
+    }
+
+    let line = state.fs.read(fd, len).unwrap_or(Vec::new());
+    write_string(state, buf, &line)?;
+    info!("0x{:x} Calling procedures fgets() reading {:?}", state.addr, String::from_utf8_lossy(&line));
+    state.set_return(buf);
+    return Ok(());
+}
+
+fn getc(state: &mut State, fd: u64) -> Result<(), String> {
+    let result = match state.fs.read(fd, 1) {
+        Ok(ref bytes) if bytes.len() == 1 => bytes[0] as u64,
+        _ => -1i64 as u64,
+    };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn fgetc(state: &mut State) -> Result<(), String> {
+    let fd = stream_fd(state, state.arg(0)?, 0);
+    return getc(state, fd);
+}
+
+// Failed calls return -1, errno isn't modelled
+fn open(state: &mut State) -> Result<(), String> {
+    let path = String::from_utf8_lossy(&read_string(state, state.arg(0)?)?).into_owned();
+    let flags = state.arg(1)?;
+    let result = state.fs.open(&path, flags).unwrap_or(-1i64 as u64);
+    state.set_return(result);
+    return Ok(());
+}
+
+fn close(state: &mut State) -> Result<(), String> {
+    let fd = state.arg(0)?;
+    let result = if state.fs.close(fd).is_ok() { 0 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn read(state: &mut State) -> Result<(), String> {
+    let (fd, buf, n) = (state.arg(0)?, state.arg(1)?, state.arg(2)? as usize);
+    let result = match state.fs.read(fd, n) {
+        Ok(bytes) => {
+            state.memory.write_bytes(buf, &bytes)?;
+            bytes.len() as u64
+        }
+        Err(_) => -1i64 as u64,
+    };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn write(state: &mut State) -> Result<(), String> {
+    let (fd, buf, n) = (state.arg(0)?, state.arg(1)?, state.arg(2)? as usize);
+    let bytes = state.memory.read_bytes(buf, n)?;
+    let result = if output(state, fd, &bytes) { n as u64 } else { -1i64 as u64 };
+    state.set_return(result);
+    return Ok(());
+}
+
+fn lseek(state: &mut State) -> Result<(), String> {
+    let (fd, offset, whence) = (state.arg(0)?, state.arg(1)? as i64, state.arg(2)?);
+    let result = state.fs.seek(fd, offset, whence).unwrap_or(-1i64 as u64);
+    state.set_return(result);
+    return Ok(());
+}
+
+// Scans the input left on a descriptor, consuming what the format matched
+fn fscan(state: &mut State, fd: u64, format_arg: usize) -> Result<(), String> {
+    let fmt = read_string(state, state.arg(format_arg)?)?;
+    let input = match state.fs.remaining(fd).map(|remaining| remaining.to_vec()) {
+        Ok(input) => input,
+        Err(_) => {
+            state.set_return(-1i64 as u64);
+            return Ok(());
+        }
+    };
+    let (assigned, consumed) = scan(state, &input, &fmt, format_arg + 1)?;
+    state.fs.consume(fd, consumed).ok();
+    info!("0x{:x} Calling procedures scanf() consuming {:?}", state.addr, String::from_utf8_lossy(&input[..consumed]));
+    state.set_return(assigned as u64);
+    return Ok(());
+}
+
+fn scanf(state: &mut State) -> Result<(), String> {
+    return fscan(state, 0, 0);
+}
+
+fn fscanf(state: &mut State) -> Result<(), String> {
+    let fd = stream_fd(state, state.arg(0)?, 0);
+    return fscan(state, fd, 1);
+}
+
+fn sscanf(state: &mut State) -> Result<(), String> {
+    let input = read_string(state, state.arg(0)?)?;
+    let fmt = read_string(state, state.arg(1)?)?;
+    let (assigned, _) = scan(state, &input, &fmt, 2)?;
+    state.set_return(assigned as u64);
+    return Ok(());
+}
+
+// Size in bytes of an integer argument with the given length modifier, size_t and ptrdiff_t
+// are as wide as an address and long depends on the calling convention
+fn integer_size(length: &[u8], state: &State) -> usize {
+    return match length {
+        b"hh" => 1,
+        b"h" => 2,
+        b"l" => state.callconv.long_size,
+        b"z" | b"t" => state.regs.address_size,
+        b"ll" | b"j" | b"q" | b"L" => 8,
+        _ => 4,
+    };
+}
+
+// Length modifier at the start of a conversion, empty if there isn't one
+fn length_modifier(fmt: &[u8]) -> &[u8] {
+    let modifiers: [&'static [u8]; 9] = [b"hh", b"ll", b"h", b"l", b"z", b"j", b"t", b"q", b"L"];
+    for modifier in modifiers.iter() {
+        if fmt.starts_with(modifier) {
+            return *modifier;
+        }
+    }
+    return b"";
+}
+
+// Matches input against a scanf format, storing conversions through the pointer arguments starting at first.
+// Returns the number of assignments (-1 if input ran out first) and how much input was consumed.
+fn scan(state: &mut State, input: &[u8], fmt: &[u8], first: usize) -> Result<(i64, usize), String> {
+    let (mut pos, mut i, mut next, mut assigned) = (0, 0, first, 0i64);
+
+    while i < fmt.len() {
+        let c = fmt[i];
+        if is_space(c) {
+            pos += skip_space(&input[pos..]);
+            i += 1;
+            continue;
+        }
+        if c != b'%' || fmt.get(i + 1) == Some(&b'%') {
+            if c == b'%' {
+                pos += skip_space(&input[pos..]);
+                i += 1;
+            }
+            if input.get(pos) != Some(&c) {
+                break;
+            }
+            pos += 1;
+            i += 1;
+            continue;
+        }
+        i += 1;
+
+        let suppress = fmt.get(i) == Some(&b'*');
+        if suppress {
+            i += 1;
+        }
+        let digits = fmt[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+        let width = String::from_utf8_lossy(&fmt[i..i + digits]).parse::<usize>().ok();
+        i += digits;
+        let length = length_modifier(&fmt[i..]);
+        i += length.len();
+        let conversion = match fmt.get(i) {
+            Some(&conversion) => conversion,
+            None => return Err(String::from("Incomplete scanf conversion")),
+        };
+        i += 1;
+
+        if conversion != b'c' {
+            pos += skip_space(&input[pos..]);
+        }
+        if pos >= input.len() {
+            // Running out of input before the first conversion is EOF
+            if assigned == 0 {
+                assigned = -1;
+            }
+            break;
+        }
+
+        match conversion {
+            b'd' | b'i' | b'u' | b'x' | b'X' | b'o' => {
+                let base = match conversion {
+                    b'i' => 0,
+                    b'x' | b'X' => 16,
+                    b'o' => 8,
+                    _ => 10,
+                };
+                let end = width.map_or(input.len(), |w| (pos + w).min(input.len()));
+                let (value, consumed) = match parse_integer(&input[pos..end], base) {
+                    Some(parsed) => parsed,
+                    None => break,
+                };
+                pos += consumed;
+                if !suppress {
+                    let size = integer_size(length, state);
+                    state.memory.store(state.arg(next)?, size, value as u128)?;
+                }
+            }
+            b's' => {
+                let len = input[pos..].iter().take(width.unwrap_or(usize::max_value())).take_while(|&&c| !is_space(c)).count();
+                if !suppress {
+                    let dst = state.arg(next)?;
+                    write_string(state, dst, &input[pos..pos + len])?;
+                }
+                pos += len;
+            }
+            b'c' => {
+                let len = width.unwrap_or(1).min(input.len() - pos);
+                if !suppress {
+                    state.memory.write_bytes(state.arg(next)?, &input[pos..pos + len])?;
+                }
+                pos += len;
+            }
+            _ => return Err(format!("Unsupported scanf conversion %{}", conversion as char)),
+        }
+
+        if !suppress {
+            next += 1;
+            assigned += 1;
+        }
+    }
+    return Ok((assigned, pos));
+}
+
+// Formats a C style exponent, which always has a sign and at least two digits
+fn c_exponent(formatted: String, upper: bool) -> String {
+    let mut parts = formatted.splitn(2, 'e');
+    let mantissa = parts.next().unwrap_or("");
+    let exponent: i32 = parts.next().and_then(|e| e.parse().ok()).unwrap_or(0);
+    let e = if upper { 'E' } else { 'e' };
+    return format!("{}{}{}{:02}", mantissa, e, if exponent < 0 { '-' } else { '+' }, exponent.abs());
+}
+
+fn strip_zeros(formatted: String) -> String {
+    if !formatted.contains('.') {
+        return formatted;
+    }
+    let (mantissa, exponent) = match formatted.find(|c| c == 'e' || c == 'E') {
+        Some(i) => (String::from(&formatted[..i]), String::from(&formatted[i..])),
+        None => (formatted.clone(), String::new()),
+    };
+    let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+    return format!("{}{}", mantissa, exponent);
+}
+
+fn format_float(value: f64, conversion: u8, precision: Option<usize>, alternate: bool) -> String {
+    let upper = conversion.is_ascii_uppercase();
+    if value.is_nan() {
+        return String::from(if upper { "NAN" } else { "nan" });
+    }
+    if value.is_infinite() {
+        return String::from(if upper { "INF" } else { "inf" });
+    }
+
+    let precision = precision.unwrap_or(6);
+    return match conversion {
+        b'e' | b'E' => c_exponent(format!("{:.*e}", precision, value.abs()), upper),
+        b'g' | b'G' => {
+            let p = if precision == 0 { 1 } else { precision };
+            let exponent = if value == 0.0 { 0 } else { value.abs().log10().floor() as i32 };
+            let formatted = if exponent < -4 || exponent >= p as i32 {
+                c_exponent(format!("{:.*e}", p - 1, value.abs()), upper)
+            } else {
+                format!("{:.*}", (p as i32 - 1 - exponent).max(0) as usize, value.abs())
+            };
+            if alternate { formatted } else { strip_zeros(formatted) }
+        }
+        _ => format!("{:.*}", precision, value.abs()),
+    };
+}
+
+// Expands a printf format, reading integer arguments from first onwards and floats where the calling convention passes variadic doubles
+fn format(state: &mut State, fmt: &[u8], first: usize) -> Result<Vec<u8>, String> {
+    let (mut out, mut i, mut next, mut floats) = (Vec::new(), 0, first, 0);
+
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            out.push(fmt[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+
+        let (mut left, mut zero, mut plus, mut space, mut alternate) = (false, false, false, false, false);
+        while let Some(&flag) = fmt.get(i) {
+            match flag {
+                b'-' => left = true,
+                b'0' => zero = true,
+                b'+' => plus = true,
+                b' ' => space = true,
+                b'#' => alternate = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        let mut width = 0;
+        if fmt.get(i) == Some(&b'*') {
+            let w = state.arg(next)? as i32;
+            next += 1;
+            i += 1;
+            left |= w < 0;
+            width = (w as i64).abs() as usize;
+        } else {
+            while let Some(d) = fmt.get(i).and_then(|c| (*c as char).to_digit(10)) {
+                width = width.saturating_mul(10).saturating_add(d as usize);
+                i += 1;
+            }
+        }
+
+        let mut precision = None;
+        if fmt.get(i) == Some(&b'.') {
+            i += 1;
+            if fmt.get(i) == Some(&b'*') {
+                let p = state.arg(next)? as i32;
+                next += 1;
+                i += 1;
+                precision = if p < 0 { None } else { Some(p as usize) };
+            } else {
+                let mut p = 0;
+                while let Some(d) = fmt.get(i).and_then(|c| (*c as char).to_digit(10)) {
+                    p = p.saturating_mul(10).saturating_add(d as usize);
+                    i += 1;
+                }
+                precision = Some(p);
+            }
+        }
+        // Padding is built in memory, so fields are bounded like strings
+        if width > MAX_STRING || precision.map_or(false, |p| p > MAX_STRING) {
+            return Err(format!("Field width or precision too large in format string {:?}", String::from_utf8_lossy(fmt)));
+        }
+
+        let length = length_modifier(&fmt[i..]);
+        i += length.len();
+        let conversion = match fmt.get(i) {
+            Some(&conversion) => conversion,
+            None => return Err(String::from("Incomplete printf conversion")),
+        };
+        i += 1;
+
+        // Sign or prefix goes before zero padding, the body after it
+        let (prefix, body): (String, Vec<u8>) = match conversion {
+            b'd' | b'i' => {
+                let value = sign_extend(state.arg(next)? as u128, integer_size(length, state));
+                next += 1;
+                let digits = integer_digits(value.abs() as u128, 10, precision, false);
+                let sign = if value < 0 { "-" } else if plus { "+" } else if space { " " } else { "" };
+                (String::from(sign), digits)
+            }
+            b'u' | b'x' | b'X' | b'o' => {
+                let size = integer_size(length, state);
+                let value = state.arg(next)? as u128 & ((1u128 << (size * 8)) - 1);
+                next += 1;
+                let base = match conversion { b'o' => 8, b'u' => 10, _ => 16 };
+                let digits = integer_digits(value, base, precision, conversion == b'X');
+                let prefix = match conversion {
+                    b'x' if alternate && value != 0 => "0x",
+                    b'X' if alternate && value != 0 => "0X",
+                    b'o' if alternate && digits.first() != Some(&b'0') => "0",
+                    _ => "",
+                };
+                (String::from(prefix), digits)
+            }
+            b'c' => {
+                let c = state.arg(next)? as u8;
+                next += 1;
+                (String::new(), vec![c])
+            }
+            b's' => {
+                let addr = state.arg(next)?;
+                next += 1;
+                let mut s = if addr == 0 { b"(null)".to_vec() } else { read_string_n(state, addr, precision.unwrap_or(MAX_STRING))? };
+                s.truncate(precision.unwrap_or(s.len()));
+                (String::new(), s)
+            }
+            b'p' => {
+                let addr = state.arg(next)?;
+                next += 1;
+                if addr == 0 { (String::new(), b"(nil)".to_vec()) } else { (String::from("0x"), format!("{:x}", addr).into_bytes()) }
+            }
+            b'f' | b'F' | b'e' | b'E' | b'g' | b'G' => {
+                let callconv = state.callconv.clone();
+                let value = callconv.variadic_float(state, &mut next, &mut floats)?;
+                let sign = if value.is_sign_negative() && !value.is_nan() { "-" } else if plus { "+" } else if space { " " } else { "" };
+                (String::from(sign), format_float(value, conversion, precision, alternate).into_bytes())
+            }
+            b'n' => {
+                let addr = state.arg(next)?;
+                next += 1;
+                let size = integer_size(length, state);
+                state.memory.store(addr, size, out.len() as u128)?;
+                (String::new(), Vec::new())
+            }
+            b'%' => (String::new(), b"%".to_vec()),
+            _ => return Err(format!("Unsupported printf conversion %{}", conversion as char)),
+        };
+
+        let len = prefix.len() + body.len();
+        let padding = width.saturating_sub(len);
+        // Zero padding is ignored for left aligned output and for integers with a precision
+        let numeric = conversion != b'c' && conversion != b's' && conversion != b'p' && conversion != b'%';
+        let zero = zero && !left && numeric && !(precision.is_some() && b"diuxXo".contains(&conversion));
+
+        if !left && !zero {
+            out.extend(vec![b' '; padding]);
+        }
+        out.extend(prefix.into_bytes());
+        if zero {
+            out.extend(vec![b'0'; padding]);
+        }
+        out.extend(body);
+        if left {
+            out.extend(vec![b' '; padding]);
+        }
+    }
+    return Ok(out);
+}
+
+// Digits of an integer with at least precision digits, a zero precision prints nothing for zero
+fn integer_digits(value: u128, base: u32, precision: Option<usize>, upper: bool) -> Vec<u8> {
+    let mut digits = match base {
+        8 => format!("{:o}", value),
+        16 if upper => format!("{:X}", value),
+        16 => format!("{:x}", value),
+        _ => format!("{}", value),
+    }.into_bytes();
+
+    match precision {
+        Some(0) if value == 0 => digits.clear(),
+        Some(p) if p > digits.len() => {
+            let mut padded = vec![b'0'; p - digits.len()];
+            padded.extend(digits);
+            digits = padded;
+        }
+        _ => {}
+    }
+    return digits;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use callconv::CallingConvention;
+    use state::STACK_TOP;
+
+    // Strings go in the default stack, which every state maps
+    const DATA: u64 = STACK_TOP - 0x1000;
+
+    fn state_with(bytes: &[u8]) -> State {
+        let mut state = State::new();
+        state.memory.write_bytes(DATA, bytes).unwrap();
+        return state;
+    }
+
+    fn set_args(state: &mut State, args: &[u64]) {
+        let callconv = state.callconv.clone();
+        for (reg, arg) in callconv.int_args.iter().zip(args) {
+            state.regs.set(reg, *arg as u128);
+        }
+    }
+
+    fn formatted(state: &mut State, fmt: &[u8], args: &[u64]) -> Result<String, String> {
+        set_args(state, args);
+        return format(state, fmt, 0).map(|out| String::from_utf8_lossy(&out).into_owned());
+    }
+
+    #[test]
+    fn printf_flags_and_widths() {
+        let mut state = state_with(b"abcdef\0");
+        let out = formatted(&mut state, b"%5d|%-5d|%05x|%.3s|%%", &[42, 42, 255, DATA]).unwrap();
+        assert_eq!(out, "   42|42   |000ff|abc|%");
+        assert_eq!(formatted(&mut state, b"%+.3d|%#o|%#x", &[7, 8, 0]).unwrap(), "+007|010|0");
+    }
+
+    #[test]
+    fn printf_star_width() {
+        let mut state = State::new();
+        assert_eq!(formatted(&mut state, b"%*d|", &[-4i32 as u32 as u64, 1]).unwrap(), "1   |");
+        assert_eq!(formatted(&mut state, b"%*d|", &[3, 1]).unwrap(), "  1|");
+        assert!(formatted(&mut state, b"%*d", &[i32::min_value() as u32 as u64, 1]).is_err());
+        assert!(formatted(&mut state, b"%99999999999999999999999d", &[1]).is_err());
+    }
+
+    #[test]
+    fn printf_long_follows_calling_convention() {
+        let mut state = State::new();
+        assert_eq!(formatted(&mut state, b"%ld|%zd", &[0xffffffff, 0xffffffff]).unwrap(), "4294967295|4294967295");
+        // Windows keeps long at 32 bits while size_t is as wide as an address
+        state.callconv = CallingConvention::win64();
+        assert_eq!(formatted(&mut state, b"%ld|%zd", &[0xffffffff, 0xffffffff]).unwrap(), "-1|4294967295");
+        state.callconv = CallingConvention::cdecl();
+        state.regs.address_size = 4;
+        assert_eq!(formatted(&mut state, b"%ld|%lld", &[0xffffffff, 0xffffffff]).unwrap(), "-1|4294967295");
+    }
+
+    #[test]
+    fn registry_knows_fixed_arguments() {
+        let mut procedures = Procedures::libc();
+        assert_eq!(procedures.arguments("strncpy"), Some(3));
+        assert_eq!(procedures.arguments("__STRNCPY_CHK"), Some(4));
+        assert_eq!(procedures.arguments("printf"), None);
+        // A hook registered over a model doesn't keep the model's count
+        procedures.register("strncpy", Box::new(|_: &mut State| Ok(())));
+        assert_eq!(procedures.arguments("strncpy"), None);
+    }
+
+    #[test]
+    fn standard_streams_are_captured_separately() {
+        // A FILE pointer libc left in the stderr global, and the stand-in for stdout
+        let stderr = 0x7ffff7dd0000;
+        let mut state = state_with(b"out\0err %d\0");
+        state.fs.streams.insert(stderr, 2);
+
+        set_args(&mut state, &[DATA, FILE_BASE + 1]);
+        fputs(&mut state).unwrap();
+        set_args(&mut state, &[stderr, DATA + 4, 7]);
+        fprintf(&mut state, 1).unwrap();
+        set_args(&mut state, &[DATA, 0x1234]);
+        fputs(&mut state).unwrap();
+
+        assert_eq!(state.fs.stdout(), b"outout");
+        assert_eq!(state.fs.stderr(), b"err 7");
+    }
+
+    fn call_strtol(input: &[u8], base: u64, signed: bool, size: usize) -> (u64, u64) {
+        let mut bytes = input.to_vec();
+        bytes.push(0);
+        let mut state = state_with(&bytes);
+        let end = DATA + 0x100;
+        set_args(&mut state, &[DATA, end, base]);
+        strtol(&mut state, signed, size).unwrap();
+        let ret = state.regs.get("rax") as u64;
+        return (ret, state.memory.load(end, 8).unwrap() as u64 - DATA);
+    }
+
+    #[test]
+    fn strtol_prefixes_and_end() {
+        assert_eq!(call_strtol(b"  -0x1fzz", 0, true, 8), (-31i64 as u64, 7));
+        assert_eq!(call_strtol(b"0755", 0, true, 8), (0o755, 4));
+        assert_eq!(call_strtol(b"0x", 16, true, 8), (0, 1));
+        assert_eq!(call_strtol(b"zz", 10, true, 8), (0, 0));
+    }
+
+    #[test]
+    fn strtol_clamps() {
+        assert_eq!(call_strtol(b"99999999999999999999", 10, true, 8).0, i64::max_value() as u64);
+        assert_eq!(call_strtol(b"-99999999999999999999", 10, true, 8).0, i64::min_value() as u64);
+        assert_eq!(call_strtol(b"-1", 10, false, 8).0, u64::max_value());
+        // A 32 bit long
+        assert_eq!(call_strtol(b"4294967296", 10, true, 4).0, i32::max_value() as u64);
+        assert_eq!(call_strtol(b"-4294967296", 10, true, 4).0, i32::min_value() as i64 as u64);
+        assert_eq!(call_strtol(b"4294967296", 10, false, 4).0, u32::max_value() as u64);
+        assert_eq!(call_strtol(b"-1", 10, false, 4).0, u32::max_value() as u64);
+    }
+
+    #[test]
+    fn strtol_end_pointer_is_address_sized() {
+        let mut state = state_with(b"12\0");
+        state.regs.address_size = 4;
+        let end = DATA + 0x100;
+        state.memory.store(end, 8, 0xaaaaaaaaaaaaaaaa).unwrap();
+        set_args(&mut state, &[DATA, end, 10]);
+        strtol(&mut state, true, 8).unwrap();
+        assert_eq!(state.memory.load(end, 8).unwrap(), 0xaaaaaaaa00000000 | (DATA + 2) as u32 as u128);
+    }
+}
@@ -0,0 +1,207 @@
+use program::Program;
+use registers::ArchKind;
+use state::*;
+
+// Auxiliary vector entry types
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_PLATFORM: u64 = 15;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+// Bytes AT_RANDOM points at, fixed so runs are repeatable
+const RANDOM: [u8; 16] = [0x4e, 0x41, 0x46, 0x21, 0x8c, 0x1f, 0x62, 0xd3, 0x07, 0xa9, 0x5b, 0x3e, 0xc4, 0x90, 0x6d, 0x12];
+
+const UID: u64 = 1000;
+
+// glibc's stack guard, the first AT_RANDOM bytes with the lowest byte zeroed so string
+// functions can't leak it
+pub fn stack_guard(word: usize) -> u64 {
+    let mut guard = 0;
+    for byte in RANDOM[1..word].iter().rev() {
+        guard = (guard << 8) | *byte as u64;
+    }
+    return guard << 8;
+}
+
+// What the program was started with
+#[derive(Clone)]
+pub struct Process {
+    // Path the program was executed as, reported in AT_EXECFN. argv[0] starts out the same but
+    // can be anything.
+    pub path: String,
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+}
+
+impl Process {
+    pub fn new(program: &Program) -> Process {
+        return Process {
+            path: program.name(),
+            args: vec![program.name()],
+            env: vec![String::from("PATH=/usr/local/bin:/usr/bin:/bin"), String::from("HOME=/"), String::from("LANG=C")],
+        }
+    }
+}
+
+// Where build_stack put things, main takes the first three as its arguments
+pub struct Stack {
+    pub argc: u64,
+    pub argv: u64,
+    pub envp: u64,
+    pub auxv: u64,
+    pub random: u64,
+}
+
+// Program header location from the ELF header, when the image has one mapped
+pub fn program_headers(state: &State, program: &Program) -> Option<(u64, u64, u64)> {
+    let base = program.start();
+    if state.memory.read_bytes(base, 4).ok()? != b"\x7fELF" {
+        return None;
+    }
+    let wide = state.memory.load(base + 4, 1).ok()? == 2;
+    let (phoff, phentsize, phnum) = if wide {
+        (state.memory.load(base + 0x20, 8).ok()?, state.memory.load(base + 0x36, 2).ok()?, state.memory.load(base + 0x38, 2).ok()?)
+    } else {
+        (state.memory.load(base + 0x1c, 4).ok()?, state.memory.load(base + 0x2a, 2).ok()?, state.memory.load(base + 0x2c, 2).ok()?)
+    };
+    return Some((base + phoff as u64, phentsize as u64, phnum as u64));
+}
+
+// Platform string the kernel reports for the architecture
+fn platform_name(program: &Program) -> String {
+    let name = match program.arch().map(|arch| ArchKind::of(&arch)).unwrap_or(ArchKind::Unknown) {
+        ArchKind::X86_64 => "x86_64",
+        ArchKind::X86 => "i686",
+        ArchKind::Aarch64 => "aarch64",
+        ArchKind::Arm => "v7l",
+        ArchKind::Riscv | ArchKind::Unknown => "riscv",
+    };
+    return String::from(name);
+}
+
+// Lays out the stack the kernel hands to _start: argc, the argv and envp pointer arrays and the
+// auxiliary vector, with the strings they point to above them. Leaves the stack pointer on argc.
+pub fn build_stack(state: &mut State, program: &Program, process: &Process) -> Result<Stack, String> {
+    let word = state.regs.address_size as u64;
+    let mut top = STACK_TOP;
+
+    // Strings first, each one's address is remembered for the pointer arrays
+    let mut push_bytes = |state: &mut State, bytes: &[u8]| -> Result<u64, String> {
+        top -= bytes.len() as u64;
+        state.memory.write_bytes(top, bytes)?;
+        return Ok(top);
+    };
+    let cstring = |s: &str| { let mut bytes = s.as_bytes().to_vec(); bytes.push(0); bytes };
+
+    let execfn = push_bytes(state, &cstring(&process.path))?;
+    let mut env = Vec::new();
+    for var in process.env.iter().rev() {
+        env.push(push_bytes(state, &cstring(var))?);
+    }
+    env.reverse();
+    let mut args = Vec::new();
+    for arg in process.args.iter().rev() {
+        args.push(push_bytes(state, &cstring(arg))?);
+    }
+    args.reverse();
+    let platform = push_bytes(state, &cstring(&platform_name(program)))?;
+    let random = push_bytes(state, &RANDOM)?;
+
+    let mut auxv = Vec::new();
+    if let Some((phdr, phent, phnum)) = program_headers(state, program) {
+        auxv.push((AT_PHDR, phdr));
+        auxv.push((AT_PHENT, phent));
+        auxv.push((AT_PHNUM, phnum));
+    }
+    auxv.extend_from_slice(&[
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, program.entry_point()),
+        (AT_UID, UID),
+        (AT_EUID, UID),
+        (AT_GID, UID),
+        (AT_EGID, UID),
+        (AT_PLATFORM, platform),
+        (AT_HWCAP, 0),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ]);
+
+    // argc, argv, NULL, envp, NULL then the auxv pairs, with argc 16 byte aligned
+    let words = 1 + args.len() + 1 + env.len() + 1 + auxv.len() * 2;
+    let sp = (top - words as u64 * word) & !15;
+    if sp < STACK_TOP - STACK_SIZE {
+        return Err(String::from("Arguments and environment don't fit on the stack"));
+    }
+
+    let mut values = vec![args.len() as u64];
+    values.extend(args.iter());
+    values.push(0);
+    values.extend(env.iter());
+    values.push(0);
+    for &(key, value) in &auxv {
+        values.push(key);
+        values.push(value);
+    }
+    for (i, value) in values.iter().enumerate() {
+        state.memory.store(sp + i as u64 * word, word as usize, *value as u128)?;
+    }
+    state.regs.set_sp(sp);
+
+    let argv = sp + word;
+    let envp = argv + (args.len() as u64 + 1) * word;
+    return Ok(Stack {
+        argc: args.len() as u64,
+        argv: argv,
+        envp: envp,
+        auxv: envp + (env.len() as u64 + 1) * word,
+        random: random,
+    });
+}
+
+// Sets up a call to main(argc, argv, envp) on top of the initial stack. The return address is
+// zero, so returning from main ends the run.
+pub fn enter_main(state: &mut State, stack: &Stack) -> Result<(), String> {
+    let word = state.regs.address_size;
+    let args = [stack.argc, stack.argv, stack.envp];
+    let callconv = state.callconv.clone();
+
+    // Arguments that don't fit in registers go on the stack, below them is the return address
+    let on_stack: Vec<u64> = args.iter().skip(callconv.int_args.len()).cloned().collect();
+    let mut sp = state.regs.sp() - callconv.stack_offset - (on_stack.len() * word) as u64;
+    sp &= !15;
+    for (i, value) in on_stack.iter().enumerate() {
+        state.memory.store(sp + callconv.stack_offset + (i * word) as u64, word, *value as u128)?;
+    }
+    for (reg, value) in callconv.int_args.iter().zip(args.iter()) {
+        state.regs.set(reg, *value as u128);
+    }
+
+    match state.regs.link_register.clone() {
+        Some(lr) => state.regs.set(&lr, 0),
+        None => {
+            sp -= word as u64;
+            state.memory.store(sp, word, 0)?;
+        }
+    }
+    state.regs.set_sp(sp);
+    return Ok(());
+}
@@ -1,9 +1,47 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use binaryninja::binaryview::{BinaryView, BinaryViewExt};
+use binaryninja::rc::Ref;
 use binaryninja::symbol::SymbolType;
+use binaryninja::Endianness;
+use binaryninja::architecture::{Architecture, CoreArchitecture, CoreRegister, Flag};
+use binaryninja::llil;
 use expression;
+use callconv::CallingConvention;
+
+// Where shared libraries get mapped, each one above the last
+const LIBRARY_BASE: u64 = 0x7f0000000000;
+const LIBRARY_ALIGN: u64 = 0x1000000;
+// Directories under the sysroot searched for libraries, along with their subdirectories
+const LIBRARY_DIRS: [&'static str; 5] = ["lib", "lib64", "usr/lib", "usr/lib64", ""];
+
+// A shared library the program depends on, loaded into its own view at its own base
+pub struct Library {
+    pub name: String,
+    pub path: String,
+    pub bv: Ref<BinaryView>,
+}
+
+impl Library {
+    pub fn start(&self) -> u64 {
+        return self.bv.start();
+    }
+
+    pub fn end(&self) -> u64 {
+        return self.bv.start() + self.bv.len() as u64;
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        return addr >= self.start() && addr < self.end();
+    }
+}
 
 pub struct Program<'a> {
     bv: &'a BinaryView,
+    libraries: Vec<Library>,
+    // Functions the loaded libraries export by name, the first library to export a name wins
+    exports: HashMap<String, u64>,
 }
 
 impl<'a> Program<'a> {
@@ -11,7 +49,103 @@ impl<'a> Program<'a> {
         bv.functions();
         return Program {
             bv: bv,
+            libraries: Vec::new(),
+            exports: HashMap::new(),
+        }
+    }
+
+    // Loads the libraries the program needs, and the ones they need, from a sysroot directory.
+    // Libraries that can't be found are logged and left to procedures. Returns the names loaded.
+    pub fn load_libraries(&mut self, sysroot: &str) -> Result<Vec<String>, String> {
+        let mut pending = needed(self.bv);
+        let mut loaded = Vec::new();
+        let mut base = LIBRARY_BASE;
+
+        while !pending.is_empty() {
+            let name = pending.remove(0);
+            if self.libraries.iter().any(|library| library.name == name) {
+                continue;
+            }
+            let path = match find_library(sysroot, &name) {
+                Some(path) => path,
+                None => {
+                    error!("Couldn't find library {} in {}", name, sysroot);
+                    continue;
+                }
+            };
+
+            let options = format!("{{\"loader.imageBase\": {}}}", base);
+            let bv = binaryninja::load_with_options(&path, true, Some(options))
+                .ok_or(format!("Failed to load library {}", path))?;
+            bv.functions();
+            info!("Loaded {} from {} at 0x{:x}", name, path, bv.start());
+
+            for function in &bv.functions() {
+                let symbol = function.symbol();
+                match symbol.sym_type() {
+                    SymbolType::Function => {
+                        self.exports.entry(String::from(symbol.full_name().as_str()))
+                            .or_insert(function.start());
+                    }
+                    _ => (),
+                }
+            }
+            pending.extend(needed(&bv));
+
+            let library = Library {
+                name: name.clone(),
+                path: path,
+                bv: bv,
+            };
+            base = (library.end() + 2 * LIBRARY_ALIGN - 1) & !(LIBRARY_ALIGN - 1);
+            self.libraries.push(library);
+            loaded.push(name);
         }
+        return Ok(loaded);
+    }
+
+    pub fn libraries(&self) -> &[Library] {
+        return &self.libraries;
+    }
+
+    // Address of a function exported by one of the loaded libraries
+    pub fn export(&self, name: &str) -> Option<u64> {
+        return self.exports.get(name).cloned();
+    }
+
+    // Addresses of the data symbols with the name in the program and its libraries, like the
+    // stdout global a copy relocation puts in the program's .bss
+    pub fn data_symbols(&self, name: &str) -> Vec<u64> {
+        let mut views = vec![self.bv];
+        views.extend(self.libraries.iter().map(|library| &*library.bv));
+
+        let mut addrs = Vec::new();
+        for bv in views {
+            for symbol in bv.symbols().into_iter() {
+                match symbol.sym_type() {
+                    SymbolType::Data | SymbolType::ImportedData => (),
+                    _ => continue,
+                }
+                if Import::from_raw_name(symbol.raw_name().as_str()).name == name && !addrs.contains(&symbol.address()) {
+                    addrs.push(symbol.address());
+                }
+            }
+        }
+        return addrs;
+    }
+
+    // The view an address belongs to, the program's own unless a library is mapped there
+    fn view_at(&self, addr: u64) -> &BinaryView {
+        for library in &self.libraries {
+            if library.contains(addr) {
+                return &library.bv;
+            }
+        }
+        return self.bv;
+    }
+
+    pub fn bv(&self) -> &'a BinaryView {
+        return self.bv;
     }
 
     pub fn seek(&self, addr: u64) {
@@ -73,11 +207,20 @@ impl<'a> Program<'a> {
         return vec;
     }
 
+    // Function names are lowercased, so names given by the user match regardless of case
+    pub fn function_named(&self, name: &str) -> Result<Function, String> {
+        return match self.functions().into_iter().find(|function| function.name.eq_ignore_ascii_case(name)) {
+            Some(function) => Ok(function),
+            None => Err(format!("Function {} not found", name)),
+        };
+    }
+
     pub fn function_at(&self, addr: u64) -> Result<Function, String> {
-        let functions = self.bv.functions_at(addr);
+        let view = self.view_at(addr);
+        let functions = view.functions_at(addr);
         for function in &functions {
             return Ok(Function {
-                bv: self.bv,
+                bv: view,
                 name: String::from(function.symbol().full_name().to_ascii_lowercase()),
                 addr: function.start(),
             })
@@ -86,10 +229,11 @@ impl<'a> Program<'a> {
     }
 
     pub fn function_containing(&self, addr: u64) -> Result<Function, String> {
-        for block in self.bv.basic_blocks_containing(addr).into_iter() {
+        let view = self.view_at(addr);
+        for block in view.basic_blocks_containing(addr).into_iter() {
             let function = block.function();
             return Ok(Function {
-                bv: self.bv,
+                bv: view,
                 name: String::from(function.symbol().full_name().to_ascii_lowercase()),
                 addr: function.start()
             })
@@ -98,30 +242,144 @@ impl<'a> Program<'a> {
         return Err(String::from("Couldn't find function"));
     }
 
+    // Returns true if the function at the address is an import stub rather than program code
+    pub fn is_import(&self, addr: u64) -> bool {
+        for function in &self.view_at(addr).functions_at(addr) {
+            match function.symbol().sym_type() {
+                SymbolType::ImportedFunction | SymbolType::LibraryFunction | SymbolType::External => return true,
+                _ => return false,
+            }
+        }
+        return false;
+    }
+
+    // Library an imported function comes from, when its raw symbol name records one as lib!name or name@lib
+    pub fn library_of(&self, addr: u64) -> Option<String> {
+        return self.resolve_import(addr).and_then(|import| import.library);
+    }
+
+    // The imported function a call target leads to. The target can be the import itself, a GOT
+    // slot, or anywhere in a PLT stub including the lazy binding part.
+    pub fn resolve_import(&self, target: u64) -> Option<Import> {
+        if let Some(import) = self.import_symbol(target) {
+            return Some(import);
+        }
+        let slot = self.plt_slot(target)?;
+        return self.import_symbol(slot);
+    }
+
+    // Import named by a symbol at the address, GOT slots have import address symbols
+    pub fn import_symbol(&self, addr: u64) -> Option<Import> {
+        let view = self.view_at(addr);
+        let mut symbols: Vec<_> = view.functions_at(addr).iter().map(|function| function.symbol()).collect();
+        if let Ok(symbol) = view.symbol_by_address(addr) {
+            symbols.push(symbol);
+        }
+
+        for symbol in symbols {
+            match symbol.sym_type() {
+                SymbolType::ImportedFunction | SymbolType::ImportAddress | SymbolType::External | SymbolType::LibraryFunction => {
+                    return Some(Import::from_raw_name(symbol.raw_name().as_str()));
+                }
+                _ => (),
+            }
+        }
+        return None;
+    }
+
+    // GOT slot a PLT stub jumps through. Stubs are short, so following constants through
+    // registers is enough to find the slot on every architecture's PLT layout.
+    pub fn plt_slot(&self, addr: u64) -> Option<u64> {
+        let sections = sections_of(self.view_at(addr));
+        let in_plt = sections.iter().any(|section| {
+            addr >= section.start && addr < section.end && (section.name.starts_with(".plt") || section.name == "__stubs")
+        });
+        if !in_plt {
+            return None;
+        }
+
+        let function = self.function_containing(addr).ok()?;
+        let mut consts: HashMap<String, u64> = HashMap::new();
+        // i386 PIC stubs index off ebx, which holds the GOT
+        if let Some(got) = sections.iter().find(|section| section.name == ".got.plt") {
+            consts.insert(String::from("ebx"), got.start);
+        }
+        let mut loaded: HashMap<String, u64> = HashMap::new();
+
+        for index in 0..function.llil_count().min(MAX_STUB) {
+            match function.llil_at_index(index).ok()?.llil {
+                LlilInst::SetReg(op) => {
+                    if let Some(value) = constant(&op.expr, &consts) {
+                        consts.insert(op.reg, value);
+                    } else if let expression::Expr::Load(ref load) = op.expr {
+                        if let Some(slot) = constant(&load.source_mem, &consts) {
+                            loaded.insert(op.reg, slot);
+                        }
+                    }
+                }
+                LlilInst::Jump(Jump {target}) | LlilInst::JumpTo(JumpTo {target}) => {
+                    return match target {
+                        expression::Expr::Load(ref load) => constant(&load.source_mem, &consts),
+                        expression::Expr::Reg(ref reg) => loaded.get(&reg.name).cloned(),
+                        _ => None,
+                    };
+                }
+                _ => (),
+            }
+        }
+        return None;
+    }
+
+    // The platform's default calling convention, or the architecture's usual one without a platform
+    pub fn calling_convention(&self) -> Result<CallingConvention, String> {
+        let arch = self.arch()?;
+        match self.bv.default_platform() {
+            Some(platform) => {
+                let windows = platform.name().to_ascii_lowercase().contains("windows");
+                if let Some(cc) = platform.get_default_calling_convention() {
+                    return Ok(CallingConvention::from_binja(&cc, &arch, windows));
+                }
+                return Ok(CallingConvention::for_arch(&arch, windows));
+            }
+            None => return Ok(CallingConvention::for_arch(&arch, false)),
+        }
+    }
+
+    // Calling convention and parameter count from the type of the function at the address
+    pub fn calling_convention_of(&self, addr: u64) -> Option<(CallingConvention, Option<usize>)> {
+        let arch = self.arch().ok()?;
+        let windows = self.bv.default_platform().map_or(false, |platform| platform.name().to_ascii_lowercase().contains("windows"));
+        for function in &self.view_at(addr).functions_at(addr) {
+            let ty = function.function_type();
+            let parameters = ty.parameters().ok().map(|parameters| parameters.len());
+            if let Ok(cc) = ty.calling_convention() {
+                return Some((CallingConvention::from_binja(&cc.contents, &arch, windows), parameters));
+            }
+            return self.calling_convention().ok().map(|cc| (cc, parameters));
+        }
+        return None;
+    }
+
     pub fn block_at(&self, addr: u64) -> Result<Block, String> {
-        for block in self.bv.basic_blocks_containing(addr).into_iter() {
+        let view = self.view_at(addr);
+        for block in view.basic_blocks_containing(addr).into_iter() {
             return Ok(Block {
-                bv: self.bv,
+                bv: view,
                 addr: block.raw_start()
             })
         }
         return Err(String::from("Couldn't find block at address"));
     }
     pub fn num_blocks(&self, addr: u64) -> usize {
-        let blocks = self.bv.basic_blocks_containing(addr);
+        let blocks = self.view_at(addr).basic_blocks_containing(addr);
         return blocks.len();
     }
 
-    // Gets the next instruction
-    pub fn inst_after(&self, addr: u64) -> Result<Inst, String> {
+    pub fn inst_at(&self, addr: u64) -> Result<Inst, String> {
         if let Ok(block) = self.block_at(addr) {
-            let mut found: bool = false;
             for inst in block.llil() {
-                if found {
-                    return Ok(inst);
-                }
                 if inst.addr == addr {
-                    found = true;
+                    return Ok(inst);
                 }
             }
         }
@@ -129,45 +387,237 @@ impl<'a> Program<'a> {
         return Err(String::from("Couldn't find instruction"));
     }
 
-    pub fn inst_at(&self, addr: u64) -> Result<Inst, String> {
-        if let Ok(block) = self.block_at(addr) {
-            for inst in block.llil() {
-                if inst.addr == addr {
-                    return Ok(inst);
-                }
+    // Finds the function and LLIL index of the first instruction lifted from the address
+    pub fn location_of(&self, addr: u64) -> Result<(u64, usize), String> {
+        let function = self.function_containing(addr)?;
+        let index = function.llil_index_at(addr)?;
+        return Ok((function.addr, index));
+    }
+
+    pub fn llil_at(&self, function: u64, index: usize) -> Result<Inst, String> {
+        return self.function_at(function)?.llil_at_index(index);
+    }
+
+    // Path the view was opened from, databases are named after the file they were made from
+    pub fn name(&self) -> String {
+        let name = String::from(self.bv.file().filename().as_str());
+        return String::from(name.trim_end_matches(".bndb"));
+    }
+
+    // Address the image starts at, where the ELF header is mapped
+    pub fn start(&self) -> u64 {
+        return self.bv.start();
+    }
+
+    pub fn entry_point(&self) -> u64 {
+        return self.bv.entry_point();
+    }
+
+    pub fn arch(&self) -> Result<CoreArchitecture, String> {
+        return self.bv.default_arch().ok_or(String::from("Binary view has no default architecture"));
+    }
+
+    // Length of the machine instruction at the address
+    pub fn instruction_len(&self, addr: u64) -> Result<usize, String> {
+        let arch = self.arch()?;
+        let data = self.view_at(addr).read_vec(addr, arch.max_instr_len());
+        return arch.instruction_info(&data, addr).map(|info| info.len())
+            .ok_or(format!("Couldn't decode the instruction at 0x{:x}", addr));
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        return self.bv.default_endianness();
+    }
+
+    // Segments with their file backed contents, anything past the file backed bytes is zero filled
+    pub fn segments(&self) -> Vec<Segment> {
+        return segments_of(self.bv);
+    }
+
+    pub fn sections(&self) -> Vec<Section> {
+        return sections_of(self.bv);
+    }
+
+    // Segments and sections of every loaded library, mapped alongside the program's own
+    pub fn library_segments(&self) -> Vec<Segment> {
+        return self.libraries.iter().flat_map(|library| segments_of(&library.bv)).collect();
+    }
+
+    pub fn library_sections(&self) -> Vec<Section> {
+        return self.libraries.iter().flat_map(|library| sections_of(&library.bv)).collect();
+    }
+}
+
+fn segments_of(bv: &BinaryView) -> Vec<Segment> {
+    let mut vec: Vec<Segment> = Vec::with_capacity(0);
+    for segment in bv.segments().into_iter() {
+        let range = segment.address_range();
+        vec.push(
+            Segment {
+                start: range.start,
+                end: range.end,
+                readable: segment.readable(),
+                writable: segment.writable(),
+                executable: segment.executable(),
+                data: bv.read_vec(range.start, (range.end - range.start) as usize),
             }
-        }
+        )
+    }
+    return vec;
+}
 
-        return Err(String::from("Couldn't find instruction"));
+fn sections_of(bv: &BinaryView) -> Vec<Section> {
+    let mut vec: Vec<Section> = Vec::with_capacity(0);
+    for section in bv.sections().into_iter() {
+        let range = section.address_range();
+        vec.push(
+            Section {
+                name: String::from(section.name().as_str()),
+                start: range.start,
+                end: range.end,
+            }
+        )
     }
+    return vec;
+}
 
-    /** Retrieves the instructions at the address */
-    pub fn insts_at_addr(&self, addr: u64) -> Result<Vec<Index>, String> { 
-        let mut curr_index: u64 = 0; 
-        let mut instructions = Vec::new();
-        if let Ok(block) = self.block_at(addr) {
-            for inst in block.llil() {
-                if inst.addr == addr {
+// ELF dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
 
-                    let return_index = Index {
-                        inst: inst,
-                        index: curr_index,
-                    };
+// Names of the libraries an ELF image lists as DT_NEEDED in its dynamic section
+fn needed(bv: &BinaryView) -> Vec<String> {
+    let sections = sections_of(bv);
+    let dynamic = match sections.iter().find(|section| section.name == ".dynamic") {
+        Some(dynamic) => dynamic,
+        None => return Vec::new(),
+    };
+    // DT_STRTAB is the address before the view was rebased, which is wrong for every library we
+    // load at LIBRARY_BASE, so the .dynstr section is used wherever it is
+    let dynstr = sections.iter().find(|section| section.name == ".dynstr").map(|section| section.start);
+    let word = bv.default_arch().map(|arch| arch.address_size()).unwrap_or(8);
+    let big = bv.default_endianness() == Endianness::BigEndian;
+    let data = bv.read_vec(dynamic.start, (dynamic.end - dynamic.start) as usize);
+    return needed_names(&data, word, big, dynstr, |addr| bv.read_vec(addr, 256));
+}
+
+// Reads the DT_NEEDED names out of a dynamic section, from the string table at dynstr if there is
+// one and at DT_STRTAB otherwise
+fn needed_names<F: Fn(u64) -> Vec<u8>>(data: &[u8], word: usize, big: bool, dynstr: Option<u64>, read: F) -> Vec<String> {
+    let value = |bytes: &[u8]| -> u64 {
+        let mut value = 0;
+        for i in 0..bytes.len() {
+            let byte = if big { bytes[i] } else { bytes[bytes.len() - 1 - i] };
+            value = (value << 8) | byte as u64;
+        }
+        return value;
+    };
+
+    let mut entries = Vec::new();
+    for entry in data.chunks(word * 2) {
+        if entry.len() < word * 2 {
+            break;
+        }
+        let tag = value(&entry[..word]);
+        if tag == DT_NULL {
+            break;
+        }
+        entries.push((tag, value(&entry[word..])));
+    }
+
+    let strtab = match dynstr.or(entries.iter().find(|&&(tag, _)| tag == DT_STRTAB).map(|&(_, strtab)| strtab)) {
+        Some(strtab) => strtab,
+        None => return Vec::new(),
+    };
+    let mut names = Vec::new();
+    for &(tag, offset) in &entries {
+        if tag != DT_NEEDED {
+            continue;
+        }
+        let bytes = read(strtab.wrapping_add(offset));
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        names.push(String::from_utf8_lossy(&bytes[..end]).into_owned());
+    }
+    return names;
+}
 
-                    instructions.push(return_index);
+// Looks for a library in the sysroot's library directories and the directories inside them,
+// which is where multiarch systems keep theirs
+fn find_library(sysroot: &str, name: &str) -> Option<String> {
+    for dir in LIBRARY_DIRS.iter() {
+        let dir = Path::new(sysroot).join(dir);
+        let mut candidates = vec![dir.join(name)];
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                if entry.path().is_dir() {
+                    candidates.push(entry.path().join(name));
                 }
-                curr_index = curr_index + 1; 
             }
-            return Ok(instructions);
         }
-        return Err(String::from("Could not any instructions at address"));
+        for candidate in candidates {
+            if candidate.is_file() {
+                return Some(candidate.to_string_lossy().into_owned());
+            }
+        }
     }
+    return None;
+}
 
-    pub fn name(&self) -> String {
-        return String::from("/bin/ls");
+// Longest PLT stub looked at, in LLIL instructions
+const MAX_STUB: usize = 16;
+
+// Value of an expression built only from constants and registers holding constants
+fn constant(expr: &expression::Expr, consts: &HashMap<String, u64>) -> Option<u64> {
+    use expression::Expr;
+
+    return match *expr {
+        Expr::Value(ref value) => Some(value.value),
+        Expr::Reg(ref reg) => consts.get(&reg.name).cloned(),
+        Expr::Add(ref op) => Some(constant(&op.left, consts)?.wrapping_add(constant(&op.right, consts)?)),
+        Expr::Sub(ref op) => Some(constant(&op.left, consts)?.wrapping_sub(constant(&op.right, consts)?)),
+        _ => None,
+    };
+}
+
+pub struct Import {
+    pub name: String,
+    pub library: Option<String>,
+}
+
+impl Import {
+    // Raw symbol names record the library as lib!name or name@lib
+    pub fn from_raw_name(raw: &str) -> Import {
+        let raw = raw.to_ascii_lowercase();
+        if let Some(i) = raw.find('!') {
+            return Import {name: String::from(&raw[i + 1..]), library: Some(String::from(&raw[..i]))};
+        }
+        if let Some(i) = raw.find('@') {
+            let library = raw[i..].trim_start_matches('@');
+            return Import {
+                name: String::from(&raw[..i]),
+                library: if library.is_empty() { None } else { Some(String::from(library)) },
+            };
+        }
+        return Import {name: raw, library: None};
     }
 }
 
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub data: Vec<u8>,
+}
+
+pub struct Section {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+}
+
 pub struct Function<'a> {
     pub bv: &'a BinaryView,
     pub name: String,
@@ -215,16 +665,79 @@ impl<'a> Function<'a> {
         return count;
     }
 
+    pub fn llil_count(&self) -> usize {
+        for function in &self.bv.functions() {
+            if function.start() == self.addr {
+                if let Ok(llil) = function.low_level_il() {
+                    return llil.instruction_count();
+                }
+            }
+        }
+        return 0;
+    }
+
     pub fn llil_at_index(&self, index: usize) -> Result<Inst, String> {
         for function in &self.bv.functions() {
             if function.start() == self.addr {
                 if let Ok(llil) = function.low_level_il() {
-                    return Ok(build_inst(llil.instruction_from_idx(index)));
+                    if index < llil.instruction_count() {
+                        return Ok(build_inst(llil.instruction_from_idx(index)));
+                    }
                 }
             }
         }
         return Err(String::from("Instruction index is out of range"));
     }
+
+    // Every LLIL instruction of the function, in index order
+    pub fn llil_instructions(&self) -> Vec<Inst> {
+        for function in &self.bv.functions() {
+            if function.start() == self.addr {
+                if let Ok(llil) = function.low_level_il() {
+                    return (0..llil.instruction_count()).map(|index| build_inst(llil.instruction_from_idx(index))).collect();
+                }
+            }
+        }
+        return Vec::new();
+    }
+
+    // Register definitions and uses from Binary Ninja's LLIL SSA form, indexed like
+    // llil_instructions. None when there's no SSA form for the function.
+    pub fn llil_ssa(&self) -> Option<SsaForm> {
+        for function in &self.bv.functions() {
+            if function.start() == self.addr {
+                let llil = function.low_level_il().ok()?;
+                let ssa = llil.ssa_form().ok()?;
+                let count = llil.instruction_count();
+                let mut form = SsaForm {
+                    defs: vec![Vec::new(); count],
+                    uses: vec![Vec::new(); count],
+                    phis: HashMap::new(),
+                };
+                for index in 0..ssa.instruction_count() {
+                    build_ssa_inst(&ssa.instruction_from_idx(index), &mut form);
+                }
+                return Some(form);
+            }
+        }
+        return None;
+    }
+
+    // Index of the first LLIL instruction lifted from the address
+    pub fn llil_index_at(&self, addr: u64) -> Result<usize, String> {
+        for function in &self.bv.functions() {
+            if function.start() == self.addr {
+                if let Ok(llil) = function.low_level_il() {
+                    for index in 0..llil.instruction_count() {
+                        if llil.instruction_from_idx(index).address() == addr {
+                            return Ok(index);
+                        }
+                    }
+                }
+            }
+        }
+        return Err(format!("No LLIL instruction at 0x{:x} in {}", addr, self.name));
+    }
 }
 
 pub struct Block<'a> {
@@ -258,48 +771,49 @@ impl<'a> Block<'a> {
 
 // Rust bindings for diassemblly not working 
 pub fn build_inst(inst: binaryninja::llil::Instruction<binaryninja::architecture::CoreArchitecture, binaryninja::llil::Finalized, binaryninja::llil::NonSSA<binaryninja::llil::RegularNonSSA>>) -> Inst {
+    let addr = inst.address();
     match inst.info() {
         SetReg(op) => {
             Inst {
                 addr: op.address(),
-                llil: LlilInst::SetReg(SetReg {reg: format!("{:?}", op.dest_reg()), expr: expression::build_expression(&op.source_expr())}),
+                llil: LlilInst::SetReg(SetReg {reg: expression::register_name(&op.dest_reg()), expr: expression::build_expression(&op.source_expr())}),
                 disass: String::from("mov eax, eax"),
             }
         },
         SetRegSplit(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::SetRegSplit(SetRegSplit {dest_reg_high: format!("{:?}", op.dest_reg_high()), dest_reg_low: format!("{:?}", op.dest_reg_low()), source_expr: expression::build_expression(&op.source_expr())}),
+                llil: LlilInst::SetRegSplit(SetRegSplit {dest_reg_high: expression::register_name(&op.dest_reg_high()), dest_reg_low: expression::register_name(&op.dest_reg_low()), source_expr: expression::build_expression(&op.source_expr()), size: op.size()}),
                 disass: String::from("mov eax, eax"),
             },
         SetFlag(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::SetFlag(SetFlag {addr: 5}),
+                llil: LlilInst::SetFlag(SetFlag {flag: String::from(op.dest_flag().name().as_ref()), expr: expression::build_expression(&op.source_expr())}),
                 disass: String::from("mov eax, eax"),
             },
         Store(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::Store(Store {source_expr: expression::build_expression(&op.source_expr()), dest_mem_expr: expression::build_expression(&op.dest_mem_expr())}),
+                llil: LlilInst::Store(Store {source_expr: expression::build_expression(&op.source_expr()), dest_mem_expr: expression::build_expression(&op.dest_mem_expr()), size: op.size()}),
                 disass: String::from("mov eax, eax"),
             },
         Push(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::Push(Push {expr: expression::build_expression(&op.operand())}),
+                llil: LlilInst::Push(Push {expr: expression::build_expression(&op.operand()), size: op.size()}),
                 disass: String::from("mov eax, eax"),
             },
         Jump(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::Jump(Jump {addr: 5}),
+                llil: LlilInst::Jump(Jump {target: expression::build_expression(&op.target())}),
                 disass: String::from("mov eax, eax"),
             },
         JumpTo(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::JumpTo(JumpTo {addr: 5}),
+                llil: LlilInst::JumpTo(JumpTo {target: expression::build_expression(&op.target())}),
                 disass: String::from("mov eax, eax"),
             },
         Call(op) =>
@@ -311,13 +825,13 @@ pub fn build_inst(inst: binaryninja::llil::Instruction<binaryninja::architecture
         Ret(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::Ret(Ret {addr: 5}),
+                llil: LlilInst::Ret(Ret {target: expression::build_expression(&op.target())}),
                 disass: String::from("mov eax, eax"),
             },
         If(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::If(If {condition: expression::build_expression(&op.condition()), target_true: build_inst(op.true_target()).addr, target_false: build_inst(op.false_target()).addr}),
+                llil: LlilInst::If(If {condition: expression::build_expression(&op.condition()), target_true: op.true_target_idx(), target_false: op.false_target_idx()}),
                 disass: String::from("mov eax, eax"),
             },
         Nop(op) => 
@@ -335,7 +849,7 @@ pub fn build_inst(inst: binaryninja::llil::Instruction<binaryninja::architecture
         Goto(op) =>
             Inst {
                 addr: op.address(),
-                llil: LlilInst::Goto(Goto {target: 115}),
+                llil: LlilInst::Goto(Goto {target: op.target_idx()}),
                 disass: String::from("mov eax, eax"),
             },
         Syscall(op) =>
@@ -362,26 +876,83 @@ pub fn build_inst(inst: binaryninja::llil::Instruction<binaryninja::architecture
                 llil: LlilInst::Undef(),
                 disass: String::from("mov eax, eax"),
             },
-        _ =>
+        // Expressions evaluated for their side effects, like the flags a cmp or test writes
+        Value(expr, _) =>
             Inst {
-                addr: 0,
-                llil: LlilInst::Undef(),
+                addr: addr,
+                llil: LlilInst::Value(expression::build_expression(&expr)),
                 disass: String::from("mov eax, eax"),
             },
+        _ => {
+            error!("0x{:x} Unsupported LLIL instruction", addr);
+            Inst {
+                addr: addr,
+                llil: LlilInst::Undef(),
+                disass: String::from("mov eax, eax"),
+            }
+        }
+    }
+}
+
+// A register version in LLIL SSA form, version 0 is the value at the function's entry
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SsaReg {
+    pub name: String,
+    pub version: usize,
+}
+
+// Which register versions each LLIL instruction defines and reads, and the versions phis merge
+pub struct SsaForm {
+    pub defs: Vec<Vec<SsaReg>>,
+    pub uses: Vec<Vec<SsaReg>>,
+    pub phis: HashMap<SsaReg, Vec<SsaReg>>,
+}
+
+impl SsaForm {
+    // Whether anything in the function defines the version, phis included
+    pub fn defines(&self, reg: &SsaReg) -> bool {
+        return self.phis.contains_key(reg) || self.defs.iter().any(|defs| defs.contains(reg));
     }
 }
 
+fn ssa_reg(reg: &llil::SSARegister<CoreRegister>) -> SsaReg {
+    return SsaReg { name: expression::register_name(&reg.reg()), version: reg.version() as usize };
+}
+
+// Records what an SSA instruction defines and reads against the non-SSA instruction it came from
+fn build_ssa_inst(inst: &llil::Instruction<CoreArchitecture, llil::Finalized, llil::SSA>, form: &mut SsaForm) {
+    // Phis are only in the SSA form, at the start of the blocks they merge paths into
+    if let RegPhi(ref op) = inst.info() {
+        form.phis.insert(ssa_reg(&op.dest_reg()), op.source_regs().iter().map(ssa_reg).collect());
+        return;
+    }
+    let index = inst.non_ssa_form();
+    if index >= form.defs.len() {
+        return;
+    }
+    match inst.info() {
+        SetReg(ref op) => form.defs[index].push(ssa_reg(&op.dest_reg())),
+        SetRegSplit(ref op) => {
+            form.defs[index].push(ssa_reg(&op.dest_reg_high()));
+            form.defs[index].push(ssa_reg(&op.dest_reg_low()));
+        }
+        _ => (),
+    }
+    let uses = &mut form.uses[index];
+    inst.visit_tree(&mut |_, info| {
+        if let llil::ExprInfo::Reg(ref op) = *info {
+            uses.push(ssa_reg(&op.source_reg()));
+        }
+        llil::VisitorAction::Descend
+    });
+}
+
 pub struct Inst {
     pub addr: u64,
     pub llil: LlilInst,
     pub disass: String,
 }
 
-pub struct Index {
-    pub inst: Inst, 
-    pub index: u64
-}
-
 pub enum LlilInst {
     SetReg(SetReg),
     SetRegSplit(SetRegSplit),
@@ -400,6 +971,8 @@ pub enum LlilInst {
     Bp(),
     Trap(),
     Undef(),
+    // An expression whose value is unused, evaluated for the flags it writes
+    Value(expression::Expr),
 }
 
 pub struct SetReg {
@@ -411,27 +984,31 @@ pub struct SetRegSplit {
     pub dest_reg_high: String,
     pub dest_reg_low: String,
     pub source_expr: expression::Expr,
+    pub size: usize,
 }
 
 pub struct SetFlag {
-    pub addr: u64,
+    pub flag: String,
+    pub expr: expression::Expr,
 }
 
 pub struct Store {
     pub dest_mem_expr: expression::Expr,
     pub source_expr: expression::Expr,
+    pub size: usize,
 }
 
 pub struct Push {
     pub expr: expression::Expr,
+    pub size: usize,
 }
 
 pub struct Jump {
-    pub addr: u64,
+    pub target: expression::Expr,
 }
 
 pub struct JumpTo {
-    pub addr: u64,
+    pub target: expression::Expr,
 }
 
 pub struct Call {
@@ -439,17 +1016,18 @@ pub struct Call {
 }
 
 pub struct Ret {
-    pub addr: u64,
+    pub target: expression::Expr,
 }
 
+// Branch targets are LLIL indexes within the same function
 pub struct If {
     pub condition: expression::Expr,
-    pub target_true: u64,
-    pub target_false: u64,
+    pub target_true: usize,
+    pub target_false: usize,
 }
 
 pub struct Goto {
-    pub target: u64,
+    pub target: usize,
 }
 
 pub struct Return {
@@ -628,3 +1206,32 @@ pub struct Return {
                     }
                 */
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needed_reads_the_rebased_string_table() {
+        // DT_NEEDED 1, DT_NEEDED 9, DT_STRTAB 0x400 as linked, DT_NULL
+        let mut dynamic = Vec::new();
+        for &(tag, value) in [(DT_NEEDED, 1u64), (DT_NEEDED, 9), (DT_STRTAB, 0x400), (DT_NULL, 0)].iter() {
+            for i in 0..16 {
+                let word = if i < 8 { tag } else { value };
+                dynamic.push((word >> ((i % 8) * 8)) as u8);
+            }
+        }
+        // The view was rebased so the string table is at LIBRARY_BASE + 0x400
+        let strtab = b"\0libc.so.6\0libm.so.6\0";
+        let base = LIBRARY_BASE + 0x400;
+        let read = |addr: u64| -> Vec<u8> {
+            if addr < base || addr >= base + strtab.len() as u64 {
+                return Vec::new();
+            }
+            return strtab[(addr - base) as usize..].to_vec();
+        };
+
+        assert_eq!(needed_names(&dynamic, 8, false, Some(base), &read), vec!["libc.so.6", "libm.so.6"]);
+        assert_eq!(needed_names(&dynamic, 8, false, None, &read), vec!["", ""]);
+    }
+}
@@ -1,4 +1,6 @@
-use cpython::{Python};
+use cpython::{Python, PyObject, PyTuple, PythonObject, ToPyObject, ObjectProtocol};
+use state::State;
+use procedures::Procedure;
 
 pub struct Python3<'p> {
     py: Python<'p>,
@@ -14,4 +16,31 @@ impl<'p> Python3<'p> {
     pub fn run(&self, code: &str) {
         self.py.run(code, None, None).expect(&format!("Failed to execute: {}", code));
     }
+
+    // Evaluates an expression that produces a callable and wraps it as a procedure
+    pub fn procedure(&self, code: &str) -> Result<Procedure, String> {
+        let callable = self.py.eval(code, None, None).map_err(|err| format!("Failed to evaluate {}: {:?}", code, err))?;
+        return Ok(procedure(callable));
+    }
+}
+
+// Wraps a Python callable as a procedure. It is called with the first six integer arguments and
+// a return value other than None becomes the return value of the call.
+pub fn procedure(callable: PyObject) -> Procedure {
+    return Box::new(move |state: &mut State| {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+
+        let mut args = Vec::new();
+        for n in 0..6 {
+            args.push(state.arg(n)?.to_py_object(py).into_object());
+        }
+
+        let result = callable.call(py, PyTuple::new(py, &args), None).map_err(|err| format!("Python procedure failed: {:?}", err))?;
+        if !result.is_none(py) {
+            let value: u64 = result.extract(py).map_err(|err| format!("Python procedure returned a non integer: {:?}", err))?;
+            state.set_return(value);
+        }
+        return Ok(());
+    });
 }
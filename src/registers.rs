@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use binaryninja::architecture::{Architecture, CoreArchitecture, Register, RegisterInfo, ImplicitRegisterExtend};
+use expression::{mask, truncate, sign_extend};
+
+// Architecture families we emulate, classified from Binary Ninja's architecture name once so
+// everything that depends on the architecture agrees on what it is
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArchKind {
+    X86_64,
+    X86,
+    Aarch64,
+    Arm,
+    Riscv,
+    Unknown,
+}
+
+impl ArchKind {
+    pub fn of(arch: &CoreArchitecture) -> ArchKind {
+        return ArchKind::from_name(arch.name().as_ref());
+    }
+
+    pub fn from_name(name: &str) -> ArchKind {
+        let name = name.to_ascii_lowercase();
+        if name.contains("x86_64") || name.contains("amd64") {
+            return ArchKind::X86_64;
+        }
+        if name.contains("x86") {
+            return ArchKind::X86;
+        }
+        if name.contains("aarch64") || name.contains("arm64") {
+            return ArchKind::Aarch64;
+        }
+        if name.contains("arm") || name.contains("thumb") {
+            return ArchKind::Arm;
+        }
+        if name.starts_with("rv") || name.contains("riscv") {
+            return ArchKind::Riscv;
+        }
+        return ArchKind::Unknown;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Extend {
+    None,
+    ZeroExtend,
+    SignExtend,
+}
+
+// Where a register lives inside its full width register
+#[derive(Clone)]
+pub struct RegisterDef {
+    pub name: String,
+    pub full_width: String,
+    pub offset: usize,
+    pub size: usize,
+    pub extend: Extend,
+}
+
+pub struct Registers {
+    pub defs: HashMap<String, RegisterDef>,
+    pub values: HashMap<String, u128>,
+    pub stack_pointer: Option<String>,
+    pub link_register: Option<String>,
+    pub address_size: usize,
+}
+
+impl Registers {
+    // Register file without an architecture, every register is treated as full width
+    pub fn empty() -> Registers {
+        return Registers {
+            defs: HashMap::new(),
+            values: HashMap::new(),
+            stack_pointer: None,
+            link_register: None,
+            address_size: 8,
+        }
+    }
+
+    // Builds the register file from the architecture's register list
+    pub fn new(arch: &CoreArchitecture) -> Registers {
+        let mut regs = Registers::empty();
+
+        for reg in arch.registers_all() {
+            let info = reg.info();
+            let full_width = match info.parent() {
+                Some(parent) => String::from(parent.name().as_ref()),
+                None => String::from(reg.name().as_ref()),
+            };
+            let extend = match info.implicit_extend() {
+                ImplicitRegisterExtend::ZeroExtendToFullWidth => Extend::ZeroExtend,
+                ImplicitRegisterExtend::SignExtendToFullWidth => Extend::SignExtend,
+                _ => Extend::None,
+            };
+
+            regs.defs.insert(String::from(reg.name().as_ref()), RegisterDef {
+                name: String::from(reg.name().as_ref()),
+                full_width: full_width,
+                offset: info.offset(),
+                size: info.size(),
+                extend: extend,
+            });
+        }
+
+        regs.stack_pointer = arch.stack_pointer_reg().map(|r| String::from(r.name().as_ref()));
+        regs.link_register = arch.link_reg().map(|r| String::from(r.name().as_ref()));
+        regs.address_size = arch.address_size();
+        return regs;
+    }
+
+    pub fn info(&self, name: &str) -> Option<&RegisterDef> {
+        return self.defs.get(name);
+    }
+
+    // Name of the full width register that holds this register
+    pub fn full_width(&self, name: &str) -> String {
+        return match self.defs.get(name) {
+            Some(def) => def.full_width.clone(),
+            None => String::from(name),
+        };
+    }
+
+    pub fn full_width_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.defs.values()
+            .filter(|def| def.name == def.full_width)
+            .map(|def| def.name.clone())
+            .collect();
+        names.sort();
+        return names;
+    }
+
+    pub fn get(&self, name: &str) -> u128 {
+        match self.defs.get(name) {
+            Some(def) => {
+                let full = *self.values.get(&def.full_width).unwrap_or(&0);
+                return truncate(full >> (def.offset * 8), def.size);
+            }
+            // Temporary registers and registers the architecture doesn't know about
+            None => return *self.values.get(name).unwrap_or(&0),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: u128) {
+        let def = match self.defs.get(name) {
+            Some(def) => def.clone(),
+            None => {
+                self.values.insert(String::from(name), value);
+                return;
+            }
+        };
+
+        let full_size = self.defs.get(&def.full_width).map(|d| d.size).unwrap_or(def.size);
+        let value = truncate(value, def.size);
+
+        let full = match def.extend {
+            Extend::ZeroExtend => value,
+            Extend::SignExtend => truncate(sign_extend(value, def.size) as u128, full_size),
+            Extend::None => {
+                // Writes to a partial register leave the rest of the full width register untouched
+                let old = *self.values.get(&def.full_width).unwrap_or(&0);
+                let shifted = mask(def.size) << (def.offset * 8);
+                (old & !shifted) | (value << (def.offset * 8))
+            }
+        };
+
+        self.values.insert(def.full_width.clone(), truncate(full, full_size));
+    }
+
+    pub fn sp(&self) -> u64 {
+        return match self.stack_pointer {
+            Some(ref sp) => self.get(sp) as u64,
+            None => 0,
+        };
+    }
+
+    pub fn set_sp(&mut self, value: u64) {
+        if let Some(sp) = self.stack_pointer.clone() {
+            self.set(&sp, value as u128);
+        }
+    }
+
+    // Every full width register that has been written, with its value
+    pub fn written(&self) -> Vec<(String, u128)> {
+        return self.full_width_names().into_iter()
+            .filter(|name| self.values.contains_key(name))
+            .map(|name| { let value = self.get(&name); (name, value) })
+            .collect();
+    }
+
+    pub fn print(&self) {
+        for chunk in self.written().chunks(3) {
+            let line: Vec<String> = chunk.iter().map(|&(ref name, value)| format!("{} 0x{:x}", name, value)).collect();
+            info!("\t{}", line.join("\t"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arch_kind_from_name() {
+        assert_eq!(ArchKind::from_name("x86_64"), ArchKind::X86_64);
+        assert_eq!(ArchKind::from_name("x86"), ArchKind::X86);
+        assert_eq!(ArchKind::from_name("aarch64"), ArchKind::Aarch64);
+        assert_eq!(ArchKind::from_name("arm64"), ArchKind::Aarch64);
+        assert_eq!(ArchKind::from_name("armv7"), ArchKind::Arm);
+        assert_eq!(ArchKind::from_name("thumb2"), ArchKind::Arm);
+        assert_eq!(ArchKind::from_name("rv64gc"), ArchKind::Riscv);
+        assert_eq!(ArchKind::from_name("mips32"), ArchKind::Unknown);
+    }
+}
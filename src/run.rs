@@ -1,44 +1,363 @@
-/* 
- * Figure out CLI
- * 
- * 
-*/
-
-use state::*;
+use z3;
+use binaryninja::binaryview::BinaryViewExt;
+use program::*;
 use project::*;
 use emulator::*;
 use taint_tracker::*;
+use shadow::TaintSet;
+use static_taint::StaticTaint;
+use symbolic_executor::*;
+use liftcheck;
+use process::Process;
 
-pub fn run(proj: Project) {
+// Where an analysis starts, either a function by name or any address inside a function
+pub enum Start {
+    Function(String),
+    Address(u64),
+}
 
-    for function in proj.program.functions() {
-        info!("{}", function.name);        
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+pub struct Options {
+    pub start: Start,
+    pub steps: usize,
+    // argv[0] when it shouldn't be the program's path, the arguments after it, and the
+    // environment when it shouldn't be the default one
+    pub argv0: Option<String>,
+    pub args: Vec<String>,
+    pub env: Option<Vec<String>>,
+    pub stdin: String,
+    // Host files copied into the emulated file system, as (path, host path)
+    pub files: Vec<(String, String)>,
+    // Directory the program's shared libraries are loaded from, when library code should run.
+    // Only the procedures listed are still used then.
+    pub sysroot: Option<String>,
+    pub procedures: Vec<String>,
+    // Shadows emulation with taint from the policy's sources and reads of files and stdin
+    pub taint: bool,
+    pub format: Format,
+    // Symbolic execution searches for find while avoiding the avoid addresses
+    pub find: Option<u64>,
+    pub avoid: Vec<u64>,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        return Options {
+            start: Start::Function(String::from("main")),
+            steps: 50,
+            argv0: None,
+            args: Vec::new(),
+            env: None,
+            stdin: String::from(""),
+            files: Vec::new(),
+            sysroot: None,
+            procedures: Vec::new(),
+            taint: false,
+            format: Format::Text,
+            find: None,
+            avoid: Vec::new(),
+        }
+    }
+}
+
+pub enum Field {
+    Text(String),
+    Number(u64),
+    Bool(bool),
+    List(Vec<String>),
+    Map(Vec<(String, String)>),
+}
+
+// Results of an analysis, printed as text or json
+pub struct Report {
+    pub command: String,
+    pub fields: Vec<(String, Field)>,
+}
+
+impl Report {
+    pub fn new(command: &str) -> Report {
+        return Report {
+            command: String::from(command),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, field: Field) {
+        self.fields.push((String::from(name), field));
     }
 
-    // Creates a new emulator at the main function
-    let mut emulator = Emulator::main(&proj.program);
-    
-    for _ in 0..50 {
-        let result = emulator.step();
-        match result {
-            Ok(_) => continue,
+    pub fn has_error(&self) -> bool {
+        return self.fields.iter().any(|&(ref name, _)| name == "error");
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => {
+                let mut lines = vec![self.command.clone()];
+                for &(ref name, ref field) in &self.fields {
+                    let value = match *field {
+                        Field::Text(ref s) => s.clone(),
+                        Field::Number(n) => format!("{}", n),
+                        Field::Bool(b) => format!("{}", b),
+                        Field::List(ref items) => items.join(", "),
+                        Field::Map(ref items) => items.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(" "),
+                    };
+                    lines.push(format!("  {}: {}", name, value));
+                }
+                return lines.join("\n");
+            }
+            Format::Json => {
+                let mut fields = vec![format!("\"command\": {}", json_string(&self.command))];
+                for &(ref name, ref field) in &self.fields {
+                    let value = match *field {
+                        Field::Text(ref s) => json_string(s),
+                        Field::Number(n) => format!("{}", n),
+                        Field::Bool(b) => format!("{}", b),
+                        Field::List(ref items) => format!("[{}]", items.iter().map(|s| json_string(s)).collect::<Vec<String>>().join(", ")),
+                        Field::Map(ref items) => format!("{{{}}}", items.iter().map(|&(ref k, ref v)| format!("{}: {}", json_string(k), json_string(v))).collect::<Vec<String>>().join(", ")),
+                    };
+                    fields.push(format!("{}: {}", json_string(name), value));
+                }
+                return format!("{{{}}}", fields.join(", "));
+            }
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    return out;
+}
+
+pub fn emulate(program: &Program, options: &Options) -> Report {
+    let mut report = Report::new("emulate");
+    let mut emulator = match options.start {
+        Start::Function(ref name) => match program.function_named(name) {
+            Ok(function) => Emulator::at_function(program, &function.name),
+            Err(err) => {
+                report.add("error", Field::Text(err));
+                return report;
+            }
+        },
+        Start::Address(addr) => match Emulator::at_address(program, addr) {
+            Ok(emulator) => emulator,
             Err(err) => {
-                error!("Run error: {}", &err);
-                break;
-            },
-        }; 
-    }
-    
-    emulator.state.print();
-
-    // let mut tainter = TaintTracker::main(&proj.program);
-
-    // for _ in 0..20 {
-    //     tainter.step();
-    //     info!("Taint tracker is at address 0x{:x}, tainted regs are:", tainter.state.addr);
-    //     for reg in &tainter.state.regs_tainted {
-    //         info!(" > {}", reg);
-    //     }
-    // }
-    
+                report.add("error", Field::Text(err));
+                return report;
+            }
+        },
+    };
+    if options.sysroot.is_some() {
+        emulator.procedures.retain(&options.procedures);
+    }
+    if options.taint {
+        emulator.enable_taint();
+    }
+    let mut process = Process::new(program);
+    if let Some(ref argv0) = options.argv0 {
+        process.args[0] = argv0.clone();
+    }
+    process.args.extend(options.args.iter().cloned());
+    if let Some(ref env) = options.env {
+        process.env = env.clone();
+    }
+    if let Err(err) = emulator.start_process(&process) {
+        report.add("error", Field::Text(err));
+        return report;
+    }
+
+    emulator.state.fs.set_stdin(options.stdin.clone().into_bytes());
+    for &(ref path, ref host_path) in &options.files {
+        if let Err(err) = emulator.state.fs.add_host_file(path, host_path) {
+            report.add("error", Field::Text(err));
+            return report;
+        }
+    }
+
+    let mut steps = 0;
+    while steps < options.steps && emulator.state.exited.is_none() {
+        if let Err(err) = emulator.step() {
+            error!("Run error: {}", &err);
+            report.add("error", Field::Text(err));
+            break;
+        }
+        steps += 1;
+    }
+
+    report.add("steps", Field::Number(steps as u64));
+    report.add("addr", Field::Text(format!("0x{:x}", emulator.state.addr)));
+    if let Some(status) = emulator.state.exited {
+        report.add("exit_status", Field::Number(status));
+    }
+    report.add("registers", Field::Map(emulator.state.regs.written().into_iter().map(|(name, value)| (name, format!("0x{:x}", value))).collect()));
+
+    let mut flags: Vec<(String, String)> = emulator.state.flags.values.iter().map(|(name, value)| (name.clone(), format!("{}", *value as u8))).collect();
+    flags.sort();
+    report.add("flags", Field::Map(flags));
+    report.add("stdout", Field::Text(String::from_utf8_lossy(emulator.state.fs.stdout()).into_owned()));
+    report.add("stderr", Field::Text(String::from_utf8_lossy(emulator.state.fs.stderr()).into_owned()));
+    if !emulator.state.heap.errors.is_empty() {
+        report.add("heap_errors", Field::List(emulator.state.heap.errors.iter().map(|err| err.describe()).collect()));
+    }
+    if let Some(ref taint) = emulator.taint {
+        let labeled = |list: &Vec<(u64, TaintSet)>| -> Vec<String> {
+            return list.iter().map(|&(addr, ref set)| format!("0x{:x} {}", addr, taint.describe(set).join(", "))).collect();
+        };
+        report.add("tainted", Field::List(taint.regs_tainted()));
+        report.add("tainted_bytes", Field::Number(taint.memory.bytes.len() as u64));
+        report.add("flows", Field::List(taint.flows.iter().map(|flow| flow.describe()).collect()));
+        report.add("tainted_branches", Field::List(labeled(&taint.tainted_branches)));
+        report.add("tainted_pointers", Field::List(labeled(&taint.tainted_pointers)));
+    }
+    return report;
+}
+
+pub fn taint(program: &Program, options: &Options) -> Report {
+    let mut report = Report::new("taint");
+    let mut tainter = match options.start {
+        Start::Function(ref name) => match program.function_named(name) {
+            Ok(function) => TaintTracker::at_function(program, &function.name),
+            Err(err) => {
+                report.add("error", Field::Text(err));
+                return report;
+            }
+        },
+        Start::Address(addr) => match TaintTracker::at_address(program, addr) {
+            Ok(tainter) => tainter,
+            Err(err) => {
+                report.add("error", Field::Text(err));
+                return report;
+            }
+        },
+    };
+
+    let mut steps = 0;
+    while steps < options.steps {
+        if tainter.finished {
+            break;
+        }
+        if let Err(err) = tainter.step() {
+            error!("Taint error: {}", &err);
+            report.add("error", Field::Text(err));
+            break;
+        }
+        steps += 1;
+    }
+
+    let mut memory: Vec<String> = tainter.state.memory.bytes.keys().map(|location| location.to_string()).collect();
+    memory.sort();
+    report.add("steps", Field::Number(steps as u64));
+    report.add("finished", Field::Bool(tainter.finished));
+    report.add("addr", Field::Text(format!("0x{:x}", tainter.state.addr)));
+    report.add("tainted", Field::List(tainter.state.regs_tainted()));
+    report.add("tainted_memory", Field::List(memory));
+    report.add("flows", Field::List(tainter.flows.iter().map(|flow| flow.describe()).collect()));
+    let labeled = |list: &Vec<(u64, TaintSet)>| -> Vec<String> {
+        return list.iter().map(|&(addr, ref set)| format!("0x{:x} {}", addr, tainter.describe(set).join(", "))).collect();
+    };
+    report.add("tainted_branches", Field::List(labeled(&tainter.tainted_branches)));
+    report.add("tainted_pointers", Field::List(labeled(&tainter.tainted_pointers)));
+    return report;
+}
+
+// Summarizes every function and reports the flows from sources to sinks across the whole program
+pub fn scan(program: &Program, _options: &Options) -> Report {
+    let mut report = Report::new("scan");
+    let mut analysis = StaticTaint::new(program);
+    analysis.run();
+
+    let mut summaries: Vec<(String, String)> = analysis.summaries.iter()
+        .filter(|&(_, summary)| !summary.is_empty())
+        .map(|(addr, summary)| (analysis.names.get(addr).cloned().unwrap_or(format!("0x{:x}", addr)), summary.describe()))
+        .collect();
+    summaries.sort();
+    report.add("functions", Field::Number(analysis.summaries.len() as u64));
+    report.add("rounds", Field::Number(analysis.rounds as u64));
+    report.add("flows", Field::List(analysis.flows.iter().map(|flow| flow.describe()).collect()));
+    report.add("summaries", Field::Map(summaries));
+    return report;
+}
+
+pub fn symex(program: &Program, options: &Options) -> Report {
+    let mut report = Report::new("symex");
+    let find = match options.find {
+        Some(find) => find,
+        None => {
+            report.add("error", Field::Text(String::from("Symbolic execution needs an address to find")));
+            return report;
+        }
+    };
+
+    let ctx = z3::Context::new(&z3::Config::new());
+    let mut executor = match options.start {
+        Start::Function(ref name) => match program.function_named(name) {
+            Ok(function) => SymbolicExecutor::at_function(program, &ctx, &function.name),
+            Err(err) => {
+                report.add("error", Field::Text(err));
+                return report;
+            }
+        },
+        Start::Address(addr) => match SymbolicExecutor::at_address(program, &ctx, addr) {
+            Ok(executor) => executor,
+            Err(err) => {
+                report.add("error", Field::Text(err));
+                return report;
+            }
+        },
+    };
+
+    match executor.explore(find, &options.avoid, options.steps) {
+        Some(state) => {
+            report.add("found", Field::Bool(true));
+            report.add("addr", Field::Text(format!("0x{:x}", state.addr)));
+            if let Some(input) = state.solve_stdin(&ctx) {
+                report.add("stdin", Field::Text(String::from_utf8_lossy(&input).into_owned()));
+            }
+        }
+        None => report.add("found", Field::Bool(false)),
+    }
+    report.add("active", Field::Number(executor.active.len() as u64));
+    report.add("deadended", Field::Number(executor.deadended.len() as u64));
+    return report;
+}
+
+// Lift check problems are logged as errors, the report only counts what was checked
+pub fn liftcheck(program: &Program, _options: &Options) -> Report {
+    let mut report = Report::new("liftcheck");
+    let functions = program.bv().functions();
+    for function in &functions {
+        liftcheck::check_function(&function);
+    }
+    report.add("functions", Field::Number(functions.len() as u64));
+    return report;
+}
+
+// Entry point for the plugin command, emulates from main with the default options
+pub fn run(proj: Project) {
+    for function in proj.program.functions() {
+        info!("{}", function.name);
+    }
+
+    let options = Options::new();
+    let report = emulate(&proj.program, &options);
+    for line in report.render(options.format).lines() {
+        info!("{}", line);
+    }
 }
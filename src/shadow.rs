@@ -0,0 +1,410 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use expression::Expr;
+use registers::{Registers, Extend};
+
+// Taint labels are indexes into a Labels table
+pub type Label = usize;
+pub type TaintSet = BTreeSet<Label>;
+
+// Names of the labels handed out so far, like "stdin byte 3" or "argv[1] byte 0"
+pub struct Labels {
+    pub names: Vec<String>,
+    // Label of each name, sources label every byte so lookups have to be cheap
+    index: HashMap<String, Label>,
+}
+
+impl Labels {
+    pub fn new() -> Labels {
+        return Labels {
+            names: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    // The label with the name, adding it if it's new
+    pub fn add(&mut self, name: &str) -> Label {
+        if let Some(&label) = self.index.get(name) {
+            return label;
+        }
+        self.names.push(String::from(name));
+        self.index.insert(String::from(name), self.names.len() - 1);
+        return self.names.len() - 1;
+    }
+
+    pub fn name(&self, label: Label) -> &str {
+        return self.names.get(label).map(|name| name.as_str()).unwrap_or("?");
+    }
+
+    pub fn describe(&self, set: &TaintSet) -> Vec<String> {
+        return set.iter().map(|&label| String::from(self.name(label))).collect();
+    }
+}
+
+// Taint of a value, one set per byte starting with the least significant
+#[derive(Clone, Debug, PartialEq)]
+pub struct Taint {
+    pub bytes: Vec<TaintSet>,
+}
+
+impl Taint {
+    pub fn clean(size: usize) -> Taint {
+        return Taint::uniform(&TaintSet::new(), size);
+    }
+
+    pub fn uniform(set: &TaintSet, size: usize) -> Taint {
+        return Taint { bytes: vec![set.clone(); size.max(1)] };
+    }
+
+    pub fn size(&self) -> usize {
+        return self.bytes.len();
+    }
+
+    pub fn is_tainted(&self) -> bool {
+        return self.bytes.iter().any(|set| !set.is_empty());
+    }
+
+    // Every label on any byte
+    pub fn union(&self) -> TaintSet {
+        let mut set = TaintSet::new();
+        for byte in &self.bytes {
+            set.extend(byte.iter().cloned());
+        }
+        return set;
+    }
+
+    // Adds the labels to every byte, like the taint of a pointer spreading to what it loads
+    pub fn with(mut self, set: &TaintSet) -> Taint {
+        for byte in self.bytes.iter_mut() {
+            byte.extend(set.iter().cloned());
+        }
+        return self;
+    }
+
+    // Truncates or extends to size bytes. Sign extended bytes depend on the top byte.
+    pub fn resize(mut self, size: usize, sign: bool) -> Taint {
+        let top = match sign {
+            true => self.bytes.last().cloned().unwrap_or(TaintSet::new()),
+            false => TaintSet::new(),
+        };
+        self.bytes.resize(size.max(1), top);
+        return self;
+    }
+}
+
+// Keys memory is shadowed by, anything that can be moved along byte by byte
+pub trait Address: Clone + Eq + Hash {
+    fn offset(&self, offset: i64) -> Self;
+}
+
+impl Address for u64 {
+    fn offset(&self, offset: i64) -> u64 {
+        return self.wrapping_add(offset as u64);
+    }
+}
+
+// Taint of every byte of memory that has any, keyed by address
+pub struct Shadow<K: Address> {
+    pub bytes: HashMap<K, TaintSet>,
+}
+
+impl<K: Address> Clone for Shadow<K> {
+    fn clone(&self) -> Shadow<K> {
+        return Shadow { bytes: self.bytes.clone() };
+    }
+}
+
+impl<K: Address> Shadow<K> {
+    pub fn new() -> Shadow<K> {
+        return Shadow {
+            bytes: HashMap::new(),
+        }
+    }
+
+    pub fn read(&self, addr: &K, size: usize) -> Taint {
+        return Taint {
+            bytes: (0..size.max(1) as i64).map(|i| self.bytes.get(&addr.offset(i)).cloned().unwrap_or(TaintSet::new())).collect(),
+        };
+    }
+
+    pub fn write(&mut self, addr: &K, taint: &Taint) {
+        for (i, set) in taint.bytes.iter().enumerate() {
+            let key = addr.offset(i as i64);
+            if set.is_empty() {
+                self.bytes.remove(&key);
+            } else {
+                self.bytes.insert(key, set.clone());
+            }
+        }
+    }
+
+    pub fn tainted(&self, addr: &K, size: usize) -> bool {
+        return (0..size as i64).any(|i| self.bytes.contains_key(&addr.offset(i)));
+    }
+}
+
+// Per byte taint of the full width registers
+#[derive(Clone)]
+pub struct RegisterShadow {
+    pub regs: HashMap<String, Taint>,
+}
+
+impl RegisterShadow {
+    pub fn new() -> RegisterShadow {
+        return RegisterShadow {
+            regs: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, registers: &Registers, name: &str) -> Taint {
+        let (full, offset, size) = match registers.info(name) {
+            Some(def) => (def.full_width.clone(), def.offset, def.size),
+            None => (String::from(name), 0, self.regs.get(name).map(|t| t.size()).unwrap_or(1)),
+        };
+        let taint = match self.regs.get(&full) {
+            Some(taint) => taint,
+            None => return Taint::clean(size),
+        };
+        return Taint {
+            bytes: (offset..offset + size).map(|i| taint.bytes.get(i).cloned().unwrap_or(TaintSet::new())).collect(),
+        };
+    }
+
+    // Writes to part of a register keep the taint of the rest, unless the write extends into it
+    pub fn set(&mut self, registers: &Registers, name: &str, taint: Taint) {
+        let def = match registers.info(name) {
+            Some(def) => def.clone(),
+            None => {
+                self.regs.insert(String::from(name), taint);
+                return;
+            }
+        };
+        let full_size = registers.info(&def.full_width).map(|d| d.size).unwrap_or(def.size);
+        let taint = taint.resize(def.size, false);
+
+        let mut full = match def.extend {
+            Extend::ZeroExtend => Taint::clean(full_size),
+            Extend::SignExtend => Taint::clean(full_size).with(taint.bytes.last().unwrap_or(&TaintSet::new())),
+            Extend::None => self.regs.get(&def.full_width).cloned().unwrap_or(Taint::clean(full_size)).resize(full_size, false),
+        };
+        for (i, set) in taint.bytes.into_iter().enumerate() {
+            if def.offset + i < full.bytes.len() {
+                full.bytes[def.offset + i] = set;
+            }
+        }
+
+        if full.is_tainted() {
+            self.regs.insert(def.full_width.clone(), full);
+        } else {
+            self.regs.remove(&def.full_width);
+        }
+    }
+
+    // Full width names of the registers with any taint
+    pub fn tainted(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.regs.iter().filter(|&(_, taint)| taint.is_tainted()).map(|(name, _)| name.clone()).collect();
+        names.sort();
+        return names;
+    }
+}
+
+// What expression taint needs to know about the state it's evaluated in
+pub trait Context {
+    fn reg(&self, name: &str) -> Taint;
+    fn flag(&self, name: &str) -> TaintSet;
+    // Taint of every flag, for conditions that read whichever flags the last operation wrote
+    fn flags(&self) -> TaintSet;
+    // Taint of the bytes a load from the address reads, the address's own taint is added after
+    fn load(&self, addr: &Expr, size: usize) -> Taint;
+    fn pop(&self, size: usize) -> Taint;
+}
+
+fn binary<C: Context>(ctx: &C, left: &Expr, right: &Expr, size: usize) -> Taint {
+    let mut set = expression_taint(ctx, left).union();
+    set.extend(expression_taint(ctx, right).union());
+    return Taint::uniform(&set, size);
+}
+
+// Taint of the value an expression produces. Moves and extensions keep taint per byte, any
+// other operation mixes its operands so every byte of the result gets all their labels.
+pub fn expression_taint<C: Context>(ctx: &C, expr: &Expr) -> Taint {
+    let size = expr.size();
+    return match *expr {
+        Expr::Value(_) => Taint::clean(size),
+        Expr::Reg(ref r) => ctx.reg(&r.name),
+        Expr::Flag(ref f) => Taint::uniform(&ctx.flag(&f.name), 1),
+        Expr::FlagBit(ref f) => Taint::uniform(&ctx.flag(&f.name), f.size),
+        Expr::FlagCond(_) | Expr::FlagGroup(_) => Taint::uniform(&ctx.flags(), 1),
+        Expr::Load(ref l) => {
+            let pointer = expression_taint(ctx, &l.source_mem).union();
+            ctx.load(&l.source_mem, l.size).with(&pointer)
+        }
+        Expr::Pop(ref p) => ctx.pop(p.size),
+
+        Expr::Zx(ref s) | Expr::LowPart(ref s) => expression_taint(ctx, &s.operand).resize(s.size, false),
+        Expr::Sx(ref s) => expression_taint(ctx, &s.operand).resize(s.size, true),
+
+        // Xoring a register with itself is how compilers zero it
+        Expr::Xor(ref s) => match (&*s.left, &*s.right) {
+            (&Expr::Reg(ref left), &Expr::Reg(ref right)) if left.name == right.name => Taint::clean(s.size),
+            _ => binary(ctx, &s.left, &s.right, s.size),
+        },
+
+        Expr::Add(ref s) | Expr::Sub(ref s) | Expr::And(ref s) | Expr::Or(ref s) | Expr::Mul(ref s) |
+        Expr::Divu(ref s) | Expr::Divs(ref s) | Expr::Modu(ref s) | Expr::Mods(ref s) |
+        Expr::Lsl(ref s) | Expr::Lsr(ref s) | Expr::Asr(ref s) | Expr::Rol(ref s) | Expr::Ror(ref s) |
+        Expr::MulsDp(ref s) | Expr::MuluDp(ref s) |
+        Expr::Fadd(ref s) | Expr::Fsub(ref s) | Expr::Fmul(ref s) | Expr::Fdiv(ref s) => binary(ctx, &s.left, &s.right, size),
+
+        Expr::CmpE(ref s) | Expr::CmpNe(ref s) |
+        Expr::CmpSlt(ref s) | Expr::CmpSle(ref s) | Expr::CmpSge(ref s) | Expr::CmpSgt(ref s) |
+        Expr::CmpUlt(ref s) | Expr::CmpUle(ref s) | Expr::CmpUge(ref s) | Expr::CmpUgt(ref s) |
+        Expr::FcmpE(ref s) | Expr::FcmpNe(ref s) | Expr::FcmpLt(ref s) | Expr::FcmpLe(ref s) | Expr::FcmpGe(ref s) | Expr::FcmpGt(ref s) |
+        Expr::FcmpO(ref s) | Expr::FcmpUo(ref s) => binary(ctx, &s.left, &s.right, 1),
+
+        Expr::DivuDp(ref s) | Expr::DivsDp(ref s) | Expr::ModuDp(ref s) | Expr::ModsDp(ref s) => {
+            let mut set = expression_taint(ctx, &s.high).union();
+            set.extend(expression_taint(ctx, &s.low).union());
+            set.extend(expression_taint(ctx, &s.right).union());
+            Taint::uniform(&set, s.size)
+        }
+        Expr::Adc(ref s) | Expr::Sbb(ref s) | Expr::Rlc(ref s) | Expr::Rrc(ref s) => {
+            let mut set = expression_taint(ctx, &s.left).union();
+            set.extend(expression_taint(ctx, &s.right).union());
+            set.extend(expression_taint(ctx, &s.carry).union());
+            Taint::uniform(&set, s.size)
+        }
+
+        Expr::Neg(ref s) | Expr::Not(ref s) | Expr::BoolToInt(ref s) |
+        Expr::Fsqrt(ref s) | Expr::Fneg(ref s) | Expr::Fabs(ref s) | Expr::FloatToInt(ref s) | Expr::IntToFloat(ref s) | Expr::FloatConv(ref s) |
+        Expr::RoundToInt(ref s) | Expr::Floor(ref s) | Expr::Ceil(ref s) | Expr::Ftrunc(ref s) => {
+            Taint::uniform(&expression_taint(ctx, &s.operand).union(), s.size)
+        }
+
+        Expr::Undef(ref s) => {error!("Undef expr {:?}", s.expr); Taint::clean(size)},
+    };
+}
+
+// Flags written by the operation at the top of an expression
+pub fn flags_written(expr: &Expr) -> Vec<String> {
+    return match *expr {
+        Expr::Add(ref s) | Expr::Sub(ref s) | Expr::And(ref s) | Expr::Or(ref s) | Expr::Xor(ref s) |
+        Expr::Mul(ref s) | Expr::Divu(ref s) | Expr::Divs(ref s) | Expr::Modu(ref s) | Expr::Mods(ref s) |
+        Expr::Lsl(ref s) | Expr::Lsr(ref s) | Expr::Asr(ref s) | Expr::Rol(ref s) | Expr::Ror(ref s) |
+        Expr::MulsDp(ref s) | Expr::MuluDp(ref s) => s.flags.clone(),
+        Expr::Adc(ref s) | Expr::Sbb(ref s) | Expr::Rlc(ref s) | Expr::Rrc(ref s) => s.flags.clone(),
+        Expr::Neg(ref s) | Expr::Not(ref s) => s.flags.clone(),
+        _ => Vec::new(),
+    };
+}
+
+// Operands an expression reads
+pub fn operands(expr: &Expr) -> Vec<&Expr> {
+    return match *expr {
+        Expr::Load(ref l) => vec![&*l.source_mem],
+        Expr::Add(ref s) | Expr::Sub(ref s) | Expr::And(ref s) | Expr::Or(ref s) | Expr::Xor(ref s) |
+        Expr::Mul(ref s) | Expr::Divu(ref s) | Expr::Divs(ref s) | Expr::Modu(ref s) | Expr::Mods(ref s) |
+        Expr::Lsl(ref s) | Expr::Lsr(ref s) | Expr::Asr(ref s) | Expr::Rol(ref s) | Expr::Ror(ref s) |
+        Expr::MulsDp(ref s) | Expr::MuluDp(ref s) |
+        Expr::Fadd(ref s) | Expr::Fsub(ref s) | Expr::Fmul(ref s) | Expr::Fdiv(ref s) => vec![&*s.left, &*s.right],
+        Expr::CmpE(ref s) | Expr::CmpNe(ref s) |
+        Expr::CmpSlt(ref s) | Expr::CmpSle(ref s) | Expr::CmpSge(ref s) | Expr::CmpSgt(ref s) |
+        Expr::CmpUlt(ref s) | Expr::CmpUle(ref s) | Expr::CmpUge(ref s) | Expr::CmpUgt(ref s) |
+        Expr::FcmpE(ref s) | Expr::FcmpNe(ref s) | Expr::FcmpLt(ref s) | Expr::FcmpLe(ref s) | Expr::FcmpGe(ref s) | Expr::FcmpGt(ref s) |
+        Expr::FcmpO(ref s) | Expr::FcmpUo(ref s) => vec![&*s.left, &*s.right],
+        Expr::DivuDp(ref s) | Expr::DivsDp(ref s) | Expr::ModuDp(ref s) | Expr::ModsDp(ref s) => vec![&*s.high, &*s.low, &*s.right],
+        Expr::Adc(ref s) | Expr::Sbb(ref s) | Expr::Rlc(ref s) | Expr::Rrc(ref s) => vec![&*s.left, &*s.right, &*s.carry],
+        Expr::Neg(ref s) | Expr::Not(ref s) | Expr::Sx(ref s) | Expr::Zx(ref s) | Expr::LowPart(ref s) | Expr::BoolToInt(ref s) |
+        Expr::Fsqrt(ref s) | Expr::Fneg(ref s) | Expr::Fabs(ref s) | Expr::FloatToInt(ref s) | Expr::IntToFloat(ref s) | Expr::FloatConv(ref s) |
+        Expr::RoundToInt(ref s) | Expr::Floor(ref s) | Expr::Ceil(ref s) | Expr::Ftrunc(ref s) => vec![&*s.operand],
+        _ => Vec::new(),
+    };
+}
+
+// Address expressions of every load in an expression
+pub fn load_addresses(expr: &Expr) -> Vec<&Expr> {
+    let mut addresses = Vec::new();
+    if let Expr::Load(ref l) = *expr {
+        addresses.push(&*l.source_mem);
+    }
+    for operand in operands(expr) {
+        addresses.extend(load_addresses(operand));
+    }
+    return addresses;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use registers::RegisterDef;
+
+    // rax with eax zero extending into it and ax and ah keeping the rest
+    fn registers() -> Registers {
+        let mut registers = Registers::empty();
+        for &(name, offset, size, extend) in [
+            ("rax", 0, 8, Extend::None),
+            ("eax", 0, 4, Extend::ZeroExtend),
+            ("ax", 0, 2, Extend::None),
+            ("ah", 1, 1, Extend::None),
+        ].iter() {
+            registers.defs.insert(String::from(name), RegisterDef {
+                name: String::from(name),
+                full_width: String::from("rax"),
+                offset: offset,
+                size: size,
+                extend: extend,
+            });
+        }
+        return registers;
+    }
+
+    fn label(label: Label) -> TaintSet {
+        return [label].iter().cloned().collect();
+    }
+
+    #[test]
+    fn partial_write_keeps_the_rest() {
+        let registers = registers();
+        let mut shadow = RegisterShadow::new();
+        shadow.set(&registers, "rax", Taint::uniform(&label(0), 8));
+        shadow.set(&registers, "ah", Taint::uniform(&label(1), 1));
+
+        let rax = shadow.get(&registers, "rax");
+        assert_eq!(rax.bytes[0], label(0));
+        assert_eq!(rax.bytes[1], label(1));
+        assert_eq!(rax.bytes[7], label(0));
+        assert_eq!(shadow.get(&registers, "ah").bytes, vec![label(1)]);
+    }
+
+    #[test]
+    fn zero_extending_write_clears_the_top() {
+        let registers = registers();
+        let mut shadow = RegisterShadow::new();
+        shadow.set(&registers, "rax", Taint::uniform(&label(0), 8));
+        shadow.set(&registers, "eax", Taint::uniform(&label(1), 4));
+
+        let rax = shadow.get(&registers, "rax");
+        assert!(rax.bytes[..4].iter().all(|set| *set == label(1)));
+        assert!(rax.bytes[4..].iter().all(|set| set.is_empty()));
+    }
+
+    #[test]
+    fn clean_write_untaints_the_register() {
+        let registers = registers();
+        let mut shadow = RegisterShadow::new();
+        shadow.set(&registers, "ax", Taint::uniform(&label(0), 2));
+        assert_eq!(shadow.tainted(), vec![String::from("rax")]);
+        shadow.set(&registers, "eax", Taint::clean(4));
+        assert!(shadow.tainted().is_empty());
+    }
+
+    #[test]
+    fn labels_are_shared_by_name() {
+        let mut labels = Labels::new();
+        let first = labels.add("stdin byte 0");
+        let second = labels.add("stdin byte 1");
+        assert_ne!(first, second);
+        assert_eq!(labels.add("stdin byte 0"), first);
+        assert_eq!(labels.name(second), "stdin byte 1");
+    }
+}
@@ -1,35 +1,190 @@
 use std::collections::HashMap;
 use program::Program;
+use binaryninja::Endianness;
+use registers::Registers;
+use flags::Flags;
+use callconv::CallingConvention;
+use vfs::Vfs;
+use heap::Heap;
+use procedures::FILE_BASE;
+
+// Where execution resumes when the current function returns
+#[derive(Clone)]
+pub struct Frame {
+    pub function: u64,
+    pub index: usize,
+    pub return_addr: u64,
+}
 
 pub struct State {
+    // Address of the current instruction, execution is tracked by function and LLIL index
     pub addr: u64,
+    pub function: u64,
     pub index: usize,
     pub memory: Memory,
-    pub regs: Regsx64,
-    pub call_stack: Vec<u64>,
-    pub stdin: String,
+    pub regs: Registers,
+    pub flags: Flags,
+    // How procedures find their arguments and return values
+    pub callconv: CallingConvention,
+    pub call_stack: Vec<Frame>,
+    // Files and descriptors, including the standard streams whose output is captured for reports
+    pub fs: Vfs,
+    // Program break, it starts just past the highest part of the program image
+    pub brk_start: u64,
+    pub brk: u64,
+    // Anonymous mappings are placed below this and it moves down as they are made
+    pub mmap_top: u64,
+    // Chunks handed out by the malloc procedures
+    pub heap: Heap,
+    // Exit status once the program has called exit
+    pub exited: Option<u64>,
+    // Where set_tid_address asked for the thread id to be cleared when the thread exits
+    pub clear_child_tid: u64,
 }
 
 impl State {
     pub fn new() -> State {
+        let mut memory = Memory::new();
+        memory.map(STACK_TOP - STACK_SIZE, STACK_SIZE, Perms::RW).unwrap();
+
         return State {
             addr: 0,
+            function: 0,
             index: 0,
-            memory: Memory::new(),
-            regs: Regsx64::new(),
+            memory: memory,
+            regs: Registers::empty(),
+            flags: Flags::empty(),
+            callconv: CallingConvention::sysv(),
             call_stack: Vec::new(),
-            stdin: String::from(""),
+            fs: Vfs::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_top: MMAP_TOP,
+            heap: Heap::new(),
+            exited: None,
+            clear_child_tid: 0,
+        }
+    }
+
+    // Creates a state with every segment and section of the program mapped into memory
+    pub fn from_program(program: &Program) -> State {
+        let mut state = State::new();
+        state.memory.map_program(program);
+
+        let end = program.segments().iter().map(|s| s.end)
+            .chain(program.sections().iter().map(|s| s.end))
+            .max().unwrap_or(0);
+        state.brk_start = page_align(end);
+        state.brk = state.brk_start;
+
+        match program.arch() {
+            Ok(arch) => {
+                state.regs = Registers::new(&arch);
+                state.flags = Flags::new(&arch);
+            }
+            Err(err) => error!("{}", err),
+        }
+        match program.calling_convention() {
+            Ok(callconv) => state.callconv = callconv,
+            Err(err) => error!("{}", err),
+        }
+        state.regs.set_sp(STACK_TOP - PAGE_SIZE);
+
+        // The standard stream globals are told apart by the FILE pointer they hold. Ones nothing
+        // initialized, without a libc to relocate them, get stand-ins like fopen hands out.
+        for (fd, name) in ["stdin", "stdout", "stderr"].iter().enumerate() {
+            for addr in program.data_symbols(name) {
+                let size = state.regs.address_size;
+                let stream = match state.memory.load(addr, size) {
+                    Ok(0) => {
+                        let stream = FILE_BASE + fd as u64;
+                        if state.memory.store(addr, size, stream as u128).is_err() {
+                            continue;
+                        }
+                        stream
+                    }
+                    Ok(stream) => stream as u64,
+                    Err(_) => continue,
+                };
+                info!("Stream {} at 0x{:x} is 0x{:x}", name, addr, stream);
+                state.fs.streams.insert(stream, fd as u64);
+            }
+        }
+        return state;
+    }
+
+    // Integer argument n of the call being made
+    pub fn arg(&self, n: usize) -> Result<u64, String> {
+        return self.callconv.arg(self, n);
+    }
+
+    pub fn float_arg(&self, n: usize) -> Result<f64, String> {
+        return self.callconv.float_arg(self, n);
+    }
+
+    pub fn set_return(&mut self, value: u64) {
+        let callconv = self.callconv.clone();
+        callconv.set_return(self, value);
+    }
+
+    pub fn set_float_return(&mut self, value: f64) -> Result<(), String> {
+        let callconv = self.callconv.clone();
+        return callconv.set_float_return(self, value);
+    }
+
+    // Moves the program break, returning the new break or the current one if it can't move
+    pub fn set_brk(&mut self, addr: u64) -> u64 {
+        if addr < self.brk_start || addr > self.mmap_top {
+            return self.brk;
+        }
+
+        let (old, new) = (page_align(self.brk), page_align(addr));
+        if new > old {
+            if (old..new).step_by(PAGE_SIZE as usize).any(|page| self.memory.is_mapped(page)) {
+                return self.brk;
+            }
+            if self.memory.map(old, new - old, Perms::RW).is_err() {
+                return self.brk;
+            }
+        } else if new < old {
+            if self.memory.unmap(new, old - new).is_err() {
+                return self.brk;
+            }
         }
+        self.brk = addr;
+        return addr;
+    }
+
+    // Maps zeroed pages for an anonymous mapping, at the hint if it's free or below the previous
+    // mappings otherwise. Returns None when there's no room.
+    pub fn map_anonymous(&mut self, hint: u64, size: u64, perms: Perms) -> Option<u64> {
+        let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+        let free = |state: &State, start: u64| match start.checked_add(size) {
+            Some(end) => (start..end).step_by(PAGE_SIZE as usize).all(|page| !state.memory.is_mapped(page)),
+            None => false,
+        };
+
+        let start = if hint != 0 && hint & (PAGE_SIZE - 1) == 0 && free(self, hint) {
+            hint
+        } else {
+            let mut start = self.mmap_top.checked_sub(size)?;
+            while !free(self, start) {
+                start = match start.checked_sub(PAGE_SIZE) {
+                    Some(start) if start > self.brk => start,
+                    _ => return None,
+                };
+            }
+            self.mmap_top = start;
+            start
+        };
+        self.memory.map(start, size, perms).ok()?;
+        return Some(start);
     }
 
     pub fn print(&self) {
         info!("______________________________________");
-        info!("\trax 0x{:x}\trbx 0x{:x}\trcx 0x{:x}", self.regs.rax, self.regs.rbx, self.regs.rcx);
-        info!("\trdx 0x{:x}\tsi 0x{:x}\trdi 0x{:x}", self.regs.rdx, self.regs.rsi, self.regs.rdi);
-        info!("\tr8 0x{:x}\tr9 0x{:x}\tr10 0x{:x}", self.regs.r8, self.regs.r9, self.regs.r10);
-        info!("\tr11 0x{:x}\tr12 0x{:x}\tr13 0x{:x}", self.regs.r11, self.regs.r12, self.regs.r13);
-        info!("\tr14 0x{:x}\tr15 0x{:x}\trip 0x{:x}", self.regs.r14, self.regs.r15, self.regs.rip);
-        info!("\trbp 0x{:x}\trflags 0x{:x}\trsp 0x{:x}", self.regs.rbp, self.regs.rflags, self.regs.rsp);
+        self.regs.print();
+        self.flags.print();
         info!("");
 
         self.memory.print();
@@ -38,157 +193,258 @@ impl State {
     }
 }
 
+pub const PAGE_SIZE: u64 = 0x1000;
+
+pub fn page_align(value: u64) -> u64 {
+    return (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+}
+
+// The pages covering a range, or an error when it runs past the end of the address space
+pub fn page_range(addr: u64, size: u64) -> Result<(u64, u64), String> {
+    return match addr.checked_add(size).and_then(|end| end.checked_add(PAGE_SIZE - 1)) {
+        Some(end) => Ok((addr & !(PAGE_SIZE - 1), end & !(PAGE_SIZE - 1))),
+        None => Err(format!("Range of 0x{:x} bytes at 0x{:x} overflows", size, addr)),
+    };
+}
+
+// Default stack region, the initial stack pointer lands inside it
+pub const STACK_TOP: u64 = 0x7ffffffff000;
+pub const STACK_SIZE: u64 = 0x21000;
+
+// Mappings grow down from here, leaving a gap below the stack
+pub const MMAP_TOP: u64 = 0x7ffff7fff000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Perms {
+    pub const R: Perms = Perms {read: true, write: false, exec: false};
+    pub const RW: Perms = Perms {read: true, write: true, exec: false};
+    pub const RX: Perms = Perms {read: true, write: false, exec: true};
+    pub const RWX: Perms = Perms {read: true, write: true, exec: true};
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+pub struct Page {
+    pub data: Vec<u8>,
+    pub perms: Perms,
+}
+
 pub struct Memory {
-    pub map: HashMap<u64, u64>
+    pub pages: HashMap<u64, Page>,
+    pub endian: Endian,
 }
 
 impl Memory {
     pub fn new() -> Memory {
         return Memory {
-            map: HashMap::new()
+            pages: HashMap::new(),
+            endian: Endian::Little,
         }
     }
 
-    pub fn store(&mut self, addr: u64, value: u64) {
-        self.map.insert(addr, value);
+    // Maps zero filled pages covering the range, pages that are already mapped keep their contents
+    pub fn map(&mut self, addr: u64, size: u64, perms: Perms) -> Result<(), String> {
+        let (start, end) = page_range(addr, size)?;
+
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            self.pages.entry(page)
+                .or_insert_with(|| Page {data: vec![0; PAGE_SIZE as usize], perms: perms})
+                .perms = perms;
+        }
+        return Ok(());
     }
-    
-    pub fn load(&mut self, addr: u64) -> u64 {
-        return match self.map.get_key_value(&addr) {
-            Some((&key, &value)) => return value,
-            _ => 0
+
+    pub fn map_program(&mut self, program: &Program) {
+        self.endian = match program.endianness() {
+            Endianness::BigEndian => Endian::Big,
+            _ => Endian::Little,
         };
+
+        for segment in program.segments().into_iter().chain(program.library_segments()) {
+            let perms = Perms {read: segment.readable, write: segment.writable, exec: segment.executable};
+            if let Err(err) = self.map(segment.start, segment.end.saturating_sub(segment.start), perms) {
+                info!("Skipped segment 0x{:x}-0x{:x}: {}", segment.start, segment.end, err);
+                continue;
+            }
+            self.init_bytes(segment.start, &segment.data);
+            info!("Mapped segment 0x{:x}-0x{:x} ({} bytes from file)", segment.start, segment.end, segment.data.len());
+        }
+
+        // Sections outside of any segment (like a .bss past the end of the file) are still addressable
+        for section in program.sections().into_iter().chain(program.library_sections()) {
+            let start = section.start & !(PAGE_SIZE - 1);
+            for page in (start..section.end).step_by(PAGE_SIZE as usize) {
+                if !self.is_mapped(page) && self.map(page, PAGE_SIZE, Perms::RW).is_ok() {
+                    info!("Mapped page 0x{:x} of section {}", page, section.name);
+                }
+            }
+        }
     }
 
-    pub fn print(&self) {
-        for (key, value) in &self.map {
-            info!("\t0x{:x}: {}", key, value);
+    // Writes bytes regardless of page permissions, used when loading the program image
+    pub fn init_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr = addr + i as u64;
+            if let Some(page) = self.pages.get_mut(&(addr & !(PAGE_SIZE - 1))) {
+                page.data[(addr & (PAGE_SIZE - 1)) as usize] = *byte;
+            }
         }
     }
-}
 
-pub struct Regsx64 {
-    pub rax: u64,
-    pub rbx: u64,
-    pub rcx: u64,
-    pub rdx: u64,
-    pub r8: u64,
-    pub r9: u64,
-    pub r10: u64,
-    pub r11: u64,
-    pub r12: u64,
-    pub r13: u64,
-    pub r14: u64,
-    pub r15: u64,
-    pub rsi: u64,
-    pub rdi: u64,
-    pub rsp: u64,
-    pub rbp: u64,
-    pub rip: u64,
-    pub rflags: u64,
-    pub rtemp: HashMap<String, u64>,
-}
+    pub fn unmap(&mut self, addr: u64, size: u64) -> Result<(), String> {
+        let (start, end) = page_range(addr, size)?;
 
-impl Regsx64 {
-    pub fn new() -> Regsx64 {
-        return Regsx64 {
-            rax: 0x004006f6, rbx: 0x004008f0, rcx: 0x004008f0, 
-            rdx: 0x7fffffffe188, r8: 0x00000000, r9: 0x7ffff7fe0d50, 
-            r10: 0xfffffffffffff40c, r11: 0x7ffff7de6fc0, r12: 0x00400600, 
-            r13: 0x7fffffffe170, r14: 0x00000000, r15: 0x00000000, 
-            rsi: 0x7fffffffe178, rdi: 0x00000001, rsp: 0x7fffffffe088, 
-            rbp: 0, rip: 0, rflags: 0, rtemp: HashMap::new(),
-        };
+        // Walks the mapped pages rather than the range, which can span most of the address space
+        self.pages.retain(|&page, _| page < start || page >= end);
+        return Ok(());
+    }
+
+    pub fn protect(&mut self, addr: u64, size: u64, perms: Perms) -> Result<(), String> {
+        let (start, end) = page_range(addr, size)?;
+
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            match self.pages.get_mut(&page) {
+                Some(page) => page.perms = perms,
+                None => return Err(format!("Can't protect unmapped page at 0x{:x}", page)),
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn is_mapped(&self, addr: u64) -> bool {
+        return self.pages.contains_key(&(addr & !(PAGE_SIZE - 1)));
+    }
+
+    pub fn perms(&self, addr: u64) -> Option<Perms> {
+        return self.pages.get(&(addr & !(PAGE_SIZE - 1))).map(|page| page.perms);
+    }
+
+    pub fn read_bytes(&self, addr: u64, len: usize) -> Result<Vec<u8>, String> {
+        // Check the whole range first so a guest supplied length can't allocate more than is mapped
+        for i in 0..len as u64 {
+            let addr = addr.wrapping_add(i);
+            match self.perms(addr) {
+                Some(perms) if perms.read => (),
+                Some(_) => return Err(format!("Read from non-readable address 0x{:x}", addr)),
+                None => return Err(format!("Read from unmapped address 0x{:x}", addr)),
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(len);
+        for i in 0..len as u64 {
+            let addr = addr.wrapping_add(i);
+            if let Some(page) = self.pages.get(&(addr & !(PAGE_SIZE - 1))) {
+                bytes.push(page.data[(addr & (PAGE_SIZE - 1)) as usize]);
+            }
+        }
+        return Ok(bytes);
     }
 
-    pub fn set(&mut self, name: String, value: u64) {
-        match name.as_str() {
-            "rax" => self.rax = value,
-            "rbx" => self.rbx = value,
-            "rcx" => self.rcx = value,
-            "rdx" => self.rdx = value,
-            "r8" => self.r8 = value,
-            "r9" => self.r9 = value,
-            "r10" => self.r10 = value,
-            "r11" => self.r11 = value,
-            "r12" => self.r12 = value,
-            "r13" => self.r13 = value,
-            "r14" => self.r14 = value,
-            "r15" => self.r15 = value,
-            "rsi" => self.rsi = value,
-            "rdi" => self.rdi = value,
-            "rsp" => self.rsp = value,
-            "rbp" => self.rbp = value,
-            "rip" => self.rip = value,
-
-            "eax" => self.rax = value,
-            "ebx" => self.rbx = value,
-            "ecx" => self.rcx = value,
-            "edx" => self.rdx = value,
-            "e8" => self.r8 = value,
-            "e9" => self.r9 = value,
-            "e10" => self.r10 = value,
-            "e11" => self.r11 = value,
-            "e12" => self.r12 = value,
-            "e13" => self.r13 = value,
-            "e14" => self.r14 = value,
-            "e15" => self.r15 = value,
-            "esi" => self.rsi = value,
-            "edi" => self.rdi = value,
-            "esp" => self.rsp = value,
-            "ebp" => self.rbp = value,
-            "eip" => self.rip = value,
-            _ => {
-                self.rtemp.insert(name, value);
-            },
-        }
-    }
-
-    pub fn get(&self, name: String) -> u64 {
-        match name.as_str() {
-            "rax" => self.rax,
-            "rbx" => self.rbx,
-            "rcx" => self.rcx,
-            "rdx" => self.rdx,
-            "r8" => self.r8,
-            "r9" => self.r9,
-            "r10" => self.r10,
-            "r11" => self.r11,
-            "r12" => self.r12,
-            "r13" => self.r13,
-            "r14" => self.r14,
-            "r15" => self.r15,
-            "rsi" => self.rsi,
-            "rdi" => self.rdi,
-            "rsp" => self.rsp,
-            "rbp" => self.rbp,
-            "rip" => self.rip,
-
-            "eax" => self.rax,
-            "ebx" => self.rbx,
-            "ecx" => self.rcx,
-            "edx" => self.rdx,
-            "e8" => self.r8,
-            "e9" => self.r9,
-            "e10" => self.r10,
-            "e11" => self.r11,
-            "e12" => self.r12,
-            "e13" => self.r13,
-            "e14" => self.r14,
-            "e15" => self.r15,
-            "esi" => self.rsi,
-            "edi" => self.rdi,
-            "esp" => self.rsp,
-            "ebp" => self.rbp,
-            "eip" => self.rip,
-            "fsbase" => 0,
-
-            _ => {
-                return match self.rtemp.get(&name) {
-                    Some(i) => *i,
-                    _ => 0 as u64,
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), String> {
+        // Check the whole range first so a faulting write has no partial effect
+        for i in 0..bytes.len() as u64 {
+            let addr = addr.wrapping_add(i);
+            match self.perms(addr) {
+                Some(perms) if perms.write => (),
+                Some(_) => return Err(format!("Write to non-writable address 0x{:x}", addr)),
+                None => return Err(format!("Write to unmapped address 0x{:x}", addr)),
+            }
+        }
+
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u64);
+            if let Some(page) = self.pages.get_mut(&(addr & !(PAGE_SIZE - 1))) {
+                page.data[(addr & (PAGE_SIZE - 1)) as usize] = *byte;
+            }
+        }
+        return Ok(());
+    }
+
+    // Sets a range to one byte a page at a time, without building the whole range in memory
+    pub fn fill(&mut self, addr: u64, size: u64, byte: u8) -> Result<(), String> {
+        if size == 0 {
+            return Ok(());
+        }
+        let (start, end) = page_range(addr, size)?;
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            match self.perms(page) {
+                Some(perms) if perms.write => (),
+                Some(_) => return Err(format!("Write to non-writable address 0x{:x}", page.max(addr))),
+                None => return Err(format!("Write to unmapped address 0x{:x}", page.max(addr))),
+            }
+        }
+
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            let low = (page.max(addr) - page) as usize;
+            let high = ((page + PAGE_SIZE).min(addr + size) - page) as usize;
+            if let Some(page) = self.pages.get_mut(&page) {
+                for b in &mut page.data[low..high] {
+                    *b = byte;
                 }
             }
         }
+        return Ok(());
     }
-}
\ No newline at end of file
+
+    // Loads a value of the given size in bytes using the memory's endianness
+    pub fn load(&self, addr: u64, size: usize) -> Result<u128, String> {
+        if size > 16 {
+            return Err(format!("Can't load {} bytes at 0x{:x} as one value", size, addr));
+        }
+        let bytes = self.read_bytes(addr, size)?;
+        let mut value: u128 = 0;
+        for i in 0..size {
+            let byte = match self.endian {
+                Endian::Little => bytes[size - 1 - i],
+                Endian::Big => bytes[i],
+            };
+            value = (value << 8) | byte as u128;
+        }
+        return Ok(value);
+    }
+
+    // Stores a value of the given size in bytes using the memory's endianness
+    pub fn store(&mut self, addr: u64, size: usize, value: u128) -> Result<(), String> {
+        if size > 16 {
+            return Err(format!("Can't store {} bytes at 0x{:x} as one value", size, addr));
+        }
+        let mut bytes = Vec::with_capacity(size);
+        for i in 0..size {
+            bytes.push((value >> (i * 8)) as u8);
+        }
+        if self.endian == Endian::Big {
+            bytes.reverse();
+        }
+        return self.write_bytes(addr, &bytes);
+    }
+
+    pub fn print(&self) {
+        let mut pages: Vec<&u64> = self.pages.keys().collect();
+        pages.sort();
+
+        // Merge adjacent pages with the same permissions into regions
+        let mut regions: Vec<(u64, u64, Perms)> = Vec::new();
+        for &page in pages {
+            let perms = self.pages[&page].perms;
+            match regions.last_mut() {
+                Some(region) if region.1 == page && region.2 == perms => region.1 += PAGE_SIZE,
+                _ => regions.push((page, page + PAGE_SIZE, perms)),
+            }
+        }
+
+        for (start, end, perms) in regions {
+            info!("\t0x{:x}-0x{:x} {}{}{}", start, end,
+                if perms.read {"r"} else {"-"}, if perms.write {"w"} else {"-"}, if perms.exec {"x"} else {"-"});
+        }
+    }
+}
@@ -0,0 +1,931 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use program::*;
+use expression::*;
+use registers::{Extend, Registers};
+use callconv::CallingConvention;
+use shadow;
+use taint_policy::*;
+use taint_tracker::Location;
+use dynamic_taint::pointees_read;
+use syscalls::{self, Abi};
+
+// Rounds of summarizing every function. Summaries settle in a few rounds, the limit stops
+// recursion from feeding itself forever.
+const MAX_ROUNDS: usize = 16;
+// Arguments a call passes, as far as summaries are concerned
+const CALL_ARGS: usize = 6;
+// Bytes past the address in an argument that count as the data it points to
+const POINTEE_SIZE: i64 = 0x1000;
+
+// Where a value may come from: sources, and the arguments of the function it's computed in or
+// the data they point to. Call sites replace the arguments with what the caller passed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Origin {
+    pub sources: BTreeSet<String>,
+    pub args: BTreeSet<usize>,
+    pub pointees: BTreeSet<usize>,
+}
+
+impl Origin {
+    pub fn new() -> Origin {
+        return Origin {
+            sources: BTreeSet::new(),
+            args: BTreeSet::new(),
+            pointees: BTreeSet::new(),
+        }
+    }
+
+    pub fn source(label: &str) -> Origin {
+        let mut origin = Origin::new();
+        origin.sources.insert(String::from(label));
+        return origin;
+    }
+
+    pub fn arg(n: usize) -> Origin {
+        let mut origin = Origin::new();
+        origin.args.insert(n);
+        return origin;
+    }
+
+    pub fn pointee(n: usize) -> Origin {
+        let mut origin = Origin::new();
+        origin.pointees.insert(n);
+        return origin;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.sources.is_empty() && self.args.is_empty() && self.pointees.is_empty();
+    }
+
+    // Whether it depends on the arguments, as opposed to sources alone
+    pub fn from_params(&self) -> bool {
+        return !self.args.is_empty() || !self.pointees.is_empty();
+    }
+
+    pub fn extend(&mut self, other: &Origin) {
+        self.sources.extend(other.sources.iter().cloned());
+        self.args.extend(other.args.iter().cloned());
+        self.pointees.extend(other.pointees.iter().cloned());
+    }
+
+    // The origin at a call site, given where the caller's arguments and their data come from
+    pub fn instantiate(&self, args: &[Origin], pointees: &[Origin]) -> Origin {
+        let mut origin = Origin::new();
+        origin.sources = self.sources.clone();
+        for &n in &self.args {
+            if let Some(arg) = args.get(n) {
+                origin.extend(arg);
+            }
+        }
+        for &n in &self.pointees {
+            if let Some(pointee) = pointees.get(n) {
+                origin.extend(pointee);
+            }
+        }
+        return origin;
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = self.sources.iter().cloned().collect();
+        parts.extend(self.args.iter().map(|n| format!("arg {}", n)));
+        parts.extend(self.pointees.iter().map(|n| format!("*arg {}", n)));
+        return write!(f, "{}", parts.join(", "));
+    }
+}
+
+// A sink inside a function, or a function it calls, that its arguments reach
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParamSink {
+    pub sink: String,
+    pub addr: u64,
+    pub origin: Origin,
+    // Call sites leading from the function to the sink
+    pub path: Vec<u64>,
+}
+
+// What a call to a function does to taint, independent of the caller
+#[derive(Clone, PartialEq, Debug)]
+pub struct Summary {
+    pub ret: Origin,
+    // What the function may store in the data each argument points to
+    pub writes: BTreeMap<usize, Origin>,
+    pub sinks: Vec<ParamSink>,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        return Summary {
+            ret: Origin::new(),
+            writes: BTreeMap::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ret.is_empty() && self.writes.is_empty() && self.sinks.is_empty();
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.ret.is_empty() {
+            parts.push(format!("returns {}", self.ret));
+        }
+        for (n, origin) in &self.writes {
+            parts.push(format!("*arg {} <- {}", n, origin));
+        }
+        for sink in &self.sinks {
+            parts.push(format!("{} reaches {} at 0x{:x}", sink.origin, sink.sink, sink.addr));
+        }
+        return parts.join("; ");
+    }
+}
+
+// Taint at one point of a function, merged over every path that gets there
+#[derive(Clone, PartialEq)]
+struct Facts {
+    regs: HashMap<String, Origin>,
+    // Locations full width registers hold, registers that aren't in here still hold their value
+    // from the function's entry
+    values: HashMap<String, Option<Location>>,
+    // Stores to known locations with their sizes
+    memory: HashMap<Location, (usize, Origin)>,
+    // Everything stored to addresses that aren't known
+    unknown: Origin,
+    flags: Origin,
+}
+
+impl Facts {
+    fn value(&self, reg: &str) -> Option<Location> {
+        return match self.values.get(reg) {
+            Some(value) => value.clone(),
+            None => Some(Location { base: String::from(reg), offset: 0 }),
+        };
+    }
+
+    // Merges the facts of another path, locations that differ become unknown. Returns whether
+    // anything changed.
+    fn join(&mut self, other: &Facts) -> bool {
+        let before = self.clone();
+        for (reg, origin) in &other.regs {
+            self.regs.entry(reg.clone()).or_insert(Origin::new()).extend(origin);
+        }
+        let regs: BTreeSet<String> = self.values.keys().chain(other.values.keys()).cloned().collect();
+        for reg in regs {
+            if self.value(&reg) != other.value(&reg) {
+                self.values.insert(reg, None);
+            }
+        }
+        for (location, &(size, ref origin)) in &other.memory {
+            let cell = self.memory.entry(location.clone()).or_insert((0, Origin::new()));
+            cell.0 = cell.0.max(size);
+            cell.1.extend(origin);
+        }
+        self.unknown.extend(&other.unknown);
+        self.flags.extend(&other.flags);
+        return *self != before;
+    }
+}
+
+// Path insensitive taint analysis of the whole program. Every function is summarized by how its
+// return value, the data its arguments point to and the sinks it reaches depend on its arguments
+// and sources, and calls apply the callee's summary, so one pass over each function finds flows
+// across any number of calls. Registers are matched to their definitions through Binary Ninja's
+// LLIL SSA form, memory by stack and constant locations merged at the instructions paths meet.
+pub struct StaticTaint<'a> {
+    pub program: &'a Program<'a>,
+    pub registers: Registers,
+    pub callconv: CallingConvention,
+    pub abi: Abi,
+    pub policy: Policy,
+    pub summaries: HashMap<u64, Summary>,
+    pub names: HashMap<u64, String>,
+    pub flows: Vec<Flow>,
+    pub rounds: usize,
+    // SSA form of the function being summarized and the instruction being applied
+    ssa: Option<Rc<SsaForm>>,
+    index: usize,
+    // What each register version holds. Every version has one definition, so this doesn't
+    // depend on the path.
+    vars: HashMap<SsaReg, (Origin, Option<Location>)>,
+    vars_changed: bool,
+}
+
+impl<'a> StaticTaint<'a> {
+    pub fn new(program: &'a Program) -> StaticTaint<'a> {
+        let registers = match program.arch() {
+            Ok(arch) => Registers::new(&arch),
+            Err(_) => Registers::empty(),
+        };
+        return StaticTaint {
+            program: program,
+            registers: registers,
+            callconv: program.calling_convention().unwrap_or(CallingConvention::sysv()),
+            abi: match program.arch() {
+                Ok(arch) => Abi::for_arch(&arch),
+                Err(_) => Abi::x86_64(),
+            },
+            policy: Policy::libc(),
+            summaries: HashMap::new(),
+            names: HashMap::new(),
+            flows: Vec::new(),
+            rounds: 0,
+            ssa: None,
+            index: 0,
+            vars: HashMap::new(),
+            vars_changed: false,
+        }
+    }
+
+    // Summarizes every function of the program until the summaries stop changing
+    pub fn run(&mut self) {
+        let mut functions = Vec::new();
+        for function in self.program.functions() {
+            if self.program.is_import(function.addr) {
+                continue;
+            }
+            self.names.insert(function.addr, function.name.clone());
+            let ssa = function.llil_ssa().map(Rc::new);
+            if ssa.is_none() {
+                warn!("No SSA form for {}, registers are tracked along paths instead", function.name);
+            }
+            functions.push((function.addr, function.llil_instructions(), ssa));
+        }
+        info!("Summarizing {} functions", functions.len());
+
+        for round in 0..MAX_ROUNDS {
+            self.rounds = round + 1;
+            let mut changed = false;
+            for &(addr, ref insts, ref ssa) in &functions {
+                self.ssa = ssa.clone();
+                let summary = self.summarize(insts);
+                if self.summaries.get(&addr) != Some(&summary) {
+                    self.summaries.insert(addr, summary);
+                    changed = true;
+                }
+            }
+            if !changed {
+                return;
+            }
+        }
+        warn!("Summaries still changing after {} rounds", MAX_ROUNDS);
+    }
+
+    pub fn summary_of(&self, name: &str) -> Option<&Summary> {
+        let addr = self.names.iter().find(|&(_, n)| n == name).map(|(&addr, _)| addr)?;
+        return self.summaries.get(&addr);
+    }
+
+    fn sp_name(&self) -> Option<String> {
+        return self.registers.stack_pointer.as_ref().map(|sp| self.registers.full_width(sp));
+    }
+
+    fn entry(&self) -> Facts {
+        let mut regs = HashMap::new();
+        for (n, reg) in self.callconv.int_args.iter().enumerate() {
+            regs.insert(self.registers.full_width(reg), Origin::arg(n));
+        }
+        return Facts {
+            regs: regs,
+            values: HashMap::new(),
+            memory: HashMap::new(),
+            unknown: Origin::new(),
+            flags: Origin::new(),
+        };
+    }
+
+    // Works out the facts at every instruction of a function and what they add up to at its returns
+    fn summarize(&mut self, insts: &[Inst]) -> Summary {
+        let mut summary = Summary::new();
+        if insts.is_empty() {
+            return summary;
+        }
+
+        let mut facts: Vec<Option<Facts>> = vec![None; insts.len()];
+        facts[0] = Some(self.entry());
+        self.vars.clear();
+        let mut work = vec![0];
+        loop {
+            self.vars_changed = false;
+            while let Some(index) = work.pop() {
+                let mut state = match facts[index] {
+                    Some(ref state) => state.clone(),
+                    None => continue,
+                };
+                for next in self.transfer(insts, index, &mut state, &mut summary) {
+                    if next >= insts.len() {
+                        continue;
+                    }
+                    let changed = match facts[next] {
+                        Some(ref mut existing) => existing.join(&state),
+                        None => true,
+                    };
+                    if facts[next].is_none() {
+                        facts[next] = Some(state.clone());
+                    }
+                    if changed && !work.contains(&next) {
+                        work.push(next);
+                    }
+                }
+            }
+            // Uses can come before their definitions, like phis at loop heads, so everything
+            // reached runs again until the register versions settle
+            if !self.vars_changed {
+                return summary;
+            }
+            work = (0..insts.len()).filter(|&index| facts[index].is_some()).collect();
+        }
+    }
+
+    // Applies an instruction to the facts, returning the indices it can continue at
+    fn transfer(&mut self, insts: &[Inst], index: usize, facts: &mut Facts, summary: &mut Summary) -> Vec<usize> {
+        use LlilInst::*;
+
+        let addr = insts[index].addr;
+        self.index = index;
+        match insts[index].llil {
+            SetReg(ref llil) => {
+                let origin = self.origin(facts, &llil.expr);
+                if !shadow::flags_written(&llil.expr).is_empty() {
+                    facts.flags = origin.clone();
+                }
+                let value = self.location(facts, &llil.expr);
+                self.pop(facts, &llil.expr);
+                self.set_reg(facts, &llil.reg, origin, value);
+            }
+            SetRegSplit(ref llil) => {
+                let origin = self.origin(facts, &llil.source_expr);
+                self.pop(facts, &llil.source_expr);
+                self.set_reg(facts, &llil.dest_reg_low, origin.clone(), None);
+                self.set_reg(facts, &llil.dest_reg_high, origin, None);
+            }
+            SetFlag(ref llil) => {
+                let origin = self.origin(facts, &llil.expr);
+                facts.flags.extend(&origin);
+            }
+            Store(ref llil) => {
+                let origin = self.origin(facts, &llil.source_expr);
+                let location = self.location(facts, &llil.dest_mem_expr);
+                self.store(facts, summary, location, llil.size, origin, true);
+            }
+            Push(ref llil) => {
+                let origin = self.origin(facts, &llil.expr);
+                let sp = self.adjust_sp(facts, -(llil.size as i64));
+                self.store(facts, summary, sp, llil.size, origin, true);
+            }
+            If(ref llil) => return vec![llil.target_true, llil.target_false],
+            Goto(ref llil) => return vec![llil.target],
+            Call(ref llil) => {
+                self.call(facts, summary, &llil.target, addr);
+            }
+            Jump(ref llil) => return self.jump(insts, facts, summary, &llil.target, addr),
+            JumpTo(ref llil) => return self.jump(insts, facts, summary, &llil.target, addr),
+            Ret(ref llil) => {
+                self.pop(facts, &llil.target);
+                self.ret(facts, summary);
+                return Vec::new();
+            }
+            NoRet() => return Vec::new(),
+            Syscall() => {
+                self.syscall(facts, summary, addr);
+            }
+            Value(ref expr) => {
+                let origin = self.origin(facts, expr);
+                if !shadow::flags_written(expr).is_empty() {
+                    facts.flags = origin;
+                }
+                self.pop(facts, expr);
+            }
+            Nop() | Bp() | Trap() | LlilInst::Undef() => (),
+        }
+        return vec![index + 1];
+    }
+
+    fn set_reg(&mut self, facts: &mut Facts, name: &str, origin: Origin, value: Option<Location>) {
+        let full = self.registers.full_width(name);
+        let mut origin = origin;
+        // Writes to part of a register that isn't extended keep what the rest held
+        let partial = full != name && self.registers.info(name).map(|def| def.extend == Extend::None).unwrap_or(false);
+        if partial {
+            origin.extend(&self.reg_origin(facts, &full));
+        }
+        if origin.is_empty() {
+            facts.regs.remove(&full);
+        } else {
+            facts.regs.insert(full.clone(), origin);
+        }
+        // Constants written to a zero extended part, like a syscall number in eax, stay known
+        let value = match (self.registers.info(name), value) {
+            _ if full == name => value,
+            (Some(def), Some(value)) => match def.extend {
+                Extend::ZeroExtend if value.base.is_empty() && def.size < 8 => {
+                    Some(Location::absolute(value.offset as u64 & ((1u64 << (def.size * 8)) - 1)))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(def) = self.ssa_def(&full) {
+            self.define(def, origin.clone(), value.clone());
+        }
+        facts.values.insert(full.clone(), value);
+    }
+
+    // The version of a full width register the current instruction defines or reads, the stack
+    // pointer is left to the facts since pushes and pops move it without naming it
+    fn ssa_def(&self, full: &str) -> Option<SsaReg> {
+        if self.sp_name().as_ref().map(|sp| sp.as_str()) == Some(full) {
+            return None;
+        }
+        let defs = self.ssa.as_ref()?.defs.get(self.index)?;
+        return defs.iter().find(|reg| self.registers.full_width(&reg.name) == full).cloned();
+    }
+
+    fn ssa_use(&self, full: &str) -> Option<SsaReg> {
+        if self.sp_name().as_ref().map(|sp| sp.as_str()) == Some(full) {
+            return None;
+        }
+        let uses = self.ssa.as_ref()?.uses.get(self.index)?;
+        return uses.iter().find(|reg| self.registers.full_width(&reg.name) == full).cloned();
+    }
+
+    // Merges what a definition computed into its version, a location that differs between
+    // visits becomes unknown
+    fn define(&mut self, reg: SsaReg, origin: Origin, value: Option<Location>) {
+        let changed = match self.vars.get_mut(&reg) {
+            Some(var) => {
+                let before = var.clone();
+                var.0.extend(&origin);
+                if var.1 != value {
+                    var.1 = None;
+                }
+                *var != before
+            }
+            None => {
+                self.vars.insert(reg, (origin, value));
+                true
+            }
+        };
+        self.vars_changed |= changed;
+    }
+
+    // What a register version holds: a definition's result, the merge of a phi's versions or the
+    // register's value at entry. None when it comes from a definition the SSA form has and
+    // LlilInst doesn't, like a call's outputs, and the facts of the path have to answer instead.
+    fn var(&self, reg: &SsaReg, seen: &mut Vec<SsaReg>) -> Option<(Origin, Option<Location>)> {
+        if let Some(var) = self.vars.get(reg) {
+            return Some(var.clone());
+        }
+        let ssa = self.ssa.as_ref()?;
+        if let Some(sources) = ssa.phis.get(reg) {
+            if seen.contains(reg) {
+                return Some((Origin::new(), None));
+            }
+            seen.push(reg.clone());
+            let mut merged: Option<(Origin, Option<Location>)> = None;
+            for source in sources {
+                // A version that can't be followed leaves the whole merge to the path
+                let (origin, value) = self.var(source, seen)?;
+                merged = Some(match merged {
+                    Some((mut all, same)) => {
+                        all.extend(&origin);
+                        (all, if same == value { same } else { None })
+                    }
+                    None => (origin, value),
+                });
+            }
+            return Some(merged.unwrap_or((Origin::new(), None)));
+        }
+        let full = self.registers.full_width(&reg.name);
+        if reg.version == 0 {
+            let origin = match self.callconv.int_args.iter().position(|arg| self.registers.full_width(arg) == full) {
+                Some(n) => Origin::arg(n),
+                None => Origin::new(),
+            };
+            return Some((origin, Some(Location { base: full, offset: 0 })));
+        }
+        // Defined by an instruction that hasn't been applied yet
+        if ssa.defines(reg) {
+            return Some((Origin::new(), None));
+        }
+        return None;
+    }
+
+    // Where a full width register's value comes from and what it holds, from its SSA version
+    // when the instruction names one and along the path otherwise
+    fn reg_origin(&self, facts: &Facts, full: &str) -> Origin {
+        if let Some(var) = self.ssa_use(full).and_then(|reg| self.var(&reg, &mut Vec::new())) {
+            return var.0;
+        }
+        return facts.regs.get(full).cloned().unwrap_or(Origin::new());
+    }
+
+    fn reg_value(&self, facts: &Facts, full: &str) -> Option<Location> {
+        if let Some(var) = self.ssa_use(full).and_then(|reg| self.var(&reg, &mut Vec::new())) {
+            return var.1;
+        }
+        return facts.value(full);
+    }
+
+    fn adjust_sp(&self, facts: &mut Facts, offset: i64) -> Option<Location> {
+        let sp = self.sp_name()?;
+        let value = facts.value(&sp).map(|value| value.add(offset));
+        facts.values.insert(sp, value.clone());
+        return value;
+    }
+
+    fn pop(&self, facts: &mut Facts, expr: &Expr) {
+        if let Expr::Pop(ref p) = *expr {
+            self.adjust_sp(facts, p.size as i64);
+        }
+    }
+
+    // Argument n when the location is where the function's entry stack holds it
+    fn stack_arg(&self, location: &Location) -> Option<usize> {
+        if Some(&location.base) != self.sp_name().as_ref() {
+            return None;
+        }
+        let word = if self.registers.link_register.is_none() { self.registers.address_size as i64 } else { 0 };
+        let offset = location.offset - word - self.callconv.stack_offset as i64;
+        if offset < 0 || self.callconv.slot_size == 0 {
+            return None;
+        }
+        return Some(self.callconv.int_args.len() + offset as usize / self.callconv.slot_size);
+    }
+
+    // Argument whose entry value a location is based on
+    fn pointer_arg(&self, location: &Location) -> Option<usize> {
+        return self.callconv.int_args.iter().position(|reg| self.registers.full_width(reg) == location.base);
+    }
+
+    fn load(&self, facts: &Facts, location: &Location, size: usize) -> Origin {
+        let mut origin = Origin::new();
+        for (cell, &(cell_size, ref cell_origin)) in &facts.memory {
+            if cell.base == location.base && cell.offset < location.offset + size as i64 && location.offset < cell.offset + cell_size as i64 {
+                origin.extend(cell_origin);
+            }
+        }
+        if let Some(n) = self.stack_arg(location) {
+            origin.extend(&Origin::arg(n));
+        }
+        if let Some(n) = self.pointer_arg(location) {
+            origin.extend(&Origin::pointee(n));
+        }
+        return origin;
+    }
+
+    // Stores to known locations replace what was there unless the store is weak, stores into the
+    // data an argument points to become part of the summary
+    fn store(&self, facts: &mut Facts, summary: &mut Summary, location: Option<Location>, size: usize, origin: Origin, strong: bool) {
+        let location = match location {
+            Some(location) => location,
+            None => {
+                facts.unknown.extend(&origin);
+                return;
+            }
+        };
+        if let Some(n) = self.pointer_arg(&location) {
+            if !origin.is_empty() {
+                summary.writes.entry(n).or_insert(Origin::new()).extend(&origin);
+            }
+        }
+        if strong {
+            facts.memory.insert(location, (size, origin));
+        } else {
+            let cell = facts.memory.entry(location).or_insert((0, Origin::new()));
+            cell.0 = cell.0.max(size);
+            cell.1.extend(&origin);
+        }
+    }
+
+    // Where the value of an expression comes from
+    fn origin(&self, facts: &Facts, expr: &Expr) -> Origin {
+        return match *expr {
+            Expr::Value(_) | Expr::Undef(_) => Origin::new(),
+            Expr::Reg(ref r) => self.reg_origin(facts, &self.registers.full_width(&r.name)),
+            Expr::Flag(_) | Expr::FlagBit(_) | Expr::FlagCond(_) | Expr::FlagGroup(_) => facts.flags.clone(),
+            Expr::Load(ref l) => {
+                let mut origin = match self.location(facts, &l.source_mem) {
+                    Some(location) => self.load(facts, &location, l.size),
+                    None => facts.unknown.clone(),
+                };
+                origin.extend(&self.origin(facts, &l.source_mem));
+                origin
+            }
+            Expr::Pop(ref p) => match self.sp_name().and_then(|sp| facts.value(&sp)) {
+                Some(sp) => self.load(facts, &sp, p.size),
+                None => Origin::new(),
+            },
+            Expr::Xor(ref s) => match (&*s.left, &*s.right) {
+                (&Expr::Reg(ref left), &Expr::Reg(ref right)) if left.name == right.name => Origin::new(),
+                _ => self.operands_origin(facts, expr),
+            },
+            _ => self.operands_origin(facts, expr),
+        };
+    }
+
+    fn operands_origin(&self, facts: &Facts, expr: &Expr) -> Origin {
+        let mut origin = Origin::new();
+        for operand in shadow::operands(expr) {
+            origin.extend(&self.origin(facts, operand));
+        }
+        return origin;
+    }
+
+    // Location an address expression evaluates to, when it's a known register or constant plus constants
+    fn location(&self, facts: &Facts, expr: &Expr) -> Option<Location> {
+        return match *expr {
+            Expr::Value(ref v) => Some(Location::absolute(v.value)),
+            Expr::Reg(ref r) => {
+                let full = self.registers.full_width(&r.name);
+                if full != r.name && self.registers.info(&r.name).is_some() {
+                    return None;
+                }
+                self.reg_value(facts, &full)
+            }
+            Expr::Add(ref s) => match (self.location(facts, &s.left), self.location(facts, &s.right)) {
+                (Some(left), Some(ref right)) if right.base.is_empty() => Some(left.add(right.offset)),
+                (Some(ref left), Some(right)) if left.base.is_empty() => Some(right.add(left.offset)),
+                _ => None,
+            },
+            Expr::Sub(ref s) => match (self.location(facts, &s.left), self.location(facts, &s.right)) {
+                (Some(left), Some(ref right)) if right.base.is_empty() => Some(left.add(right.offset.wrapping_neg())),
+                _ => None,
+            },
+            _ => None,
+        };
+    }
+
+    // Where argument n of a call about to be made comes from
+    fn arg_origin(&self, facts: &Facts, n: usize) -> Origin {
+        if let Some(reg) = self.callconv.int_args.get(n) {
+            return self.reg_origin(facts, &self.registers.full_width(reg));
+        }
+        return match self.arg_slot(facts, n) {
+            Some(slot) => self.load(facts, &slot, self.callconv.slot_size),
+            None => Origin::new(),
+        };
+    }
+
+    fn arg_slot(&self, facts: &Facts, n: usize) -> Option<Location> {
+        let sp = self.sp_name().and_then(|sp| facts.value(&sp))?;
+        let i = n - self.callconv.int_args.len();
+        return Some(sp.add((self.callconv.stack_offset + (i * self.callconv.slot_size) as u64) as i64));
+    }
+
+    // Syscalls the policy lists as sources taint their buffer like the libc wrapper would, so
+    // statically linked code has sources too. The number has to be a constant to tell.
+    fn syscall(&mut self, facts: &mut Facts, summary: &mut Summary, addr: u64) {
+        let number = self.reg_value(facts, &self.registers.full_width(&self.abi.number));
+        let name = match number {
+            Some(ref number) if number.base.is_empty() => syscalls::name(self.abi.table, number.offset as u64),
+            _ => None,
+        };
+        if let Some(name) = name {
+            let label = format!("{}@0x{:x}", name, addr);
+            for source in self.policy.sources_of(name) {
+                if let Source::Buffer { arg, ref size, .. } = source {
+                    let size = match *size {
+                        Size::Fixed(size) => size,
+                        Size::Arg(_) => POINTEE_SIZE as usize,
+                    };
+                    let location = self.abi.args.get(arg).and_then(|reg| self.reg_value(facts, &self.registers.full_width(reg)));
+                    self.store(facts, summary, location, size, Origin::source(&label), false);
+                }
+            }
+        }
+        let ret = self.abi.ret.clone();
+        self.set_reg(facts, &ret, Origin::new(), None);
+    }
+
+    fn arg_location(&self, facts: &Facts, n: usize) -> Option<Location> {
+        let reg = self.callconv.int_args.get(n)?;
+        return self.reg_value(facts, &self.registers.full_width(reg));
+    }
+
+    // Where the data argument n points to comes from
+    fn pointee_origin(&self, facts: &Facts, n: usize) -> Origin {
+        return match self.arg_location(facts, n) {
+            Some(location) => self.load(facts, &location, POINTEE_SIZE as usize),
+            None => Origin::new(),
+        };
+    }
+
+    // Records data reaching a sink, as a flow when sources reach it and in the summary when the
+    // function's arguments do
+    fn sink(&mut self, summary: &mut Summary, sink: &str, origin: &Origin, addr: u64, path: Vec<u64>) {
+        if !origin.sources.is_empty() {
+            let sources: Vec<String> = origin.sources.iter().cloned().collect();
+            match self.flows.iter_mut().find(|flow| flow.addr == addr && flow.sink == sink) {
+                Some(flow) => {
+                    for source in sources {
+                        if !flow.sources.contains(&source) {
+                            flow.sources.push(source);
+                        }
+                    }
+                }
+                None => {
+                    let flow = Flow { sources: sources, sink: String::from(sink), addr: addr, path: path.clone() };
+                    info!("{}", flow.describe());
+                    self.flows.push(flow);
+                }
+            }
+        }
+        if origin.from_params() {
+            let mut params = origin.clone();
+            params.sources.clear();
+            match summary.sinks.iter_mut().find(|s| s.addr == addr && s.sink == sink) {
+                Some(existing) => existing.origin.extend(&params),
+                None => summary.sinks.push(ParamSink { sink: String::from(sink), addr: addr, origin: params, path: path }),
+            }
+        }
+    }
+
+    fn ret(&self, facts: &Facts, summary: &mut Summary) {
+        let ret = self.registers.full_width(&self.callconv.int_return);
+        summary.ret.extend(&self.reg_origin(facts, &ret));
+    }
+
+    // Jumps inside the function continue there, jumps to other functions are tail calls
+    fn jump(&mut self, insts: &[Inst], facts: &mut Facts, summary: &mut Summary, target: &Expr, addr: u64) -> Vec<usize> {
+        let dest = match self.location(facts, target) {
+            Some(ref location) if location.base.is_empty() => location.offset as u64,
+            _ => return Vec::new(),
+        };
+        if let Some(index) = insts.iter().position(|inst| inst.addr == dest) {
+            return vec![index];
+        }
+        self.call(facts, summary, target, addr);
+        self.ret(facts, summary);
+        return Vec::new();
+    }
+
+    // Applies what the called function does: an import's sources, sinks and model, or the summary
+    // of one of the program's functions
+    fn call(&mut self, facts: &mut Facts, summary: &mut Summary, target: &Expr, addr: u64) {
+        let args: Vec<Origin> = (0..CALL_ARGS).map(|n| self.arg_origin(facts, n)).collect();
+        let pointees: Vec<Origin> = (0..CALL_ARGS).map(|n| self.pointee_origin(facts, n)).collect();
+        let mut passed = Origin::new();
+        for arg in &args {
+            passed.extend(arg);
+        }
+
+        let callee = match self.location(facts, target) {
+            Some(ref location) if location.base.is_empty() => Some(location.offset as u64),
+            _ => None,
+        };
+        // Calls through a GOT slot have no known target but the slot names the import
+        let import = got_slot(target).and_then(|slot| self.program.import_symbol(slot))
+            .or_else(|| callee.and_then(|callee| self.program.resolve_import(callee)));
+        let ret = match (import, callee) {
+            (Some(import), _) => self.import(facts, summary, &import.name, addr, &args, &pointees),
+            (None, Some(callee)) => match self.summaries.get(&callee).cloned() {
+                Some(callee) => self.apply(facts, summary, &callee, addr, &args, &pointees),
+                None if self.names.contains_key(&callee) => Origin::new(),
+                None => passed,
+            },
+            (None, None) => {
+                let origin = self.origin(facts, target);
+                if self.policy.sinks.iter().any(|sink| match *sink { Sink::CallTarget => true, _ => false }) {
+                    self.sink(summary, &Sink::CallTarget.to_string(), &origin, addr, vec![addr]);
+                }
+                passed
+            }
+        };
+        let reg = self.callconv.int_return.clone();
+        self.set_reg(facts, &reg, ret, None);
+    }
+
+    fn apply(&mut self, facts: &mut Facts, summary: &mut Summary, callee: &Summary, addr: u64, args: &[Origin], pointees: &[Origin]) -> Origin {
+        for (&n, origin) in &callee.writes {
+            let origin = origin.instantiate(args, pointees);
+            let location = self.arg_location(facts, n);
+            self.store(facts, summary, location, POINTEE_SIZE as usize, origin, false);
+        }
+        for param in &callee.sinks {
+            let origin = param.origin.instantiate(args, pointees);
+            let mut path = vec![addr];
+            path.extend(param.path.iter().cloned());
+            self.sink(summary, &param.sink, &origin, param.addr, path);
+        }
+        return callee.ret.instantiate(args, pointees);
+    }
+
+    // Imports aren't analyzed. Their return value comes from their arguments, copies move the
+    // data they copy, and the policy's sources and sinks apply.
+    fn import(&mut self, facts: &mut Facts, summary: &mut Summary, name: &str, addr: u64, args: &[Origin], pointees: &[Origin]) -> Origin {
+        for sink in self.policy.sinks_of(name) {
+            if let Sink::Argument { arg, pointee, .. } = sink {
+                let mut origin = args.get(arg).cloned().unwrap_or(Origin::new());
+                if pointee {
+                    origin.extend(&pointees.get(arg).cloned().unwrap_or(Origin::new()));
+                }
+                self.sink(summary, &sink.to_string(), &origin, addr, vec![addr]);
+            }
+        }
+
+        let mut ret = Origin::new();
+        for arg in args {
+            ret.extend(arg);
+        }
+        for pointee in pointees.iter().take(pointees_read(name)) {
+            ret.extend(pointee);
+        }
+
+        match name {
+            "memcpy" | "memmove" | "strncpy" | "strcpy" | "strcat" => {
+                let location = self.arg_location(facts, 0);
+                self.store(facts, summary, location, POINTEE_SIZE as usize, pointees[1].clone(), false);
+            }
+            _ => (),
+        }
+
+        let label = format!("{}@0x{:x}", name, addr);
+        for source in self.policy.sources_of(name) {
+            match source {
+                Source::Return { .. } => ret.extend(&Origin::source(&label)),
+                Source::Buffer { arg, ref size, .. } => {
+                    let size = match *size {
+                        Size::Fixed(size) => size,
+                        Size::Arg(_) => POINTEE_SIZE as usize,
+                    };
+                    let location = self.arg_location(facts, arg);
+                    self.store(facts, summary, location, size, Origin::source(&label), false);
+                }
+                _ => (),
+            }
+        }
+        return ret;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts() -> Facts {
+        return Facts {
+            regs: HashMap::new(),
+            values: HashMap::new(),
+            memory: HashMap::new(),
+            unknown: Origin::new(),
+            flags: Origin::new(),
+        };
+    }
+
+    #[test]
+    fn instantiate_replaces_arguments() {
+        let mut origin = Origin::source("read@0x10");
+        origin.extend(&Origin::arg(0));
+        origin.extend(&Origin::pointee(1));
+
+        let args = vec![Origin::source("getenv@0x20"), Origin::arg(3)];
+        let pointees = vec![Origin::new(), Origin::pointee(2)];
+        let result = origin.instantiate(&args, &pointees);
+
+        let sources: Vec<&str> = result.sources.iter().map(|s| s.as_str()).collect();
+        assert_eq!(sources, vec!["getenv@0x20", "read@0x10"]);
+        assert!(result.args.is_empty());
+        assert_eq!(result.pointees.iter().cloned().collect::<Vec<usize>>(), vec![2]);
+        assert_eq!(result.to_string(), "getenv@0x20, read@0x10, *arg 2");
+    }
+
+    #[test]
+    fn join_reaches_a_fixed_point() {
+        let sp = Location { base: String::from("rsp"), offset: -8 };
+        let mut left = facts();
+        left.regs.insert(String::from("rax"), Origin::arg(0));
+        left.values.insert(String::from("rsp"), Some(sp.clone()));
+        left.memory.insert(sp.clone(), (4, Origin::source("read@0x10")));
+
+        let mut right = facts();
+        right.regs.insert(String::from("rax"), Origin::arg(1));
+        right.values.insert(String::from("rsp"), Some(sp.add(-8)));
+        right.memory.insert(sp.clone(), (8, Origin::new()));
+        right.unknown = Origin::source("fgets@0x20");
+
+        assert!(left.join(&right));
+        assert_eq!(left.regs["rax"].args.iter().cloned().collect::<Vec<usize>>(), vec![0, 1]);
+        // Paths that disagree on a register's location leave it unknown
+        assert_eq!(left.value("rsp"), None);
+        assert_eq!(left.memory[&sp].0, 8);
+        assert!(left.memory[&sp].1.sources.contains("read@0x10"));
+        assert!(left.unknown.sources.contains("fgets@0x20"));
+
+        // Joining the same facts again changes nothing
+        assert!(!left.join(&right));
+        let joined = left.clone();
+        assert!(!left.join(&joined));
+    }
+
+    #[test]
+    fn join_keeps_untouched_registers_at_entry() {
+        let mut left = facts();
+        let right = facts();
+        assert!(!left.join(&right));
+        assert_eq!(left.value("rdi"), Some(Location { base: String::from("rdi"), offset: 0 }));
+    }
+}
@@ -0,0 +1,1223 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use z3;
+use z3::ast;
+use z3::ast::Ast;
+use program::*;
+use expression::*;
+use state::{Frame, Memory, STACK_TOP, PAGE_SIZE};
+use registers::{Registers, Extend};
+use flags::{Flags, Role, Condition, FlagOp};
+use syscalls::{self, Abi};
+use callconv::CallingConvention;
+
+// Number of symbolic bytes fgets() hands back when the program reads stdin
+const STDIN_LEN: usize = 16;
+// Longest string the string hooks scan before giving up on finding its end
+const MAX_STRING: u64 = 0x1000;
+
+pub struct SymbolicState<'ctx> {
+    pub addr: u64,
+    pub function: u64,
+    pub index: usize,
+    pub defs: Rc<Registers>,
+    pub image: Rc<Memory>,
+    pub regs: HashMap<String, ast::BV<'ctx>>,
+    pub memory: HashMap<u64, ast::BV<'ctx>>,
+    pub flags: HashMap<String, ast::Bool<'ctx>>,
+    pub constraints: Vec<ast::Bool<'ctx>>,
+    pub call_stack: Vec<Frame>,
+    pub stdin: Vec<ast::BV<'ctx>>,
+}
+
+impl<'ctx> SymbolicState<'ctx> {
+    pub fn new(ctx: &'ctx z3::Context, defs: Rc<Registers>, image: Rc<Memory>, function: u64) -> SymbolicState<'ctx> {
+        let mut state = SymbolicState {
+            addr: function,
+            function: function,
+            index: 0,
+            defs: defs,
+            image: image,
+            regs: HashMap::new(),
+            memory: HashMap::new(),
+            flags: HashMap::new(),
+            constraints: Vec::new(),
+            call_stack: Vec::new(),
+            stdin: Vec::new(),
+        };
+
+        // The stack pointer has to be concrete so that stack accesses land on known addresses
+        let sp = ast::BV::from_u64(ctx, STACK_TOP - PAGE_SIZE, state.defs.address_size as u32 * 8);
+        state.set_sp(sp);
+        return state;
+    }
+
+    // Creates an identical copy of the state for the other side of a branch
+    pub fn fork(&self) -> SymbolicState<'ctx> {
+        return SymbolicState {
+            addr: self.addr,
+            function: self.function,
+            index: self.index,
+            defs: self.defs.clone(),
+            image: self.image.clone(),
+            regs: self.regs.clone(),
+            memory: self.memory.clone(),
+            flags: self.flags.clone(),
+            constraints: self.constraints.clone(),
+            call_stack: self.call_stack.clone(),
+            stdin: self.stdin.clone(),
+        }
+    }
+
+    // Reads a register out of its full width register, registers that were never written are unconstrained
+    pub fn get_reg(&mut self, ctx: &'ctx z3::Context, name: &str) -> ast::BV<'ctx> {
+        let (full_width, offset, size, full_size) = self.layout(name);
+        let full = self.regs.entry(full_width.clone())
+            .or_insert_with(|| ast::BV::new_const(ctx, format!("reg_{}", full_width), bits(full_size)))
+            .clone();
+
+        if offset == 0 && size == full_size {
+            return full;
+        }
+        return full.extract(bits(offset + size) - 1, bits(offset)).simplify();
+    }
+
+    pub fn set_reg(&mut self, ctx: &'ctx z3::Context, name: &str, value: ast::BV<'ctx>) {
+        let (full_width, offset, size, full_size) = self.layout(name);
+        let value = resize(&value, size);
+        let extend = self.defs.info(name).map(|def| def.extend).unwrap_or(Extend::ZeroExtend);
+
+        let full = match extend {
+            Extend::ZeroExtend => resize(&value, full_size),
+            Extend::SignExtend if full_size > size => value.sign_ext(bits(full_size - size)),
+            _ if offset == 0 && size == full_size => value,
+            _ => {
+                // Splice the value into the untouched parts of the full width register
+                let old = self.get_reg(ctx, &full_width);
+                let mut full = value;
+                if offset > 0 {
+                    full = full.concat(&old.extract(bits(offset) - 1, 0));
+                }
+                if offset + size < full_size {
+                    full = old.extract(bits(full_size) - 1, bits(offset + size)).concat(&full);
+                }
+                full
+            }
+        };
+
+        self.regs.insert(full_width, full.simplify());
+    }
+
+    // Full width register name, byte offset, size and full width size of a register
+    fn layout(&self, name: &str) -> (String, usize, usize, usize) {
+        match self.defs.info(name) {
+            Some(def) => {
+                let full_size = self.defs.info(&def.full_width).map(|d| d.size).unwrap_or(def.size);
+                return (def.full_width.clone(), def.offset, def.size, full_size);
+            }
+            None => return (String::from(name), 0, self.defs.address_size, self.defs.address_size),
+        }
+    }
+
+    pub fn sp(&mut self, ctx: &'ctx z3::Context) -> ast::BV<'ctx> {
+        let sp = self.defs.stack_pointer.clone().unwrap_or(String::from("sp"));
+        return self.get_reg(ctx, &sp);
+    }
+
+    pub fn set_sp(&mut self, value: ast::BV<'ctx>) {
+        let sp = self.defs.stack_pointer.clone().unwrap_or(String::from("sp"));
+        self.regs.insert(self.defs.full_width(&sp), value);
+    }
+
+    // Flags that were never written are unconstrained
+    pub fn get_flag(&mut self, ctx: &'ctx z3::Context, name: &str) -> ast::Bool<'ctx> {
+        return self.flags.entry(String::from(name))
+            .or_insert_with(|| ast::Bool::new_const(ctx, format!("flag_{}", name)))
+            .clone();
+    }
+
+    // Loads a little endian value of the given size in bytes
+    pub fn load(&mut self, ctx: &'ctx z3::Context, addr: u64, size: u32) -> ast::BV<'ctx> {
+        let mut value = self.load_byte(ctx, addr + size as u64 - 1);
+        for i in (0..size as u64 - 1).rev() {
+            value = value.concat(&self.load_byte(ctx, addr + i));
+        }
+        return value.simplify();
+    }
+
+    // Stores a little endian value of the given size in bytes
+    pub fn store(&mut self, addr: u64, size: u32, value: &ast::BV<'ctx>) {
+        for i in 0..size {
+            self.memory.insert(addr + i as u64, value.extract(i * 8 + 7, i * 8).simplify());
+        }
+    }
+
+    fn load_byte(&mut self, ctx: &'ctx z3::Context, addr: u64) -> ast::BV<'ctx> {
+        if let Some(byte) = self.memory.get(&addr) {
+            return byte.clone();
+        }
+
+        // Bytes that were never written come from the program image, anything outside of it is unconstrained
+        let byte = match self.image.load(addr, 1) {
+            Ok(value) => ast::BV::from_u64(ctx, value as u64, 8),
+            Err(_) => ast::BV::new_const(ctx, format!("mem_0x{:x}", addr), 8),
+        };
+        self.memory.insert(addr, byte.clone());
+        return byte;
+    }
+
+    pub fn satisfiable(&self, ctx: &'ctx z3::Context) -> bool {
+        let solver = z3::Solver::new(ctx);
+        for constraint in &self.constraints {
+            solver.assert(constraint);
+        }
+        return solver.check() == z3::SatResult::Sat;
+    }
+
+    // Whether a byte can be the value under the current constraints
+    pub fn can_be(&self, ctx: &'ctx z3::Context, byte: &ast::BV<'ctx>, value: u64) -> bool {
+        if let Some(v) = byte.as_u64() {
+            return v == value;
+        }
+        let solver = z3::Solver::new(ctx);
+        for constraint in &self.constraints {
+            solver.assert(constraint);
+        }
+        solver.assert(&byte._eq(&ast::BV::from_u64(ctx, value, byte.get_size())));
+        return solver.check() != z3::SatResult::Unsat;
+    }
+
+    // Picks a concrete value for an expression, pinning it down so later queries stay consistent
+    pub fn concretize(&mut self, ctx: &'ctx z3::Context, value: &ast::BV<'ctx>) -> Result<u64, String> {
+        if let Some(v) = value.simplify().as_u64() {
+            return Ok(v);
+        }
+
+        let solver = z3::Solver::new(ctx);
+        for constraint in &self.constraints {
+            solver.assert(constraint);
+        }
+
+        if solver.check() != z3::SatResult::Sat {
+            return Err(String::from("State is unsatisfiable"));
+        }
+
+        let model = solver.get_model().ok_or(String::from("Failed to get model"))?;
+        let concrete = model.eval(value).and_then(|v| v.as_u64()).ok_or(String::from("Failed to evaluate value"))?;
+        self.constraints.push(value._eq(&ast::BV::from_u64(ctx, concrete, value.get_size())));
+        return Ok(concrete);
+    }
+
+    // Solves for a stdin that drives the program into this state
+    pub fn solve_stdin(&self, ctx: &'ctx z3::Context) -> Option<Vec<u8>> {
+        let solver = z3::Solver::new(ctx);
+        for constraint in &self.constraints {
+            solver.assert(constraint);
+        }
+
+        if solver.check() != z3::SatResult::Sat {
+            return None;
+        }
+
+        let model = solver.get_model()?;
+        let mut input = Vec::new();
+        for byte in &self.stdin {
+            input.push(model.eval(byte).and_then(|b| b.as_u64()).unwrap_or(0) as u8);
+        }
+        return Some(input);
+    }
+}
+
+pub struct SymbolicExecutor<'a, 'ctx> {
+    pub program: &'a Program<'a>,
+    pub ctx: &'ctx z3::Context,
+    pub active: Vec<SymbolicState<'ctx>>,
+    pub deadended: Vec<SymbolicState<'ctx>>,
+    // Flag roles of the architecture, the flag values live in each state
+    pub roles: HashMap<String, Role>,
+    pub abi: Abi,
+    // Used by the hooks when the callee's type doesn't give a convention
+    pub callconv: CallingConvention,
+}
+
+impl<'a, 'ctx> SymbolicExecutor<'a, 'ctx> {
+    pub fn new(program: &'a Program, ctx: &'ctx z3::Context, state: SymbolicState<'ctx>) -> SymbolicExecutor<'a, 'ctx> {
+        let (roles, abi) = match program.arch() {
+            Ok(arch) => (Flags::new(&arch).roles, Abi::for_arch(&arch)),
+            Err(_) => (HashMap::new(), Abi::x86_64()),
+        };
+        let callconv = match program.calling_convention() {
+            Ok(callconv) => callconv,
+            Err(err) => {
+                error!("{}", err);
+                CallingConvention::sysv()
+            }
+        };
+        return SymbolicExecutor {
+            program: program,
+            ctx: ctx,
+            active: vec![state],
+            deadended: Vec::new(),
+            roles: roles,
+            abi: abi,
+            callconv: callconv,
+        }
+    }
+
+    // Searches for the entry point and starts working with it there.
+    pub fn entry(program: &'a Program, ctx: &'ctx z3::Context) -> SymbolicExecutor<'a, 'ctx> {
+        return SymbolicExecutor::at_function(program, ctx, "_start");
+    }
+
+    // Searches for the main function and starts working with it there.
+    pub fn main(program: &'a Program, ctx: &'ctx z3::Context) -> SymbolicExecutor<'a, 'ctx> {
+        return SymbolicExecutor::at_function(program, ctx, "main");
+    }
+
+    pub fn at_function(program: &'a Program, ctx: &'ctx z3::Context, name: &str) -> SymbolicExecutor<'a, 'ctx> {
+        let defs = register_defs(program);
+        let image = program_image(program);
+
+        for function in program.functions() {
+            if function.name.eq_ignore_ascii_case(name) {
+                return SymbolicExecutor::new(program, ctx, SymbolicState::new(ctx, defs, image, function.addr));
+            }
+        }
+        return SymbolicExecutor::new(program, ctx, SymbolicState::new(ctx, defs, image, 0));
+    }
+
+    // Starts at any address inside a function rather than at its start
+    pub fn at_address(program: &'a Program, ctx: &'ctx z3::Context, addr: u64) -> Result<SymbolicExecutor<'a, 'ctx>, String> {
+        let (function, index) = program.location_of(addr)?;
+        let mut state = SymbolicState::new(ctx, register_defs(program), program_image(program), function);
+        state.addr = addr;
+        state.index = index;
+        return Ok(SymbolicExecutor::new(program, ctx, state));
+    }
+
+    // Steps every active state forward by one address, forking on satisfiable branches
+    pub fn step(&mut self) {
+        let mut next = Vec::new();
+        let states = std::mem::replace(&mut self.active, Vec::new());
+        for state in states {
+            match self.step_state(state) {
+                Ok(states) => next.extend(states),
+                Err((state, err)) => {
+                    info!("0x{:x} State deadended: {}", state.addr, err);
+                    self.deadended.push(state);
+                }
+            }
+        }
+        self.active = next;
+    }
+
+    // Runs until a state reaches the find address, dropping states that reach any avoid address
+    pub fn explore(&mut self, find: u64, avoid: &[u64], max_steps: usize) -> Option<SymbolicState<'ctx>> {
+        for _ in 0..max_steps {
+            if let Some(i) = self.active.iter().position(|s| s.addr == find) {
+                return Some(self.active.remove(i));
+            }
+
+            let (avoided, active): (Vec<SymbolicState<'ctx>>, Vec<SymbolicState<'ctx>>) =
+                self.active.drain(..).partition(|s| avoid.contains(&s.addr));
+            self.active = active;
+            self.deadended.extend(avoided);
+
+            if self.active.is_empty() {
+                break;
+            }
+
+            self.step();
+        }
+        return None;
+    }
+
+    fn step_state(&self, mut state: SymbolicState<'ctx>) -> Result<Vec<SymbolicState<'ctx>>, (SymbolicState<'ctx>, String)> {
+        use LlilInst::*;
+
+        let inst = match self.program.llil_at(state.function, state.index) {
+            Ok(inst) => inst,
+            Err(err) => return Err((state, err)),
+        };
+        state.addr = inst.addr;
+
+        // Location to continue at if the instruction transfers control
+        let result: Result<Option<(u64, usize)>, String> = match inst.llil {
+            SetReg(llil) => {
+                self.eval(llil.expr, &mut state).map(|val| state.set_reg(self.ctx, &llil.reg, val)).map(|_| None)
+            }
+            SetRegSplit(llil) => {
+                let size = llil.size;
+                self.eval(llil.source_expr, &mut state).map(|val| {
+                    let val = resize(&val, size * 2);
+                    let bits = bits(size);
+                    state.set_reg(self.ctx, &llil.dest_reg_low, val.extract(bits - 1, 0));
+                    state.set_reg(self.ctx, &llil.dest_reg_high, val.extract(bits * 2 - 1, bits));
+                    None
+                })
+            }
+            Push(llil) => {
+                let size = llil.size;
+                let result = self.eval(llil.expr, &mut state);
+                result.and_then(|val| self.push(&mut state, &resize(&val, size))).map(|_| None)
+            }
+            Store(llil) => {
+                let size = llil.size;
+                let val = self.eval(llil.source_expr, &mut state);
+                let addr = self.eval(llil.dest_mem_expr, &mut state);
+                match (val, addr) {
+                    (Ok(val), Ok(addr)) => {
+                        state.concretize(self.ctx, &addr).map(|addr| state.store(addr, size as u32, &resize(&val, size))).map(|_| None)
+                    }
+                    (Err(err), _) | (_, Err(err)) => Err(err),
+                }
+            }
+            If(llil) => {
+                let condition = match self.eval(llil.condition, &mut state) {
+                    Ok(condition) => condition._eq(&ast::BV::from_u64(self.ctx, 0, condition.get_size())).not(),
+                    Err(err) => return Err((state, err)),
+                };
+
+                let function = state.function;
+                let mut taken = state.fork();
+                taken.constraints.push(condition.clone());
+                self.relocate(&mut taken, function, llil.target_true);
+
+                let mut not_taken = state;
+                not_taken.constraints.push(condition.not());
+                self.relocate(&mut not_taken, function, llil.target_false);
+
+                let mut states = Vec::new();
+                for branch in vec![taken, not_taken] {
+                    if branch.satisfiable(self.ctx) {
+                        info!(" > Branch to 0x{:x} is satisfiable", branch.addr);
+                        states.push(branch);
+                    }
+                }
+                return Ok(states);
+            }
+            Call(llil) => {
+                // Calls through a GOT slot are named by the slot, like the emulator does
+                let slot = got_slot(&llil.target);
+                match self.eval(llil.target, &mut state) {
+                    Ok(target) => match state.concretize(self.ctx, &target) {
+                        Ok(target) => self.call(&mut state, target, slot),
+                        Err(err) => Err(err),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            Goto(llil) => Ok(Some((state.function, llil.target))),
+            Jump(llil) | JumpTo(llil) => {
+                match self.eval(llil.target, &mut state) {
+                    Ok(target) => match state.concretize(self.ctx, &target) {
+                        Ok(target) => self.resolve(&state, target).map(Some),
+                        Err(err) => Err(err),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            Ret(llil) => {
+                match self.eval(llil.target, &mut state) {
+                    Ok(target) => match state.concretize(self.ctx, &target) {
+                        Ok(target) => match state.call_stack.pop() {
+                            Some(ref frame) if frame.return_addr == target => Ok(Some((frame.function, frame.index))),
+                            Some(_) => self.program.location_of(target).map(Some),
+                            None => Err(String::from("Returned from the starting function")),
+                        },
+                        Err(err) => Err(err),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            SetFlag(llil) => {
+                self.eval(llil.expr, &mut state).map(|val| {
+                    let set = val._eq(&ast::BV::from_u64(self.ctx, 0, val.get_size())).not();
+                    state.flags.insert(llil.flag, set.simplify());
+                    None
+                })
+            }
+            Syscall() => self.syscall(&mut state).map(|_| None),
+            NoRet() => Err(String::from("Reached a no return instruction")),
+            Value(expr) => self.eval(expr, &mut state).map(|_| None),
+            _ => Ok(None),
+        };
+
+        match result {
+            Ok(Some((function, index))) => self.relocate(&mut state, function, index),
+            Ok(None) => {
+                let (function, index) = (state.function, state.index + 1);
+                self.relocate(&mut state, function, index);
+            }
+            Err(err) => return Err((state, err)),
+        }
+        return Ok(vec![state]);
+    }
+
+    // Moves the state to a location, keeping its address in sync for find and avoid checks
+    fn relocate(&self, state: &mut SymbolicState<'ctx>, function: u64, index: usize) {
+        state.function = function;
+        state.index = index;
+        if let Ok(inst) = self.program.llil_at(function, index) {
+            state.addr = inst.addr;
+        }
+    }
+
+    // Maps a jump target to a location, preferring the current function
+    fn resolve(&self, state: &SymbolicState<'ctx>, target: u64) -> Result<(u64, usize), String> {
+        if let Ok(function) = self.program.function_at(state.function) {
+            if let Ok(index) = function.llil_index_at(target) {
+                return Ok((function.addr, index));
+            }
+        }
+        return self.program.location_of(target);
+    }
+
+    fn push(&self, state: &mut SymbolicState<'ctx>, value: &ast::BV<'ctx>) -> Result<(), String> {
+        let size = value.get_size() / 8;
+        let sp = state.sp(self.ctx);
+        let sp = sp.bvsub(&ast::BV::from_u64(self.ctx, size as u64, sp.get_size()));
+        let addr = state.concretize(self.ctx, &sp)?;
+        state.set_sp(sp);
+        state.store(addr, size, value);
+        return Ok(());
+    }
+
+    // Returns the callee's location if control transferred into it
+    fn call(&self, state: &mut SymbolicState<'ctx>, target: u64, slot: Option<u64>) -> Result<Option<(u64, usize)>, String> {
+        let function = self.program.function_at(target);
+        let import = slot.and_then(|slot| self.program.import_symbol(slot))
+            .or_else(|| self.program.resolve_import(target));
+        let name = match import {
+            Some(ref import) => import.name.clone(),
+            None => function.as_ref().map(|f| f.name.clone()).unwrap_or(String::new()),
+        };
+        info!("0x{:x} Symbolic call to {}", state.addr, name);
+
+        // Hooks see the arguments the way the callee's type says they were passed
+        let (callconv, parameters) = match self.program.calling_convention_of(target) {
+            Some((callconv, parameters)) => (callconv, parameters),
+            None => (self.callconv.clone(), None),
+        };
+        let hooked = match name.as_str() {
+            "fgets" => {
+                self.fgets(state, &callconv)?;
+                true
+            }
+            "strlen" => {
+                self.strlen(state, &callconv)?;
+                true
+            }
+            "atoi" => {
+                self.atoi(state, &callconv)?;
+                true
+            }
+            "puts" | "printf" => {
+                state.set_reg(self.ctx, &callconv.int_return, ast::BV::from_u64(self.ctx, 0, bits(state.defs.address_size)));
+                true
+            }
+            _ => false,
+        };
+        if hooked {
+            if let Some(parameters) = parameters {
+                self.cleanup(state, &callconv, parameters);
+            }
+            return Ok(None);
+        }
+
+        if import.is_some() || self.program.is_import(target) {
+            // Nothing to step into so the return value is unconstrained
+            let ret = ast::BV::new_const(self.ctx, format!("ret_{}_0x{:x}", name, state.addr), bits(state.defs.address_size));
+            state.set_reg(self.ctx, &callconv.int_return, ret);
+            return Ok(None);
+        }
+
+        let function = match function {
+            Ok(function) => function,
+            Err(_) => return Err(format!("Call to 0x{:x} which isn't the start of a function", target)),
+        };
+        let index = state.index + 1;
+        let ret = state.addr + self.program.instruction_len(state.addr)? as u64;
+        let ret_bv = ast::BV::from_u64(self.ctx, ret, state.defs.address_size as u32 * 8);
+        match state.defs.link_register.clone() {
+            Some(lr) => state.set_reg(self.ctx, &lr, ret_bv),
+            None => self.push(state, &ret_bv)?,
+        }
+        state.call_stack.push(Frame {
+            function: state.function,
+            index: index,
+            return_addr: ret,
+        });
+        return Ok(Some((function.addr, 0)));
+    }
+
+    // Integer argument n of a call, read from the same places as CallingConvention::arg
+    fn arg(&self, state: &mut SymbolicState<'ctx>, callconv: &CallingConvention, n: usize) -> Result<ast::BV<'ctx>, String> {
+        if n < callconv.int_args.len() {
+            return Ok(state.get_reg(self.ctx, &callconv.int_args[n]));
+        }
+        let sp = state.sp(self.ctx);
+        let sp = state.concretize(self.ctx, &sp)?;
+        let addr = sp + callconv.stack_offset + ((n - callconv.int_args.len()) * callconv.slot_size) as u64;
+        return Ok(state.load(self.ctx, addr, callconv.slot_size as u32));
+    }
+
+    // Pops the stack arguments for conventions where the callee cleans up
+    fn cleanup(&self, state: &mut SymbolicState<'ctx>, callconv: &CallingConvention, arg_count: usize) {
+        if callconv.callee_cleans && arg_count > callconv.int_args.len() {
+            let sp = state.sp(self.ctx);
+            let size = ((arg_count - callconv.int_args.len()) * callconv.slot_size) as u64;
+            state.set_sp(sp.bvadd(&ast::BV::from_u64(self.ctx, size, sp.get_size())));
+        }
+    }
+
+    fn fgets(&self, state: &mut SymbolicState<'ctx>, callconv: &CallingConvention) -> Result<(), String> {
+        let dest = self.arg(state, callconv, 0)?;
+        let size = self.arg(state, callconv, 1)?;
+        let buf = state.concretize(self.ctx, &dest)?;
+        let size = state.concretize(self.ctx, &resize(&size, 4))? as u32 as i32;
+        // Without room for the terminator nothing is read and NULL comes back
+        if size <= 0 {
+            state.set_reg(self.ctx, &callconv.int_return, ast::BV::from_u64(self.ctx, 0, dest.get_size()));
+            return Ok(());
+        }
+        // With room for only the terminator that's all that's written
+        if size == 1 {
+            state.store(buf, 1, &ast::BV::from_u64(self.ctx, 0, 8));
+            state.set_reg(self.ctx, &callconv.int_return, dest);
+            return Ok(());
+        }
+        let size = size as usize;
+        let len = if size > STDIN_LEN + 1 { STDIN_LEN } else { size - 2 };
+
+        for i in 0..len {
+            let byte = ast::BV::new_const(self.ctx, format!("stdin_{}", state.stdin.len()), 8);
+            state.constraints.push(byte._eq(&ast::BV::from_u64(self.ctx, 0, 8)).not());
+            state.constraints.push(byte._eq(&ast::BV::from_u64(self.ctx, '\n' as u64, 8)).not());
+            state.store(buf + i as u64, 1, &byte);
+            state.stdin.push(byte);
+        }
+
+        state.store(buf + len as u64, 1, &ast::BV::from_u64(self.ctx, '\n' as u64, 8));
+        state.store(buf + len as u64 + 1, 1, &ast::BV::from_u64(self.ctx, 0, 8));
+        state.set_reg(self.ctx, &callconv.int_return, dest);
+        return Ok(());
+    }
+
+    fn strlen(&self, state: &mut SymbolicState<'ctx>, callconv: &CallingConvention) -> Result<(), String> {
+        let string = self.arg(state, callconv, 0)?;
+        let addr = state.concretize(self.ctx, &string)?;
+
+        // The terminator has to be concrete. Symbolic bytes are fine as long as they can't be
+        // null, like the stdin bytes fgets hands out.
+        let mut len = 0;
+        loop {
+            let byte = state.load(self.ctx, addr + len, 1);
+            if byte.as_u64() == Some(0) {
+                break;
+            }
+            if state.can_be(self.ctx, &byte, 0) {
+                return Err(format!("strlen of 0x{:x} reaches a byte that may be null at 0x{:x}", addr, addr + len));
+            }
+            len += 1;
+            if len >= MAX_STRING {
+                return Err(format!("strlen of 0x{:x} found no terminator", addr));
+            }
+        }
+
+        state.set_reg(self.ctx, &callconv.int_return, ast::BV::from_u64(self.ctx, len, bits(state.defs.address_size)));
+        return Ok(());
+    }
+
+    fn atoi(&self, state: &mut SymbolicState<'ctx>, callconv: &CallingConvention) -> Result<(), String> {
+        let string = self.arg(state, callconv, 0)?;
+        let addr = state.concretize(self.ctx, &string)?;
+
+        // Every byte up to the newline or terminator is constrained to be a decimal digit
+        let mut result = ast::BV::from_u64(self.ctx, 0, 32);
+        let mut i = 0;
+        loop {
+            let byte = state.load(self.ctx, addr + i, 1);
+            if byte.as_u64() == Some(0) || byte.as_u64() == Some('\n' as u64) {
+                break;
+            }
+            if state.can_be(self.ctx, &byte, 0) || state.can_be(self.ctx, &byte, '\n' as u64) {
+                return Err(format!("atoi of 0x{:x} reaches a byte that may end the string at 0x{:x}", addr, addr + i));
+            }
+            if i >= MAX_STRING {
+                return Err(format!("atoi of 0x{:x} found no terminator", addr));
+            }
+
+            state.constraints.push(byte.bvuge(&ast::BV::from_u64(self.ctx, '0' as u64, 8)));
+            state.constraints.push(byte.bvule(&ast::BV::from_u64(self.ctx, '9' as u64, 8)));
+
+            let digit = byte.bvsub(&ast::BV::from_u64(self.ctx, '0' as u64, 8)).zero_ext(24);
+            result = result.bvmul(&ast::BV::from_u64(self.ctx, 10, 32)).bvadd(&digit);
+            i += 1;
+        }
+
+        // atoi returns an int, sign extended into wider return registers
+        state.set_reg(self.ctx, &callconv.int_return, result.sign_ext(bits(state.defs.address_size) - 32));
+        return Ok(());
+    }
+
+    pub fn eval(&self, expr: Expr, state: &mut SymbolicState<'ctx>) -> Result<ast::BV<'ctx>, String> {
+        let ctx = self.ctx;
+        let one = ast::BV::from_u64(ctx, 1, 8);
+        let zero = ast::BV::from_u64(ctx, 0, 8);
+        let size = expr.size();
+
+        let result = match expr {
+            Expr::Value(v) => ast::BV::from_u64(ctx, v.value, 64),
+            Expr::Reg(r) => state.get_reg(ctx, &r.name),
+            Expr::Load(l) => {
+                let addr = self.eval(*l.source_mem, state)?;
+                let addr = state.concretize(ctx, &addr)?;
+                state.load(ctx, addr, l.size as u32)
+            }
+            Expr::Pop(p) => {
+                let sp = state.sp(ctx);
+                let addr = state.concretize(ctx, &sp)?;
+                state.set_sp(sp.bvadd(&ast::BV::from_u64(ctx, p.size as u64, sp.get_size())));
+                state.load(ctx, addr, p.size as u32)
+            }
+
+            Expr::CmpE(s) => self.eval_sized(*s.left, s.size, state)?._eq(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpNe(s) => self.eval_sized(*s.left, s.size, state)?._eq(&self.eval_sized(*s.right, s.size, state)?).not().ite(&one, &zero),
+            Expr::CmpSlt(s) => self.eval_sized(*s.left, s.size, state)?.bvslt(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpSle(s) => self.eval_sized(*s.left, s.size, state)?.bvsle(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpSge(s) => self.eval_sized(*s.left, s.size, state)?.bvsge(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpSgt(s) => self.eval_sized(*s.left, s.size, state)?.bvsgt(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpUlt(s) => self.eval_sized(*s.left, s.size, state)?.bvult(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpUle(s) => self.eval_sized(*s.left, s.size, state)?.bvule(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpUge(s) => self.eval_sized(*s.left, s.size, state)?.bvuge(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+            Expr::CmpUgt(s) => self.eval_sized(*s.left, s.size, state)?.bvugt(&self.eval_sized(*s.right, s.size, state)?).ite(&one, &zero),
+
+            Expr::Add(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvadd(&right);
+                self.write_flags(state, &s.flags, FlagOp::Add, &left, &right, &result);
+                result
+            }
+            Expr::Sub(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvsub(&right);
+                self.write_flags(state, &s.flags, FlagOp::Sub, &left, &right, &result);
+                result
+            }
+            Expr::And(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvand(&right);
+                self.write_flags(state, &s.flags, FlagOp::Logic, &left, &right, &result);
+                result
+            }
+            Expr::Or(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvor(&right);
+                self.write_flags(state, &s.flags, FlagOp::Logic, &left, &right, &result);
+                result
+            }
+            Expr::Xor(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvxor(&right);
+                self.write_flags(state, &s.flags, FlagOp::Logic, &left, &right, &result);
+                result
+            }
+            Expr::Mul(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvmul(&right);
+                self.write_flags(state, &s.flags, FlagOp::Mul, &left, &right, &result);
+                result
+            }
+            Expr::Divu(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvudiv(&right);
+                self.write_flags(state, &s.flags, FlagOp::Other, &left, &right, &result);
+                result
+            }
+            Expr::Divs(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvsdiv(&right);
+                self.write_flags(state, &s.flags, FlagOp::Other, &left, &right, &result);
+                result
+            }
+            Expr::Modu(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvurem(&right);
+                self.write_flags(state, &s.flags, FlagOp::Other, &left, &right, &result);
+                result
+            }
+            Expr::Mods(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvsrem(&right);
+                self.write_flags(state, &s.flags, FlagOp::Other, &left, &right, &result);
+                result
+            }
+
+            // Shift amounts can be any size so they are brought up to the size of the value being shifted
+            Expr::Lsl(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvshl(&right);
+                self.write_flags(state, &s.flags, FlagOp::ShiftLeft, &left, &right, &result);
+                result
+            }
+            Expr::Lsr(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvlshr(&right);
+                self.write_flags(state, &s.flags, FlagOp::ShiftRight, &left, &right, &result);
+                result
+            }
+            Expr::Asr(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvashr(&right);
+                self.write_flags(state, &s.flags, FlagOp::ShiftRight, &left, &right, &result);
+                result
+            }
+            Expr::Rol(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvrotl(&right);
+                self.write_flags(state, &s.flags, FlagOp::Other, &left, &right, &result);
+                result
+            }
+            Expr::Ror(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvrotr(&right);
+                self.write_flags(state, &s.flags, FlagOp::Other, &left, &right, &result);
+                result
+            }
+
+            Expr::MulsDp(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, s.size, state)?;
+                let result = left.sign_ext(bits(s.size)).bvmul(&right.sign_ext(bits(s.size)));
+                self.write_flags(state, &s.flags, FlagOp::Mul, &left, &right, &result);
+                result
+            }
+            Expr::MuluDp(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, s.size, state)?;
+                let result = left.zero_ext(bits(s.size)).bvmul(&right.zero_ext(bits(s.size)));
+                self.write_flags(state, &s.flags, FlagOp::MulUnsigned, &left, &right, &result);
+                result
+            }
+
+            Expr::DivuDp(s) => {
+                let (left, right) = self.eval_double(s, state)?;
+                left.bvudiv(&right)
+            }
+            Expr::ModuDp(s) => {
+                let (left, right) = self.eval_double(s, state)?;
+                left.bvurem(&right)
+            }
+            Expr::DivsDp(s) => {
+                let size = s.size;
+                let (left, right) = self.eval_double(s, state)?;
+                left.bvsdiv(&resize(&right, size).sign_ext(bits(size)))
+            }
+            Expr::ModsDp(s) => {
+                let size = s.size;
+                let (left, right) = self.eval_double(s, state)?;
+                left.bvsrem(&resize(&right, size).sign_ext(bits(size)))
+            }
+
+            Expr::Adc(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvadd(&right).bvadd(&self.eval_carry(*s.carry, size, state)?);
+                self.write_flags(state, &s.flags, FlagOp::Add, &left, &right, &result);
+                result
+            }
+            Expr::Sbb(s) => {
+                let (left, right) = self.eval_operands(*s.left, *s.right, size, state)?;
+                let result = left.bvsub(&right).bvsub(&self.eval_carry(*s.carry, size, state)?);
+                self.write_flags(state, &s.flags, FlagOp::Sub, &left, &right, &result);
+                result
+            }
+            Expr::Rlc(s) => self.eval_rotate_carry(s, true, state)?,
+            Expr::Rrc(s) => self.eval_rotate_carry(s, false, state)?,
+
+            Expr::Neg(s) => {
+                let operand = self.eval_sized(*s.operand, size, state)?;
+                let result = operand.bvneg();
+                self.write_flags(state, &s.flags, FlagOp::Sub, &ast::BV::from_u64(ctx, 0, bits(size)), &operand, &result);
+                result
+            }
+            Expr::Not(s) => {
+                let operand = self.eval_sized(*s.operand, size, state)?;
+                let result = operand.bvnot();
+                self.write_flags(state, &s.flags, FlagOp::Logic, &operand, &ast::BV::from_u64(ctx, 0, bits(size)), &result);
+                result
+            }
+            Expr::Sx(s) => {
+                let operand_size = s.operand.size();
+                let operand = self.eval_sized(*s.operand, operand_size, state)?;
+                if bits(size) > bits(operand_size) {
+                    operand.sign_ext(bits(size) - bits(operand_size))
+                } else {
+                    operand
+                }
+            }
+            Expr::Zx(s) => {
+                let operand_size = s.operand.size();
+                self.eval_sized(*s.operand, operand_size, state)?
+            }
+            Expr::LowPart(s) => self.eval(*s.operand, state)?,
+            Expr::BoolToInt(s) => {
+                let operand = self.eval(*s.operand, state)?;
+                operand._eq(&ast::BV::from_u64(ctx, 0, operand.get_size())).ite(&zero, &one)
+            }
+
+            Expr::Flag(f) => state.get_flag(ctx, &f.name).ite(&one, &zero),
+            Expr::FlagBit(f) => {
+                let bit = ast::BV::from_u64(ctx, 1, bits(f.size)).bvshl(&ast::BV::from_u64(ctx, f.bit as u64, bits(f.size)));
+                state.get_flag(ctx, &f.name).ite(&bit, &ast::BV::from_u64(ctx, 0, bits(f.size)))
+            }
+            Expr::FlagCond(f) => match f.condition {
+                Some(condition) => self.condition(state, condition)?.ite(&one, &zero),
+                None => return Err(format!("Unsupported flag condition {}", f.expr)),
+            },
+            Expr::FlagGroup(f) => match f.condition {
+                Some(condition) => self.condition(state, condition)?.ite(&one, &zero),
+                None => return Err(format!("Flag group {} has no supported condition", f.name)),
+            },
+
+            Expr::Undef(s) => {
+                error!("Undef expr {:?}", s.expr);
+                ast::BV::fresh_const(ctx, "undef", bits(size))
+            }
+            float => self.eval_float(float, state)?,
+        };
+
+        return Ok(resize(&result, size).simplify());
+    }
+
+    fn eval_operands(&self, left: Expr, right: Expr, size: usize, state: &mut SymbolicState<'ctx>) -> Result<(ast::BV<'ctx>, ast::BV<'ctx>), String> {
+        return Ok((self.eval_sized(left, size, state)?, self.eval_sized(right, size, state)?));
+    }
+
+    // Sets the written flags from an operation's operands and result the way Flags::write does,
+    // the operands are at the operation's size and the result may be wider
+    fn write_flags(&self, state: &mut SymbolicState<'ctx>, written: &Vec<String>, op: FlagOp, left: &ast::BV<'ctx>, right: &ast::BV<'ctx>, result: &ast::BV<'ctx>) {
+        if written.is_empty() {
+            return;
+        }
+
+        let ctx = self.ctx;
+        let width = left.get_size();
+        let low = result.extract(width - 1, 0);
+        let zero = ast::BV::from_u64(ctx, 0, width);
+        let sign = |v: &ast::BV<'ctx>| v.extract(width - 1, width - 1)._eq(&ast::BV::from_u64(ctx, 1, 1));
+        let bit = |v: &ast::BV<'ctx>, n: u32| v.extract(n, n)._eq(&ast::BV::from_u64(ctx, 1, 1));
+        let in_range = |amount: &ast::BV<'ctx>| amount.bvugt(&zero).and(&[&amount.bvule(&ast::BV::from_u64(ctx, width as u64, width))]);
+
+        let carry = match op {
+            // The result only equals the left operand with a non-zero right when a carry in wrapped around
+            FlagOp::Add => low.bvult(left).or(&[&low._eq(left).and(&[&right._eq(&zero).not()])]),
+            FlagOp::Sub => low.bvugt(left).or(&[&low._eq(left).and(&[&right._eq(&zero).not()])]),
+            FlagOp::Mul => {
+                let product = left.sign_ext(width).bvmul(&right.sign_ext(width));
+                product._eq(&low.sign_ext(width)).not()
+            }
+            FlagOp::MulUnsigned => {
+                let product = left.zero_ext(width).bvmul(&right.zero_ext(width));
+                product.extract(width * 2 - 1, width)._eq(&zero).not()
+            }
+            FlagOp::ShiftLeft => {
+                let out = left.bvlshr(&ast::BV::from_u64(ctx, width as u64, width).bvsub(right));
+                in_range(right).and(&[&bit(&out, 0)])
+            }
+            FlagOp::ShiftRight => {
+                let out = left.bvlshr(&right.bvsub(&ast::BV::from_u64(ctx, 1, width)));
+                in_range(right).and(&[&bit(&out, 0)])
+            }
+            FlagOp::RotateCarry(carry) => ast::Bool::from_bool(ctx, carry),
+            FlagOp::Logic | FlagOp::Other => ast::Bool::from_bool(ctx, false),
+        };
+        let overflow = match op {
+            FlagOp::Add => sign(left)._eq(&sign(right)).and(&[&sign(&low)._eq(&sign(left)).not()]),
+            FlagOp::Sub => sign(left)._eq(&sign(right)).not().and(&[&sign(&low)._eq(&sign(left)).not()]),
+            FlagOp::Mul | FlagOp::MulUnsigned => carry.clone(),
+            _ => ast::Bool::from_bool(ctx, false),
+        };
+
+        for name in written {
+            let value = match self.roles.get(name) {
+                Some(Role::Zero) => low._eq(&zero),
+                Some(Role::Negative) => sign(&low),
+                Some(Role::Positive) => sign(&low).not(),
+                Some(Role::Carry) => carry.clone(),
+                Some(Role::InvertedCarry) => if op == FlagOp::Sub { carry.not() } else { carry.clone() },
+                Some(Role::Overflow) => overflow.clone(),
+                Some(Role::HalfCarry) => bit(&left.bvxor(right).bvxor(&low), 4),
+                Some(Role::EvenParity) | Some(Role::OddParity) => {
+                    let mut odd = bit(&low, 0);
+                    for i in 1..8 {
+                        odd = odd.xor(&bit(&low, i));
+                    }
+                    if self.roles.get(name) == Some(&Role::OddParity) { odd } else { odd.not() }
+                }
+                // Architecture specific flags keep their value
+                _ => continue,
+            };
+            state.flags.insert(name.clone(), value.simplify());
+        }
+    }
+
+    // Value of the first flag with the role, errors if the architecture doesn't have one
+    fn role(&self, state: &mut SymbolicState<'ctx>, role: Role) -> Result<ast::Bool<'ctx>, String> {
+        for (name, r) in self.roles.iter() {
+            if *r == role {
+                return Ok(state.get_flag(self.ctx, name));
+            }
+        }
+        return Err(String::from("Architecture has no flag for the condition"));
+    }
+
+    fn negative(&self, state: &mut SymbolicState<'ctx>) -> Result<ast::Bool<'ctx>, String> {
+        return self.role(state, Role::Negative).or_else(|_| self.role(state, Role::Positive).map(|p| p.not()));
+    }
+
+    // Whether the last subtraction borrowed, whichever way the architecture stores carry
+    fn borrow(&self, state: &mut SymbolicState<'ctx>) -> Result<ast::Bool<'ctx>, String> {
+        return self.role(state, Role::Carry).or_else(|_| self.role(state, Role::InvertedCarry).map(|c| c.not()));
+    }
+
+    // Flag conditions in terms of flag roles, the same as Flags::condition
+    fn condition(&self, state: &mut SymbolicState<'ctx>, condition: Condition) -> Result<ast::Bool<'ctx>, String> {
+        use flags::Condition::*;
+
+        let result = match condition {
+            E => self.role(state, Role::Zero)?,
+            Ne => self.role(state, Role::Zero)?.not(),
+            Slt => self.negative(state)?._eq(&self.role(state, Role::Overflow)?).not(),
+            Sge => self.negative(state)?._eq(&self.role(state, Role::Overflow)?),
+            Sle => {
+                let less = self.negative(state)?._eq(&self.role(state, Role::Overflow)?).not();
+                self.role(state, Role::Zero)?.or(&[&less])
+            }
+            Sgt => {
+                let greater_equal = self.negative(state)?._eq(&self.role(state, Role::Overflow)?);
+                self.role(state, Role::Zero)?.not().and(&[&greater_equal])
+            }
+            Ult => self.borrow(state)?,
+            Uge => self.borrow(state)?.not(),
+            Ule => self.borrow(state)?.or(&[&self.role(state, Role::Zero)?]),
+            Ugt => self.borrow(state)?.not().and(&[&self.role(state, Role::Zero)?.not()]),
+            Neg => self.negative(state)?,
+            Pos => self.negative(state)?.not(),
+            O => self.role(state, Role::Overflow)?,
+            No => self.role(state, Role::Overflow)?.not(),
+        };
+        return Ok(result);
+    }
+
+    // Rotates through carry bit by bit, the amount has to be concrete
+    fn eval_rotate_carry(&self, expr: Carry, left: bool, state: &mut SymbolicState<'ctx>) -> Result<ast::BV<'ctx>, String> {
+        let ctx = self.ctx;
+        let size = expr.size;
+        let (mut value, amount) = self.eval_operands(*expr.left, *expr.right, size, state)?;
+        let mut carry = self.eval_carry(*expr.carry, 1, state)?;
+
+        let width = bits(size);
+        let amount = match amount.simplify().as_u64() {
+            Some(amount) => amount % (width as u64 + 1),
+            None => {
+                error!("Rotate through carry by a symbolic amount");
+                return Ok(ast::BV::fresh_const(ctx, "rotate", width));
+            }
+        };
+
+        let original = value.clone();
+        for _ in 0..amount {
+            if left {
+                let out = value.extract(width - 1, width - 1);
+                value = value.extract(width - 2, 0).concat(&carry.extract(0, 0));
+                carry = out;
+            } else {
+                let out = value.extract(0, 0);
+                value = carry.extract(0, 0).concat(&value.extract(width - 1, 1));
+                carry = out;
+            }
+        }
+
+        // Rotated out carry replaces the carry flag, the other flags come from the result
+        let carry = carry.extract(0, 0)._eq(&ast::BV::from_u64(ctx, 1, 1));
+        self.write_flags(state, &expr.flags, FlagOp::Other, &original, &ast::BV::from_u64(ctx, amount, width), &value);
+        for name in &expr.flags {
+            if let Some(Role::Carry) | Some(Role::InvertedCarry) = self.roles.get(name) {
+                state.flags.insert(name.clone(), carry.clone());
+            }
+        }
+        return Ok(value);
+    }
+
+    // This z3 has no floating point sort, so float operations only evaluate on concrete operands
+    fn eval_float(&self, expr: Expr, state: &mut SymbolicState<'ctx>) -> Result<ast::BV<'ctx>, String> {
+        let size = expr.size();
+
+        let value = match expr {
+            Expr::Fadd(s) => self.float_binary(*s.left, *s.right, size, state, |l, r| l + r)?,
+            Expr::Fsub(s) => self.float_binary(*s.left, *s.right, size, state, |l, r| l - r)?,
+            Expr::Fmul(s) => self.float_binary(*s.left, *s.right, size, state, |l, r| l * r)?,
+            Expr::Fdiv(s) => self.float_binary(*s.left, *s.right, size, state, |l, r| l / r)?,
+
+            Expr::Fsqrt(s) => self.float_unary(*s.operand, size, size, state, f64::sqrt)?,
+            Expr::Fneg(s) => self.float_unary(*s.operand, size, size, state, |v| -v)?,
+            Expr::Fabs(s) => self.float_unary(*s.operand, size, size, state, f64::abs)?,
+            Expr::RoundToInt(s) => self.float_unary(*s.operand, size, size, state, round_even)?,
+            Expr::Floor(s) => self.float_unary(*s.operand, size, size, state, f64::floor)?,
+            Expr::Ceil(s) => self.float_unary(*s.operand, size, size, state, f64::ceil)?,
+            Expr::Ftrunc(s) => self.float_unary(*s.operand, size, size, state, f64::trunc)?,
+            Expr::FloatConv(s) => {
+                let operand_size = s.operand.size();
+                self.float_unary(*s.operand, operand_size, size, state, |v| v)?
+            }
+            Expr::FloatToInt(s) => {
+                let operand_size = s.operand.size();
+                self.concrete_float(*s.operand, operand_size, state)?.map(|v| truncate(v.trunc() as i128 as u128, size))
+            }
+            Expr::IntToFloat(s) => {
+                let operand_size = s.operand.size();
+                match concrete(&self.eval_sized(*s.operand, operand_size, state)?) {
+                    Some(v) => Some(from_float(sign_extend(v, operand_size) as f64, size)?),
+                    None => None,
+                }
+            }
+
+            Expr::FcmpE(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l == r)?,
+            Expr::FcmpNe(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l != r)?,
+            Expr::FcmpLt(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l < r)?,
+            Expr::FcmpLe(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l <= r)?,
+            Expr::FcmpGe(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l >= r)?,
+            Expr::FcmpGt(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l > r)?,
+            Expr::FcmpO(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| !l.is_nan() && !r.is_nan())?,
+            Expr::FcmpUo(s) => self.float_compare(*s.left, *s.right, s.size, state, |l, r| l.is_nan() || r.is_nan())?,
+
+            _ => return Err(String::from("Not a float expression")),
+        };
+
+        match value {
+            Some(value) => return Ok(from_u128(self.ctx, value, size)),
+            None => {
+                error!("Float operation on a symbolic value");
+                return Ok(ast::BV::fresh_const(self.ctx, "float", bits(size)));
+            }
+        }
+    }
+
+    fn concrete_float(&self, expr: Expr, size: usize, state: &mut SymbolicState<'ctx>) -> Result<Option<f64>, String> {
+        return match concrete(&self.eval_sized(expr, size, state)?) {
+            Some(value) => to_float(value, size).map(Some),
+            None => Ok(None),
+        };
+    }
+
+    fn float_unary<F: Fn(f64) -> f64>(&self, operand: Expr, operand_size: usize, size: usize, state: &mut SymbolicState<'ctx>, op: F) -> Result<Option<u128>, String> {
+        return match self.concrete_float(operand, operand_size, state)? {
+            Some(value) => from_float(op(value), size).map(Some),
+            None => Ok(None),
+        };
+    }
+
+    fn float_binary<F: Fn(f64, f64) -> f64>(&self, left: Expr, right: Expr, size: usize, state: &mut SymbolicState<'ctx>, op: F) -> Result<Option<u128>, String> {
+        let left = self.concrete_float(left, size, state)?;
+        let right = self.concrete_float(right, size, state)?;
+        return match (left, right) {
+            (Some(left), Some(right)) => from_float(op(left, right), size).map(Some),
+            _ => Ok(None),
+        };
+    }
+
+    fn float_compare<F: Fn(f64, f64) -> bool>(&self, left: Expr, right: Expr, size: usize, state: &mut SymbolicState<'ctx>, op: F) -> Result<Option<u128>, String> {
+        let left = self.concrete_float(left, size, state)?;
+        let right = self.concrete_float(right, size, state)?;
+        return match (left, right) {
+            (Some(left), Some(right)) => Ok(Some(if op(left, right) {1} else {0})),
+            _ => Ok(None),
+        };
+    }
+
+    // Syscalls aren't modelled, the return value is left unconstrained
+    fn syscall(&self, state: &mut SymbolicState<'ctx>) -> Result<(), String> {
+        let number = state.get_reg(self.ctx, &self.abi.number).simplify();
+        let name = number.as_u64().and_then(|n| syscalls::name(self.abi.table, n)).unwrap_or("unknown");
+        info!("0x{:x} Syscall {} returns an unconstrained value", state.addr, name);
+        let ret = ast::BV::fresh_const(self.ctx, "syscall", bits(state.defs.address_size));
+        state.set_reg(self.ctx, &self.abi.ret, ret);
+        return Ok(());
+    }
+
+    fn eval_sized(&self, expr: Expr, size: usize, state: &mut SymbolicState<'ctx>) -> Result<ast::BV<'ctx>, String> {
+        return Ok(resize(&self.eval(expr, state)?, size));
+    }
+
+    // Carry operands are booleans, brought up to the size of the operation
+    fn eval_carry(&self, expr: Expr, size: usize, state: &mut SymbolicState<'ctx>) -> Result<ast::BV<'ctx>, String> {
+        let carry = self.eval(expr, state)?;
+        let carry = carry._eq(&ast::BV::from_u64(self.ctx, 0, carry.get_size())).not();
+        return Ok(carry.ite(&ast::BV::from_u64(self.ctx, 1, bits(size)), &ast::BV::from_u64(self.ctx, 0, bits(size))));
+    }
+
+    // Double precision dividend built from its halves, and the divisor extended to match
+    fn eval_double(&self, expr: DivDp, state: &mut SymbolicState<'ctx>) -> Result<(ast::BV<'ctx>, ast::BV<'ctx>), String> {
+        let high = self.eval_sized(*expr.high, expr.size, state)?;
+        let low = self.eval_sized(*expr.low, expr.size, state)?;
+        let right = self.eval_sized(*expr.right, expr.size, state)?;
+        return Ok((high.concat(&low), right.zero_ext(bits(expr.size))));
+    }
+}
+
+fn register_defs(program: &Program) -> Rc<Registers> {
+    return match program.arch() {
+        Ok(arch) => Rc::new(Registers::new(&arch)),
+        Err(err) => {
+            error!("{}", err);
+            Rc::new(Registers::empty())
+        }
+    };
+}
+
+// Initial contents of the program's segments, the stack and heap are left out
+fn program_image(program: &Program) -> Rc<Memory> {
+    let mut image = Memory::new();
+    image.map_program(program);
+    return Rc::new(image);
+}
+
+// Width in bits of a value of the given size, booleans are kept as a byte
+fn bits(size: usize) -> u32 {
+    if size == 0 {
+        return 8;
+    }
+    return size as u32 * 8;
+}
+
+// Value of a concrete bitvector, bitvectors wider than 64 bits are read in two halves
+fn concrete(value: &ast::BV) -> Option<u128> {
+    let value = value.simplify();
+    let width = value.get_size();
+    if width <= 64 {
+        return value.as_u64().map(|v| v as u128);
+    }
+    let low = value.extract(63, 0).simplify().as_u64()?;
+    let high = value.extract(width - 1, 64).simplify().as_u64()?;
+    return Some(((high as u128) << 64) | low as u128);
+}
+
+fn from_u128<'ctx>(ctx: &'ctx z3::Context, value: u128, size: usize) -> ast::BV<'ctx> {
+    if bits(size) <= 64 {
+        return ast::BV::from_u64(ctx, value as u64, bits(size));
+    }
+    let high = ast::BV::from_u64(ctx, (value >> 64) as u64, bits(size) - 64);
+    return high.concat(&ast::BV::from_u64(ctx, value as u64, 64));
+}
+
+// Truncates or zero extends a bitvector to the given size in bytes
+fn resize<'ctx>(value: &ast::BV<'ctx>, size: usize) -> ast::BV<'ctx> {
+    let current = value.get_size();
+    let target = bits(size);
+    if current > target {
+        return value.extract(target - 1, 0);
+    }
+    if current < target {
+        return value.zero_ext(target - current);
+    }
+    return value.clone();
+}
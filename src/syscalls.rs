@@ -0,0 +1,629 @@
+use std::collections::HashMap;
+use binaryninja::architecture::{Architecture, CoreArchitecture};
+use state::*;
+use procedures::read_string;
+use heap::MAX_ALLOCATION;
+use registers::ArchKind;
+
+// Errno values, handlers return them negated like the kernel does
+pub const ENOENT: u64 = 2;
+pub const EBADF: u64 = 9;
+pub const ENOMEM: u64 = 12;
+pub const EFAULT: u64 = 14;
+pub const EEXIST: u64 = 17;
+pub const EINVAL: u64 = 22;
+pub const ENOTTY: u64 = 25;
+pub const EFBIG: u64 = 27;
+pub const ESPIPE: u64 = 29;
+pub const ENOSYS: u64 = 38;
+
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// arch_prctl codes for the x86-64 segment bases
+const ARCH_SET_GS: u64 = 0x1001;
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+const ARCH_GET_GS: u64 = 0x1004;
+// GDT entry set_thread_area hands out for TLS on x86
+const TLS_ENTRY: u64 = 12;
+
+pub fn errno(value: u64) -> u64 {
+    return (-(value as i64)) as u64;
+}
+
+// Syscall numbering used by the architecture
+#[derive(Clone, Copy, PartialEq)]
+pub enum Table {
+    X86_64,
+    X86,
+    Arm,
+    // The asm-generic numbers used by AArch64 and RISC-V
+    Generic,
+}
+
+// Registers a syscall takes its number and arguments from
+#[derive(Clone)]
+pub struct Abi {
+    pub table: Table,
+    pub number: String,
+    pub args: Vec<String>,
+    pub ret: String,
+    // Reported by uname
+    pub machine: String,
+}
+
+fn names(regs: &[&str]) -> Vec<String> {
+    return regs.iter().map(|r| String::from(*r)).collect();
+}
+
+impl Abi {
+    pub fn x86_64() -> Abi {
+        return Abi {
+            table: Table::X86_64,
+            number: String::from("rax"),
+            args: names(&["rdi", "rsi", "rdx", "r10", "r8", "r9"]),
+            ret: String::from("rax"),
+            machine: String::from("x86_64"),
+        }
+    }
+
+    pub fn x86() -> Abi {
+        return Abi {
+            table: Table::X86,
+            number: String::from("eax"),
+            args: names(&["ebx", "ecx", "edx", "esi", "edi", "ebp"]),
+            ret: String::from("eax"),
+            machine: String::from("i686"),
+        }
+    }
+
+    pub fn arm() -> Abi {
+        return Abi {
+            table: Table::Arm,
+            number: String::from("r7"),
+            args: names(&["r0", "r1", "r2", "r3", "r4", "r5"]),
+            ret: String::from("r0"),
+            machine: String::from("armv7l"),
+        }
+    }
+
+    pub fn aarch64() -> Abi {
+        return Abi {
+            table: Table::Generic,
+            number: String::from("x8"),
+            args: names(&["x0", "x1", "x2", "x3", "x4", "x5"]),
+            ret: String::from("x0"),
+            machine: String::from("aarch64"),
+        }
+    }
+
+    pub fn riscv(address_size: usize) -> Abi {
+        return Abi {
+            table: Table::Generic,
+            number: String::from("a7"),
+            args: names(&["a0", "a1", "a2", "a3", "a4", "a5"]),
+            ret: String::from("a0"),
+            machine: String::from(if address_size == 4 { "riscv32" } else { "riscv64" }),
+        }
+    }
+
+    pub fn for_arch(arch: &CoreArchitecture) -> Abi {
+        return match ArchKind::of(arch) {
+            ArchKind::X86_64 => Abi::x86_64(),
+            ArchKind::X86 => Abi::x86(),
+            ArchKind::Aarch64 => Abi::aarch64(),
+            ArchKind::Arm => Abi::arm(),
+            ArchKind::Riscv => Abi::riscv(arch.address_size()),
+            ArchKind::Unknown => {
+                warn!("No syscall ABI known for {}, using RISC-V", arch.name().as_ref());
+                Abi::riscv(arch.address_size())
+            }
+        };
+    }
+}
+
+// Name of a syscall number, only covering the syscalls programs commonly make
+pub fn name(table: Table, number: u64) -> Option<&'static str> {
+    let name = match table {
+        Table::X86_64 => match number {
+            0 => "read", 1 => "write", 2 => "open", 3 => "close", 5 => "fstat", 8 => "lseek",
+            9 => "mmap", 10 => "mprotect", 11 => "munmap", 12 => "brk", 13 => "rt_sigaction",
+            14 => "rt_sigprocmask", 16 => "ioctl", 19 => "readv", 20 => "writev", 21 => "access",
+            32 => "dup", 39 => "getpid", 60 => "exit", 63 => "uname", 72 => "fcntl", 79 => "getcwd", 89 => "readlink",
+            102 => "getuid", 104 => "getgid", 107 => "geteuid", 108 => "getegid", 158 => "arch_prctl",
+            186 => "gettid", 201 => "time", 202 => "futex", 218 => "set_tid_address", 228 => "clock_gettime",
+            231 => "exit_group", 257 => "openat", 262 => "newfstatat", 273 => "set_robust_list",
+            302 => "prlimit64", 318 => "getrandom", 334 => "rseq",
+            _ => return None,
+        },
+        // 32 bit ARM shares most of the i386 numbers
+        Table::X86 | Table::Arm => match number {
+            1 => "exit", 3 => "read", 4 => "write", 5 => "open", 6 => "close", 19 => "lseek",
+            20 => "getpid", 33 => "access", 41 => "dup", 45 => "brk", 54 => "ioctl", 55 => "fcntl", 85 => "readlink",
+            91 => "munmap", 122 => "uname", 125 => "mprotect", 145 => "readv", 146 => "writev",
+            174 => "rt_sigaction", 175 => "rt_sigprocmask", 183 => "getcwd", 192 => "mmap2",
+            197 => "fstat64", 199 => "getuid", 200 => "getgid", 201 => "geteuid", 202 => "getegid",
+            224 => "gettid", 240 => "futex",
+            13 if table == Table::X86 => "time",
+            243 if table == Table::X86 => "set_thread_area",
+            252 if table == Table::X86 => "exit_group",
+            258 if table == Table::X86 => "set_tid_address",
+            265 if table == Table::X86 => "clock_gettime",
+            295 if table == Table::X86 => "openat",
+            311 if table == Table::X86 => "set_robust_list",
+            355 if table == Table::X86 => "getrandom",
+            248 if table == Table::Arm => "exit_group",
+            256 if table == Table::Arm => "set_tid_address",
+            263 if table == Table::Arm => "clock_gettime",
+            322 if table == Table::Arm => "openat",
+            338 if table == Table::Arm => "set_robust_list",
+            384 if table == Table::Arm => "getrandom",
+            0xf0005 if table == Table::Arm => "set_tls",
+            _ => return None,
+        },
+        Table::Generic => match number {
+            17 => "getcwd", 23 => "dup", 25 => "fcntl", 29 => "ioctl", 48 => "faccessat", 56 => "openat", 57 => "close",
+            62 => "lseek", 63 => "read", 64 => "write", 65 => "readv", 66 => "writev", 78 => "readlinkat",
+            79 => "newfstatat", 80 => "fstat", 93 => "exit", 94 => "exit_group", 96 => "set_tid_address",
+            98 => "futex", 99 => "set_robust_list", 113 => "clock_gettime", 134 => "rt_sigaction",
+            135 => "rt_sigprocmask", 160 => "uname", 172 => "getpid", 174 => "getuid", 175 => "geteuid",
+            176 => "getgid", 177 => "getegid", 178 => "gettid", 214 => "brk", 215 => "munmap",
+            222 => "mmap", 226 => "mprotect", 261 => "prlimit64", 278 => "getrandom", 293 => "rseq",
+            _ => return None,
+        },
+    };
+    return Some(name);
+}
+
+// Handlers get the six argument registers and return the value for the return register, with
+// errors as negated errno values. An Err stops emulation.
+pub type Handler = Box<Fn(&mut State, &Abi, &[u64]) -> Result<u64, String>>;
+
+// Linux syscalls, keyed by name so one handler serves every architecture
+pub struct Syscalls {
+    pub abi: Abi,
+    handlers: HashMap<String, Handler>,
+}
+
+impl Syscalls {
+    pub fn new(abi: Abi) -> Syscalls {
+        return Syscalls {
+            abi: abi,
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn linux(abi: Abi) -> Syscalls {
+        let mut syscalls = Syscalls::new(abi);
+
+        syscalls.register("read", Box::new(read));
+        syscalls.register("write", Box::new(write));
+        syscalls.register("readv", Box::new(readv));
+        syscalls.register("writev", Box::new(writev));
+        syscalls.register("open", Box::new(open));
+        syscalls.register("openat", Box::new(openat));
+        syscalls.register("close", Box::new(close));
+        syscalls.register("dup", Box::new(dup));
+        syscalls.register("lseek", Box::new(lseek));
+        syscalls.register("ioctl", Box::new(ioctl));
+        syscalls.register("fcntl", Box::new(fcntl));
+        syscalls.register("access", Box::new(access));
+        syscalls.register("faccessat", Box::new(faccessat));
+        for name in ["readlink", "readlinkat"].iter() {
+            syscalls.register(name, Box::new(missing));
+        }
+        syscalls.register("getcwd", Box::new(getcwd));
+        syscalls.register("exit", Box::new(exit));
+        syscalls.register("exit_group", Box::new(exit));
+        syscalls.register("brk", Box::new(brk));
+        syscalls.register("mmap", Box::new(mmap));
+        syscalls.register("mmap2", Box::new(mmap2));
+        syscalls.register("munmap", Box::new(munmap));
+        syscalls.register("mprotect", Box::new(mprotect));
+        syscalls.register("getrandom", Box::new(getrandom));
+        syscalls.register("uname", Box::new(uname));
+        syscalls.register("clock_gettime", Box::new(clock_gettime));
+        syscalls.register("time", Box::new(time));
+        syscalls.register("getpid", Box::new(getpid));
+        syscalls.register("arch_prctl", Box::new(arch_prctl));
+        syscalls.register("set_tid_address", Box::new(set_tid_address));
+        syscalls.register("set_thread_area", Box::new(set_thread_area));
+        syscalls.register("set_tls", Box::new(set_tls));
+        syscalls.register("gettid", Box::new(getpid));
+        for name in ["getuid", "getgid", "geteuid", "getegid"].iter() {
+            syscalls.register(name, Box::new(getuid));
+        }
+        // Signals and threads don't exist in the emulator, so these succeed without doing anything
+        for name in ["rt_sigaction", "rt_sigprocmask", "futex", "set_robust_list"].iter() {
+            syscalls.register(name, Box::new(ignore));
+        }
+        return syscalls;
+    }
+
+    pub fn register(&mut self, name: &str, handler: Handler) {
+        self.handlers.insert(String::from(name), handler);
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<Handler> {
+        return self.handlers.remove(name);
+    }
+
+    // Runs the syscall the registers ask for and writes its result to the return register
+    pub fn call(&self, state: &mut State) -> Result<(), String> {
+        let number = state.regs.get(&self.abi.number) as u64;
+        let args: Vec<u64> = self.abi.args.iter().map(|reg| state.regs.get(reg) as u64).collect();
+
+        let handler = name(self.abi.table, number).and_then(|name| self.handlers.get(name).map(|h| (name, h)));
+        let result = match handler {
+            Some((name, handler)) => {
+                info!("0x{:x} Syscall {}(0x{:x}, 0x{:x}, 0x{:x})", state.addr, name, args[0], args[1], args[2]);
+                handler(state, &self.abi, &args)?
+            }
+            None => {
+                warn!("0x{:x} Unsupported syscall {}", state.addr, number);
+                errno(ENOSYS)
+            }
+        };
+
+        info!(" > Syscall returned 0x{:x}", result);
+        state.regs.set(&self.abi.ret, result as u128);
+        return Ok(());
+    }
+}
+
+const PID: u64 = 1000;
+const UID: u64 = 1000;
+// Time reported to the program, fixed so runs are repeatable
+const EPOCH_SECONDS: u64 = 1700000000;
+
+fn perms(prot: u64) -> Perms {
+    return Perms {read: prot & PROT_READ != 0, write: prot & PROT_WRITE != 0, exec: prot & PROT_EXEC != 0};
+}
+
+/* These implement the syscalls against the emulated state */
+
+// Turns a file system result into a return value
+fn result(value: Result<u64, u64>) -> u64 {
+    return match value {
+        Ok(value) => value,
+        Err(value) => errno(value),
+    };
+}
+
+fn read(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let (fd, buf, n) = (args[0], args[1], args[2] as usize);
+    let bytes = match state.fs.remaining(fd) {
+        Ok(remaining) => remaining[..n.min(remaining.len())].to_vec(),
+        Err(err) => return Ok(errno(err)),
+    };
+    if state.memory.write_bytes(buf, &bytes).is_err() {
+        return Ok(errno(EFAULT));
+    }
+    state.fs.consume(fd, bytes.len()).ok();
+    return Ok(bytes.len() as u64);
+}
+
+fn write(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let (fd, buf, n) = (args[0], args[1], args[2] as usize);
+    let bytes = match state.memory.read_bytes(buf, n) {
+        Ok(bytes) => bytes,
+        Err(_) => return Ok(errno(EFAULT)),
+    };
+    return Ok(result(state.fs.write(fd, &bytes).map(|n| n as u64)));
+}
+
+// The buffers of an iovec array as (base, len) pairs
+fn iovecs(state: &State, addr: u64, count: u64) -> Result<Vec<(u64, usize)>, String> {
+    let size = state.regs.address_size;
+    let mut vecs = Vec::new();
+    for i in 0..count {
+        let entry = addr + i * 2 * size as u64;
+        let base = state.memory.load(entry, size)? as u64;
+        let len = state.memory.load(entry + size as u64, size)? as usize;
+        vecs.push((base, len));
+    }
+    return Ok(vecs);
+}
+
+fn readv(state: &mut State, abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let vecs = match iovecs(state, args[1], args[2]) {
+        Ok(vecs) => vecs,
+        Err(_) => return Ok(errno(EFAULT)),
+    };
+    let mut total = 0;
+    for (base, len) in vecs {
+        let n = read(state, abi, &[args[0], base, len as u64])?;
+        if (n as i64) < 0 {
+            return Ok(n);
+        }
+        total += n;
+        if (n as usize) < len {
+            break;
+        }
+    }
+    return Ok(total);
+}
+
+fn writev(state: &mut State, abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let vecs = match iovecs(state, args[1], args[2]) {
+        Ok(vecs) => vecs,
+        Err(_) => return Ok(errno(EFAULT)),
+    };
+    let mut total = 0;
+    for (base, len) in vecs {
+        let n = write(state, abi, &[args[0], base, len as u64])?;
+        if (n as i64) < 0 {
+            return Ok(n);
+        }
+        total += n;
+    }
+    return Ok(total);
+}
+
+fn open_path(state: &mut State, path: u64, flags: u64) -> u64 {
+    let path = match read_string(state, path) {
+        Ok(path) => String::from_utf8_lossy(&path).into_owned(),
+        Err(_) => return errno(EFAULT),
+    };
+    let fd = result(state.fs.open(&path, flags));
+    info!("0x{:x} Opened {} as 0x{:x}", state.addr, path, fd);
+    return fd;
+}
+
+fn open(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(open_path(state, args[0], args[1]));
+}
+
+// Paths are all absolute in the file system, so the directory descriptor isn't needed
+fn openat(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(open_path(state, args[1], args[2]));
+}
+
+fn close(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(result(state.fs.close(args[0]).map(|_| 0)));
+}
+
+fn dup(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(result(state.fs.dup(args[0])));
+}
+
+fn lseek(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(result(state.fs.seek(args[0], args[1] as i64, args[2])));
+}
+
+// Nothing is a terminal
+fn ioctl(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(result(state.fs.descriptor(args[0]).and(Err::<u64, u64>(ENOTTY))));
+}
+
+fn fcntl(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(result(state.fs.descriptor(args[0]).map(|_| 0)));
+}
+
+fn exists(state: &State, path: u64) -> u64 {
+    return match read_string(state, path) {
+        Ok(ref path) if state.fs.file(&String::from_utf8_lossy(path)).is_some() => 0,
+        Ok(_) => errno(ENOENT),
+        Err(_) => errno(EFAULT),
+    };
+}
+
+// Every file is accessible to the program if it exists
+fn access(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(exists(state, args[0]));
+}
+
+fn faccessat(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(exists(state, args[1]));
+}
+
+// There are no symbolic links
+fn missing(_state: &mut State, _abi: &Abi, _args: &[u64]) -> Result<u64, String> {
+    return Ok(errno(ENOENT));
+}
+
+fn getcwd(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    if args[1] < 2 {
+        return Ok(errno(EINVAL));
+    }
+    if state.memory.write_bytes(args[0], b"/\0").is_err() {
+        return Ok(errno(EFAULT));
+    }
+    return Ok(2);
+}
+
+fn ignore(_state: &mut State, _abi: &Abi, _args: &[u64]) -> Result<u64, String> {
+    return Ok(0);
+}
+
+fn getpid(_state: &mut State, _abi: &Abi, _args: &[u64]) -> Result<u64, String> {
+    return Ok(PID);
+}
+
+fn getuid(_state: &mut State, _abi: &Abi, _args: &[u64]) -> Result<u64, String> {
+    return Ok(UID);
+}
+
+fn arch_prctl(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let (code, addr) = (args[0], args[1]);
+    match code {
+        ARCH_SET_FS => state.regs.set("fsbase", addr as u128),
+        ARCH_SET_GS => state.regs.set("gsbase", addr as u128),
+        ARCH_GET_FS | ARCH_GET_GS => {
+            let base = state.regs.get(if code == ARCH_GET_FS { "fsbase" } else { "gsbase" });
+            if state.memory.store(addr, 8, base).is_err() {
+                return Ok(errno(EFAULT));
+            }
+        }
+        _ => return Ok(errno(EINVAL)),
+    }
+    return Ok(0);
+}
+
+// Returns the caller's thread id like the kernel does
+fn set_tid_address(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    state.clear_child_tid = args[0];
+    return Ok(PID);
+}
+
+// x86 passes a user_desc, only its base address matters since gs is used as a flat base
+fn set_thread_area(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let desc = args[0];
+    let (entry, base) = match (state.memory.load(desc, 4), state.memory.load(desc + 4, 4)) {
+        (Ok(entry), Ok(base)) => (entry as u64, base),
+        _ => return Ok(errno(EFAULT)),
+    };
+    // An entry number of -1 asks for a free entry, which is written back
+    if entry == 0xffffffff {
+        state.memory.store(desc, 4, TLS_ENTRY as u128)?;
+    }
+    state.regs.set("gsbase", base);
+    return Ok(0);
+}
+
+fn set_tls(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    state.regs.set("tpidruro", args[0] as u128);
+    return Ok(0);
+}
+
+fn exit(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    info!("0x{:x} Program exited with status {}", state.addr, args[0] & 0xff);
+    state.exited = Some(args[0] & 0xff);
+    return Ok(0);
+}
+
+// A failed request returns the current break
+fn brk(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    return Ok(state.set_brk(args[0]));
+}
+
+fn mmap(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let (addr, len, prot, flags) = (args[0], args[1], args[2], args[3]);
+    if len == 0 {
+        return Ok(errno(EINVAL));
+    }
+    // Pages are backed as soon as they're mapped, so mappings are capped like allocations
+    if len > MAX_ALLOCATION {
+        return Ok(errno(ENOMEM));
+    }
+    // File backed mappings get a copy of the file, writes to them aren't written back
+    let contents = if flags & MAP_ANONYMOUS == 0 {
+        let (fd, offset) = (args[4], args[5] as usize);
+        if offset & (PAGE_SIZE as usize - 1) != 0 {
+            return Ok(errno(EINVAL));
+        }
+        let path = match state.fs.descriptor(fd) {
+            Ok(descriptor) => descriptor.path.clone(),
+            Err(err) => return Ok(errno(err)),
+        };
+        let data = state.fs.file(&path).map(|data| data.clone()).unwrap_or(Vec::new());
+        let end = data.len().min(offset.saturating_add(len as usize));
+        if offset < end { data[offset..end].to_vec() } else { Vec::new() }
+    } else {
+        Vec::new()
+    };
+
+    let start = if flags & MAP_FIXED != 0 {
+        if addr & (PAGE_SIZE - 1) != 0 {
+            return Ok(errno(EINVAL));
+        }
+        // Fixed mappings replace whatever was there
+        if state.memory.unmap(addr, len).and_then(|_| state.memory.map(addr, len, perms(prot))).is_err() {
+            return Ok(errno(ENOMEM));
+        }
+        addr
+    } else {
+        match state.map_anonymous(addr, len, perms(prot)) {
+            Some(start) => start,
+            None => return Ok(errno(ENOMEM)),
+        }
+    };
+
+    state.memory.init_bytes(start, &contents);
+    return Ok(start);
+}
+
+// mmap with the offset in pages
+fn mmap2(state: &mut State, abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let mut args = args.to_vec();
+    args[5] = match args[5].checked_mul(PAGE_SIZE) {
+        Some(offset) => offset,
+        None => return Ok(errno(EINVAL)),
+    };
+    return mmap(state, abi, &args);
+}
+
+fn munmap(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    if args[0] & (PAGE_SIZE - 1) != 0 || args[1] == 0 {
+        return Ok(errno(EINVAL));
+    }
+    if state.memory.unmap(args[0], args[1]).is_err() {
+        return Ok(errno(EINVAL));
+    }
+    return Ok(0);
+}
+
+fn mprotect(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    if args[0] & (PAGE_SIZE - 1) != 0 {
+        return Ok(errno(EINVAL));
+    }
+    if state.memory.protect(args[0], args[1], perms(args[2])).is_err() {
+        return Ok(errno(ENOMEM));
+    }
+    return Ok(0);
+}
+
+// Fills the buffer from a fixed seed so runs are repeatable
+// Like Linux, bigger requests are cut short rather than filled in one call
+const GETRANDOM_MAX: u64 = 0x1ffffff;
+
+fn getrandom(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let (buf, n) = (args[0], args[1].min(GETRANDOM_MAX) as usize);
+    let mut x: u64 = 0x2545f4914f6cdd1d ^ buf;
+    let mut bytes = Vec::with_capacity(n);
+    while bytes.len() < n {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        bytes.push(x as u8);
+    }
+    if state.memory.write_bytes(buf, &bytes).is_err() {
+        return Ok(errno(EFAULT));
+    }
+    return Ok(n as u64);
+}
+
+fn uname(state: &mut State, abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let fields = ["Linux", "naf", "5.15.0", "#1 SMP", abi.machine.as_str(), "(none)"];
+    let mut bytes = vec![0u8; 65 * fields.len()];
+    for (i, field) in fields.iter().enumerate() {
+        bytes[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+    }
+    if state.memory.write_bytes(args[0], &bytes).is_err() {
+        return Ok(errno(EFAULT));
+    }
+    return Ok(0);
+}
+
+fn clock_gettime(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    let size = state.regs.address_size;
+    let tp = args[1];
+    if state.memory.store(tp, size, EPOCH_SECONDS as u128).and_then(|_| state.memory.store(tp + size as u64, size, 0)).is_err() {
+        return Ok(errno(EFAULT));
+    }
+    return Ok(0);
+}
+
+fn time(state: &mut State, _abi: &Abi, args: &[u64]) -> Result<u64, String> {
+    if args[0] != 0 {
+        let size = state.regs.address_size;
+        if state.memory.store(args[0], size, EPOCH_SECONDS as u128).is_err() {
+            return Ok(errno(EFAULT));
+        }
+    }
+    return Ok(EPOCH_SECONDS);
+}
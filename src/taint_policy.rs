@@ -0,0 +1,144 @@
+use std::fmt;
+use taint_tracker::Location;
+
+// How many bytes a buffer source taints
+#[derive(Clone, Debug)]
+pub enum Size {
+    Fixed(usize),
+    // Taken from another argument of the call when its value is known
+    Arg(usize),
+}
+
+// Where tainted data comes from
+#[derive(Clone, Debug)]
+pub enum Source {
+    // The value a call to the function returns
+    Return { function: String },
+    // The buffer an argument of the function points to, filled by the call
+    Buffer { function: String, arg: usize, size: Size },
+    // Memory that is tainted from the start, each byte labeled with the name and its index
+    Memory { name: String, location: Location, size: usize },
+    // A register when execution reaches the address
+    Register { addr: u64, reg: String },
+}
+
+impl Source {
+    pub fn function(&self) -> Option<&str> {
+        return match *self {
+            Source::Return { ref function } | Source::Buffer { ref function, .. } => Some(function.as_str()),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Source::Return { ref function } => write!(f, "return value of {}", function),
+            Source::Buffer { ref function, arg, .. } => write!(f, "buffer in argument {} of {}", arg, function),
+            Source::Memory { ref name, ref location, size } => write!(f, "{} (0x{:x} bytes at {})", name, size, location),
+            Source::Register { addr, ref reg } => write!(f, "{} at 0x{:x}", reg, addr),
+        };
+    }
+}
+
+// Where tainted data shouldn't end up
+#[derive(Clone, Debug)]
+pub enum Sink {
+    // An argument of a call to the function, or the string it points to when pointee is set
+    Argument { function: String, arg: usize, pointee: bool },
+    // The target of a call through a register or memory
+    CallTarget,
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Sink::Argument { ref function, arg, pointee: true } => write!(f, "data pointed to by argument {} of {}", arg, function),
+            Sink::Argument { ref function, arg, pointee: false } => write!(f, "argument {} of {}", arg, function),
+            Sink::CallTarget => write!(f, "indirect call target"),
+        };
+    }
+}
+
+// Tainted data reaching a sink, with the sources that were live and the instructions that moved
+// tainted data on the way there
+#[derive(Clone, Debug)]
+pub struct Flow {
+    pub sources: Vec<String>,
+    pub sink: String,
+    pub addr: u64,
+    pub path: Vec<u64>,
+}
+
+impl Flow {
+    pub fn describe(&self) -> String {
+        let path: Vec<String> = self.path.iter().map(|addr| format!("0x{:x}", addr)).collect();
+        return format!("0x{:x} {} reaches {} via {}", self.addr, self.sources.join(", "), self.sink, path.join(" "));
+    }
+}
+
+pub struct Policy {
+    pub sources: Vec<Source>,
+    pub sinks: Vec<Sink>,
+}
+
+fn buffer(function: &str, arg: usize, size: Size) -> Source {
+    return Source::Buffer { function: String::from(function), arg: arg, size: size };
+}
+
+fn argument(function: &str, arg: usize, pointee: bool) -> Sink {
+    return Sink::Argument { function: String::from(function), arg: arg, pointee: pointee };
+}
+
+impl Policy {
+    pub fn new() -> Policy {
+        return Policy {
+            sources: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    // Input functions of libc as sources, and the calls where input leads to command injection,
+    // memory corruption or format string bugs as sinks
+    pub fn libc() -> Policy {
+        let mut policy = Policy::new();
+
+        for name in ["fgets", "gets", "fread"].iter() {
+            policy.sources.push(buffer(name, 0, if *name == "fgets" { Size::Arg(1) } else { Size::Fixed(0x100) }));
+        }
+        for name in ["read", "recv", "recvfrom"].iter() {
+            policy.sources.push(buffer(name, 1, Size::Arg(2)));
+        }
+        for name in ["getenv", "getchar", "fgetc", "getc"].iter() {
+            policy.sources.push(Source::Return { function: String::from(*name) });
+        }
+
+        for name in ["system", "popen", "execve", "execl", "execlp", "execv", "execvp"].iter() {
+            policy.sinks.push(argument(name, 0, true));
+        }
+        for name in ["strcpy", "strcat", "sprintf"].iter() {
+            policy.sinks.push(argument(name, 1, true));
+        }
+        for name in ["memcpy", "memmove", "strncpy"].iter() {
+            policy.sinks.push(argument(name, 2, false));
+        }
+        for &(name, arg) in [("printf", 0), ("fprintf", 1), ("dprintf", 1), ("sprintf", 1), ("snprintf", 2), ("syslog", 1)].iter() {
+            policy.sinks.push(argument(name, arg, true));
+        }
+        policy.sinks.push(Sink::CallTarget);
+        return policy;
+    }
+
+    // Function and import names from the program are lowercase, names in the policy may not be
+    pub fn sources_of(&self, function: &str) -> Vec<Source> {
+        return self.sources.iter().filter(|source| source.function().map(|name| name.eq_ignore_ascii_case(function)).unwrap_or(false)).cloned().collect();
+    }
+
+    pub fn sinks_of(&self, function: &str) -> Vec<Sink> {
+        return self.sinks.iter().filter(|sink| match **sink {
+            Sink::Argument { function: ref name, .. } => name.eq_ignore_ascii_case(function),
+            _ => false,
+        }).cloned().collect();
+    }
+}
@@ -1,145 +1,765 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use program::*;
 use expression::*;
+use registers::Registers;
+use callconv::CallingConvention;
+use taint_policy::*;
+use shadow::{self, Address, Context, Labels, RegisterShadow, Shadow, Taint, TaintSet};
 
+// Arguments looked at when deciding whether a call to an import passes taint to its return value
+const IMPORT_ARGS: usize = 6;
+// Bytes a buffer source taints when its size argument isn't known, and the most it ever taints
+const DEFAULT_BUFFER: usize = 0x100;
+pub const MAX_BUFFER: usize = 0x10000;
+// Bytes of a string checked when a sink looks at what an argument points to
+const POINTEE_SIZE: usize = 0x40;
+// Instructions kept in the trail of a path, the oldest are dropped first. The dynamic tracker
+// uses the same limits.
+pub const MAX_TRAIL: usize = 256;
+// Calls nested deeper than this aren't entered, which keeps recursion from adding contexts forever
+const MAX_CALL_DEPTH: usize = 64;
+
+// Where paths meet, a branch target in the context of the calls that led to it
+pub type JoinPoint = (Vec<(u64, usize)>, u64, usize);
+
+// An address the tracker can follow without concrete values, a register's value when tracking
+// started plus an offset. Absolute addresses have an empty base.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Location {
+    pub base: String,
+    pub offset: i64,
+}
+
+impl Location {
+    pub fn absolute(addr: u64) -> Location {
+        return Location { base: String::new(), offset: addr as i64 };
+    }
+
+    pub fn add(&self, offset: i64) -> Location {
+        return Location { base: self.base.clone(), offset: self.offset.wrapping_add(offset) };
+    }
+}
+
+impl Address for Location {
+    fn offset(&self, offset: i64) -> Location {
+        return self.add(offset);
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.base.is_empty() {
+            return write!(f, "0x{:x}", self.offset as u64);
+        }
+        if self.offset < 0 {
+            return write!(f, "{}-0x{:x}", self.base, -self.offset);
+        }
+        return write!(f, "{}+0x{:x}", self.base, self.offset);
+    }
+}
+
+#[derive(Clone)]
 pub struct TaintState {
     pub addr: u64,
+    pub function: u64,
     pub index: usize,
-    pub regs_tainted: Vec<String>,
-    // Todo: Add structure to track stack tainting
+    // Labels on each byte of the registers, flags and memory
+    pub regs: RegisterShadow,
+    pub flags: HashMap<String, TaintSet>,
+    pub memory: Shadow<Location>,
+    // Registers whose value is known as a location, registers that aren't in here and haven't
+    // been written still hold their starting value
+    pub values: Vec<(String, Option<Location>)>,
+    // Where to continue when the current function returns
+    pub call_stack: Vec<(u64, usize)>,
+    // Instructions that moved tainted data on this path
+    pub trail: Vec<u64>,
+}
+
+impl TaintState {
+    // Location a full width register holds, None once it was overwritten with something unknown
+    pub fn value(&self, reg: &str) -> Option<Location> {
+        return match self.values.iter().find(|&&(ref name, _)| name == reg) {
+            Some(&(_, ref value)) => value.clone(),
+            None => Some(Location { base: String::from(reg), offset: 0 }),
+        };
+    }
+
+    pub fn set_value(&mut self, reg: &str, value: Option<Location>) {
+        self.values.retain(|&(ref name, _)| name != reg);
+        self.values.push((String::from(reg), value));
+    }
+
+    // Full width names of the registers holding tainted data
+    pub fn regs_tainted(&self) -> Vec<String> {
+        return self.regs.tainted();
+    }
+
+    pub fn set_flag(&mut self, flag: &str, set: TaintSet) {
+        if set.is_empty() {
+            self.flags.remove(flag);
+        } else {
+            self.flags.insert(String::from(flag), set);
+        }
+    }
+
+    // Merges the state of another path that reaches the same place, labels are combined and
+    // locations that differ become unknown. Returns whether anything changed.
+    pub fn join(&mut self, other: &TaintState) -> bool {
+        let mut changed = false;
+        for (reg, taint) in &other.regs.regs {
+            let mine = self.regs.regs.entry(reg.clone()).or_insert(Taint::clean(taint.size()));
+            if mine.size() < taint.size() {
+                mine.bytes.resize(taint.size(), TaintSet::new());
+            }
+            for (i, set) in taint.bytes.iter().enumerate() {
+                changed |= merge(&mut mine.bytes[i], set);
+            }
+        }
+        for (flag, set) in &other.flags {
+            changed |= merge(self.flags.entry(flag.clone()).or_insert(TaintSet::new()), set);
+        }
+        for (location, set) in &other.memory.bytes {
+            changed |= merge(self.memory.bytes.entry(location.clone()).or_insert(TaintSet::new()), set);
+        }
+
+        let regs: BTreeSet<String> = self.values.iter().chain(other.values.iter()).map(|&(ref name, _)| name.clone()).collect();
+        for reg in regs {
+            let value = self.value(&reg);
+            if value.is_some() && value != other.value(&reg) {
+                self.set_value(&reg, None);
+                changed = true;
+            }
+        }
+        return changed;
+    }
+}
+
+// Adds the labels of other to set, returning whether there were new ones
+fn merge(set: &mut TaintSet, other: &TaintSet) -> bool {
+    let before = set.len();
+    set.extend(other.iter().cloned());
+    return set.len() != before;
 }
 
 pub struct TaintTracker<'a> {
     pub program: &'a Program<'a>,
+    pub registers: Registers,
+    pub callconv: CallingConvention,
     pub state: TaintState,
+    pub labels: Labels,
+    pub policy: Policy,
+    // Every time tainted data reached a sink
+    pub flows: Vec<Flow>,
+    // Join points left to follow, the other sides of branches taken so far
+    pub pending: Vec<JoinPoint>,
+    // The state merged over every path that reached a join point. A path stops when it brings
+    // nothing new to one, so loops run until their taint reaches a fixed point.
+    pub joins: HashMap<JoinPoint, TaintState>,
+    // Branches and memory accesses whose condition or address depends on tainted data, with the
+    // labels that reach them
+    pub tainted_branches: Vec<(u64, TaintSet)>,
+    pub tainted_pointers: Vec<(u64, TaintSet)>,
+    // Set once every path has been followed
+    pub finished: bool,
+}
+
+impl<'a> Context for TaintTracker<'a> {
+    fn reg(&self, name: &str) -> Taint {
+        return self.state.regs.get(&self.registers, name);
+    }
+
+    fn flag(&self, name: &str) -> TaintSet {
+        return self.state.flags.get(name).cloned().unwrap_or(TaintSet::new());
+    }
+
+    fn flags(&self) -> TaintSet {
+        let mut set = TaintSet::new();
+        for flag in self.state.flags.values() {
+            set.extend(flag.iter().cloned());
+        }
+        return set;
+    }
+
+    fn load(&self, addr: &Expr, size: usize) -> Taint {
+        return match self.location(addr) {
+            Some(location) => self.state.memory.read(&location, size),
+            None => Taint::clean(size),
+        };
+    }
+
+    fn pop(&self, size: usize) -> Taint {
+        return match self.sp_name().and_then(|sp| self.state.value(&sp)) {
+            Some(sp) => self.state.memory.read(&sp, size),
+            None => Taint::clean(size),
+        };
+    }
+}
+
+// Adds an address and its labels to a list, merging the labels when the address is already there
+pub fn record(list: &mut Vec<(u64, TaintSet)>, addr: u64, set: TaintSet) {
+    match list.iter_mut().find(|&&mut (a, _)| a == addr) {
+        Some(&mut (_, ref mut labels)) => labels.extend(set),
+        None => list.push((addr, set)),
+    }
 }
 
 impl<'a> TaintTracker<'a> {
     // Initialize a taint tracker at the entry point
     pub fn entry(program: &'a Program) -> TaintTracker<'a> {
-        for function in program.functions() {
-            if function.name.eq("_start") {
+        return TaintTracker::at_function(program, "_start");
+    }
 
-                let temp = function.llil_start();
+    // Initialize a taint tracker at the main function
+    pub fn main(program: &'a Program) -> TaintTracker<'a> {
+        return TaintTracker::at_function(program, "main");
+    }
 
-                return TaintTracker {
-                    program: program,
-                    state: TaintState {
-                        addr: temp,
-                        index: 1,
-                        regs_tainted: Vec::new(),
-                    },
-                }
+    pub fn at_function(program: &'a Program, name: &str) -> TaintTracker<'a> {
+        let mut addr = 0;
+        for function in program.functions() {
+            if function.name.eq_ignore_ascii_case(name) {
+                addr = function.addr;
+                break;
             }
         }
+
+        return TaintTracker::at_location(program, addr, addr, 0);
+    }
+
+    // Starts at any address inside a function rather than at its start
+    pub fn at_address(program: &'a Program, addr: u64) -> Result<TaintTracker<'a>, String> {
+        let (function, index) = program.location_of(addr)?;
+        return Ok(TaintTracker::at_location(program, addr, function, index));
+    }
+
+    fn at_location(program: &'a Program, addr: u64, function: u64, index: usize) -> TaintTracker<'a> {
+        let registers = match program.arch() {
+            Ok(arch) => Registers::new(&arch),
+            Err(_) => Registers::empty(),
+        };
+        let callconv = program.calling_convention().unwrap_or(CallingConvention::sysv());
+
         return TaintTracker {
             program: program,
+            registers: registers,
+            callconv: callconv,
             state: TaintState {
-                addr: 0,
-                index: 1,
-                regs_tainted: Vec::new(),
-            }
+                addr: addr,
+                function: function,
+                index: index,
+                regs: RegisterShadow::new(),
+                flags: HashMap::new(),
+                memory: Shadow::new(),
+                values: Vec::new(),
+                call_stack: Vec::new(),
+                trail: Vec::new(),
+            },
+            labels: Labels::new(),
+            policy: Policy::libc(),
+            flows: Vec::new(),
+            pending: Vec::new(),
+            joins: HashMap::new(),
+            tainted_branches: Vec::new(),
+            tainted_pointers: Vec::new(),
+            finished: false,
         }
     }
 
-    // Initialize a taint tracker at the main function
-    pub fn main(program: &'a Program) -> TaintTracker<'a> {
-        for function in program.functions() {
-            if function.name.eq("main") {
+    // Memory sources taint their bytes right away, the rest apply when execution gets to them
+    pub fn add_source(&mut self, source: Source) {
+        if let Source::Memory { ref name, ref location, size } = source {
+            self.taint_memory(location, size, name);
+            return;
+        }
+        self.policy.sources.push(source);
+    }
 
-                let temp = function.llil_start();
+    pub fn add_sink(&mut self, sink: Sink) {
+        self.policy.sinks.push(sink);
+    }
 
-                return TaintTracker {
-                    program: program,
-                    state: TaintState {
-                        addr: temp,
-                        index: 1,
-                        regs_tainted: Vec::new(),
-                    },
-                }
-            }
+    // Taints every byte of the register with the label
+    pub fn taint_register(&mut self, reg: &str, label: &str) {
+        let label = self.labels.add(label);
+        let size = self.registers.info(reg).map(|def| def.size).unwrap_or(self.registers.address_size);
+        let mut set = TaintSet::new();
+        set.insert(label);
+        self.state.regs.set(&self.registers, reg, Taint::uniform(&set, size));
+    }
+
+    // Labels each byte with the name and its index, like "stdin byte 3"
+    pub fn taint_memory(&mut self, location: &Location, size: usize, name: &str) {
+        let mut taint = Taint::clean(size);
+        for i in 0..size {
+            taint.bytes[i].insert(self.labels.add(&format!("{} byte {}", name, i)));
         }
-        return TaintTracker {
-            program: program,
-            state: TaintState {
-                addr: 0,
-                index: 1,
-                regs_tainted: Vec::new(),
-            }
+        self.state.memory.write(location, &taint);
+    }
+
+    pub fn reg_taint(&self, name: &str) -> Taint {
+        return self.state.regs.get(&self.registers, name);
+    }
+
+    pub fn describe(&self, set: &TaintSet) -> Vec<String> {
+        return self.labels.describe(set);
+    }
+
+    // Adds the current instruction to the trail of instructions that moved tainted data
+    fn mark(&mut self) {
+        if self.state.trail.last() != Some(&self.state.addr) {
+            self.state.trail.push(self.state.addr);
+        }
+        if self.state.trail.len() > MAX_TRAIL {
+            self.state.trail.remove(0);
+        }
+    }
+
+    // Records tainted data reaching a sink, once per sink and address
+    fn flow(&mut self, sink: &Sink, set: &TaintSet) {
+        let sink = sink.to_string();
+        if self.flows.iter().any(|flow| flow.addr == self.state.addr && flow.sink == sink) {
+            return;
         }
+        let flow = Flow {
+            sources: self.labels.describe(set),
+            sink: sink,
+            addr: self.state.addr,
+            path: self.state.trail.clone(),
+        };
+        warn!("{}", flow.describe());
+        self.flows.push(flow);
     }
 
-    // Todo: Add functions so the user can specify taint sources
+    // Records loads whose address depends on tainted data
+    fn check_pointers(&mut self, expr: &Expr) {
+        let mut set = TaintSet::new();
+        for addr in shadow::load_addresses(expr) {
+            set.extend(shadow::expression_taint(self, addr).union());
+        }
+        if !set.is_empty() {
+            info!("0x{:x} Access through a tainted pointer", self.state.addr);
+            record(&mut self.tainted_pointers, self.state.addr, set);
+        }
+    }
+
+    fn taint_of(&self, expr: &Expr) -> Taint {
+        return shadow::expression_taint(self, expr);
+    }
 
     // Step the taint state over the next LLIL instruction
-    pub fn step(&mut self) {
+    pub fn step(&mut self) -> Result<(), String> {
         use LlilInst::*;
-        
-        let inst: Inst = self.program.inst_at(self.state.addr).expect("No such instruction");
+
+        if self.finished {
+            return Ok(());
+        }
+
+        let inst: Inst = self.program.llil_at(self.state.function, self.state.index)?;
+        self.state.addr = inst.addr;
+
+        // Register sources taint their register when execution reaches them
+        let registers: Vec<String> = self.policy.sources.iter().filter_map(|source| match *source {
+            Source::Register { addr, ref reg } if addr == inst.addr => Some(reg.clone()),
+            _ => None,
+        }).collect();
+        for reg in registers {
+            let label = format!("{}@0x{:x}", reg, inst.addr);
+            self.taint_register(&reg, &label);
+        }
+
+        // Set when an instruction transfers control somewhere other than the next instruction
+        let mut next: Option<(u64, usize)> = None;
 
         // This match statement should have an entry for each LLIL instruction
         match inst.llil {
             SetReg(llil) => {
-                if self.expression_tainted(llil.expr) {
-                    // Right hand side expression is tainted, so taint the destination register
-                } else {
-                    // Right hand side expression not tainted, so don't propogate taint and remove taint from destination if previously tainted
+                self.check_pointers(&llil.expr);
+                let taint = self.taint_of(&llil.expr);
+                self.write_flags(&llil.expr, taint.union());
+                let value = self.location(&llil.expr);
+                self.pop(&llil.expr);
+                if taint.is_tainted() {
+                    info!("0x{:x} Tainted register {}", self.state.addr, llil.reg);
+                    self.mark();
+                }
+                self.set_reg(&llil.reg, taint, value);
+            }
+            SetRegSplit(llil) => {
+                self.check_pointers(&llil.source_expr);
+                let taint = self.taint_of(&llil.source_expr);
+                self.write_flags(&llil.source_expr, taint.union());
+                self.pop(&llil.source_expr);
+                if taint.is_tainted() {
+                    self.mark();
                 }
+                let size = llil.size;
+                let low = Taint { bytes: taint.bytes.iter().take(size).cloned().collect() };
+                let high = Taint { bytes: taint.bytes.iter().skip(size).cloned().collect() };
+                self.set_reg(&llil.dest_reg_low, low.resize(size, false), None);
+                self.set_reg(&llil.dest_reg_high, high.resize(size, false), None);
+            }
+            SetFlag(llil) => {
+                self.check_pointers(&llil.expr);
+                let set = self.taint_of(&llil.expr).union();
+                self.state.set_flag(&llil.flag, set);
+            }
+            Store(llil) => {
+                self.check_pointers(&llil.source_expr);
+                self.check_pointers(&llil.dest_mem_expr);
+                let pointer = self.taint_of(&llil.dest_mem_expr).union();
+                if !pointer.is_empty() {
+                    record(&mut self.tainted_pointers, self.state.addr, pointer);
+                }
+                let taint = self.taint_of(&llil.source_expr).resize(llil.size, false);
+                self.write_flags(&llil.source_expr, taint.union());
+                if taint.is_tainted() {
+                    self.mark();
+                }
+                self.store(&llil.dest_mem_expr, &taint);
+            }
+            Push(llil) => {
+                self.check_pointers(&llil.expr);
+                let taint = self.taint_of(&llil.expr).resize(llil.size, false);
+                if taint.is_tainted() {
+                    self.mark();
+                }
+                let sp = self.adjust_sp(-(llil.size as i64));
+                match sp {
+                    Some(sp) => self.state.memory.write(&sp, &taint),
+                    None if taint.is_tainted() => warn!("0x{:x} Lost taint pushed to an unknown stack pointer", self.state.addr),
+                    None => (),
+                }
+            }
+            If(llil) => {
+                self.check_pointers(&llil.condition);
+                let set = self.taint_of(&llil.condition).union();
+                if !set.is_empty() {
+                    info!("0x{:x} Branch depends on {}", self.state.addr, self.labels.describe(&set).join(", "));
+                    self.mark();
+                    record(&mut self.tainted_branches, self.state.addr, set);
+                }
+                next = self.follow(&[llil.target_true, llil.target_false]);
+                if next.is_none() {
+                    return Ok(self.next_path());
+                }
+            }
+            Call(llil) => {
+                self.check_pointers(&llil.target);
+                let set = self.taint_of(&llil.target).union();
+                if !set.is_empty() {
+                    let sinks: Vec<Sink> = self.policy.sinks.iter().filter(|sink| match **sink {
+                        Sink::CallTarget => true,
+                        _ => false,
+                    }).cloned().collect();
+                    for sink in sinks {
+                        self.flow(&sink, &set);
+                    }
+                }
+                next = self.call(&llil.target)?;
             }
             Goto(llil) => {
-                // No need to propogate taint for this instruction since there aren't any side effects
+                // No taint to propagate, but loop back edges are gotos so their target is a join point
+                next = self.follow(&[llil.target]);
+                if next.is_none() {
+                    return Ok(self.next_path());
+                }
+            }
+            Jump(llil) => {
+                next = self.jump(&llil.target)?;
+                if next.is_none() {
+                    return Ok(self.next_path());
+                }
             }
+            JumpTo(llil) => {
+                next = self.jump(&llil.target)?;
+                if next.is_none() {
+                    return Ok(self.next_path());
+                }
+            }
+            Ret(llil) => {
+                self.pop(&llil.target);
+                match self.state.call_stack.pop() {
+                    Some(location) => next = Some(location),
+                    None => return Ok(self.next_path()),
+                }
+            }
+            NoRet() => {
+                return Ok(self.next_path());
+            }
+            Value(expr) => {
+                self.check_pointers(&expr);
+                let set = self.taint_of(&expr).union();
+                self.write_flags(&expr, set);
+                self.pop(&expr);
+            }
+            // Sources and sinks decide what syscalls do to taint, on their own they have no effect
+            Syscall() | Nop() | Bp() | Trap() | LlilInst::Undef() => (),
+        }
+
+        match next {
+            Some((function, index)) => {
+                self.state.function = function;
+                self.state.index = index;
+            }
+            None => self.state.index += 1,
+        }
+        return Ok(());
+    }
+
+    // Sets a register's taint and location, writes to part of a register keep the rest's taint
+    fn set_reg(&mut self, name: &str, taint: Taint, value: Option<Location>) {
+        let full = self.registers.full_width(name);
+        self.state.regs.set(&self.registers, name, taint);
+        self.state.set_value(&full, if full == name { value } else { None });
+    }
+
+    fn sp_name(&self) -> Option<String> {
+        return self.registers.stack_pointer.as_ref().map(|sp| self.registers.full_width(sp));
+    }
 
-            // Todo: Implement the other instructions
+    // Moves the stack pointer, returning its new location when it's known
+    fn adjust_sp(&mut self, offset: i64) -> Option<Location> {
+        let sp = self.sp_name()?;
+        let value = self.state.value(&sp).map(|value| value.add(offset));
+        self.state.set_value(&sp, value.clone());
+        return value;
+    }
+
+    // A pop in an expression moves the stack pointer once its value was read
+    fn pop(&mut self, expr: &Expr) {
+        if let Expr::Pop(ref p) = *expr {
+            self.adjust_sp(p.size as i64);
+        }
+    }
+
+    fn store(&mut self, dest: &Expr, taint: &Taint) {
+        match self.location(dest) {
+            Some(location) => {
+                self.state.memory.write(&location, taint);
+                if taint.is_tainted() {
+                    info!("0x{:x} Tainted {} bytes at {}", self.state.addr, taint.size(), location);
+                }
+            }
+            None if taint.is_tainted() => warn!("0x{:x} Lost taint stored to an unknown address", self.state.addr),
+            None => (),
+        }
+    }
+
+    // Flags written by the operation at the top of an expression take its taint
+    fn write_flags(&mut self, expr: &Expr, set: TaintSet) {
+        for flag in shadow::flags_written(expr) {
+            self.state.set_flag(&flag, set.clone());
+        }
+    }
 
+    // Merges the state into each target's join point. The first target that changed is followed
+    // with the merged state and the others are queued, None when no target changed.
+    fn follow(&mut self, targets: &[usize]) -> Option<(u64, usize)> {
+        let function = self.state.function;
+        let mut changed: Vec<JoinPoint> = Vec::new();
+        for &target in targets {
+            let point = (self.state.call_stack.clone(), function, target);
+            if changed.contains(&point) {
+                continue;
+            }
+            let grew = match self.joins.get_mut(&point) {
+                Some(joined) => joined.join(&self.state),
+                None => {
+                    let mut state = self.state.clone();
+                    state.index = target;
+                    self.joins.insert(point.clone(), state);
+                    true
+                }
+            };
+            if grew {
+                changed.push(point);
+            }
+        }
+
+        for point in changed.iter().skip(1) {
+            if !self.pending.contains(point) {
+                self.pending.push(point.clone());
+            }
+        }
+        let point = changed.into_iter().next()?;
+        let trail = self.state.trail.clone();
+        self.state = self.joins[&point].clone();
+        self.state.trail = trail;
+        return Some((function, point.2));
+    }
+
+    // Continues with the next queued join point once the current path can't go further
+    fn next_path(&mut self) {
+        match self.pending.pop().and_then(|point| self.joins.get(&point).cloned()) {
+            Some(state) => {
+                info!("0x{:x} Path ended, continuing at index {} of 0x{:x}", self.state.addr, state.index, state.function);
+                self.state = state;
+            }
+            None => {
+                info!("0x{:x} Every path has been followed", self.state.addr);
+                self.finished = true;
+            }
+        }
+    }
+
+    // Jumps can only be followed to addresses known without running the program
+    fn jump(&self, target: &Expr) -> Result<Option<(u64, usize)>, String> {
+        match self.location(target) {
+            Some(ref location) if location.base.is_empty() => return Ok(Some(self.program.location_of(location.offset as u64)?)),
+            _ => {
+                warn!("0x{:x} Can't follow jump to an unknown address", self.state.addr);
+                return Ok(None);
+            }
+        }
+    }
+
+    // Enters calls to the program's own functions. Imports aren't followed, their return value
+    // gets the labels of their arguments.
+    fn call(&mut self, target: &Expr) -> Result<Option<(u64, usize)>, String> {
+        let addr = match self.location(target) {
+            Some(ref location) if location.base.is_empty() => Some(location.offset as u64),
+            _ => None,
+        };
+        // Calls through a GOT slot have no known target but the slot names the import
+        let resolved = got_slot(target).and_then(|slot| self.program.import_symbol(slot))
+            .or_else(|| addr.and_then(|addr| self.program.resolve_import(addr)));
+        let import = resolved.is_some() || addr.map(|addr| self.program.is_import(addr)).unwrap_or(true);
+        let function = addr.and_then(|addr| self.program.function_at(addr).ok());
+        let name = match resolved {
+            Some(import) => Some(import.name),
+            None => function.as_ref().map(|function| function.name.clone()),
+        };
+
+        if let Some(ref name) = name {
+            for sink in self.policy.sinks_of(name) {
+                if let Sink::Argument { arg, pointee, .. } = sink {
+                    let mut set = self.arg_taint(arg);
+                    if pointee {
+                        if let Some(location) = self.arg_location(arg) {
+                            set.extend(self.state.memory.read(&location, POINTEE_SIZE).union());
+                        }
+                    }
+                    if !set.is_empty() {
+                        self.flow(&sink, &set);
+                    }
+                }
+            }
+        }
+
+        match function {
+            Some(function) if !import && self.state.call_stack.len() < MAX_CALL_DEPTH => {
+                let word = self.registers.address_size;
+                if self.registers.link_register.is_none() {
+                    if let Some(sp) = self.adjust_sp(-(word as i64)) {
+                        self.state.memory.write(&sp, &Taint::clean(word));
+                    }
+                }
+                self.state.call_stack.push((self.state.function, self.state.index + 1));
+                info!("0x{:x} Entering {}", self.state.addr, function.name);
+                return Ok(Some((function.addr, 0)));
+            }
             _ => {
-                error!("0x{:x} Unimplemented instruction", self.state.addr);
-            }
-        }
-
-        self.state.addr = self.program.inst_after(self.state.addr).expect("Failed to get next instruction").addr;
-    }
-
-    // This function should return true if any of the expression elements are tainted
-    pub fn expression_tainted(&self, expr: Expr) -> bool {
-        match expr {
-            Expr::Value(v) => false,
-            Expr::Reg(r) => self.state.regs_tainted.contains(&r.name),
-            Expr::Load(l) => self.expression_tainted(*l.source_mem),
-    
-            Expr::CmpE(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::CmpSlt(s) => self.expression_tainted(*s.left) < self.expression_tainted(*s.right),
-            Expr::CmpSle(s) => self.expression_tainted(*s.left) <= self.expression_tainted(*s.right),
-            Expr::CmpSge(s) => self.expression_tainted(*s.left) >= self.expression_tainted(*s.right),
-            Expr::CmpSgt(s) => self.expression_tainted(*s.left) > self.expression_tainted(*s.right),
-    
-            Expr::CmpNe(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::CmpUlt(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::CmpUle(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::CmpUge(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::CmpUgt(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-    
-            Expr::Add(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Sub(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::And(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Or(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Xor(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Mul(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Divu(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Divs(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Modu(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Mods(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            
-            Expr::Lsl(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Lsr(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Asr(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Rol(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::Ror(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-    
-            Expr::MulsDp(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-            Expr::MuluDp(s) => self.expression_tainted(*s.left) || self.expression_tainted(*s.right),
-    
-            Expr::Undef(s) => {error!("Undef expr {:?}", s.expr); false},
-            _ => {error!("Unimplemented expr"); false}
+                let mut set = TaintSet::new();
+                for n in 0..IMPORT_ARGS {
+                    set.extend(self.arg_taint(n));
+                }
+                let ret = self.callconv.int_return.clone();
+                let size = self.registers.info(&ret).map(|def| def.size).unwrap_or(self.registers.address_size);
+                self.set_reg(&ret, Taint::uniform(&set, size), None);
+                if let Some(ref name) = name {
+                    self.apply_sources(name);
+                }
+                return Ok(None);
+            }
+        }
+    }
+
+    // Taints what the sources of a function that was just called produce, labeled with the
+    // function and call site
+    fn apply_sources(&mut self, name: &str) {
+        let site = format!("{}@0x{:x}", name, self.state.addr);
+        for source in self.policy.sources_of(name) {
+            match source {
+                Source::Return { .. } => {
+                    let ret = self.callconv.int_return.clone();
+                    self.taint_register(&ret, &site);
+                }
+                Source::Buffer { arg, ref size, .. } => {
+                    let size = match *size {
+                        Size::Fixed(size) => size,
+                        Size::Arg(n) => match self.arg_location(n) {
+                            Some(ref location) if location.base.is_empty() => (location.offset as usize).min(MAX_BUFFER),
+                            _ => DEFAULT_BUFFER,
+                        },
+                    };
+                    match self.arg_location(arg) {
+                        Some(location) => self.taint_memory(&location, size, &site),
+                        None => {
+                            warn!("0x{:x} Can't taint the buffer of {} at an unknown address", self.state.addr, name);
+                            continue;
+                        }
+                    }
+                }
+                _ => continue,
+            }
+            info!("0x{:x} Tainted {}", self.state.addr, source);
+            self.mark();
+        }
+    }
+
+    // Stack slot an argument that doesn't fit in registers is passed in
+    fn arg_slot(&self, n: usize) -> Option<Location> {
+        let callconv = &self.callconv;
+        let sp = self.sp_name().and_then(|sp| self.state.value(&sp))?;
+        let i = n - callconv.int_args.len();
+        return Some(sp.add((callconv.stack_offset + (i * callconv.slot_size) as u64) as i64));
+    }
+
+    // Labels on an argument of a call about to be made, in a register or on the stack
+    fn arg_taint(&self, n: usize) -> TaintSet {
+        if let Some(reg) = self.callconv.int_args.get(n) {
+            return self.state.regs.get(&self.registers, reg).union();
         }
+        return match self.arg_slot(n) {
+            Some(slot) => self.state.memory.read(&slot, self.callconv.slot_size).union(),
+            None => TaintSet::new(),
+        };
+    }
+
+    // Location an argument holds, only known for arguments passed in registers
+    fn arg_location(&self, n: usize) -> Option<Location> {
+        let reg = self.callconv.int_args.get(n)?;
+        return self.state.value(&self.registers.full_width(reg));
+    }
+
+    // Location an address expression evaluates to, when it's a known register or constant plus constants
+    pub fn location(&self, expr: &Expr) -> Option<Location> {
+        return match *expr {
+            Expr::Value(ref v) => Some(Location::absolute(v.value)),
+            Expr::Reg(ref r) => {
+                let full = self.registers.full_width(&r.name);
+                if full != r.name && self.registers.info(&r.name).is_some() {
+                    return None;
+                }
+                self.state.value(&full)
+            }
+            Expr::Add(ref s) => match (self.location(&s.left), self.location(&s.right)) {
+                (Some(left), Some(ref right)) if right.base.is_empty() => Some(left.add(right.offset)),
+                (Some(ref left), Some(right)) if left.base.is_empty() => Some(right.add(left.offset)),
+                _ => None,
+            },
+            Expr::Sub(ref s) => match (self.location(&s.left), self.location(&s.right)) {
+                (Some(left), Some(ref right)) if right.base.is_empty() => Some(left.add(right.offset.wrapping_neg())),
+                _ => None,
+            },
+            _ => None,
+        };
+    }
+
+    // Whether any byte of the expression's value carries a label
+    pub fn expression_tainted(&self, expr: &Expr) -> bool {
+        return self.taint_of(expr).is_tainted();
     }
 }
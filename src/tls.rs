@@ -0,0 +1,131 @@
+use program::Program;
+use registers::ArchKind;
+use process;
+use state::*;
+
+// Program header type of the TLS template
+const PT_LOAD: u64 = 1;
+const PT_TLS: u64 = 7;
+
+// Room for glibc's struct pthread, which sits next to the TCB and is read by libc code
+const PTHREAD_SIZE: u64 = 0x1000;
+
+// The TLS template from the program's PT_TLS header, relocated to where the image is mapped
+pub struct Template {
+    pub addr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+// How glibc lays out the thread pointer on an architecture
+pub struct Layout {
+    // Register holding the thread pointer
+    pub register: &'static str,
+    // Variant II puts the TLS blocks below the thread pointer, variant I above it
+    pub below: bool,
+    // Bytes of TCB between the thread pointer and the first TLS block in variant I
+    pub tcb_size: u64,
+    // Offset of the stack guard from the thread pointer, architectures without one use __stack_chk_guard
+    pub stack_guard: Option<u64>,
+}
+
+impl Layout {
+    pub fn for_arch(kind: ArchKind) -> Layout {
+        return match kind {
+            ArchKind::X86_64 => Layout { register: "fsbase", below: true, tcb_size: 0, stack_guard: Some(0x28) },
+            ArchKind::X86 => Layout { register: "gsbase", below: true, tcb_size: 0, stack_guard: Some(0x14) },
+            ArchKind::Aarch64 => Layout { register: "tpidr_el0", below: false, tcb_size: 16, stack_guard: None },
+            ArchKind::Arm => Layout { register: "tpidruro", below: false, tcb_size: 8, stack_guard: None },
+            // RISC-V's thread pointer points just past the TCB
+            ArchKind::Riscv | ArchKind::Unknown => Layout { register: "tp", below: false, tcb_size: 0, stack_guard: None },
+        };
+    }
+}
+
+fn align(value: u64, align: u64) -> u64 {
+    return (value + align - 1) & !(align - 1);
+}
+
+// Reads the PT_TLS header, PIE images are moved by the difference between where the image
+// was mapped and its first PT_LOAD address
+pub fn template(state: &State, program: &Program) -> Option<Template> {
+    let (phdr, phent, phnum) = process::program_headers(state, program)?;
+    let wide = state.regs.address_size == 8;
+    let field = |header: u64, wide_offset: u64, offset: u64| -> Option<u64> {
+        return match wide {
+            true => state.memory.load(header + wide_offset, 8).ok().map(|v| v as u64),
+            false => state.memory.load(header + offset, 4).ok().map(|v| v as u64),
+        };
+    };
+
+    let mut tls = None;
+    let mut first_load = None;
+    for i in 0..phnum {
+        let header = phdr + i * phent;
+        let kind = state.memory.load(header, 4).ok()? as u64;
+        let vaddr = field(header, 0x10, 0x8)?;
+        if kind == PT_LOAD && first_load.is_none() {
+            first_load = Some(vaddr & !(PAGE_SIZE - 1));
+        }
+        if kind == PT_TLS {
+            tls = Some((vaddr, field(header, 0x20, 0x10)?, field(header, 0x28, 0x14)?, field(header, 0x30, 0x1c)?));
+        }
+    }
+
+    let (vaddr, file_size, mem_size, alignment) = tls?;
+    let bias = program.start().wrapping_sub(first_load.unwrap_or(program.start()));
+    return Some(Template {
+        addr: vaddr.wrapping_add(bias),
+        file_size: file_size,
+        mem_size: mem_size,
+        align: alignment.max(1).next_power_of_two(),
+    });
+}
+
+// Allocates the main thread's TLS block and TCB like glibc's startup does, with the stack guard
+// from the AT_RANDOM bytes, and points the thread register at it. Returns the thread pointer.
+pub fn setup(state: &mut State, program: &Program) -> Result<u64, String> {
+    let arch = program.arch()?;
+    let layout = Layout::for_arch(ArchKind::of(&arch));
+    let word = state.regs.address_size as u64;
+
+    let template = template(state, program);
+    let (mem_size, alignment) = match template {
+        Some(ref template) => (template.mem_size, template.align),
+        None => (0, 1),
+    };
+    // The linker put the program's TLS variables at these offsets from the thread pointer
+    let tls_size = align(mem_size, alignment);
+    let tcb_size = align(layout.tcb_size, alignment);
+    let alignment = alignment.max(16);
+
+    // Variant II is [TLS block][TCB and struct pthread], variant I is [struct pthread][TCB][TLS block]
+    let size = PTHREAD_SIZE + tcb_size + tls_size + alignment;
+    let region = state.map_anonymous(0, size, Perms::RW).ok_or(String::from("No room for the TLS block"))?;
+    let (tp, block) = if layout.below {
+        let tp = align(region + tls_size, alignment);
+        (tp, tp - tls_size)
+    } else {
+        let tp = align(region + PTHREAD_SIZE, alignment);
+        (tp, tp + tcb_size)
+    };
+
+    if let Some(template) = template {
+        let bytes = state.memory.read_bytes(template.addr, template.file_size as usize)?;
+        state.memory.write_bytes(block, &bytes)?;
+    }
+
+    if layout.below {
+        // tcbhead_t starts with pointers to itself, code reads fs:[0] to find the thread pointer
+        state.memory.store(tp, word as usize, tp as u128)?;
+        state.memory.store(tp + 2 * word, word as usize, tp as u128)?;
+    }
+    if let Some(offset) = layout.stack_guard {
+        state.memory.store(tp + offset, word as usize, process::stack_guard(word as usize) as u128)?;
+    }
+
+    state.regs.set(layout.register, tp as u128);
+    info!("Thread pointer {} at 0x{:x} with 0x{:x} bytes of TLS at 0x{:x}", layout.register, tp, mem_size, block);
+    return Ok(tp);
+}
@@ -0,0 +1,278 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use syscalls::{EBADF, EEXIST, EFBIG, EINVAL, ENOENT, ESPIPE};
+
+// open() flags, the same values on every architecture we emulate
+pub const O_ACCMODE: u64 = 3;
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0x40;
+pub const O_EXCL: u64 = 0x80;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// Largest file the program can write, so a write far past the end can't exhaust host memory
+pub const MAX_FILE_SIZE: usize = 1 << 28;
+
+pub const STDIN: &'static str = "/dev/stdin";
+pub const STDOUT: &'static str = "/dev/stdout";
+pub const STDERR: &'static str = "/dev/stderr";
+
+// An open file, the position is shared by every descriptor dup'ed from the one that opened it
+#[derive(Clone)]
+pub struct Descriptor {
+    pub path: String,
+    pub pos: usize,
+    pub readable: bool,
+    pub writable: bool,
+    pub append: bool,
+    // Streams can't seek, like a terminal or pipe
+    pub stream: bool,
+}
+
+// Files the emulated program can see, by absolute path, and its open descriptors.
+// Errors are errno values for the caller to hand back to the program.
+pub struct Vfs {
+    pub files: HashMap<String, Vec<u8>>,
+    // Open file each descriptor refers to, dup'ed descriptors refer to the same one
+    pub fds: BTreeMap<u64, usize>,
+    pub open_files: HashMap<usize, Descriptor>,
+    next_open_file: usize,
+    // FILE pointers the program's stdin, stdout and stderr globals hold, with their descriptors
+    pub streams: HashMap<u64, u64>,
+}
+
+impl Vfs {
+    // Starts with empty standard streams on descriptors 0, 1 and 2
+    pub fn new() -> Vfs {
+        let mut vfs = Vfs {
+            files: HashMap::new(),
+            fds: BTreeMap::new(),
+            open_files: HashMap::new(),
+            next_open_file: 0,
+            streams: HashMap::new(),
+        };
+
+        for (fd, path) in [STDIN, STDOUT, STDERR].iter().enumerate() {
+            vfs.files.insert(String::from(*path), Vec::new());
+            vfs.insert(Descriptor {
+                path: String::from(*path),
+                pos: 0,
+                readable: fd == 0,
+                writable: fd != 0,
+                append: fd != 0,
+                stream: true,
+            });
+        }
+        return vfs;
+    }
+
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) {
+        self.files.insert(String::from(path), data);
+    }
+
+    // Copies a host file into the file system at path
+    pub fn add_host_file(&mut self, path: &str, host_path: &str) -> Result<(), String> {
+        let data = fs::read(host_path).map_err(|err| format!("Failed to read {}: {}", host_path, err))?;
+        self.add_file(path, data);
+        return Ok(());
+    }
+
+    pub fn set_stdin(&mut self, data: Vec<u8>) {
+        self.add_file(STDIN, data);
+    }
+
+    pub fn file(&self, path: &str) -> Option<&Vec<u8>> {
+        return self.files.get(path);
+    }
+
+    pub fn stdout(&self) -> &[u8] {
+        return self.files.get(STDOUT).map(|data| data.as_slice()).unwrap_or(&[]);
+    }
+
+    pub fn stderr(&self) -> &[u8] {
+        return self.files.get(STDERR).map(|data| data.as_slice()).unwrap_or(&[]);
+    }
+
+    pub fn descriptor(&self, fd: u64) -> Result<&Descriptor, u64> {
+        let id = self.fds.get(&fd).ok_or(EBADF)?;
+        return self.open_files.get(id).ok_or(EBADF);
+    }
+
+    fn descriptor_mut(&mut self, fd: u64) -> Result<&mut Descriptor, u64> {
+        let id = self.fds.get(&fd).ok_or(EBADF)?;
+        return self.open_files.get_mut(id).ok_or(EBADF);
+    }
+
+    // Adds an open file on the lowest free descriptor
+    fn insert(&mut self, descriptor: Descriptor) -> u64 {
+        let id = self.next_open_file;
+        self.next_open_file += 1;
+        self.open_files.insert(id, descriptor);
+        let fd = (0..).find(|fd| !self.fds.contains_key(fd)).unwrap();
+        self.fds.insert(fd, id);
+        return fd;
+    }
+
+    // Opens a file on the lowest free descriptor
+    pub fn open(&mut self, path: &str, flags: u64) -> Result<u64, u64> {
+        let exists = self.files.contains_key(path);
+        if !exists && flags & O_CREAT == 0 {
+            return Err(ENOENT);
+        }
+        if exists && flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+            return Err(EEXIST);
+        }
+
+        let (readable, writable) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => return Err(EINVAL),
+        };
+        if !exists || (writable && flags & O_TRUNC != 0) {
+            self.files.insert(String::from(path), Vec::new());
+        }
+
+        let fd = self.insert(Descriptor {
+            path: String::from(path),
+            pos: 0,
+            readable: readable,
+            writable: writable,
+            append: flags & O_APPEND != 0,
+            stream: path == STDIN || path == STDOUT || path == STDERR,
+        });
+        return Ok(fd);
+    }
+
+    // Closes the open file too once no descriptor refers to it
+    pub fn close(&mut self, fd: u64) -> Result<(), u64> {
+        let id = self.fds.remove(&fd).ok_or(EBADF)?;
+        if !self.fds.values().any(|other| *other == id) {
+            self.open_files.remove(&id);
+        }
+        return Ok(());
+    }
+
+    // Refers the lowest free descriptor to the same open file, sharing its position
+    pub fn dup(&mut self, fd: u64) -> Result<u64, u64> {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let new = (0..).find(|fd| !self.fds.contains_key(fd)).unwrap();
+        self.fds.insert(new, id);
+        return Ok(new);
+    }
+
+    // Bytes left to read without consuming them
+    pub fn remaining(&self, fd: u64) -> Result<&[u8], u64> {
+        let descriptor = self.descriptor(fd)?;
+        if !descriptor.readable {
+            return Err(EBADF);
+        }
+        let data = self.files.get(&descriptor.path).ok_or(ENOENT)?;
+        return Ok(&data[descriptor.pos.min(data.len())..]);
+    }
+
+    // Moves past bytes that were looked at with remaining
+    pub fn consume(&mut self, fd: u64, n: usize) -> Result<(), u64> {
+        let len = self.remaining(fd)?.len();
+        let descriptor = self.descriptor_mut(fd)?;
+        descriptor.pos += n.min(len);
+        return Ok(());
+    }
+
+    pub fn read(&mut self, fd: u64, n: usize) -> Result<Vec<u8>, u64> {
+        let bytes = {
+            let remaining = self.remaining(fd)?;
+            remaining[..n.min(remaining.len())].to_vec()
+        };
+        self.consume(fd, bytes.len())?;
+        return Ok(bytes);
+    }
+
+    pub fn write(&mut self, fd: u64, bytes: &[u8]) -> Result<usize, u64> {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let descriptor = self.open_files.get_mut(&id).ok_or(EBADF)?;
+        if !descriptor.writable {
+            return Err(EBADF);
+        }
+        let data = self.files.get_mut(&descriptor.path).ok_or(ENOENT)?;
+
+        if descriptor.append {
+            descriptor.pos = data.len();
+        }
+        let end = match descriptor.pos.checked_add(bytes.len()) {
+            Some(end) if end <= MAX_FILE_SIZE => end,
+            _ => return Err(EFBIG),
+        };
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[descriptor.pos..end].copy_from_slice(bytes);
+        descriptor.pos = end;
+        return Ok(bytes.len());
+    }
+
+    pub fn seek(&mut self, fd: u64, offset: i64, whence: u64) -> Result<u64, u64> {
+        let id = *self.fds.get(&fd).ok_or(EBADF)?;
+        let descriptor = self.open_files.get_mut(&id).ok_or(EBADF)?;
+        if descriptor.stream {
+            return Err(ESPIPE);
+        }
+        let size = self.files.get(&descriptor.path).map(|data| data.len()).unwrap_or(0);
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => descriptor.pos as i64,
+            SEEK_END => size as i64,
+            _ => return Err(EINVAL),
+        };
+        let pos = base.checked_add(offset).ok_or(EINVAL)?;
+        if pos < 0 {
+            return Err(EINVAL);
+        }
+        descriptor.pos = pos as usize;
+        return Ok(pos as u64);
+    }
+
+    pub fn size(&self, fd: u64) -> Result<usize, u64> {
+        let descriptor = self.descriptor(fd)?;
+        return self.files.get(&descriptor.path).map(|data| data.len()).ok_or(ENOENT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dup_shares_position() {
+        let mut vfs = Vfs::new();
+        vfs.add_file("/tmp/a", b"abcdef".to_vec());
+        let fd = vfs.open("/tmp/a", O_RDONLY).unwrap();
+        let copy = vfs.dup(fd).unwrap();
+        assert_eq!(vfs.read(fd, 2).unwrap(), b"ab");
+        assert_eq!(vfs.read(copy, 2).unwrap(), b"cd");
+
+        vfs.close(fd).unwrap();
+        assert_eq!(vfs.read(copy, 2).unwrap(), b"ef");
+        vfs.close(copy).unwrap();
+        assert_eq!(vfs.open_files.len(), 3);
+    }
+
+    #[test]
+    fn seek_and_write_bounds() {
+        let mut vfs = Vfs::new();
+        let fd = vfs.open("/tmp/a", O_CREAT | O_RDWR).unwrap();
+        assert_eq!(vfs.seek(fd, 1 << 40, SEEK_SET), Ok(1 << 40));
+        assert_eq!(vfs.write(fd, b"x"), Err(EFBIG));
+        assert_eq!(vfs.seek(fd, i64::max_value(), SEEK_CUR), Err(EINVAL));
+        assert_eq!(vfs.seek(fd, 2, SEEK_SET), Ok(2));
+        assert_eq!(vfs.write(fd, b"x"), Ok(1));
+        assert_eq!(vfs.file("/tmp/a").unwrap(), &vec![0, 0, b'x']);
+    }
+}